@@ -46,7 +46,8 @@ impl<'a> BVH<'a> {
         bounder: F,
     ) -> BVH<'a>
     where
-        F: 'b + Fn(&T) -> &'b [BBox],
+        T: Send,
+        F: 'b + Fn(&T) -> &'b [BBox] + Sync,
     {
         if objects.is_empty() {
             BVH {
@@ -207,6 +208,8 @@ impl<'a> BVH<'a> {
 
                 node
             }
+
+            BVHBaseNode::TimeSplit { .. } => unreachable!("time splits are only built for BVH4s"),
         }
     }
 }
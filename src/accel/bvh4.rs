@@ -1,6 +1,7 @@
 #![allow(dead_code)]
 
 use std;
+use std::mem;
 
 use mem_arena::MemArena;
 
@@ -15,6 +16,7 @@ use bvh_order::{TRAVERSAL_TABLE, SplitAxes, calc_traversal_code};
 use super::bvh_base::{BVHBase, BVHBaseNode, BVH_MAX_DEPTH};
 use super::ACCEL_TRAV_TIME;
 use super::ACCEL_NODE_RAY_TESTS;
+use super::record_bvh_memory;
 
 
 #[derive(Copy, Clone, Debug)]
@@ -37,6 +39,14 @@ pub enum BVH4Node<'a> {
         bounds_len: u16,
         object_range: (usize, usize),
     },
+
+    // Splits the node's time interval in half between its two children.
+    // See `BVHBaseNode::TimeSplit`.
+    Time {
+        bounds_start: &'a BBox,
+        bounds_len: u16,
+        children: &'a [BVH4Node<'a>],
+    },
 }
 
 impl<'a> BVH4<'a> {
@@ -47,7 +57,8 @@ impl<'a> BVH4<'a> {
         bounder: F,
     ) -> BVH4<'a>
     where
-        F: 'b + Fn(&T) -> &'b [BBox],
+        T: Send,
+        F: 'b + Fn(&T) -> &'b [BBox] + Sync,
     {
         if objects.is_empty() {
             BVH4 {
@@ -66,6 +77,93 @@ impl<'a> BVH4<'a> {
         }
     }
 
+    /// Like `from_objects()`, but builds the BVH with spatial splits.
+    ///
+    /// Objects may be referenced by more than one leaf, so rather than
+    /// reordering `objects`, this returns the references for the leaves,
+    /// which should be passed to `traverse()` instead.  See
+    /// `BVHBase::from_objects_with_spatial_splits()` for details.
+    pub fn from_objects_with_spatial_splits<T, F, C>(
+        arena: &'a MemArena,
+        objects: &[T],
+        objects_per_leaf: usize,
+        bounder: F,
+        clipper: C,
+    ) -> (BVH4<'a>, Vec<T>)
+    where
+        T: Copy,
+        F: Fn(&T) -> BBox,
+        C: Fn(&T, usize, f32) -> (BBox, BBox),
+    {
+        if objects.is_empty() {
+            (
+                BVH4 {
+                    root: None,
+                    depth: 0,
+                },
+                Vec::new(),
+            )
+        } else {
+            let (base, refs) = BVHBase::from_objects_with_spatial_splits(
+                objects,
+                objects_per_leaf,
+                bounder,
+                clipper,
+            );
+
+            let mut root = unsafe { arena.alloc_uninitialized::<BVH4Node>() };
+            BVH4::construct_from_base(arena, &base, base.root_node_index(), root);
+            (
+                BVH4 {
+                    root: Some(root),
+                    depth: base.depth,
+                },
+                refs,
+            )
+        }
+    }
+
+    /// Like `from_objects()`, but builds the BVH with time splits, for
+    /// objects that move a lot over the shutter interval.
+    ///
+    /// Objects may be referenced by more than one leaf, so rather than
+    /// reordering `objects`, this returns the references for the leaves,
+    /// which should be passed to `traverse()` instead.  See
+    /// `BVHBase::from_objects_with_time_splits()` for details.
+    pub fn from_objects_with_time_splits<'b, T, F>(
+        arena: &'a MemArena,
+        objects: &[T],
+        objects_per_leaf: usize,
+        bounder: F,
+    ) -> (BVH4<'a>, Vec<T>)
+    where
+        T: Copy,
+        F: 'b + Fn(&T) -> &'b [BBox],
+    {
+        if objects.is_empty() {
+            (
+                BVH4 {
+                    root: None,
+                    depth: 0,
+                },
+                Vec::new(),
+            )
+        } else {
+            let (base, refs) =
+                BVHBase::from_objects_with_time_splits(objects, objects_per_leaf, bounder);
+
+            let root = unsafe { arena.alloc_uninitialized::<BVH4Node>() };
+            BVH4::construct_from_base(arena, &base, base.root_node_index(), root);
+            (
+                BVH4 {
+                    root: Some(root),
+                    depth: base.depth,
+                },
+                refs,
+            )
+        }
+    }
+
     pub fn tree_depth(&self) -> usize {
         self.depth
     }
@@ -96,6 +194,9 @@ impl<'a> BVH4<'a> {
         // +2 of max depth for root and last child
         let mut node_stack = [self.root.unwrap(); (BVH_MAX_DEPTH * 3) + 2];
         let mut ray_i_stack = [rays.len(); (BVH_MAX_DEPTH * 3) + 2];
+        // The time interval each node's bounds are sampled over, which is
+        // only narrower than the whole shutter interval under time splits.
+        let mut time_stack = [(0.0f32, 1.0f32); (BVH_MAX_DEPTH * 3) + 2];
         let mut stack_ptr = 1;
 
         while stack_ptr > 0 {
@@ -109,9 +210,11 @@ impl<'a> BVH4<'a> {
                 } => {
                     let bounds =
                         unsafe { std::slice::from_raw_parts(bounds_start, bounds_len as usize) };
-                    let part = partition(&mut rays[..ray_i_stack[stack_ptr]], |r| {
-                        (!r.is_done()) && lerp_slice(bounds, r.time).intersect_accel_ray(r)
-                    });
+                    let part = partition_rays(
+                        &mut rays[..ray_i_stack[stack_ptr]],
+                        bounds,
+                        time_stack[stack_ptr],
+                    );
                     if part > 0 {
                         let order_code = traversal_table[traversal_code as usize];
                         match children.len() {
@@ -162,6 +265,48 @@ impl<'a> BVH4<'a> {
                             }
                             _ => unreachable!(),
                         }
+
+                        // The children share the node's time interval.
+                        let interval = time_stack[stack_ptr + 1 - children.len()];
+                        for i in 1..children.len() {
+                            time_stack[stack_ptr + 1 - i] = interval;
+                        }
+                    } else {
+                        stack_ptr -= 1;
+                    }
+                }
+
+                BVH4Node::Time {
+                    bounds_start,
+                    bounds_len,
+                    children,
+                } => {
+                    let bounds =
+                        unsafe { std::slice::from_raw_parts(bounds_start, bounds_len as usize) };
+                    let part = partition_rays(
+                        &mut rays[..ray_i_stack[stack_ptr]],
+                        bounds,
+                        time_stack[stack_ptr],
+                    );
+                    if part > 0 {
+                        let (t0, t1) = time_stack[stack_ptr];
+                        let t_mid = (t0 + t1) * 0.5;
+
+                        // The first half is traversed first, so its rays can
+                        // be moved to the front for it.  The second half
+                        // still has to filter out the first half's rays.
+                        let part_first = partition(&mut rays[..part], |r| r.time <= t_mid);
+
+                        ray_i_stack[stack_ptr] = part;
+                        ray_i_stack[stack_ptr + 1] = part_first;
+
+                        node_stack[stack_ptr] = &children[1];
+                        node_stack[stack_ptr + 1] = &children[0];
+
+                        time_stack[stack_ptr] = (t_mid, t1);
+                        time_stack[stack_ptr + 1] = (t0, t_mid);
+
+                        stack_ptr += 1;
                     } else {
                         stack_ptr -= 1;
                     }
@@ -174,9 +319,11 @@ impl<'a> BVH4<'a> {
                 } => {
                     let bounds =
                         unsafe { std::slice::from_raw_parts(bounds_start, bounds_len as usize) };
-                    let part = partition(&mut rays[..ray_i_stack[stack_ptr]], |r| {
-                        (!r.is_done()) && lerp_slice(bounds, r.time).intersect_accel_ray(r)
-                    });
+                    let part = partition_rays(
+                        &mut rays[..ray_i_stack[stack_ptr]],
+                        bounds,
+                        time_stack[stack_ptr],
+                    );
 
                     trav_time += timer.tick() as f64;
 
@@ -240,7 +387,8 @@ impl<'a> BVH4<'a> {
                                 child_indices = [i_l.0, i_l.1, i_r.0, i_r.1];
                                 split_info = SplitAxes::Full((split_axis, s_l, s_r));
                             }
-                            BVHBaseNode::Leaf { .. } => {
+                            BVHBaseNode::Leaf { .. } |
+                            BVHBaseNode::TimeSplit { .. } => {
                                 // Three nodes with left split
                                 child_count = 3;
                                 child_indices = [i_l.0, i_l.1, children_indices.1, 0];
@@ -248,7 +396,8 @@ impl<'a> BVH4<'a> {
                             }
                         }
                     }
-                    BVHBaseNode::Leaf { .. } => {
+                    BVHBaseNode::Leaf { .. } |
+                    BVHBaseNode::TimeSplit { .. } => {
                         match *child_r {
                             BVHBaseNode::Internal {
                                 children_indices: i_r,
@@ -260,7 +409,8 @@ impl<'a> BVH4<'a> {
                                 child_indices = [children_indices.0, i_r.0, i_r.1, 0];
                                 split_info = SplitAxes::Right((split_axis, s_r));
                             }
-                            BVHBaseNode::Leaf { .. } => {
+                            BVHBaseNode::Leaf { .. } |
+                            BVHBaseNode::TimeSplit { .. } => {
                                 // Two nodes
                                 child_count = 2;
                                 child_indices = [children_indices.0, children_indices.1, 0, 0];
@@ -284,6 +434,9 @@ impl<'a> BVH4<'a> {
                     BVH4::construct_from_base(arena, base, child_indices[i], &mut children_mem[i]);
                 }
 
+                let bytes = mem::size_of::<BVH4Node>() + (bounds.len() * mem::size_of::<BBox>());
+                record_bvh_memory(bytes, bytes);
+
                 // Fill in node
                 *node_mem = BVH4Node::Inner {
                     traversal_code: calc_traversal_code(split_info),
@@ -299,16 +452,64 @@ impl<'a> BVH4<'a> {
             } => {
                 let bounds = arena.copy_slice(&base.bounds[bounds_range.0..bounds_range.1]);
 
+                let bytes = mem::size_of::<BVH4Node>() + (bounds.len() * mem::size_of::<BBox>());
+                record_bvh_memory(bytes, bytes);
+
                 *node_mem = BVH4Node::Leaf {
                     bounds_start: &bounds[0],
                     bounds_len: bounds.len() as u16,
                     object_range: object_range,
                 };
             }
+
+            BVHBaseNode::TimeSplit {
+                bounds_range,
+                children_indices,
+            } => {
+                let bounds = arena.copy_slice_with_alignment(
+                    &base.bounds[bounds_range.0..bounds_range.1],
+                    32,
+                );
+
+                let children_mem =
+                    unsafe { arena.alloc_array_uninitialized_with_alignment::<BVH4Node>(2, 32) };
+                BVH4::construct_from_base(arena, base, children_indices.0, &mut children_mem[0]);
+                BVH4::construct_from_base(arena, base, children_indices.1, &mut children_mem[1]);
+
+                let bytes = mem::size_of::<BVH4Node>() + (bounds.len() * mem::size_of::<BBox>());
+                record_bvh_memory(bytes, bytes);
+
+                *node_mem = BVH4Node::Time {
+                    bounds_start: &bounds[0],
+                    bounds_len: bounds.len() as u16,
+                    children: children_mem,
+                };
+            }
         }
     }
 }
 
+
+/// Partitions the rays that hit `bounds` to the front of `rays`, returning
+/// how many there are.  `bounds` are time samples over the `interval` of the
+/// shutter, and rays outside of it are treated as misses.
+#[inline(always)]
+fn partition_rays(rays: &mut [AccelRay], bounds: &[BBox], interval: (f32, f32)) -> usize {
+    if interval == (0.0, 1.0) {
+        partition(rays, |r| {
+            (!r.is_done()) && lerp_slice(bounds, r.time).intersect_accel_ray(r)
+        })
+    } else {
+        let (t0, t1) = interval;
+        partition(rays, |r| {
+            // Rays outside of the whole shutter interval belong to its ends.
+            let time = r.time.max(0.0).min(1.0);
+            (!r.is_done()) && time >= t0 && time <= t1 &&
+                lerp_slice(bounds, (time - t0) / (t1 - t0)).intersect_accel_ray(r)
+        })
+    }
+}
+
 lazy_static! {
     static ref DEGENERATE_BOUNDS: [BBox; 1] = [BBox::new()];
 }
@@ -328,9 +529,96 @@ impl<'a> Boundable for BVH4<'a> {
                         bounds_start,
                         bounds_len,
                         ..
+                    } |
+                    BVH4Node::Time {
+                        bounds_start,
+                        bounds_len,
+                        ..
                     } => unsafe { std::slice::from_raw_parts(bounds_start, bounds_len as usize) },
                 }
             }
         }
     }
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use math::{Point, Vector};
+    use ray::Ray;
+
+    fn has_time_split(node: &BVH4Node) -> bool {
+        match *node {
+            BVH4Node::Inner { children, .. } => children.iter().any(|c| has_time_split(c)),
+            BVH4Node::Leaf { .. } => false,
+            BVH4Node::Time { .. } => true,
+        }
+    }
+
+    #[test]
+    fn time_splits_traverse_matches() {
+        let arena = MemArena::new();
+
+        // Small boxes that each sweep a long way across the scene over the
+        // shutter interval, in alternating directions.
+        let bounds: Vec<BBox> = (0..200)
+            .flat_map(|i| {
+                let x = ((i * 37) % 101) as f32 * 0.13;
+                let y = ((i * 53) % 97) as f32 * 0.13;
+                let z = (i % 13) as f32 * 0.13;
+                let dx = if i % 2 == 0 { 8.0 } else { -8.0 };
+                let bb1 =
+                    BBox::from_points(Point::new(x, y, z), Point::new(x + 0.3, y + 0.2, z + 0.6));
+                let bb2 = BBox::from_points(
+                    Point::new(x + dx, y, z),
+                    Point::new(x + dx + 0.3, y + 0.2, z + 0.6),
+                );
+                vec![bb1, bb2]
+            })
+            .collect();
+        let bounder = |i: &usize| &bounds[(*i * 2)..((*i + 1) * 2)];
+
+        let mut objects: Vec<usize> = (0..(bounds.len() / 2)).collect();
+        let bvh = BVH4::from_objects(&arena, &mut objects[..], 1, bounder);
+        let (bvh_ts, refs) = BVH4::from_objects_with_time_splits(&arena, &objects, 1, bounder);
+        assert!(has_time_split(bvh_ts.root.unwrap()));
+
+        let mut rays: Vec<_> = (0..200)
+            .map(|i| {
+                let ray = Ray::new(
+                    Point::new(-10.0, (i % 10) as f32 * 1.3 + 0.05, 0.75),
+                    Vector::new(1.0, 0.02 * ((i / 10) % 10) as f32 + 0.001, 0.01),
+                    (i / 100) as f32 * 0.3 + (i % 7) as f32 * 0.1,
+                    50.0,
+                    false,
+                );
+                AccelRay::new(&ray, i)
+            })
+            .collect();
+
+        // Collect which objects each ray hits at its time.  Objects can be
+        // referenced from both sides of a time split, so duplicates are
+        // removed.
+        let mut hits = vec![Vec::new(); rays.len()];
+        bvh.traverse(&mut rays[..], &objects, |obj, rs| for r in rs {
+            if lerp_slice(bounder(obj), r.time).intersect_accel_ray(r) {
+                hits[r.id as usize].push(*obj);
+            }
+        });
+        let mut hits_ts = vec![Vec::new(); rays.len()];
+        bvh_ts.traverse(&mut rays[..], &refs, |obj, rs| for r in rs {
+            if lerp_slice(bounder(obj), r.time).intersect_accel_ray(r) {
+                hits_ts[r.id as usize].push(*obj);
+            }
+        });
+
+        for (h, h_ts) in hits.iter_mut().zip(hits_ts.iter_mut()) {
+            h.sort();
+            h_ts.sort();
+            h_ts.dedup();
+        }
+        assert!(hits.iter().any(|h| h.len() > 1));
+        assert_eq!(hits, hits_ts);
+    }
+}
@@ -0,0 +1,452 @@
+#![allow(dead_code)]
+
+use std;
+use std::mem;
+
+use mem_arena::MemArena;
+
+use algorithm::{partition, merged_len};
+use bbox::BBox;
+use bbox8::BBox8;
+use boundable::Boundable;
+use lerp::lerp_slice;
+use ray::AccelRay;
+use timer::Timer;
+
+use bvh_order::{calc_traversal_code_8, traversal_order_8};
+use super::bvh_base::{BVHBase, BVHBaseNode, BVH_MAX_DEPTH};
+use super::ACCEL_TRAV_TIME;
+use super::ACCEL_NODE_RAY_TESTS;
+use super::record_bvh_memory;
+
+
+/// An eight-wide BVH, collapsed from three levels of a binary BVH at a time.
+///
+/// Inner nodes store the bounds of all of their children together, so that
+/// rays are tested against all of them at once with 8-wide SIMD.
+#[derive(Copy, Clone, Debug)]
+pub struct BVH8<'a> {
+    root: Option<&'a BVH8Node<'a>>,
+    depth: usize,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub enum BVH8Node<'a> {
+    Inner {
+        traversal_code: u8,
+        split_axes: [u8; 3], // Which BVH2 nodes were split on x, y, and z
+        bounds_start: &'a BBox,
+        bounds_len: u16,
+        children_bounds: &'a [BBox8], // Time samples of the children's bounds
+        children: &'a [BVH8Node<'a>],
+    },
+
+    Leaf {
+        bounds_start: &'a BBox,
+        bounds_len: u16,
+        object_range: (usize, usize),
+    },
+}
+
+impl<'a> BVH8<'a> {
+    pub fn from_objects<'b, T, F>(
+        arena: &'a MemArena,
+        objects: &mut [T],
+        objects_per_leaf: usize,
+        bounder: F,
+    ) -> BVH8<'a>
+    where
+        T: Send,
+        F: 'b + Fn(&T) -> &'b [BBox] + Sync,
+    {
+        if objects.is_empty() {
+            BVH8 {
+                root: None,
+                depth: 0,
+            }
+        } else {
+            let base = BVHBase::from_objects(objects, objects_per_leaf, bounder);
+
+            let root = unsafe { arena.alloc_uninitialized::<BVH8Node>() };
+            BVH8::construct_from_base(arena, &base, base.root_node_index(), root);
+            BVH8 {
+                root: Some(root),
+                depth: base.depth,
+            }
+        }
+    }
+
+    /// Like `from_objects()`, but builds the BVH with spatial splits.  See
+    /// `BVH4::from_objects_with_spatial_splits()` for details.
+    pub fn from_objects_with_spatial_splits<T, F, C>(
+        arena: &'a MemArena,
+        objects: &[T],
+        objects_per_leaf: usize,
+        bounder: F,
+        clipper: C,
+    ) -> (BVH8<'a>, Vec<T>)
+    where
+        T: Copy,
+        F: Fn(&T) -> BBox,
+        C: Fn(&T, usize, f32) -> (BBox, BBox),
+    {
+        if objects.is_empty() {
+            (
+                BVH8 {
+                    root: None,
+                    depth: 0,
+                },
+                Vec::new(),
+            )
+        } else {
+            let (base, refs) = BVHBase::from_objects_with_spatial_splits(
+                objects,
+                objects_per_leaf,
+                bounder,
+                clipper,
+            );
+
+            let root = unsafe { arena.alloc_uninitialized::<BVH8Node>() };
+            BVH8::construct_from_base(arena, &base, base.root_node_index(), root);
+            (
+                BVH8 {
+                    root: Some(root),
+                    depth: base.depth,
+                },
+                refs,
+            )
+        }
+    }
+
+    pub fn tree_depth(&self) -> usize {
+        self.depth
+    }
+
+    pub fn traverse<T, F>(&self, rays: &mut [AccelRay], objects: &[T], mut obj_ray_test: F)
+    where
+        F: FnMut(&T, &mut [AccelRay]),
+    {
+        if self.root.is_none() {
+            return;
+        }
+
+        let mut timer = Timer::new();
+        let mut trav_time: f64 = 0.0;
+        let mut node_tests: u64 = 0;
+
+        // Bitmasks for each axis of whether to visit the children of the
+        // BVH2 nodes split on that axis in reverse.
+        let reverse_masks = [
+            if rays[0].dir_inv.x() < 0.0 { 0xff } else { 0 },
+            if rays[0].dir_inv.y() < 0.0 { 0xff } else { 0 },
+            if rays[0].dir_inv.z() < 0.0 { 0xff } else { 0 },
+        ];
+
+        // +2 of max depth for root and last child
+        let mut node_stack = [self.root.unwrap(); (BVH_MAX_DEPTH * 7) + 2];
+        let mut ray_i_stack = [rays.len(); (BVH_MAX_DEPTH * 7) + 2];
+        let mut stack_ptr = 1;
+
+        while stack_ptr > 0 {
+            node_tests += ray_i_stack[stack_ptr] as u64;
+            match *node_stack[stack_ptr] {
+                BVH8Node::Inner {
+                    traversal_code,
+                    split_axes,
+                    children_bounds,
+                    children,
+                    ..
+                } => {
+                    // Rays that hit any of the children.  Each child tests
+                    // the rays again itself, either against its own children
+                    // or, for leaves, against its own bounds.
+                    let part = partition(&mut rays[..ray_i_stack[stack_ptr]], |r| {
+                        (!r.is_done()) &&
+                            lerp_slice(children_bounds, r.time).intersect_accel_ray(r) != 0
+                    });
+                    if part > 0 {
+                        let reversed = (split_axes[0] & reverse_masks[0]) |
+                            (split_axes[1] & reverse_masks[1]) |
+                            (split_axes[2] & reverse_masks[2]);
+                        let order = traversal_order_8(traversal_code, reversed);
+
+                        // Push the children so that the first to visit is on
+                        // top of the stack.
+                        let child_count = children.len();
+                        for i in 0..child_count {
+                            let ci = (order >> ((child_count - 1 - i) * 4)) & 0xf;
+                            node_stack[stack_ptr + i] = &children[ci as usize];
+                            ray_i_stack[stack_ptr + i] = part;
+                        }
+                        stack_ptr += child_count - 1;
+                    } else {
+                        stack_ptr -= 1;
+                    }
+                }
+
+                BVH8Node::Leaf {
+                    object_range,
+                    bounds_start,
+                    bounds_len,
+                } => {
+                    let bounds =
+                        unsafe { std::slice::from_raw_parts(bounds_start, bounds_len as usize) };
+                    let part = partition(&mut rays[..ray_i_stack[stack_ptr]], |r| {
+                        (!r.is_done()) && lerp_slice(bounds, r.time).intersect_accel_ray(r)
+                    });
+
+                    trav_time += timer.tick() as f64;
+
+                    if part > 0 {
+                        for obj in &objects[object_range.0..object_range.1] {
+                            obj_ray_test(obj, &mut rays[..part]);
+                        }
+                    }
+
+                    timer.tick();
+
+                    stack_ptr -= 1;
+                }
+            }
+        }
+
+        trav_time += timer.tick() as f64;
+        ACCEL_TRAV_TIME.with(|att| {
+            let v = att.get();
+            att.set(v + trav_time);
+        });
+        ACCEL_NODE_RAY_TESTS.with(|anv| {
+            let v = anv.get();
+            anv.set(v + node_tests);
+        });
+    }
+
+    fn construct_from_base(
+        arena: &'a MemArena,
+        base: &BVHBase,
+        node_index: usize,
+        node_mem: &mut BVH8Node<'a>,
+    ) {
+        match base.nodes[node_index] {
+            BVHBaseNode::Internal { bounds_range, .. } => {
+                // Gather up to three levels of BVH2 nodes below this one
+                let mut internal_nodes = 0u8;
+                let mut split_axes = [0u8; 3];
+                let mut child_indices = Vec::with_capacity(8);
+                BVH8::gather_children(
+                    base,
+                    node_index,
+                    0,
+                    &mut internal_nodes,
+                    &mut split_axes,
+                    &mut child_indices,
+                );
+
+                // Copy bounds
+                let bounds = arena.copy_slice(&base.bounds[bounds_range.0..bounds_range.1]);
+
+                // Combine the children's bounds, using enough time samples
+                // for all of them.
+                let children_bounds = {
+                    let child_bounds: Vec<_> = child_indices
+                        .iter()
+                        .map(|ci| {
+                            let range = base.nodes[*ci].bounds_range();
+                            &base.bounds[range.0..range.1]
+                        })
+                        .collect();
+                    let sample_count = child_bounds.iter().fold(1, |len, cb| {
+                        merged_len(len, cb.len())
+                    });
+                    let samples: Vec<_> = (0..sample_count)
+                        .map(|si| {
+                            let time = if sample_count > 1 {
+                                si as f32 / (sample_count - 1) as f32
+                            } else {
+                                0.0
+                            };
+                            let bbs: Vec<_> =
+                                child_bounds.iter().map(|cb| lerp_slice(cb, time)).collect();
+                            BBox8::from_bboxes(&bbs)
+                        })
+                        .collect();
+                    arena.copy_slice_with_alignment(&samples, 32)
+                };
+
+                // Build children
+                let children_mem = unsafe {
+                    arena.alloc_array_uninitialized_with_alignment::<BVH8Node>(
+                        child_indices.len(),
+                        32,
+                    )
+                };
+                for (i, ci) in child_indices.iter().enumerate() {
+                    BVH8::construct_from_base(arena, base, *ci, &mut children_mem[i]);
+                }
+
+                let bytes = mem::size_of::<BVH8Node>() + (bounds.len() * mem::size_of::<BBox>()) +
+                    (children_bounds.len() * mem::size_of::<BBox8>());
+                record_bvh_memory(bytes, bytes);
+
+                // Fill in node
+                *node_mem = BVH8Node::Inner {
+                    traversal_code: calc_traversal_code_8(internal_nodes),
+                    split_axes: split_axes,
+                    bounds_start: &bounds[0],
+                    bounds_len: bounds.len() as u16,
+                    children_bounds: children_bounds,
+                    children: children_mem,
+                };
+            }
+
+            BVHBaseNode::Leaf {
+                bounds_range,
+                object_range,
+            } => {
+                let bounds = arena.copy_slice(&base.bounds[bounds_range.0..bounds_range.1]);
+
+                let bytes = mem::size_of::<BVH8Node>() + (bounds.len() * mem::size_of::<BBox>());
+                record_bvh_memory(bytes, bytes);
+
+                *node_mem = BVH8Node::Leaf {
+                    bounds_start: &bounds[0],
+                    bounds_len: bounds.len() as u16,
+                    object_range: object_range,
+                };
+            }
+
+            BVHBaseNode::TimeSplit { .. } => unreachable!("time splits are only built for BVH4s"),
+        }
+    }
+
+    /// Collects the nodes that become the children of a BVH8 node, in left
+    /// to right order, by descending up to three levels into the BVH2.
+    ///
+    /// `heap_index` numbers the BVH2 nodes as described for
+    /// `calc_traversal_code_8()`, and the internal ones are recorded in
+    /// `internal_nodes` and, by split axis, in `split_axes`.
+    fn gather_children(
+        base: &BVHBase,
+        node_index: usize,
+        heap_index: usize,
+        internal_nodes: &mut u8,
+        split_axes: &mut [u8; 3],
+        child_indices: &mut Vec<usize>,
+    ) {
+        match base.nodes[node_index] {
+            BVHBaseNode::Internal {
+                children_indices,
+                split_axis,
+                ..
+            } if heap_index < 7 => {
+                *internal_nodes |= 1 << heap_index;
+                split_axes[split_axis as usize] |= 1 << heap_index;
+                BVH8::gather_children(
+                    base,
+                    children_indices.0,
+                    (heap_index * 2) + 1,
+                    internal_nodes,
+                    split_axes,
+                    child_indices,
+                );
+                BVH8::gather_children(
+                    base,
+                    children_indices.1,
+                    (heap_index * 2) + 2,
+                    internal_nodes,
+                    split_axes,
+                    child_indices,
+                );
+            }
+
+            _ => child_indices.push(node_index),
+        }
+    }
+}
+
+lazy_static! {
+    static ref DEGENERATE_BOUNDS: [BBox; 1] = [BBox::new()];
+}
+
+impl<'a> Boundable for BVH8<'a> {
+    fn bounds(&self) -> &[BBox] {
+        match self.root {
+            None => &DEGENERATE_BOUNDS[..],
+            Some(root) => {
+                match *root {
+                    BVH8Node::Inner {
+                        bounds_start,
+                        bounds_len,
+                        ..
+                    } |
+                    BVH8Node::Leaf {
+                        bounds_start,
+                        bounds_len,
+                        ..
+                    } => unsafe { std::slice::from_raw_parts(bounds_start, bounds_len as usize) },
+                }
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use math::{Point, Vector};
+    use ray::Ray;
+    use accel::BVH4;
+
+    #[test]
+    fn traverse_matches_bvh4() {
+        let arena = MemArena::new();
+        let bounds: Vec<BBox> = (0..200)
+            .map(|i| {
+                let x = ((i * 37) % 101) as f32;
+                let y = ((i * 53) % 97) as f32;
+                let z = (i % 13) as f32;
+                BBox::from_points(Point::new(x, y, z), Point::new(x + 3.0, y + 2.0, z + 6.0))
+            })
+            .collect();
+        let mut objects4: Vec<usize> = (0..bounds.len()).collect();
+        let mut objects8 = objects4.clone();
+        let bvh4 = BVH4::from_objects(&arena, &mut objects4[..], 1, |i| &bounds[*i..(*i + 1)]);
+        let bvh8 = BVH8::from_objects(&arena, &mut objects8[..], 1, |i| &bounds[*i..(*i + 1)]);
+
+        let mut rays: Vec<_> = (0..100)
+            .map(|i| {
+                let ray = Ray::new(
+                    Point::new(-10.0, (i % 10) as f32 * 10.0 + 0.5, 5.5),
+                    Vector::new(1.0, 0.02 * (i / 10) as f32 + 0.001, 0.01),
+                    0.0,
+                    500.0,
+                    false,
+                );
+                AccelRay::new(&ray, i)
+            })
+            .collect();
+
+        // Collect which objects each ray reaches
+        let mut hits4 = vec![Vec::new(); rays.len()];
+        bvh4.traverse(&mut rays[..], &objects4, |obj, rs| for r in rs {
+            if bounds[*obj].intersect_accel_ray(r) {
+                hits4[r.id as usize].push(*obj);
+            }
+        });
+        let mut hits8 = vec![Vec::new(); rays.len()];
+        bvh8.traverse(&mut rays[..], &objects8, |obj, rs| for r in rs {
+            if bounds[*obj].intersect_accel_ray(r) {
+                hits8[r.id as usize].push(*obj);
+            }
+        });
+
+        for (h4, h8) in hits4.iter_mut().zip(hits8.iter_mut()) {
+            h4.sort();
+            h8.sort();
+        }
+        assert!(hits4.iter().any(|h| h.len() > 1));
+        assert_eq!(hits4, hits8);
+        assert_eq!(bvh4.bounds()[0].min, bvh8.bounds()[0].min);
+    }
+}
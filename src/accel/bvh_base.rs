@@ -1,11 +1,17 @@
 #![allow(dead_code)]
 
+use std::cmp;
+
+use crossbeam;
+
 use algorithm::merge_slices_append;
 use bbox::BBox;
 use lerp::lerp_slice;
 use math::log2_64;
 
+use super::{ThreadBudget, build_threads};
 use super::objects_split::{sah_split, median_split};
+use super::spatial_split::{SplitRef, Split, SPATIAL_SPLIT_BUDGET, find_split, apply_split};
 
 
 pub const BVH_MAX_DEPTH: usize = 42;
@@ -15,6 +21,14 @@ pub const BVH_MAX_DEPTH: usize = 42;
 // individual time samples.
 const USE_UNION_FACTOR: f32 = 1.4;
 
+// The most time splits there can be along any path from the root to a
+// leaf.  Each one duplicates the references to the objects under it.
+const MAX_TIME_SPLITS: usize = 2;
+
+// Minimum number of objects a node needs for its children to be built on
+// separate threads.  Below this, the threading overhead isn't worth it.
+pub const PARALLEL_BUILD_MIN_OBJECTS: usize = 1 << 12;
+
 /// An intermediary structure for creating a BVH.
 #[derive(Debug)]
 pub struct BVHBase {
@@ -36,17 +50,35 @@ pub enum BVHBaseNode {
         bounds_range: (usize, usize),
         object_range: (usize, usize),
     },
+
+    // Splits the node's time interval in half, with the first child
+    // covering the first half and the second child the second half.  The
+    // bounds of the nodes under each child are time samples over its half.
+    TimeSplit {
+        bounds_range: (usize, usize),
+        children_indices: (usize, usize),
+    },
 }
 
 impl BVHBaseNode {
     pub fn bounds_range(&self) -> (usize, usize) {
         match *self {
             BVHBaseNode::Internal { bounds_range, .. } |
-            BVHBaseNode::Leaf { bounds_range, .. } => bounds_range,
+            BVHBaseNode::Leaf { bounds_range, .. } |
+            BVHBaseNode::TimeSplit { bounds_range, .. } => bounds_range,
         }
     }
 }
 
+
+// A reference to an object for building with time splits, with the object's
+// bounds over the time interval of the node being built.
+#[derive(Copy, Clone, Debug)]
+struct TimeRef<T> {
+    object: T,
+    bounds: (usize, usize), // Range in the bounds pool of the node's refs
+}
+
 impl BVHBase {
     fn new() -> BVHBase {
         BVHBase {
@@ -57,15 +89,124 @@ impl BVHBase {
         }
     }
 
+    /// Builds a BVH over `objects`, reordering them to match its leaves.
+    ///
+    /// The children of large nodes are built on separate threads while
+    /// there are threads left in the shared `build_threads()` budget.
     pub fn from_objects<'b, T, F>(objects: &mut [T], objects_per_leaf: usize, bounder: F) -> BVHBase
     where
-        F: 'b + Fn(&T) -> &'b [BBox],
+        T: Send,
+        F: 'b + Fn(&T) -> &'b [BBox] + Sync,
     {
         let mut bvh = BVHBase::new();
-        bvh.recursive_build(0, 0, objects_per_leaf, objects, &bounder);
+        bvh.recursive_build(
+            0,
+            0,
+            objects_per_leaf,
+            objects,
+            &bounder,
+            build_threads(),
+        );
         bvh
     }
 
+    /// Builds a BVH over `objects` using spatial splits, which split
+    /// objects that straddle a splitting plane into separate references
+    /// for each side.  This gives much tighter nodes for long, thin, or
+    /// overlapping objects, at the cost of some objects being referenced by
+    /// more than one leaf.
+    ///
+    /// Returns the BVH along with the references its leaves index into.
+    /// `clipper` returns the bounds of the parts of an object on either side
+    /// of a plane along an axis.  Only a single bounds time sample is
+    /// supported, and the build isn't multi-threaded.
+    pub fn from_objects_with_spatial_splits<T, F, C>(
+        objects: &[T],
+        objects_per_leaf: usize,
+        bounder: F,
+        clipper: C,
+    ) -> (BVHBase, Vec<T>)
+    where
+        T: Copy,
+        F: Fn(&T) -> BBox,
+        C: Fn(&T, usize, f32) -> (BBox, BBox),
+    {
+        let refs: Vec<_> = objects
+            .iter()
+            .map(|obj| {
+                SplitRef {
+                    object: *obj,
+                    bounds: bounder(obj),
+                }
+            })
+            .collect();
+        let root_area = refs.iter()
+            .fold(BBox::new(), |bb, r| bb | r.bounds)
+            .surface_area();
+        let mut spare_refs = (objects.len() as f32 * SPATIAL_SPLIT_BUDGET) as usize;
+
+        let mut bvh = BVHBase::new();
+        let mut out_objects = Vec::with_capacity(objects.len());
+        if !refs.is_empty() {
+            bvh.recursive_build_spatial(
+                0,
+                objects_per_leaf,
+                refs,
+                root_area,
+                &mut spare_refs,
+                &clipper,
+                &mut out_objects,
+            );
+        }
+        (bvh, out_objects)
+    }
+
+    /// Builds a BVH over `objects` that can also split time, using the
+    /// surface area heuristic weighted by time to choose between splitting
+    /// the objects and splitting the node's time interval in half.  This
+    /// gives much tighter nodes for fast-moving objects, whose bounds would
+    /// otherwise sweep across large parts of the scene.
+    ///
+    /// Like `from_objects_with_spatial_splits()`, objects under a time split
+    /// are referenced from both of its sides, so this returns the references
+    /// that the leaves index into.  The build isn't multi-threaded.
+    pub fn from_objects_with_time_splits<'b, T, F>(
+        objects: &[T],
+        objects_per_leaf: usize,
+        bounder: F,
+    ) -> (BVHBase, Vec<T>)
+    where
+        T: Copy,
+        F: 'b + Fn(&T) -> &'b [BBox],
+    {
+        let mut pool = Vec::new();
+        let refs: Vec<_> = objects
+            .iter()
+            .map(|obj| {
+                let start = pool.len();
+                pool.extend_from_slice(bounder(obj));
+                TimeRef {
+                    object: *obj,
+                    bounds: (start, pool.len()),
+                }
+            })
+            .collect();
+
+        let mut bvh = BVHBase::new();
+        let mut out_objects = Vec::with_capacity(objects.len());
+        if !refs.is_empty() {
+            bvh.recursive_build_temporal(
+                0,
+                0,
+                objects_per_leaf,
+                refs,
+                &pool,
+                &mut out_objects,
+            );
+        }
+        (bvh, out_objects)
+    }
+
     pub fn root_node_index(&self) -> usize {
         0
     }
@@ -96,6 +237,60 @@ impl BVHBase {
         }
     }
 
+    /// Appends the nodes and bounds of `other`, a subtree built separately
+    /// from this one, returning the node index and bounds range of its root.
+    fn append_subtree(
+        &mut self,
+        other: BVHBase,
+        root: (usize, (usize, usize)),
+    ) -> (usize, (usize, usize)) {
+        let node_offset = self.nodes.len();
+        let bounds_offset = self.bounds.len();
+        let offset_bounds = |range: (usize, usize)| {
+            (range.0 + bounds_offset, range.1 + bounds_offset)
+        };
+
+        for node in other.nodes {
+            self.nodes.push(match node {
+                BVHBaseNode::Internal {
+                    bounds_range,
+                    children_indices,
+                    split_axis,
+                } => BVHBaseNode::Internal {
+                    bounds_range: offset_bounds(bounds_range),
+                    children_indices: (
+                        children_indices.0 + node_offset,
+                        children_indices.1 + node_offset,
+                    ),
+                    split_axis: split_axis,
+                },
+
+                BVHBaseNode::Leaf {
+                    bounds_range,
+                    object_range,
+                } => BVHBaseNode::Leaf {
+                    bounds_range: offset_bounds(bounds_range),
+                    object_range: object_range,
+                },
+
+                BVHBaseNode::TimeSplit {
+                    bounds_range,
+                    children_indices,
+                } => BVHBaseNode::TimeSplit {
+                    bounds_range: offset_bounds(bounds_range),
+                    children_indices: (
+                        children_indices.0 + node_offset,
+                        children_indices.1 + node_offset,
+                    ),
+                },
+            });
+        }
+        self.bounds.extend(other.bounds);
+        self.depth = cmp::max(self.depth, other.depth);
+
+        (root.0 + node_offset, offset_bounds(root.1))
+    }
+
     fn recursive_build<'a, T, F>(
         &mut self,
         offset: usize,
@@ -103,9 +298,11 @@ impl BVHBase {
         objects_per_leaf: usize,
         objects: &mut [T],
         bounder: &F,
+        threads: &ThreadBudget,
     ) -> (usize, (usize, usize))
     where
-        F: 'a + Fn(&T) -> &'a [BBox],
+        T: Send,
+        F: 'a + Fn(&T) -> &'a [BBox] + Sync,
     {
         let me = self.nodes.len();
 
@@ -165,21 +362,56 @@ impl BVHBase {
                     median_split(objects, &bounder)
                 };
 
-            // Create child nodes
-            let (c1_index, c1_bounds) = self.recursive_build(
-                offset,
-                depth + 1,
-                objects_per_leaf,
-                &mut objects[..split_index],
-                bounder,
-            );
-            let (c2_index, c2_bounds) = self.recursive_build(
-                offset + split_index,
-                depth + 1,
-                objects_per_leaf,
-                &mut objects[split_index..],
-                bounder,
-            );
+            // Create child nodes, building the second child on another
+            // thread if there are threads to spare and enough objects to
+            // make it worthwhile.
+            let ((c1_index, c1_bounds), (c2_index, c2_bounds)) =
+                if objects.len() >= PARALLEL_BUILD_MIN_OBJECTS && threads.take(1) == 1 {
+                    let (objects1, objects2) = objects.split_at_mut(split_index);
+                    let (c1, (subtree, c2)) = crossbeam::scope(|scope| {
+                        let handle = scope.spawn(move || {
+                            let mut subtree = BVHBase::new();
+                            let c2 = subtree.recursive_build(
+                                offset + split_index,
+                                depth + 1,
+                                objects_per_leaf,
+                                objects2,
+                                bounder,
+                                threads,
+                            );
+                            (subtree, c2)
+                        });
+                        let c1 = self.recursive_build(
+                            offset,
+                            depth + 1,
+                            objects_per_leaf,
+                            objects1,
+                            bounder,
+                            threads,
+                        );
+                        (c1, handle.join())
+                    });
+                    threads.give_back(1);
+                    (c1, self.append_subtree(subtree, c2))
+                } else {
+                    let c1 = self.recursive_build(
+                        offset,
+                        depth + 1,
+                        objects_per_leaf,
+                        &mut objects[..split_index],
+                        bounder,
+                        threads,
+                    );
+                    let c2 = self.recursive_build(
+                        offset + split_index,
+                        depth + 1,
+                        objects_per_leaf,
+                        &mut objects[split_index..],
+                        bounder,
+                        threads,
+                    );
+                    (c1, c2)
+                };
 
             // Determine bounds
             // TODO: do merging without the temporary vec.
@@ -215,4 +447,382 @@ impl BVHBase {
             return (me, (bi, self.bounds.len()));
         }
     }
+
+    fn recursive_build_spatial<T, C>(
+        &mut self,
+        depth: usize,
+        objects_per_leaf: usize,
+        mut refs: Vec<SplitRef<T>>,
+        root_area: f32,
+        spare_refs: &mut usize,
+        clipper: &C,
+        out_objects: &mut Vec<T>,
+    ) -> (usize, (usize, usize))
+    where
+        T: Copy,
+        C: Fn(&T, usize, f32) -> (BBox, BBox),
+    {
+        let me = self.nodes.len();
+        let bounds = refs.iter().fold(BBox::new(), |bb, r| bb | r.bounds);
+
+        if refs.len() <= objects_per_leaf {
+            // Leaf node
+            let bi = self.bounds.len();
+            self.bounds.push(bounds);
+            self.nodes.push(BVHBaseNode::Leaf {
+                bounds_range: (bi, bi + 1),
+                object_range: (out_objects.len(), out_objects.len() + refs.len()),
+            });
+            out_objects.extend(refs.iter().map(|r| r.object));
+
+            if self.depth < depth {
+                self.depth = depth;
+            }
+
+            return (me, (bi, bi + 1));
+        }
+
+        // Not a leaf node
+        self.nodes.push(BVHBaseNode::Internal {
+            bounds_range: (0, 0),
+            children_indices: (0, 0),
+            split_axis: 0,
+        });
+
+        // Find a split.  If we're too near the max depth, we do balanced
+        // splitting to avoid exceeding it.
+        let (split, split_axis) =
+            if (log2_64(refs.len() as u64) as usize) < (BVH_MAX_DEPTH - depth) {
+                let split = find_split(&refs, bounds, root_area, *spare_refs, clipper);
+                let axis = match split {
+                    Split::Object { axis, .. } |
+                    Split::Spatial { axis, .. } => axis,
+                    Split::Half => longest_axis(bounds),
+                };
+                (split, axis)
+            } else {
+                let axis = longest_axis(bounds);
+                refs.sort_by(|a, b| {
+                    let a = a.bounds.center().get_n(axis);
+                    let b = b.bounds.center().get_n(axis);
+                    a.partial_cmp(&b).unwrap_or(cmp::Ordering::Equal)
+                });
+                (Split::Half, axis)
+            };
+        let ref_count = refs.len();
+        let (refs1, refs2) = apply_split(refs, split, clipper);
+        *spare_refs = spare_refs.saturating_sub(refs1.len() + refs2.len() - ref_count);
+
+        // Create child nodes
+        let (c1_index, _) = self.recursive_build_spatial(
+            depth + 1,
+            objects_per_leaf,
+            refs1,
+            root_area,
+            spare_refs,
+            clipper,
+            out_objects,
+        );
+        let (c2_index, _) = self.recursive_build_spatial(
+            depth + 1,
+            objects_per_leaf,
+            refs2,
+            root_area,
+            spare_refs,
+            clipper,
+            out_objects,
+        );
+
+        // Set node
+        let bi = self.bounds.len();
+        self.bounds.push(bounds);
+        self.nodes[me] = BVHBaseNode::Internal {
+            bounds_range: (bi, bi + 1),
+            children_indices: (c1_index, c2_index),
+            split_axis: split_axis as u8,
+        };
+
+        (me, (bi, bi + 1))
+    }
+
+    // `pool` holds the bounds of `refs`, as time samples over the node's
+    // time interval, and `time_splits` is the number of time splits above
+    // the node.
+    fn recursive_build_temporal<T: Copy>(
+        &mut self,
+        depth: usize,
+        time_splits: usize,
+        objects_per_leaf: usize,
+        mut refs: Vec<TimeRef<T>>,
+        pool: &[BBox],
+        out_objects: &mut Vec<T>,
+    ) -> (usize, (usize, usize)) {
+        let me = self.nodes.len();
+        let bounds = merged_bounds(&refs, pool);
+
+        if refs.len() <= objects_per_leaf {
+            // Leaf node
+            let bounds_range = self.push_bounds(bounds);
+            self.nodes.push(BVHBaseNode::Leaf {
+                bounds_range: bounds_range,
+                object_range: (out_objects.len(), out_objects.len() + refs.len()),
+            });
+            out_objects.extend(refs.iter().map(|r| r.object));
+
+            if self.depth < depth {
+                self.depth = depth;
+            }
+
+            return (me, bounds_range);
+        }
+
+        // Not a leaf node
+        self.nodes.push(BVHBaseNode::Internal {
+            bounds_range: (0, 0),
+            children_indices: (0, 0),
+            split_axis: 0,
+        });
+
+        // Split the objects.  If we're too near the max depth, we do
+        // balanced splitting to avoid exceeding it, and don't split time.
+        let near_max_depth = (log2_64(refs.len() as u64) as usize) >= (BVH_MAX_DEPTH - depth);
+        let (split_index, split_axis) = {
+            let bounder = |r: &TimeRef<T>| &pool[r.bounds.0..r.bounds.1];
+            if near_max_depth {
+                median_split(&mut refs[..], &bounder)
+            } else {
+                sah_split(&mut refs[..], &bounder)
+            }
+        };
+
+        // Split time instead, if the node moves and it's cheaper by the
+        // surface area heuristic.  Objects are split by where they are in
+        // the middle of the node's time interval, so a time split pays off
+        // by letting each half split its objects by where they are in that
+        // half.  Its cost is therefore that of the object splits of its
+        // halves, which rays only visit for their half of the time, plus
+        // the time split node itself.
+        let can_split_time = bounds.len() > 1 && time_splits < MAX_TIME_SPLITS && !near_max_depth;
+        let time_split = if can_split_time {
+            let object_cost = split_cost(&refs, split_index, pool);
+            let mut halves = (
+                time_restricted(&refs, pool, 0.0, 0.5),
+                time_restricted(&refs, pool, 0.5, 1.0),
+            );
+            let time_cost = {
+                let half_cost = |half: &mut (Vec<TimeRef<T>>, Vec<BBox>)| {
+                    let pool = &half.1;
+                    let (split_index, _) =
+                        sah_split(&mut half.0[..], &|r: &TimeRef<T>| &pool[r.bounds.0..r.bounds.1]);
+                    split_cost(&half.0, split_index, pool)
+                };
+                time_area(&bounds) + (0.5 * (half_cost(&mut halves.0) + half_cost(&mut halves.1)))
+            };
+            if time_cost < object_cost {
+                Some(halves)
+            } else {
+                None
+            }
+        } else {
+            None
+        };
+
+        // Create child nodes
+        let is_time_split = time_split.is_some();
+        let (c1_index, c2_index) = if let Some(((refs1, pool1), (refs2, pool2))) = time_split {
+            let (c1_index, _) = self.recursive_build_temporal(
+                depth + 1,
+                time_splits + 1,
+                objects_per_leaf,
+                refs1,
+                &pool1,
+                out_objects,
+            );
+            let (c2_index, _) = self.recursive_build_temporal(
+                depth + 1,
+                time_splits + 1,
+                objects_per_leaf,
+                refs2,
+                &pool2,
+                out_objects,
+            );
+            (c1_index, c2_index)
+        } else {
+            let refs2 = refs.split_off(split_index);
+            let (c1_index, _) = self.recursive_build_temporal(
+                depth + 1,
+                time_splits,
+                objects_per_leaf,
+                refs,
+                pool,
+                out_objects,
+            );
+            let (c2_index, _) = self.recursive_build_temporal(
+                depth + 1,
+                time_splits,
+                objects_per_leaf,
+                refs2,
+                pool,
+                out_objects,
+            );
+            (c1_index, c2_index)
+        };
+
+        // Set node
+        let bounds_range = self.push_bounds(bounds);
+        self.nodes[me] = if is_time_split {
+            BVHBaseNode::TimeSplit {
+                bounds_range: bounds_range,
+                children_indices: (c1_index, c2_index),
+            }
+        } else {
+            BVHBaseNode::Internal {
+                bounds_range: bounds_range,
+                children_indices: (c1_index, c2_index),
+                split_axis: split_axis as u8,
+            }
+        };
+
+        (me, bounds_range)
+    }
+
+    /// Appends a node's bounds time samples, reducing them to their union if
+    /// it isn't much bigger, and returns their range.
+    fn push_bounds(&mut self, bounds: Vec<BBox>) -> (usize, usize) {
+        let bi = self.bounds.len();
+        let union_bounds = bounds.iter().fold(BBox::new(), |b1, b2| b1 | *b2);
+        let average_area = bounds.iter().fold(0.0, |area, bb| area + bb.surface_area()) /
+            bounds.len() as f32;
+        if union_bounds.surface_area() <= (average_area * USE_UNION_FACTOR) {
+            self.bounds.push(union_bounds);
+        } else {
+            self.bounds.extend(bounds);
+        }
+        (bi, self.bounds.len())
+    }
+}
+
+
+/// Merges the bounds of `refs`, using as many time samples as the ref with
+/// the most.
+fn merged_bounds<T>(refs: &[TimeRef<T>], pool: &[BBox]) -> Vec<BBox> {
+    let max_len = refs.iter().map(|r| r.bounds.1 - r.bounds.0).max().unwrap_or(1);
+    let mut merged = vec![BBox::new(); max_len];
+    for r in refs {
+        let bounds = &pool[r.bounds.0..r.bounds.1];
+        if bounds.len() == max_len {
+            for (m, bb) in merged.iter_mut().zip(bounds) {
+                *m |= *bb;
+            }
+        } else {
+            let s = (max_len - 1) as f32;
+            for (i, m) in merged.iter_mut().enumerate() {
+                *m |= lerp_slice(bounds, i as f32 / s);
+            }
+        }
+    }
+    merged
+}
+
+/// The cost by the surface area heuristic of splitting `refs` at
+/// `split_index`.
+fn split_cost<T>(refs: &[TimeRef<T>], split_index: usize, pool: &[BBox]) -> f32 {
+    let (refs1, refs2) = refs.split_at(split_index);
+    (time_area(&merged_bounds(refs1, pool)) * refs1.len() as f32) +
+        (time_area(&merged_bounds(refs2, pool)) * refs2.len() as f32)
+}
+
+/// The surface area of time samples of bounds, averaged over time.
+fn time_area(bounds: &[BBox]) -> f32 {
+    bounds.iter().fold(0.0, |area, bb| area + bb.surface_area()) / bounds.len() as f32
+}
+
+/// Restricts the bounds of `refs` to the part of their time interval from
+/// `time1` to `time2`, returning new refs along with their bounds pool.
+///
+/// The bounds are resampled with the same number of time samples over the
+/// shorter interval.  Between the new samples, the original bounds are
+/// piecewise linear, so the original samples that fall between new ones are
+/// merged into the new ones on either side, to keep enclosing the motion.
+fn time_restricted<T: Copy>(
+    refs: &[TimeRef<T>],
+    pool: &[BBox],
+    time1: f32,
+    time2: f32,
+) -> (Vec<TimeRef<T>>, Vec<BBox>) {
+    let mut new_pool = Vec::with_capacity(pool.len());
+    let new_refs = refs.iter()
+        .map(|r| {
+            let bounds = &pool[r.bounds.0..r.bounds.1];
+            let start = new_pool.len();
+            if bounds.len() == 1 {
+                new_pool.push(bounds[0]);
+            } else {
+                let s = (bounds.len() - 1) as f32;
+                for i in 0..bounds.len() {
+                    let time = time1 + ((time2 - time1) * (i as f32 / s));
+                    new_pool.push(lerp_slice(bounds, time));
+                }
+                for (i, bb) in bounds.iter().enumerate() {
+                    let time = i as f32 / s;
+                    if time > time1 && time < time2 {
+                        let new_i = ((time - time1) / (time2 - time1) * s) as usize;
+                        let new_i = cmp::min(new_i, bounds.len() - 2);
+                        new_pool[start + new_i] |= *bb;
+                        new_pool[start + new_i + 1] |= *bb;
+                    }
+                }
+            }
+            TimeRef {
+                object: r.object,
+                bounds: (start, new_pool.len()),
+            }
+        })
+        .collect();
+    (new_refs, new_pool)
+}
+
+
+fn longest_axis(bb: BBox) -> usize {
+    let d = bb.max - bb.min;
+    if d.x() >= d.y() && d.x() >= d.z() {
+        0
+    } else if d.y() >= d.z() {
+        1
+    } else {
+        2
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use math::Point;
+
+    #[test]
+    fn parallel_build_matches_serial() {
+        let bounds: Vec<BBox> = (0..(PARALLEL_BUILD_MIN_OBJECTS + 1000))
+            .map(|i| {
+                let x = ((i * 7919) % 1013) as f32;
+                let y = ((i * 104729) % 997) as f32;
+                let z = (i % 89) as f32;
+                BBox::from_points(Point::new(x, y, z), Point::new(x + 2.0, y + 1.0, z + 3.0))
+            })
+            .collect();
+        let bounder = |i: &usize| &bounds[*i..(*i + 1)];
+
+        let mut objects1: Vec<usize> = (0..bounds.len()).collect();
+        let mut serial = BVHBase::new();
+        serial.recursive_build(0, 0, 3, &mut objects1[..], &bounder, &ThreadBudget::new(0));
+
+        let mut objects2: Vec<usize> = (0..bounds.len()).collect();
+        let mut parallel = BVHBase::new();
+        parallel.recursive_build(0, 0, 3, &mut objects2[..], &bounder, &ThreadBudget::new(1));
+
+        assert_eq!(objects1, objects2);
+        assert_eq!(serial.depth, parallel.depth);
+        assert_eq!(format!("{:?}", serial.nodes), format!("{:?}", parallel.nodes));
+        assert_eq!(format!("{:?}", serial.bounds), format!("{:?}", parallel.bounds));
+    }
 }
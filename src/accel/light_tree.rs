@@ -1,3 +1,7 @@
+use std::cmp;
+
+use crossbeam;
+
 use mem_arena::MemArena;
 
 use algorithm::merge_slices_append;
@@ -6,7 +10,8 @@ use lerp::lerp_slice;
 use math::{Vector, Point, Normal};
 use shading::surface_closure::SurfaceClosure;
 
-use super::LightAccel;
+use super::{LightAccel, ThreadBudget, build_threads};
+use super::bvh_base::PARALLEL_BUILD_MIN_OBJECTS;
 use super::objects_split::sah_split;
 
 const ARITY_LOG2: usize = 3; // Determines how much to collapse the binary tree,
@@ -65,7 +70,8 @@ impl<'a> LightTree<'a> {
         info_getter: F,
     ) -> LightTree<'a>
     where
-        F: 'b + Fn(&T) -> (&'b [BBox], f32),
+        T: Send,
+        F: 'b + Fn(&T) -> (&'b [BBox], f32) + Sync,
     {
         if objects.is_empty() {
             LightTree {
@@ -74,7 +80,7 @@ impl<'a> LightTree<'a> {
             }
         } else {
             let mut builder = LightTreeBuilder::new();
-            builder.recursive_build(0, 0, objects, &info_getter);
+            builder.recursive_build(0, 0, objects, &info_getter, build_threads());
 
             let mut root = unsafe { arena.alloc_uninitialized::<Node>() };
             LightTree::construct_from_builder(arena, &builder, builder.root_node_index(), root);
@@ -295,15 +301,52 @@ impl LightTreeBuilder {
         }
     }
 
+    /// Appends the nodes and bounds of `other`, a subtree built separately
+    /// from this one, returning the node index and bounds range of its root.
+    fn append_subtree(
+        &mut self,
+        other: LightTreeBuilder,
+        root: (usize, (usize, usize)),
+    ) -> (usize, (usize, usize)) {
+        let node_offset = self.nodes.len();
+        let bounds_offset = self.bounds.len();
+
+        for node in other.nodes {
+            self.nodes.push(BuilderNode {
+                is_leaf: node.is_leaf,
+                bounds_range: (
+                    node.bounds_range.0 + bounds_offset,
+                    node.bounds_range.1 + bounds_offset,
+                ),
+                energy: node.energy,
+                // Leaves refer to lights rather than nodes
+                child_index: if node.is_leaf {
+                    node.child_index
+                } else {
+                    node.child_index + node_offset
+                },
+            });
+        }
+        self.bounds.extend(other.bounds);
+        self.depth = cmp::max(self.depth, other.depth);
+
+        (
+            root.0 + node_offset,
+            ((root.1).0 + bounds_offset, (root.1).1 + bounds_offset),
+        )
+    }
+
     fn recursive_build<'a, T, F>(
         &mut self,
         offset: usize,
         depth: usize,
         objects: &mut [T],
         info_getter: &F,
+        threads: &ThreadBudget,
     ) -> (usize, (usize, usize))
     where
-        F: 'a + Fn(&T) -> (&'a [BBox], f32),
+        T: Send,
+        F: 'a + Fn(&T) -> (&'a [BBox], f32) + Sync,
     {
         let me_index = self.nodes.len();
 
@@ -338,15 +381,53 @@ impl LightTreeBuilder {
             // Partition objects.
             let (split_index, _) = sah_split(objects, &|obj_ref| info_getter(obj_ref).0);
 
-            // Create child nodes
-            let (_, c1_bounds) =
-                self.recursive_build(offset, depth + 1, &mut objects[..split_index], info_getter);
-            let (c2_index, c2_bounds) = self.recursive_build(
-                offset + split_index,
-                depth + 1,
-                &mut objects[split_index..],
-                info_getter,
-            );
+            // Create child nodes, building the second child on another
+            // thread if there are threads to spare and enough objects to
+            // make it worthwhile.  The first child has to directly follow
+            // this node, so it's the one built in place.
+            let ((_, c1_bounds), (c2_index, c2_bounds)) =
+                if objects.len() >= PARALLEL_BUILD_MIN_OBJECTS && threads.take(1) == 1 {
+                    let (objects1, objects2) = objects.split_at_mut(split_index);
+                    let (c1, (subtree, c2)) = crossbeam::scope(|scope| {
+                        let handle = scope.spawn(move || {
+                            let mut subtree = LightTreeBuilder::new();
+                            let c2 = subtree.recursive_build(
+                                offset + split_index,
+                                depth + 1,
+                                objects2,
+                                info_getter,
+                                threads,
+                            );
+                            (subtree, c2)
+                        });
+                        let c1 = self.recursive_build(
+                            offset,
+                            depth + 1,
+                            objects1,
+                            info_getter,
+                            threads,
+                        );
+                        (c1, handle.join())
+                    });
+                    threads.give_back(1);
+                    (c1, self.append_subtree(subtree, c2))
+                } else {
+                    let c1 = self.recursive_build(
+                        offset,
+                        depth + 1,
+                        &mut objects[..split_index],
+                        info_getter,
+                        threads,
+                    );
+                    let c2 = self.recursive_build(
+                        offset + split_index,
+                        depth + 1,
+                        &mut objects[split_index..],
+                        info_getter,
+                        threads,
+                    );
+                    (c1, c2)
+                };
 
             // Determine bounds
             // TODO: do merging without the temporary vec.
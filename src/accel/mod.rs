@@ -1,19 +1,155 @@
 mod bvh_base;
 mod bvh;
 mod bvh4;
+mod bvh8;
 mod light_array;
 mod light_tree;
 mod objects_split;
+mod qbvh4;
+mod spatial_split;
+mod wide_bvh;
 
 use std::cell::Cell;
+use std::cmp;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 
 use math::{Vector, Point, Normal};
 use shading::surface_closure::SurfaceClosure;
 
 pub use self::bvh::{BVH, BVHNode};
 pub use self::bvh4::{BVH4, BVH4Node};
+pub use self::bvh8::BVH8Node;
 pub use self::light_tree::LightTree;
 pub use self::light_array::LightArray;
+pub use self::qbvh4::{QBVH4, QBVH4Node};
+pub use self::wide_bvh::WideBVH;
+
+/// A number of extra threads that builds can start, shared between all of
+/// the builds that run at once so that nested builds can't start more
+/// threads between them than it allows.
+pub struct ThreadBudget {
+    free: AtomicUsize, // Extra threads that can still be started
+}
+
+impl ThreadBudget {
+    #[allow(dead_code)]
+    pub fn new(free: usize) -> ThreadBudget {
+        ThreadBudget { free: AtomicUsize::new(free) }
+    }
+
+    /// Takes up to `count` threads from the budget without waiting, and
+    /// returns how many it got.  They go back with `give_back()`.
+    pub fn take(&self, count: usize) -> usize {
+        let mut free = self.free.load(Ordering::Relaxed);
+        loop {
+            let taken = cmp::min(free, count);
+            if taken == 0 {
+                return 0;
+            }
+            match self.free.compare_exchange_weak(
+                free,
+                free - taken,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return taken,
+                Err(actual) => free = actual,
+            }
+        }
+    }
+
+    pub fn give_back(&self, count: usize) {
+        self.free.fetch_add(count, Ordering::Relaxed);
+    }
+}
+
+// The extra threads acceleration structures and scenes are built with,
+// besides the thread that starts the build.
+static BUILD_THREADS: ThreadBudget = ThreadBudget { free: AtomicUsize::new(0) };
+
+/// Sets the number of threads acceleration structures and scenes are built
+/// with, normally the same as the number of render threads.
+pub fn set_build_thread_count(count: usize) {
+    BUILD_THREADS.free.store(cmp::max(count, 1) - 1, Ordering::Relaxed);
+}
+
+/// The thread budget shared by all builds.
+pub fn build_threads() -> &'static ThreadBudget {
+    &BUILD_THREADS
+}
+
+// Whether triangle meshes use spatial split BVHs unless they say otherwise.
+static SPATIAL_SPLITS: AtomicBool = AtomicBool::new(false);
+
+/// Sets whether triangle meshes use spatial split BVHs by default.
+pub fn set_spatial_splits(enabled: bool) {
+    SPATIAL_SPLITS.store(enabled, Ordering::Relaxed);
+}
+
+pub fn spatial_splits() -> bool {
+    SPATIAL_SPLITS.load(Ordering::Relaxed)
+}
+
+// Whether BVHs of moving objects are built with time splits.
+static TIME_SPLITS: AtomicBool = AtomicBool::new(false);
+
+/// Sets whether the BVHs of moving objects are built with time splits,
+/// which split the shutter interval between nodes where that makes their
+/// bounds much tighter.  BVHs with time splits are always 4 wide and
+/// unquantized.
+pub fn set_time_splits(enabled: bool) {
+    TIME_SPLITS.store(enabled, Ordering::Relaxed);
+}
+
+pub fn time_splits() -> bool {
+    TIME_SPLITS.load(Ordering::Relaxed)
+}
+
+/// How the bounds of the nodes of BVHs of objects are stored.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum BVHQuantization {
+    Off, // Full precision
+    Bits8,
+    Bits16,
+}
+
+static BVH_QUANTIZATION: AtomicUsize = AtomicUsize::new(0);
+
+/// Sets whether the bounds of BVH nodes are quantized relative to their
+/// parents, trading some traversal speed for memory.  Quantized BVHs are
+/// always 4 wide.
+pub fn set_bvh_quantization(quantization: BVHQuantization) {
+    let bits = match quantization {
+        BVHQuantization::Off => 0,
+        BVHQuantization::Bits8 => 8,
+        BVHQuantization::Bits16 => 16,
+    };
+    BVH_QUANTIZATION.store(bits, Ordering::Relaxed);
+}
+
+pub fn bvh_quantization() -> BVHQuantization {
+    match BVH_QUANTIZATION.load(Ordering::Relaxed) {
+        8 => BVHQuantization::Bits8,
+        16 => BVHQuantization::Bits16,
+        _ => BVHQuantization::Off,
+    }
+}
+
+// The memory used by the nodes of all BVHs built so far, and the memory they
+// would have used without quantization, for reporting.
+static BVH_BYTES: AtomicUsize = AtomicUsize::new(0);
+static BVH_FULL_BYTES: AtomicUsize = AtomicUsize::new(0);
+
+fn record_bvh_memory(bytes: usize, full_bytes: usize) {
+    BVH_BYTES.fetch_add(bytes, Ordering::Relaxed);
+    BVH_FULL_BYTES.fetch_add(full_bytes, Ordering::Relaxed);
+}
+
+/// Returns the bytes used by the nodes of all BVHs built so far, and the
+/// bytes they would have used without quantization.
+pub fn bvh_memory() -> (usize, usize) {
+    (BVH_BYTES.load(Ordering::Relaxed), BVH_FULL_BYTES.load(Ordering::Relaxed))
+}
 
 // Track BVH traversal time
 thread_local! {
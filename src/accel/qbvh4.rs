@@ -0,0 +1,517 @@
+#![allow(dead_code)]
+
+use std;
+use std::mem;
+
+use mem_arena::MemArena;
+
+use algorithm::partition;
+use bbox::BBox;
+use boundable::Boundable;
+use lerp::lerp_slice;
+use quantize::{QuantGrid, write_quantized, read_quantized, quantized_size};
+use ray::AccelRay;
+use timer::Timer;
+
+use bvh_order::{TRAVERSAL_TABLE, SplitAxes, calc_traversal_code};
+use super::bvh_base::{BVHBase, BVHBaseNode, BVH_MAX_DEPTH};
+use super::BVH4Node;
+use super::{ACCEL_TRAV_TIME, ACCEL_NODE_RAY_TESTS, record_bvh_memory};
+
+
+/// A `BVH4` that stores the bounds of its nodes quantized to 8 or 16 bits,
+/// for using less memory at some cost in traversal speed.
+///
+/// Each node's bounds are quantized to a grid spanning the bounds of its
+/// parent (the union of all of their time samples), rounding outward.  The
+/// root's grid spans the full-precision bounds of the whole BVH.
+#[derive(Copy, Clone, Debug)]
+pub struct QBVH4<'a> {
+    root: Option<&'a QBVH4Node<'a>>,
+    root_bounds: &'a [BBox],
+    bits: u32,
+    depth: usize,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub enum QBVH4Node<'a> {
+    Inner {
+        traversal_code: u8,
+        bounds_start: &'a u8,
+        bounds_len: u16,
+        children: &'a [QBVH4Node<'a>],
+    },
+
+    Leaf {
+        bounds_start: &'a u8,
+        bounds_len: u16,
+        object_range: (usize, usize),
+    },
+}
+
+impl<'a> QBVH4<'a> {
+    pub fn from_objects<'b, T, F>(
+        arena: &'a MemArena,
+        objects: &mut [T],
+        objects_per_leaf: usize,
+        bits: u32,
+        bounder: F,
+    ) -> QBVH4<'a>
+    where
+        T: Send,
+        F: 'b + Fn(&T) -> &'b [BBox] + Sync,
+    {
+        if objects.is_empty() {
+            QBVH4::empty(bits)
+        } else {
+            let base = BVHBase::from_objects(objects, objects_per_leaf, bounder);
+            QBVH4::from_base(arena, &base, bits)
+        }
+    }
+
+    /// Like `from_objects()`, but builds the BVH with spatial splits.  See
+    /// `BVH4::from_objects_with_spatial_splits()` for details.
+    pub fn from_objects_with_spatial_splits<T, F, C>(
+        arena: &'a MemArena,
+        objects: &[T],
+        objects_per_leaf: usize,
+        bits: u32,
+        bounder: F,
+        clipper: C,
+    ) -> (QBVH4<'a>, Vec<T>)
+    where
+        T: Copy,
+        F: Fn(&T) -> BBox,
+        C: Fn(&T, usize, f32) -> (BBox, BBox),
+    {
+        if objects.is_empty() {
+            (QBVH4::empty(bits), Vec::new())
+        } else {
+            let (base, refs) = BVHBase::from_objects_with_spatial_splits(
+                objects,
+                objects_per_leaf,
+                bounder,
+                clipper,
+            );
+            (QBVH4::from_base(arena, &base, bits), refs)
+        }
+    }
+
+    fn empty(bits: u32) -> QBVH4<'a> {
+        QBVH4 {
+            root: None,
+            root_bounds: &DEGENERATE_BOUNDS[..],
+            bits: bits,
+            depth: 0,
+        }
+    }
+
+    fn from_base(arena: &'a MemArena, base: &BVHBase, bits: u32) -> QBVH4<'a> {
+        let root_bounds = match base.nodes[base.root_node_index()] {
+            BVHBaseNode::Internal { bounds_range, .. } |
+            BVHBaseNode::Leaf { bounds_range, .. } |
+            BVHBaseNode::TimeSplit { bounds_range, .. } => {
+                arena.copy_slice(&base.bounds[bounds_range.0..bounds_range.1])
+            }
+        };
+        let root_grid = QuantGrid::new(envelope(root_bounds), bits);
+
+        let mut memory = (root_bounds.len() * mem::size_of::<BBox>(), 0);
+        let root = unsafe { arena.alloc_uninitialized::<QBVH4Node>() };
+        QBVH4::construct_from_base(
+            arena,
+            base,
+            base.root_node_index(),
+            &root_grid,
+            root,
+            &mut memory,
+        );
+        record_bvh_memory(memory.0, memory.1);
+
+        QBVH4 {
+            root: Some(root),
+            root_bounds: root_bounds,
+            bits: bits,
+            depth: base.depth,
+        }
+    }
+
+    pub fn tree_depth(&self) -> usize {
+        self.depth
+    }
+
+    pub fn traverse<T, F>(&self, rays: &mut [AccelRay], objects: &[T], mut obj_ray_test: F)
+    where
+        F: FnMut(&T, &mut [AccelRay]),
+    {
+        if self.root.is_none() {
+            return;
+        }
+
+        let mut timer = Timer::new();
+        let mut trav_time: f64 = 0.0;
+        let mut node_tests: u64 = 0;
+
+        let traversal_table = {
+            let ray_sign_is_neg = [
+                rays[0].dir_inv.x() < 0.0,
+                rays[0].dir_inv.y() < 0.0,
+                rays[0].dir_inv.z() < 0.0,
+            ];
+            let ray_code = ray_sign_is_neg[0] as usize + ((ray_sign_is_neg[1] as usize) << 1) +
+                ((ray_sign_is_neg[2] as usize) << 2);
+            &TRAVERSAL_TABLE[ray_code]
+        };
+
+        // The decoded bounds of the current node
+        let mut bounds = Vec::new();
+
+        // +2 of max depth for root and last child.  Each node is stored with
+        // the grid its bounds were quantized to.
+        let root_grid = QuantGrid::new(envelope(self.root_bounds), self.bits);
+        let mut node_stack = [self.root.unwrap(); (BVH_MAX_DEPTH * 3) + 2];
+        let mut grid_stack = [root_grid; (BVH_MAX_DEPTH * 3) + 2];
+        let mut ray_i_stack = [rays.len(); (BVH_MAX_DEPTH * 3) + 2];
+        let mut stack_ptr = 1;
+
+        while stack_ptr > 0 {
+            node_tests += ray_i_stack[stack_ptr] as u64;
+            match *node_stack[stack_ptr] {
+                QBVH4Node::Inner {
+                    traversal_code,
+                    bounds_start,
+                    bounds_len,
+                    children,
+                } => {
+                    decode_bounds(
+                        bounds_start,
+                        bounds_len,
+                        &grid_stack[stack_ptr],
+                        self.bits,
+                        &mut bounds,
+                    );
+                    let part = partition(&mut rays[..ray_i_stack[stack_ptr]], |r| {
+                        (!r.is_done()) && lerp_slice(&bounds, r.time).intersect_accel_ray(r)
+                    });
+                    if part > 0 {
+                        let child_grid = QuantGrid::new(envelope(&bounds), self.bits);
+                        let order_code = traversal_table[traversal_code as usize];
+                        let child_count = children.len();
+
+                        // Push the children so that the first to visit is
+                        // on top of the stack.
+                        for i in 0..child_count {
+                            let shift = (child_count - 1 - i) * 2;
+                            let ci = ((order_code >> shift) & 0b11) as usize;
+                            node_stack[stack_ptr + i] = &children[ci];
+                            grid_stack[stack_ptr + i] = child_grid;
+                            ray_i_stack[stack_ptr + i] = part;
+                        }
+                        stack_ptr += child_count - 1;
+                    } else {
+                        stack_ptr -= 1;
+                    }
+                }
+
+                QBVH4Node::Leaf {
+                    object_range,
+                    bounds_start,
+                    bounds_len,
+                } => {
+                    decode_bounds(
+                        bounds_start,
+                        bounds_len,
+                        &grid_stack[stack_ptr],
+                        self.bits,
+                        &mut bounds,
+                    );
+                    let part = partition(&mut rays[..ray_i_stack[stack_ptr]], |r| {
+                        (!r.is_done()) && lerp_slice(&bounds, r.time).intersect_accel_ray(r)
+                    });
+
+                    trav_time += timer.tick() as f64;
+
+                    if part > 0 {
+                        for obj in &objects[object_range.0..object_range.1] {
+                            obj_ray_test(obj, &mut rays[..part]);
+                        }
+                    }
+
+                    timer.tick();
+
+                    stack_ptr -= 1;
+                }
+            }
+        }
+
+        trav_time += timer.tick() as f64;
+        ACCEL_TRAV_TIME.with(|att| {
+            let v = att.get();
+            att.set(v + trav_time);
+        });
+        ACCEL_NODE_RAY_TESTS.with(|anv| {
+            let v = anv.get();
+            anv.set(v + node_tests);
+        });
+    }
+
+    // `grid` is the grid to quantize the node's bounds to, and `memory`
+    // accumulates the bytes used by the nodes and the bytes a `BVH4` would
+    // have used for them.
+    fn construct_from_base(
+        arena: &'a MemArena,
+        base: &BVHBase,
+        node_index: usize,
+        grid: &QuantGrid,
+        node_mem: &mut QBVH4Node<'a>,
+        memory: &mut (usize, usize),
+    ) {
+        match base.nodes[node_index] {
+            BVHBaseNode::Internal {
+                bounds_range,
+                children_indices,
+                split_axis,
+            } => {
+                let child_l = &base.nodes[children_indices.0];
+                let child_r = &base.nodes[children_indices.1];
+
+                // Prepare convenient access to the stuff we need.
+                let child_count: usize;
+                let child_indices: [usize; 4];
+                let split_info: SplitAxes;
+                match *child_l {
+                    BVHBaseNode::Internal {
+                        children_indices: i_l,
+                        split_axis: s_l,
+                        ..
+                    } => {
+                        match *child_r {
+                            BVHBaseNode::Internal {
+                                children_indices: i_r,
+                                split_axis: s_r,
+                                ..
+                            } => {
+                                // Four nodes
+                                child_count = 4;
+                                child_indices = [i_l.0, i_l.1, i_r.0, i_r.1];
+                                split_info = SplitAxes::Full((split_axis, s_l, s_r));
+                            }
+                            BVHBaseNode::Leaf { .. } |
+                            BVHBaseNode::TimeSplit { .. } => {
+                                // Three nodes with left split
+                                child_count = 3;
+                                child_indices = [i_l.0, i_l.1, children_indices.1, 0];
+                                split_info = SplitAxes::Left((split_axis, s_l));
+                            }
+                        }
+                    }
+                    BVHBaseNode::Leaf { .. } |
+                    BVHBaseNode::TimeSplit { .. } => {
+                        match *child_r {
+                            BVHBaseNode::Internal {
+                                children_indices: i_r,
+                                split_axis: s_r,
+                                ..
+                            } => {
+                                // Three nodes with right split
+                                child_count = 3;
+                                child_indices = [children_indices.0, i_r.0, i_r.1, 0];
+                                split_info = SplitAxes::Right((split_axis, s_r));
+                            }
+                            BVHBaseNode::Leaf { .. } |
+                            BVHBaseNode::TimeSplit { .. } => {
+                                // Two nodes
+                                child_count = 2;
+                                child_indices = [children_indices.0, children_indices.1, 0, 0];
+                                split_info = SplitAxes::TopOnly(split_axis);
+                            }
+                        }
+                    }
+                }
+
+                // Quantize bounds
+                let (bounds, decoded) = encode_bounds(
+                    arena,
+                    &base.bounds[bounds_range.0..bounds_range.1],
+                    grid,
+                    memory,
+                );
+                let child_grid = QuantGrid::new(envelope(&decoded), grid.bits());
+
+                // Build children
+                let children_mem = unsafe {
+                    arena.alloc_array_uninitialized_with_alignment::<QBVH4Node>(child_count, 32)
+                };
+                for i in 0..child_count {
+                    QBVH4::construct_from_base(
+                        arena,
+                        base,
+                        child_indices[i],
+                        &child_grid,
+                        &mut children_mem[i],
+                        memory,
+                    );
+                }
+
+                // Fill in node
+                *node_mem = QBVH4Node::Inner {
+                    traversal_code: calc_traversal_code(split_info),
+                    bounds_start: &bounds[0],
+                    bounds_len: (bounds_range.1 - bounds_range.0) as u16,
+                    children: children_mem,
+                };
+            }
+
+            BVHBaseNode::Leaf {
+                bounds_range,
+                object_range,
+            } => {
+                let (bounds, _) = encode_bounds(
+                    arena,
+                    &base.bounds[bounds_range.0..bounds_range.1],
+                    grid,
+                    memory,
+                );
+
+                *node_mem = QBVH4Node::Leaf {
+                    bounds_start: &bounds[0],
+                    bounds_len: (bounds_range.1 - bounds_range.0) as u16,
+                    object_range: object_range,
+                };
+            }
+
+            BVHBaseNode::TimeSplit { .. } => unreachable!("time splits are only built for BVH4s"),
+        }
+    }
+}
+
+lazy_static! {
+    static ref DEGENERATE_BOUNDS: [BBox; 1] = [BBox::new()];
+}
+
+impl<'a> Boundable for QBVH4<'a> {
+    fn bounds(&self) -> &[BBox] {
+        self.root_bounds
+    }
+}
+
+
+/// The union of all of the time samples of a node's bounds, which its
+/// children are quantized relative to.
+fn envelope(bounds: &[BBox]) -> BBox {
+    bounds.iter().fold(BBox::new(), |env, bb| env | *bb)
+}
+
+/// Quantizes a node's bounds to `grid`, returning the quantized bounds and
+/// the bounds they decode to.
+fn encode_bounds<'a>(
+    arena: &'a MemArena,
+    bounds: &[BBox],
+    grid: &QuantGrid,
+    memory: &mut (usize, usize),
+) -> (&'a [u8], Vec<BBox>) {
+    let bits = grid.bits();
+    let mut bytes = Vec::with_capacity(bounds.len() * 6 * quantized_size(bits));
+    let mut decoded = Vec::with_capacity(bounds.len());
+    for bb in bounds {
+        let q = grid.quantize_bbox(*bb);
+        write_quantized(&q, bits, &mut bytes);
+        decoded.push(grid.dequantize_bbox(q));
+    }
+
+    memory.0 += mem::size_of::<QBVH4Node>() + bytes.len();
+    memory.1 += mem::size_of::<BVH4Node>() + (bounds.len() * mem::size_of::<BBox>());
+
+    (arena.copy_slice(&bytes), decoded)
+}
+
+/// Decodes a node's bounds, quantized to `grid`, into `out`.
+fn decode_bounds(
+    bounds_start: &u8,
+    bounds_len: u16,
+    grid: &QuantGrid,
+    bits: u32,
+    out: &mut Vec<BBox>,
+) {
+    let bytes = unsafe {
+        std::slice::from_raw_parts(
+            bounds_start,
+            bounds_len as usize * 6 * quantized_size(bits),
+        )
+    };
+    out.clear();
+    for i in 0..(bounds_len as usize) {
+        let mut q = [0u32; 6];
+        for (j, n) in q.iter_mut().enumerate() {
+            *n = read_quantized(bytes, bits, (i * 6) + j);
+        }
+        out.push(grid.dequantize_bbox(q));
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use math::{Point, Vector};
+    use ray::Ray;
+    use accel::BVH4;
+
+    #[test]
+    fn traverse_matches_bvh4() {
+        let arena = MemArena::new();
+        let bounds: Vec<BBox> = (0..200)
+            .map(|i| {
+                let x = ((i * 37) % 101) as f32 * 0.13;
+                let y = ((i * 53) % 97) as f32 * 0.13;
+                let z = (i % 13) as f32 * 0.13;
+                BBox::from_points(Point::new(x, y, z), Point::new(x + 0.3, y + 0.2, z + 0.6))
+            })
+            .collect();
+        let mut objects4: Vec<usize> = (0..bounds.len()).collect();
+        let bvh4 = BVH4::from_objects(&arena, &mut objects4[..], 1, |i| &bounds[*i..(*i + 1)]);
+
+        for bits in &[8, 16] {
+            let mut objects_q: Vec<usize> = (0..bounds.len()).collect();
+            let qbvh4 = QBVH4::from_objects(&arena, &mut objects_q[..], 1, *bits, |i| {
+                &bounds[*i..(*i + 1)]
+            });
+
+            let mut rays: Vec<_> = (0..100)
+                .map(|i| {
+                    let ray = Ray::new(
+                        Point::new(-1.0, (i % 10) as f32 * 1.3 + 0.05, 0.75),
+                        Vector::new(1.0, 0.02 * (i / 10) as f32 + 0.001, 0.01),
+                        0.0,
+                        50.0,
+                        false,
+                    );
+                    AccelRay::new(&ray, i)
+                })
+                .collect();
+
+            // Collect which objects each ray hits.  The quantized bounds are
+            // looser, but shouldn't miss anything.
+            let mut hits4 = vec![Vec::new(); rays.len()];
+            bvh4.traverse(&mut rays[..], &objects4, |obj, rs| for r in rs {
+                if bounds[*obj].intersect_accel_ray(r) {
+                    hits4[r.id as usize].push(*obj);
+                }
+            });
+            let mut hits_q = vec![Vec::new(); rays.len()];
+            qbvh4.traverse(&mut rays[..], &objects_q, |obj, rs| for r in rs {
+                if bounds[*obj].intersect_accel_ray(r) {
+                    hits_q[r.id as usize].push(*obj);
+                }
+            });
+
+            for (h4, hq) in hits4.iter_mut().zip(hits_q.iter_mut()) {
+                h4.sort();
+                hq.sort();
+            }
+            assert!(hits4.iter().any(|h| h.len() > 1));
+            assert_eq!(hits4, hits_q);
+            assert_eq!(bvh4.bounds()[0].min, qbvh4.bounds()[0].min);
+        }
+    }
+}
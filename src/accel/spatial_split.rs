@@ -0,0 +1,398 @@
+#![allow(dead_code)]
+
+use std;
+
+use bbox::BBox;
+
+
+const OBJECT_BIN_COUNT: usize = 16;
+const SPATIAL_BIN_COUNT: usize = 16;
+
+// Spatial splits are only tried for nodes whose best object split has
+// children that overlap by at least this fraction of the root's surface
+// area.  Splitting references costs memory, so it's only worth it where
+// object splits do poorly.
+const SPATIAL_SPLIT_ALPHA: f32 = 1.0e-5;
+
+/// How many extra references spatial splits may create, as a fraction of
+/// the number of objects.
+pub const SPATIAL_SPLIT_BUDGET: f32 = 0.5;
+
+
+/// A reference to an object during a spatial split build, with the bounds
+/// of the part of the object it covers.
+#[derive(Copy, Clone, Debug)]
+pub struct SplitRef<T: Copy> {
+    pub object: T,
+    pub bounds: BBox,
+}
+
+
+/// A way of splitting a node's references in two.
+#[derive(Copy, Clone, Debug)]
+pub enum Split {
+    // Partition the references by their centroids, without splitting any
+    Object { axis: usize, pos: f32 },
+
+    // Split the references that straddle the plane, so they go on both sides
+    Spatial { axis: usize, pos: f32 },
+
+    // Split the references into two halves by their order
+    Half,
+}
+
+
+/// Finds the best way to split `refs` according to the Surface Area
+/// Heuristic, considering spatial splits if object splits overlap too much.
+///
+/// `root_area` is the surface area of the root node, and `spare_refs` is how
+/// many more references spatial splits may create.  `clipper` returns the
+/// bounds of the parts of an object on either side of a plane along an axis.
+pub fn find_split<T, F>(
+    refs: &[SplitRef<T>],
+    bounds: BBox,
+    root_area: f32,
+    spare_refs: usize,
+    clipper: &F,
+) -> Split
+where
+    T: Copy,
+    F: Fn(&T, usize, f32) -> (BBox, BBox),
+{
+    let object_split = best_object_split(refs);
+
+    // Only try spatial splits where the object split's children overlap
+    // significantly.
+    let try_spatial = match object_split {
+        Some((_, _, _, left, right)) => {
+            let overlap = left.intersection(right);
+            !overlap.is_empty() && overlap.surface_area() > (root_area * SPATIAL_SPLIT_ALPHA)
+        }
+        None => true,
+    };
+    let spatial_split = if try_spatial && spare_refs > 0 {
+        best_spatial_split(refs, bounds, spare_refs, clipper)
+    } else {
+        None
+    };
+
+    match (object_split, spatial_split) {
+        (Some((o_cost, _, _, _, _)), Some((s_cost, axis, pos))) if s_cost < o_cost => {
+            Split::Spatial {
+                axis: axis,
+                pos: pos,
+            }
+        }
+        (None, Some((_, axis, pos))) => Split::Spatial {
+            axis: axis,
+            pos: pos,
+        },
+        (Some((_, axis, pos, _, _)), _) => Split::Object {
+            axis: axis,
+            pos: pos,
+        },
+        (None, None) => Split::Half,
+    }
+}
+
+
+/// Splits `refs` into two non-empty sets according to `split`.
+pub fn apply_split<T, F>(
+    refs: Vec<SplitRef<T>>,
+    split: Split,
+    clipper: &F,
+) -> (Vec<SplitRef<T>>, Vec<SplitRef<T>>)
+where
+    T: Copy,
+    F: Fn(&T, usize, f32) -> (BBox, BBox),
+{
+    let (mut left, mut right) = (Vec::new(), Vec::new());
+    match split {
+        Split::Object { axis, pos } => {
+            for r in refs {
+                if r.bounds.center().get_n(axis) < pos {
+                    left.push(r);
+                } else {
+                    right.push(r);
+                }
+            }
+        }
+
+        Split::Spatial { axis, pos } => {
+            for r in refs {
+                if r.bounds.max.get_n(axis) <= pos {
+                    left.push(r);
+                } else if r.bounds.min.get_n(axis) >= pos {
+                    right.push(r);
+                } else {
+                    let (l, rt) = clipper(&r.object, axis, pos);
+                    let (l, rt) = (r.bounds.intersection(l), r.bounds.intersection(rt));
+                    if l.is_empty() {
+                        right.push(r);
+                    } else if rt.is_empty() {
+                        left.push(r);
+                    } else {
+                        left.push(SplitRef {
+                            object: r.object,
+                            bounds: l,
+                        });
+                        right.push(SplitRef {
+                            object: r.object,
+                            bounds: rt,
+                        });
+                    }
+                }
+            }
+        }
+
+        Split::Half => {
+            let mut refs = refs;
+            let half = refs.len() / 2;
+            right = refs.split_off(half);
+            left = refs;
+        }
+    }
+
+    // Make sure neither side ends up empty
+    if left.is_empty() {
+        let half = right.len() / 2;
+        left = right.drain(..half).collect();
+    } else if right.is_empty() {
+        let half = left.len() - (left.len() / 2);
+        right = left.split_off(half);
+    }
+
+    (left, right)
+}
+
+
+/// Finds the best binned SAH split of the references by their centroids.
+///
+/// Returns the cost, axis, and position of the split, and the bounds of the
+/// resulting children, or None if the centroids can't be split.
+fn best_object_split<T: Copy>(refs: &[SplitRef<T>]) -> Option<(f32, usize, f32, BBox, BBox)> {
+    let centroid_bounds = refs.iter().fold(BBox::new(), |bb, r| bb | r.bounds.center());
+
+    let mut best = None;
+    let mut best_cost = std::f32::INFINITY;
+    for axis in 0..3 {
+        let min = centroid_bounds.min.get_n(axis);
+        let extent = centroid_bounds.max.get_n(axis) - min;
+        if extent <= 0.0 {
+            continue;
+        }
+
+        // Fill bins
+        let mut bins = [(BBox::new(), 0usize); OBJECT_BIN_COUNT];
+        for r in refs {
+            let b = bin_index(r.bounds.center().get_n(axis), min, extent, OBJECT_BIN_COUNT);
+            bins[b].0 |= r.bounds;
+            bins[b].1 += 1;
+        }
+
+        // Evaluate the planes between the bins
+        let (right_bounds, right_counts) = sweep_from_right(&bins);
+        let mut left = (BBox::new(), 0);
+        for b in 0..(OBJECT_BIN_COUNT - 1) {
+            left.0 |= bins[b].0;
+            left.1 += bins[b].1;
+            if left.1 == 0 || right_counts[b + 1] == 0 {
+                continue;
+            }
+            let cost = (left.0.surface_area() * left.1 as f32) +
+                (right_bounds[b + 1].surface_area() * right_counts[b + 1] as f32);
+            if cost < best_cost {
+                best_cost = cost;
+                let pos = min + (extent * (b + 1) as f32 / OBJECT_BIN_COUNT as f32);
+                best = Some((cost, axis, pos, left.0, right_bounds[b + 1]));
+            }
+        }
+    }
+
+    best
+}
+
+
+/// Finds the best binned SAH spatial split of the references, which may
+/// create at most `spare_refs` new references.
+///
+/// Returns the cost, axis, and position of the split.
+fn best_spatial_split<T, F>(
+    refs: &[SplitRef<T>],
+    bounds: BBox,
+    spare_refs: usize,
+    clipper: &F,
+) -> Option<(f32, usize, f32)>
+where
+    T: Copy,
+    F: Fn(&T, usize, f32) -> (BBox, BBox),
+{
+    let mut best = None;
+    let mut best_cost = std::f32::INFINITY;
+    for axis in 0..3 {
+        let min = bounds.min.get_n(axis);
+        let extent = bounds.max.get_n(axis) - min;
+        if extent <= 0.0 {
+            continue;
+        }
+        let plane = |b: usize| min + (extent * b as f32 / SPATIAL_BIN_COUNT as f32);
+
+        // Fill bins, with each reference clipped to the bins it overlaps.
+        // The counts are of references entering each bin, and exiting each
+        // bin.
+        let mut bins = [(BBox::new(), 0usize); SPATIAL_BIN_COUNT];
+        let mut exits = [0usize; SPATIAL_BIN_COUNT];
+        for r in refs {
+            let first = bin_index(r.bounds.min.get_n(axis), min, extent, SPATIAL_BIN_COUNT);
+            let last = bin_index(r.bounds.max.get_n(axis), min, extent, SPATIAL_BIN_COUNT);
+            bins[first].1 += 1;
+            exits[last] += 1;
+
+            if first == last {
+                bins[first].0 |= r.bounds;
+            } else {
+                let mut rest = r.bounds;
+                for b in first..last {
+                    let (l, rt) = clipper(&r.object, axis, plane(b + 1));
+                    let part = rest.intersection(l);
+                    if !part.is_empty() {
+                        bins[b].0 |= part;
+                    }
+                    rest = rest.intersection(rt);
+                }
+                if !rest.is_empty() {
+                    bins[last].0 |= rest;
+                }
+            }
+        }
+
+        // Evaluate the planes between the bins
+        let mut right_bounds = [BBox::new(); SPATIAL_BIN_COUNT];
+        let mut right_counts = [0usize; SPATIAL_BIN_COUNT];
+        {
+            let mut acc = (BBox::new(), 0);
+            for b in (0..SPATIAL_BIN_COUNT).rev() {
+                acc.0 |= bins[b].0;
+                acc.1 += exits[b];
+                right_bounds[b] = acc.0;
+                right_counts[b] = acc.1;
+            }
+        }
+        let mut left = (BBox::new(), 0);
+        for b in 0..(SPATIAL_BIN_COUNT - 1) {
+            left.0 |= bins[b].0;
+            left.1 += bins[b].1;
+            let right_count = right_counts[b + 1];
+            if left.1 == 0 || right_count == 0 || (left.1 + right_count - refs.len()) > spare_refs
+            {
+                continue;
+            }
+            let cost = (left.0.surface_area() * left.1 as f32) +
+                (right_bounds[b + 1].surface_area() * right_count as f32);
+            if cost < best_cost {
+                best_cost = cost;
+                best = Some((cost, axis, plane(b + 1)));
+            }
+        }
+    }
+
+    best
+}
+
+
+/// Accumulates bins from the right, returning for each bin the bounds and
+/// count of it and all bins to its right.
+fn sweep_from_right(
+    bins: &[(BBox, usize); OBJECT_BIN_COUNT],
+) -> ([BBox; OBJECT_BIN_COUNT], [usize; OBJECT_BIN_COUNT]) {
+    let mut bounds = [BBox::new(); OBJECT_BIN_COUNT];
+    let mut counts = [0usize; OBJECT_BIN_COUNT];
+    let mut acc = (BBox::new(), 0);
+    for b in (0..OBJECT_BIN_COUNT).rev() {
+        acc.0 |= bins[b].0;
+        acc.1 += bins[b].1;
+        bounds[b] = acc.0;
+        counts[b] = acc.1;
+    }
+    (bounds, counts)
+}
+
+
+fn bin_index(v: f32, min: f32, extent: f32, bin_count: usize) -> usize {
+    let b = ((v - min) / extent * bin_count as f32) as isize;
+    if b < 0 {
+        0
+    } else if b as usize >= bin_count {
+        bin_count - 1
+    } else {
+        b as usize
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use math::Point;
+    use surface::triangle;
+
+    #[test]
+    fn clip_triangle_bounds() {
+        let tri = (
+            Point::new(0.0, 0.0, 0.0),
+            Point::new(4.0, 0.0, 0.0),
+            Point::new(0.0, 4.0, 1.0),
+        );
+        let (left, right) = triangle::clip_bounds(tri, 0, 1.0);
+        assert!((left.max.x() - 1.0).abs() < 0.0001);
+        assert_eq!(left.max.y(), 4.0);
+        assert!((right.min.x() - 1.0).abs() < 0.0001);
+        assert!((right.max.y() - 3.0).abs() < 0.0001);
+        assert!((right.max.z() - 0.75).abs() < 0.0001);
+
+        let (left, right) = triangle::clip_bounds(tri, 1, 5.0);
+        assert!(right.is_empty());
+        assert_eq!(left.max.y(), 4.0);
+    }
+
+    #[test]
+    fn spatial_split_overlapping_slivers() {
+        // Long diagonal slivers, whose bounds all overlap, are better split
+        // spatially.
+        let tris: Vec<_> = (0..8)
+            .map(|i| {
+                let o = i as f32 * 0.1;
+                (
+                    Point::new(o, 0.0, 0.0),
+                    Point::new(o + 0.05, 0.0, 0.0),
+                    Point::new(o + 10.0, 10.0, 0.0),
+                )
+            })
+            .collect();
+        let refs: Vec<_> = tris.iter()
+            .enumerate()
+            .map(|(i, t)| {
+                SplitRef {
+                    object: i,
+                    bounds: BBox::from_points(t.0.min(t.2), t.1.max(t.2)),
+                }
+            })
+            .collect();
+        let bounds = refs.iter().fold(BBox::new(), |bb, r| bb | r.bounds);
+        let clipper = |i: &usize, axis: usize, pos: f32| triangle::clip_bounds(tris[*i], axis, pos);
+
+        let split = find_split(&refs, bounds, bounds.surface_area(), 8, &clipper);
+        if let Split::Spatial { .. } = split {
+        } else {
+            panic!("Expected a spatial split, got {:?}", split);
+        }
+
+        let (left, right) = apply_split(refs, split, &clipper);
+        assert_eq!(left.len(), 8);
+        assert_eq!(right.len(), 8);
+        let left_bounds = left.iter().fold(BBox::new(), |bb, r| bb | r.bounds);
+        let right_bounds = right.iter().fold(BBox::new(), |bb, r| bb | r.bounds);
+        assert!(left_bounds.surface_area() + right_bounds.surface_area() <
+            bounds.surface_area());
+    }
+}
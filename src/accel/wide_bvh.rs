@@ -0,0 +1,139 @@
+#![allow(dead_code)]
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use mem_arena::MemArena;
+
+use bbox::BBox;
+use boundable::Boundable;
+use ray::AccelRay;
+
+use super::{BVH4, QBVH4, BVHQuantization, bvh_quantization};
+
+
+// Whether the user has been warned that time split BVHs ignore the BVH
+// quantization setting, so they're only warned once.
+static TIME_SPLITS_WARNED: AtomicBool = AtomicBool::new(false);
+
+
+/// A BVH of objects that is either a `BVH4` or a quantized `QBVH4`,
+/// depending on the BVH quantization setting.
+#[derive(Copy, Clone, Debug)]
+pub enum WideBVH<'a> {
+    BVH4(BVH4<'a>),
+    QBVH4(QBVH4<'a>),
+}
+
+impl<'a> WideBVH<'a> {
+    pub fn from_objects<'b, T, F>(
+        arena: &'a MemArena,
+        objects: &mut [T],
+        objects_per_leaf: usize,
+        bounder: F,
+    ) -> WideBVH<'a>
+    where
+        T: Send,
+        F: 'b + Fn(&T) -> &'b [BBox] + Sync,
+    {
+        if let Some(bits) = quantization_bits() {
+            WideBVH::QBVH4(QBVH4::from_objects(arena, objects, objects_per_leaf, bits, bounder))
+        } else {
+            WideBVH::BVH4(BVH4::from_objects(arena, objects, objects_per_leaf, bounder))
+        }
+    }
+
+    pub fn from_objects_with_spatial_splits<T, F, C>(
+        arena: &'a MemArena,
+        objects: &[T],
+        objects_per_leaf: usize,
+        bounder: F,
+        clipper: C,
+    ) -> (WideBVH<'a>, Vec<T>)
+    where
+        T: Copy,
+        F: Fn(&T) -> BBox,
+        C: Fn(&T, usize, f32) -> (BBox, BBox),
+    {
+        if let Some(bits) = quantization_bits() {
+            let (bvh, refs) = QBVH4::from_objects_with_spatial_splits(
+                arena,
+                objects,
+                objects_per_leaf,
+                bits,
+                bounder,
+                clipper,
+            );
+            (WideBVH::QBVH4(bvh), refs)
+        } else {
+            let (bvh, refs) = BVH4::from_objects_with_spatial_splits(
+                arena,
+                objects,
+                objects_per_leaf,
+                bounder,
+                clipper,
+            );
+            (WideBVH::BVH4(bvh), refs)
+        }
+    }
+
+    /// Builds a `BVH4` with time splits.  See
+    /// `BVH4::from_objects_with_time_splits()`.
+    ///
+    /// Time splits are only supported by `BVH4`s, so this warns if
+    /// quantization was asked for.
+    pub fn from_objects_with_time_splits<'b, T, F>(
+        arena: &'a MemArena,
+        objects: &[T],
+        objects_per_leaf: usize,
+        bounder: F,
+    ) -> (WideBVH<'a>, Vec<T>)
+    where
+        T: Copy,
+        F: 'b + Fn(&T) -> &'b [BBox],
+    {
+        if quantization_bits().is_some() && !TIME_SPLITS_WARNED.swap(true, Ordering::Relaxed) {
+            println!(
+                "WARNING: BVHs with time splits are always unquantized.  \
+                 Ignoring the BVH quantization setting for them."
+            );
+        }
+
+        let (bvh, refs) =
+            BVH4::from_objects_with_time_splits(arena, objects, objects_per_leaf, bounder);
+        (WideBVH::BVH4(bvh), refs)
+    }
+
+    pub fn tree_depth(&self) -> usize {
+        match *self {
+            WideBVH::BVH4(ref bvh) => bvh.tree_depth(),
+            WideBVH::QBVH4(ref bvh) => bvh.tree_depth(),
+        }
+    }
+
+    pub fn traverse<T, F>(&self, rays: &mut [AccelRay], objects: &[T], obj_ray_test: F)
+    where
+        F: FnMut(&T, &mut [AccelRay]),
+    {
+        match *self {
+            WideBVH::BVH4(ref bvh) => bvh.traverse(rays, objects, obj_ray_test),
+            WideBVH::QBVH4(ref bvh) => bvh.traverse(rays, objects, obj_ray_test),
+        }
+    }
+}
+
+impl<'a> Boundable for WideBVH<'a> {
+    fn bounds(&self) -> &[BBox] {
+        match *self {
+            WideBVH::BVH4(ref bvh) => bvh.bounds(),
+            WideBVH::QBVH4(ref bvh) => bvh.bounds(),
+        }
+    }
+}
+
+fn quantization_bits() -> Option<u32> {
+    match bvh_quantization() {
+        BVHQuantization::Off => None,
+        BVHQuantization::Bits8 => Some(8),
+        BVHQuantization::Bits16 => Some(16),
+    }
+}
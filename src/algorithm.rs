@@ -200,6 +200,32 @@ where
     }
 }
 
+/// Reorders two slices in tandem, so that the items at index `order[i]`
+/// end up at index `i`.
+///
+/// `order` must be a permutation of the slices' indices.  It's used as
+/// scratch space, and is left in an unspecified state.
+pub fn permute_pair<A, B>(slc1: &mut [A], slc2: &mut [B], order: &mut [usize]) {
+    assert_eq!(slc1.len(), slc2.len());
+    assert_eq!(slc1.len(), order.len());
+
+    // Follow each cycle of the permutation, marking the indices that
+    // are done by pointing them at themselves.
+    for i in 0..order.len() {
+        let mut j = i;
+        while order[j] != j {
+            let k = order[j];
+            order[j] = j;
+            if k == i {
+                break;
+            }
+            slc1.swap(j, k);
+            slc2.swap(j, k);
+            j = k;
+        }
+    }
+}
+
 /// Partitions the slice of items to place the nth-ordered item in the nth place,
 /// and the items less than it before and the items more than it after.
 pub fn quick_select<T, F>(slc: &mut [T], n: usize, mut order: F)
@@ -233,69 +259,96 @@ where
     }
 }
 
-/// Merges two slices of things, appending the result to `vec_out`
-pub fn merge_slices_append<T: Lerp + Copy, F>(
-    slice1: &[T],
-    slice2: &[T],
-    vec_out: &mut Vec<T>,
+/// The number of evenly spaced time samples that two sets of evenly spaced
+/// time samples, of lengths `len1` and `len2`, are merged into.
+///
+/// Where possible this puts a merged sample at every time either set has a
+/// sample at, so that no motion between the samples is lost.  That's only
+/// done if it takes no more samples than the two sets have between them,
+/// and otherwise it falls back to the length of the longer set.
+pub fn merged_len(len1: usize, len2: usize) -> usize {
+    if len1 <= 1 || len2 <= 1 || len1 == len2 {
+        return cmp::max(len1, len2);
+    }
+
+    let (seg1, seg2) = (len1 - 1, len2 - 1);
+    let gcd = {
+        let (mut a, mut b) = (seg1, seg2);
+        while b != 0 {
+            let t = a % b;
+            a = b;
+            b = t;
+        }
+        a
+    };
+    let lcm = (seg1 / gcd) * seg2;
+    if lcm < (len1 + len2) {
+        lcm + 1
+    } else {
+        cmp::max(len1, len2)
+    }
+}
+
+/// Returns the value of evenly spaced time samples `s` at the time of
+/// sample `i` of `len` evenly spaced samples.  Exact when the times coincide.
+fn sample_at<T: Lerp + Copy>(s: &[T], i: usize, len: usize) -> T {
+    let seg = s.len() - 1;
+    if len <= 1 || seg == 0 {
+        s[0]
+    } else if (i * seg) % (len - 1) == 0 {
+        s[(i * seg) / (len - 1)]
+    } else {
+        lerp_slice(s, i as f32 / (len - 1) as f32)
+    }
+}
+
+/// Merges two slices of time samples, appending the result to `vec_out`.
+/// See `merged_len()` for the number of samples appended.
+pub fn merge_slices_append<T1, T2, T3, F>(
+    slice1: &[T1],
+    slice2: &[T2],
+    vec_out: &mut Vec<T3>,
     merge: F,
 ) where
-    F: Fn(&T, &T) -> T,
+    T1: Lerp + Copy,
+    T2: Lerp + Copy,
+    F: Fn(&T1, &T2) -> T3,
 {
-    // Transform the bounding boxes
     if slice1.is_empty() || slice2.is_empty() {
         return;
-    } else if slice1.len() == slice2.len() {
-        for (xf1, xf2) in Iterator::zip(slice1.iter(), slice2.iter()) {
-            vec_out.push(merge(xf1, xf2));
-        }
-    } else if slice1.len() > slice2.len() {
-        let s = (slice1.len() - 1) as f32;
-        for (i, xf1) in slice1.iter().enumerate() {
-            let xf2 = lerp_slice(slice2, i as f32 / s);
-            vec_out.push(merge(xf1, &xf2));
-        }
-    } else if slice1.len() < slice2.len() {
-        let s = (slice2.len() - 1) as f32;
-        for (i, xf2) in slice2.iter().enumerate() {
-            let xf1 = lerp_slice(slice1, i as f32 / s);
-            vec_out.push(merge(&xf1, xf2));
-        }
+    }
+
+    let len = merged_len(slice1.len(), slice2.len());
+    for i in 0..len {
+        let v1 = sample_at(slice1, i, len);
+        let v2 = sample_at(slice2, i, len);
+        vec_out.push(merge(&v1, &v2));
     }
 }
 
-/// Merges two slices of things, storing the result in `slice_out`.
-/// Panics if `slice_out` is not the right size.
-pub fn merge_slices_to<T: Lerp + Copy, F>(slice1: &[T], slice2: &[T], slice_out: &mut [T], merge: F)
-where
-    F: Fn(&T, &T) -> T,
+/// Merges two slices of time samples, storing the result in `slice_out`.
+/// Panics if `slice_out` is not the length given by `merged_len()`.
+pub fn merge_slices_to<T1, T2, T3, F>(
+    slice1: &[T1],
+    slice2: &[T2],
+    slice_out: &mut [T3],
+    merge: F,
+) where
+    T1: Lerp + Copy,
+    T2: Lerp + Copy,
+    F: Fn(&T1, &T2) -> T3,
 {
-    assert_eq!(slice_out.len(), cmp::max(slice1.len(), slice2.len()));
+    assert_eq!(slice_out.len(), merged_len(slice1.len(), slice2.len()));
 
-    // Transform the bounding boxes
     if slice1.is_empty() || slice2.is_empty() {
         return;
-    } else if slice1.len() == slice2.len() {
-        for (xfo, (xf1, xf2)) in
-            Iterator::zip(
-                slice_out.iter_mut(),
-                Iterator::zip(slice1.iter(), slice2.iter()),
-            )
-        {
-            *xfo = merge(xf1, xf2);
-        }
-    } else if slice1.len() > slice2.len() {
-        let s = (slice1.len() - 1) as f32;
-        for (i, (xfo, xf1)) in Iterator::zip(slice_out.iter_mut(), slice1.iter()).enumerate() {
-            let xf2 = lerp_slice(slice2, i as f32 / s);
-            *xfo = merge(xf1, &xf2);
-        }
-    } else if slice1.len() < slice2.len() {
-        let s = (slice2.len() - 1) as f32;
-        for (i, (xfo, xf2)) in Iterator::zip(slice_out.iter_mut(), slice2.iter()).enumerate() {
-            let xf1 = lerp_slice(slice1, i as f32 / s);
-            *xfo = merge(&xf1, xf2);
-        }
+    }
+
+    let len = slice_out.len();
+    for (i, out) in slice_out.iter_mut().enumerate() {
+        let v1 = sample_at(slice1, i, len);
+        let v2 = sample_at(slice2, i, len);
+        *out = merge(&v1, &v2);
     }
 }
 
@@ -341,4 +394,38 @@ mod tests {
         quick_select_ints(&mut list, 9);
         assert_eq!(list[9], 9);
     }
+
+    #[test]
+    fn permute_pair_1() {
+        let mut list1 = [10, 11, 12, 13, 14, 15];
+        let mut list2 = ['a', 'b', 'c', 'd', 'e', 'f'];
+        let mut order = [3, 5, 0, 1, 4, 2];
+        permute_pair(&mut list1, &mut list2, &mut order);
+        assert_eq!(list1, [13, 15, 10, 11, 14, 12]);
+        assert_eq!(list2, ['d', 'f', 'a', 'b', 'e', 'c']);
+    }
+
+    #[test]
+    fn merged_len_1() {
+        assert_eq!(merged_len(1, 3), 3);
+        assert_eq!(merged_len(4, 4), 4);
+        assert_eq!(merged_len(3, 4), 7);
+        assert_eq!(merged_len(3, 5), 5);
+        assert_eq!(merged_len(2, 11), 11);
+        assert_eq!(merged_len(4, 5), 5);
+    }
+
+    #[test]
+    fn merge_slices_keeps_all_samples() {
+        // Three samples and four samples only share their first and last
+        // times, so the merge needs samples at both sets of times.
+        let s1 = [0.0f32, 1.0, 0.0];
+        let s2 = [0.0f32, 0.0, 0.0, 3.0];
+        let mut merged = Vec::new();
+        merge_slices_append(&s1, &s2, &mut merged, |a, b| a + b);
+        assert_eq!(merged.len(), 7);
+        assert_eq!(merged[3], 1.0);
+        assert_eq!(merged[6], 3.0);
+        assert_eq!(lerp_slice(&merged, 0.5), 1.0);
+    }
 }
@@ -1,16 +1,20 @@
 #![allow(dead_code)]
 
 use std;
-use std::iter::Iterator;
 use std::ops::{BitOr, BitOrAssign};
 
+use algorithm::merge_slices_append;
 use lerp::{lerp, lerp_slice, Lerp};
-use math::{Point, Matrix4x4, fast_minf32};
+use math::{Point, Vector, Matrix4x4, Transform, fast_minf32};
 use ray::AccelRay;
 
 
 const BBOX_MAXT_ADJUST: f32 = 1.00000024;
 
+// The largest rotation, in radians, between the extra samples used to bound
+// rotating motion.
+const MAX_ROTATION_STEP: f32 = std::f32::consts::PI / 16.0;
+
 /// A 3D axis-aligned bounding box.
 #[derive(Debug, Copy, Clone)]
 pub struct BBox {
@@ -80,6 +84,20 @@ impl BBox {
         b
     }
 
+    /// Returns the overlap of two `BBox`es, which is empty if they don't
+    /// overlap.
+    pub fn intersection(&self, other: BBox) -> BBox {
+        BBox::from_points(
+            Point { co: self.min.co.v_max(other.min.co) },
+            Point { co: self.max.co.v_min(other.max.co) },
+        )
+    }
+
+    /// Returns whether the `BBox` contains no points at all.
+    pub fn is_empty(&self) -> bool {
+        self.min.x() > self.max.x() || self.min.y() > self.max.y() || self.min.z() > self.max.z()
+    }
+
     pub fn surface_area(&self) -> f32 {
         let d = self.max - self.min;
         ((d.x() * d.y()) + (d.y() * d.z()) + (d.z() * d.x())) * 2.0
@@ -146,25 +164,102 @@ impl Lerp for BBox {
 }
 
 
-pub fn transform_bbox_slice_from(bbs_in: &[BBox], xforms: &[Matrix4x4], bbs_out: &mut Vec<BBox>) {
+/// Transforms bounding boxes into world space by decomposed local-to-world
+/// transforms, with both being motion samples spread evenly over time.
+///
+/// Rotating transforms move things along arcs between the time samples, so
+/// the resulting bounds are grown to enclose extra samples along those arcs.
+/// That way the motion is enclosed when they're interpolated.
+pub fn transform_bbox_slice_from(bbs_in: &[BBox], xforms: &[Transform], bbs_out: &mut Vec<BBox>) {
     bbs_out.clear();
 
     // Transform the bounding boxes
     if xforms.is_empty() {
         bbs_out.extend_from_slice(bbs_in);
-    } else if bbs_in.len() == xforms.len() {
-        for (bb, xf) in Iterator::zip(bbs_in.iter(), xforms.iter()) {
-            bbs_out.push(bb.transformed(xf.inverse()));
-        }
-    } else if bbs_in.len() > xforms.len() {
-        let s = (bbs_in.len() - 1) as f32;
-        for (i, bb) in bbs_in.iter().enumerate() {
-            bbs_out.push(bb.transformed(lerp_slice(xforms, i as f32 / s).inverse()));
+        return;
+    }
+    merge_slices_append(bbs_in, xforms, bbs_out, |bb, xf| bb.transformed(xf.to_matrix()));
+
+    // Find the bounds of any rotating motion between the time samples
+    let s = (bbs_out.len() - 1) as f32;
+    let mut arc_bounds = Vec::new();
+    for i in 1..bbs_out.len() {
+        let (time1, time2) = ((i - 1) as f32 / s, i as f32 / s);
+        let angle = lerp_slice(xforms, time1).rotation.angle_to(
+            lerp_slice(xforms, time2).rotation,
+        );
+        if angle <= 0.0 {
+            continue;
         }
-    } else if bbs_in.len() < xforms.len() {
-        let s = (xforms.len() - 1) as f32;
-        for (i, xf) in xforms.iter().enumerate() {
-            bbs_out.push(lerp_slice(bbs_in, i as f32 / s).transformed(xf.inverse()));
+
+        let steps = (angle / MAX_ROTATION_STEP).ceil() as usize;
+        let mut bb = bbs_out[i - 1] | bbs_out[i];
+        let mut radius = 0.0f32;
+        for step in 0..(steps + 1) {
+            let time = time1 + ((time2 - time1) * step as f32 / steps as f32);
+            let xform = lerp_slice(xforms, time).to_matrix();
+            let step_bb = lerp_slice(bbs_in, time).transformed(xform);
+            bb |= step_bb;
+
+            // Distance from the center of rotation to the farthest corner
+            let center = Point::new(0.0, 0.0, 0.0) * xform;
+            let d1 = (center - step_bb.min).abs();
+            let d2 = (center - step_bb.max).abs();
+            radius = radius.max(
+                Vector::new(d1.x().max(d2.x()), d1.y().max(d2.y()), d1.z().max(d2.z())).length(),
+            );
         }
+
+        // Between the extra samples, the arcs stray from a straight line by
+        // at most their sagitta.
+        let sagitta = radius * (1.0 - (angle / steps as f32 * 0.5).cos());
+        let pad = Vector::new(sagitta, sagitta, sagitta);
+        arc_bounds.push((i, BBox::from_points(bb.min - pad, bb.max + pad)));
+    }
+    for (i, bb) in arc_bounds {
+        bbs_out[i - 1] |= bb;
+        bbs_out[i] |= bb;
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn transform_bbox_slice_from_rotation() {
+        // Half a turn around the z axis, about the origin
+        let xforms = [
+            Transform::new(),
+            Transform::from_matrix(Matrix4x4::new_from_values(
+                -1.0,
+                0.0,
+                0.0,
+                0.0,
+                0.0,
+                -1.0,
+                0.0,
+                0.0,
+                0.0,
+                0.0,
+                1.0,
+                0.0,
+                0.0,
+                0.0,
+                0.0,
+                1.0,
+            )),
+        ];
+        let bbs = [BBox::from_points(Point::new(1.0, -0.1, 0.0), Point::new(2.0, 0.1, 1.0))];
+        let mut bbs_out = Vec::new();
+        transform_bbox_slice_from(&bbs, &xforms, &mut bbs_out);
+        assert_eq!(bbs_out.len(), 2);
+
+        // The box sweeps through y = 2 (or -2) halfway through the turn,
+        // which interpolating the bounds should still enclose.
+        let mid = lerp_slice(&bbs_out, 0.5);
+        assert!(mid.max.y() >= 2.0 || mid.min.y() <= -2.0);
+        assert!(mid.max.x() >= 2.0 && mid.min.x() <= -2.0);
     }
 }
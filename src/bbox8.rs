@@ -0,0 +1,123 @@
+#![allow(dead_code)]
+
+use std;
+
+use float4::Float8;
+
+use bbox::BBox;
+use lerp::{lerp, Lerp};
+use ray::AccelRay;
+
+
+const BBOX_MAXT_ADJUST: f32 = 1.00000024;
+
+/// Eight 3D axis-aligned bounding boxes, laid out so that a ray can be
+/// tested against all of them at once with 8-wide SIMD.
+#[derive(Debug, Copy, Clone)]
+pub struct BBox8 {
+    pub x: (Float8, Float8), // (min, max)
+    pub y: (Float8, Float8), // (min, max)
+    pub z: (Float8, Float8), // (min, max)
+}
+
+impl BBox8 {
+    /// Creates a BBox8 from up to eight `BBox`es.  Any boxes beyond those
+    /// given are placed at infinity, where no ray can hit them.
+    pub fn from_bboxes(bbs: &[BBox]) -> BBox8 {
+        assert!(bbs.len() <= 8);
+        let mut min = [[std::f32::INFINITY; 8]; 3];
+        let mut max = [[std::f32::INFINITY; 8]; 3];
+        for (i, bb) in bbs.iter().enumerate() {
+            for axis in 0..3 {
+                min[axis][i] = bb.min.get_n(axis);
+                max[axis][i] = bb.max.get_n(axis);
+            }
+        }
+        BBox8 {
+            x: (Float8::from_array(min[0]), Float8::from_array(max[0])),
+            y: (Float8::from_array(min[1]), Float8::from_array(max[1])),
+            z: (Float8::from_array(min[2]), Float8::from_array(max[2])),
+        }
+    }
+
+    /// Returns a bitmask of which of the boxes the given ray intersects,
+    /// with the 0th box as the lowest bit.
+    #[inline]
+    pub fn intersect_accel_ray(&self, ray: &AccelRay) -> u8 {
+        let orig = (
+            Float8::splat(ray.orig.x()),
+            Float8::splat(ray.orig.y()),
+            Float8::splat(ray.orig.z()),
+        );
+        let dir_inv = (
+            Float8::splat(ray.dir_inv.x()),
+            Float8::splat(ray.dir_inv.y()),
+            Float8::splat(ray.dir_inv.z()),
+        );
+
+        // Calculate slab intersections
+        let tx1 = (self.x.0 - orig.0) * dir_inv.0;
+        let tx2 = (self.x.1 - orig.0) * dir_inv.0;
+        let ty1 = (self.y.0 - orig.1) * dir_inv.1;
+        let ty2 = (self.y.1 - orig.1) * dir_inv.1;
+        let tz1 = (self.z.0 - orig.2) * dir_inv.2;
+        let tz2 = (self.z.1 - orig.2) * dir_inv.2;
+
+        // Find the far and near intersections
+        let far_t = tx1.v_max(tx2).v_min(ty1.v_max(ty2)).v_min(tz1.v_max(tz2));
+        let near_t = tx1.v_min(tx2)
+            .v_max(ty1.v_min(ty2))
+            .v_max(tz1.v_min(tz2))
+            .v_max(Float8::splat(0.0));
+        let far_hit_t = (far_t * BBOX_MAXT_ADJUST).v_min(Float8::splat(ray.max_t));
+
+        // Did we hit?
+        near_t.lte(far_hit_t).to_bitmask()
+    }
+}
+
+
+impl Lerp for BBox8 {
+    fn lerp(self, other: BBox8, alpha: f32) -> BBox8 {
+        BBox8 {
+            x: lerp(self.x, other.x, alpha),
+            y: lerp(self.y, other.y, alpha),
+            z: lerp(self.z, other.z, alpha),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use math::{Point, Vector};
+    use ray::Ray;
+
+    #[test]
+    fn intersect_accel_ray_matches_bbox() {
+        let bbs: Vec<_> = (0..5)
+            .map(|i| {
+                let p = Point::new(i as f32 * 2.0, 0.0, (i % 2) as f32);
+                BBox::from_points(p, p + Vector::new(1.0, 1.0, 1.0))
+            })
+            .collect();
+        let bb8 = BBox8::from_bboxes(&bbs);
+
+        for i in 0..20 {
+            let mut ray = Ray::new(
+                Point::new(-1.0, 0.5, 0.5),
+                Vector::new(1.0, (i as f32 - 10.0) * 0.01, (i as f32 - 10.0) * 0.03),
+                0.0,
+                500.0,
+                false,
+            );
+            ray.max_t = 6.0 + i as f32 * 0.1;
+            let ray = AccelRay::new(&ray, 0);
+            let mask = bbs.iter().enumerate().fold(0, |mask, (bi, bb)| {
+                mask | ((bb.intersect_accel_ray(&ray) as u8) << bi)
+            });
+            assert_eq!(bb8.intersect_accel_ray(&ray), mask);
+        }
+    }
+}
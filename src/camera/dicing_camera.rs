@@ -0,0 +1,87 @@
+#![allow(dead_code)]
+
+use std::f32;
+
+use bbox::BBox;
+use math::Point;
+
+use super::Camera;
+
+
+/// A simplified view of the scene's cameras, for choosing tessellation
+/// rates of geometry that is diced at scene build time.
+#[derive(Clone, Debug)]
+pub struct DicingCamera {
+    // Camera position, the size of a pixel at unit distance from it, and
+    // its near clip distance, for each time sample of each camera.
+    views: Vec<(Point, f32, f32)>,
+}
+
+impl DicingCamera {
+    pub fn from_cameras(cameras: &[Camera], resolution_x: usize) -> DicingCamera {
+        let mut views = Vec::new();
+        for camera in cameras {
+            let tfov = camera.tfovs.iter().fold(f32::INFINITY, |a, b| a.min(*b));
+            let pixel_size = (2.0 * tfov) / resolution_x.max(1) as f32;
+            for xform in camera.transforms {
+                views.push((
+                    Point::new(0.0, 0.0, 0.0) * xform.to_matrix(),
+                    pixel_size,
+                    camera.clip_range.0,
+                ));
+            }
+        }
+
+        DicingCamera { views: views }
+    }
+
+    /// Returns the smallest size of a pixel projected into the scene at
+    /// `bounds`, over all the cameras.
+    ///
+    /// The distance from each camera is clamped to at least `min_distance`
+    /// and the camera's near clip distance.  Pixel sizes shrink to nothing
+    /// at the camera, so `bounds` should be small, e.g. a single face, and
+    /// `min_distance` about its size, which it covers most of the screen at.
+    pub fn pixel_size(&self, bounds: &BBox, min_distance: f32) -> f32 {
+        self.views
+            .iter()
+            .map(|&(pos, pixel_size, near)| {
+                let nearest = pos.max(bounds.min).min(bounds.max);
+                let distance = (nearest - pos).length().max(min_distance).max(near);
+                distance * pixel_size
+            })
+            .fold(f32::INFINITY, |a, b| a.min(b))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mem_arena::MemArena;
+    use math::Matrix4x4;
+    use super::super::CameraParams;
+
+    #[test]
+    fn pixel_size_inside_bounds() {
+        let arena = MemArena::new();
+        let camera = Camera::new(
+            &arena,
+            CameraParams {
+                transforms: vec![Matrix4x4::new()],
+                fovs: vec![f32::consts::FRAC_PI_2],
+                ..CameraParams::default()
+            },
+        );
+        let dicing_camera = DicingCamera::from_cameras(&[camera], 100);
+
+        // A pixel at unit distance is 0.02 across.
+        let far = BBox::from_points(Point::new(-1.0, -1.0, 10.0), Point::new(1.0, 1.0, 12.0));
+        assert!((dicing_camera.pixel_size(&far, 0.0) - 0.2).abs() < 0.0001);
+
+        // Around the camera, the distance is clamped.
+        let around = BBox::from_points(Point::new(-1.0, -1.0, -1.0), Point::new(1.0, 1.0, 1.0));
+        assert_eq!(dicing_camera.pixel_size(&around, 0.0), 0.0);
+        assert!((dicing_camera.pixel_size(&around, 2.0) - 0.04).abs() < 0.0001);
+    }
+}
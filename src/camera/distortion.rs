@@ -0,0 +1,152 @@
+#![allow(dead_code)]
+
+use lerp::Lerp;
+
+
+// Max number of iterations used when inverting the distortion.
+const UNDISTORT_MAX_ITERATIONS: usize = 20;
+
+
+/// How distortion is applied to the rendered image.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum DistortionMode {
+    Distort, // Render a distorted image, matching the plate.
+    Undistort, // Render an undistorted image from a distorted camera.
+}
+
+
+/// Brown-Conrady lens distortion coefficients.
+///
+/// Operates on normalized image coordinates, i.e. the tangents of the
+/// angles from the camera's view axis, which matches the usual convention
+/// of camera tracking and calibration software.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct LensDistortion {
+    pub k1: f32, // Radial
+    pub k2: f32,
+    pub k3: f32,
+    pub p1: f32, // Tangential
+    pub p2: f32,
+}
+
+impl LensDistortion {
+    pub fn none() -> LensDistortion {
+        LensDistortion {
+            k1: 0.0,
+            k2: 0.0,
+            k3: 0.0,
+            p1: 0.0,
+            p2: 0.0,
+        }
+    }
+
+    pub fn is_none(&self) -> bool {
+        *self == LensDistortion::none()
+    }
+
+    /// Maps undistorted coordinates to distorted coordinates.
+    pub fn distort(&self, co: (f32, f32)) -> (f32, f32) {
+        let (x, y) = co;
+        let r2 = (x * x) + (y * y);
+        let radial = 1.0 + (r2 * (self.k1 + (r2 * (self.k2 + (r2 * self.k3)))));
+        let dx = (2.0 * self.p1 * x * y) + (self.p2 * (r2 + (2.0 * x * x)));
+        let dy = (self.p1 * (r2 + (2.0 * y * y))) + (2.0 * self.p2 * x * y);
+
+        ((x * radial) + dx, (y * radial) + dy)
+    }
+
+    /// Maps distorted coordinates back to undistorted coordinates.
+    ///
+    /// There is no closed-form inverse, so this iterates to a solution.
+    pub fn undistort(&self, co: (f32, f32)) -> (f32, f32) {
+        let (xd, yd) = co;
+        let (mut x, mut y) = co;
+        for _ in 0..UNDISTORT_MAX_ITERATIONS {
+            let r2 = (x * x) + (y * y);
+            let radial = 1.0 + (r2 * (self.k1 + (r2 * (self.k2 + (r2 * self.k3)))));
+            let dx = (2.0 * self.p1 * x * y) + (self.p2 * (r2 + (2.0 * x * x)));
+            let dy = (self.p1 * (r2 + (2.0 * y * y))) + (2.0 * self.p2 * x * y);
+            if radial <= 0.0 {
+                break;
+            }
+
+            let nx = (xd - dx) / radial;
+            let ny = (yd - dy) / radial;
+            let done = (nx - x).abs() < 1.0e-7 && (ny - y).abs() < 1.0e-7;
+            x = nx;
+            y = ny;
+            if done {
+                break;
+            }
+        }
+
+        (x, y)
+    }
+}
+
+impl Lerp for LensDistortion {
+    fn lerp(self, other: LensDistortion, alpha: f32) -> LensDistortion {
+        LensDistortion {
+            k1: self.k1.lerp(other.k1, alpha),
+            k2: self.k2.lerp(other.k2, alpha),
+            k3: self.k3.lerp(other.k3, alpha),
+            p1: self.p1.lerp(other.p1, alpha),
+            p2: self.p2.lerp(other.p2, alpha),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn distort_none() {
+        let d = LensDistortion::none();
+        assert_eq!(d.distort((0.3, -0.2)), (0.3, -0.2));
+        assert_eq!(d.undistort((0.3, -0.2)), (0.3, -0.2));
+    }
+
+    #[test]
+    fn distort_barrel() {
+        let d = LensDistortion {
+            k1: -0.2,
+            ..LensDistortion::none()
+        };
+        let (x, y) = d.distort((0.5, 0.0));
+        assert!(x < 0.5);
+        assert_eq!(y, 0.0);
+    }
+
+    #[test]
+    fn undistort_inverts_distort() {
+        let d = LensDistortion {
+            k1: -0.12,
+            k2: 0.03,
+            k3: -0.005,
+            p1: 0.001,
+            p2: -0.002,
+        };
+        for &co in &[(0.0, 0.0), (0.4, 0.1), (-0.3, 0.25), (0.6, -0.5)] {
+            let (x, y) = d.undistort(d.distort(co));
+            assert!((x - co.0).abs() < 1.0e-5);
+            assert!((y - co.1).abs() < 1.0e-5);
+        }
+    }
+
+    #[test]
+    fn lerp_distortion() {
+        let a = LensDistortion::none();
+        let b = LensDistortion {
+            k1: 1.0,
+            k2: 2.0,
+            k3: 3.0,
+            p1: 4.0,
+            p2: 5.0,
+        };
+        let c = a.lerp(b, 0.5);
+        assert_eq!(c.k1, 0.5);
+        assert_eq!(c.p2, 2.5);
+    }
+}
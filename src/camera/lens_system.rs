@@ -0,0 +1,596 @@
+#![allow(dead_code)]
+
+use std::f32;
+
+use mem_arena::MemArena;
+
+use lerp::lerp_slice;
+use math::{Vector, Point, dot};
+use sampling::square_to_circle;
+
+
+// Wavelengths (in nanometers) of the Fraunhofer F, d, and C lines, which
+// are what index of refraction and Abbe numbers are specified against.
+const WL_F: f32 = 486.13;
+const WL_D: f32 = 587.56;
+const WL_C: f32 = 656.27;
+
+// Max number of Newton iterations when intersecting aspheric surfaces.
+const ASPHERIC_MAX_ITERATIONS: usize = 16;
+
+
+/// A single surface of a lens system, as specified in a lens file.
+///
+/// All distances are in lens units (typically millimeters).  The index of
+/// refraction and Abbe number describe the medium on the film side of the
+/// interface, i.e. between this interface and the next one.
+#[derive(Copy, Clone, Debug)]
+pub struct LensInterface {
+    pub radius: f32, // Radius of curvature.  Zero means the surface is flat.
+    pub thickness: f32, // Distance along the optical axis to the next interface.
+    pub ior: f32, // Index of refraction at the d line.
+    pub abbe: f32, // Abbe number.  Zero means no dispersion.
+    pub aperture_radius: f32,
+    pub conic: f32,
+    pub aspheric: [f32; 4], // 4th, 6th, 8th, and 10th order coefficients.
+    pub is_stop: bool,
+}
+
+impl LensInterface {
+    /// Creates an aperture stop.
+    pub fn new_stop(thickness: f32, aperture_radius: f32) -> LensInterface {
+        LensInterface {
+            radius: 0.0,
+            thickness: thickness,
+            ior: 1.0,
+            abbe: 0.0,
+            aperture_radius: aperture_radius,
+            conic: 0.0,
+            aspheric: [0.0; 4],
+            is_stop: true,
+        }
+    }
+
+    /// Index of refraction of the medium behind the interface at the given
+    /// wavelength (in nanometers), using Cauchy's equation fitted to the
+    /// interface's d-line index and Abbe number.
+    pub fn ior_at(&self, wavelength: f32) -> f32 {
+        if self.abbe <= 0.0 || self.ior <= 1.0 {
+            return self.ior;
+        }
+
+        let b = (self.ior - 1.0) /
+            (self.abbe * ((1.0 / (WL_F * WL_F)) - (1.0 / (WL_C * WL_C))));
+        let a = self.ior - (b / (WL_D * WL_D));
+
+        a + (b / (wavelength * wavelength))
+    }
+
+    fn is_dispersive(&self) -> bool {
+        !self.is_stop && self.abbe > 0.0 && self.ior > 1.0
+    }
+
+    fn is_aspheric(&self) -> bool {
+        self.aspheric.iter().any(|n| *n != 0.0)
+    }
+
+    /// Returns the sag of the surface and its derivative with respect to
+    /// the distance from the optical axis, or None if the surface isn't
+    /// defined at that distance.
+    fn sag(&self, r: f32) -> Option<(f32, f32)> {
+        let (mut sag, mut dsag) = if self.radius == 0.0 {
+            (0.0, 0.0)
+        } else {
+            let c = 1.0 / self.radius;
+            let root2 = 1.0 - ((1.0 + self.conic) * c * c * r * r);
+            if root2 <= 0.0 {
+                return None;
+            }
+            let root = root2.sqrt();
+            ((c * r * r) / (1.0 + root), (c * r) / root)
+        };
+
+        let r2 = r * r;
+        let mut rn = r2 * r; // r^(n-1), starting at n = 4
+        for (i, a) in self.aspheric.iter().enumerate() {
+            let n = (i * 2 + 4) as f32;
+            sag += a * rn * r;
+            dsag += a * n * rn;
+            rn *= r2;
+        }
+
+        Some((sag, dsag))
+    }
+
+    /// Intersects a ray with the interface, whose vertex is at the given
+    /// z position.  Returns the ray parameter of the hit and the surface
+    /// normal at that point (not normalized, facing the +z side).
+    fn intersect(&self, vertex_z: f32, orig: Vector, dir: Vector) -> Option<(f32, Vector)> {
+        // Plane or conic section first, which is exact for everything but
+        // aspheric surfaces.
+        let s0 = vertex_z - orig.z();
+        let mut t = if self.radius == 0.0 {
+            if dir.z() == 0.0 {
+                return None;
+            }
+            s0 / dir.z()
+        } else {
+            let k1 = 1.0 + self.conic;
+            let a = (dir.x() * dir.x()) + (dir.y() * dir.y()) + (k1 * dir.z() * dir.z());
+            let b = 2.0 *
+                ((orig.x() * dir.x()) + (orig.y() * dir.y()) - (k1 * s0 * dir.z()) +
+                     (self.radius * dir.z()));
+            let c = (orig.x() * orig.x()) + (orig.y() * orig.y()) + (k1 * s0 * s0) -
+                (2.0 * self.radius * s0);
+
+            // Solve the quadratic, keeping only hits on the same sheet of
+            // the surface as the vertex.
+            let on_vertex_sheet = |t: f32| {
+                let s = s0 - (t * dir.z());
+                (k1 * s / self.radius) < 1.0
+            };
+            let mut best_t = f32::INFINITY;
+            if a.abs() < 1.0e-12 {
+                if b != 0.0 {
+                    let t = -c / b;
+                    if t > 0.0 && on_vertex_sheet(t) {
+                        best_t = t;
+                    }
+                }
+            } else {
+                let discriminant = (b * b) - (4.0 * a * c);
+                if discriminant < 0.0 {
+                    return None;
+                }
+                let root = discriminant.sqrt();
+                let q = if b < 0.0 {
+                    -0.5 * (b - root)
+                } else {
+                    -0.5 * (b + root)
+                };
+                for &t in &[q / a, if q != 0.0 { c / q } else { f32::INFINITY }] {
+                    if t > 0.0 && t < best_t && on_vertex_sheet(t) {
+                        best_t = t;
+                    }
+                }
+            }
+            best_t
+        };
+        if !t.is_finite() || t <= 0.0 {
+            return None;
+        }
+
+        // Refine with Newton's method for aspheric terms.
+        if self.is_aspheric() {
+            let mut converged = false;
+            for _ in 0..ASPHERIC_MAX_ITERATIONS {
+                let x = orig.x() + (dir.x() * t);
+                let y = orig.y() + (dir.y() * t);
+                let r = ((x * x) + (y * y)).sqrt();
+                let (sag, dsag) = self.sag(r)?;
+                let f = s0 - (t * dir.z()) - sag;
+                let dr_dt = if r > 0.0 {
+                    ((x * dir.x()) + (y * dir.y())) / r
+                } else {
+                    0.0
+                };
+                let df = -dir.z() - (dsag * dr_dt);
+                if df == 0.0 {
+                    return None;
+                }
+                let dt = f / df;
+                t -= dt;
+                if dt.abs() <= (t.abs() * 1.0e-6) {
+                    converged = true;
+                    break;
+                }
+            }
+            if !converged || t <= 0.0 {
+                return None;
+            }
+        }
+
+        // Calculate the normal
+        let x = orig.x() + (dir.x() * t);
+        let y = orig.y() + (dir.y() * t);
+        let r = ((x * x) + (y * y)).sqrt();
+        let (_, dsag) = self.sag(r)?;
+        let nor = if r > 0.0 {
+            Vector::new(dsag * x / r, dsag * y / r, 1.0)
+        } else {
+            Vector::new(0.0, 0.0, 1.0)
+        };
+
+        Some((t, nor))
+    }
+}
+
+
+/// A multi-element lens system, with the film at z = 0 and the lens
+/// elements extending towards +z.
+///
+/// Rays are traced from the film through each interface of the lens
+/// prescription.  Rays that are blocked by an element's aperture or that
+/// undergo total internal reflection are lost, so vignetting falls out
+/// naturally.  Distances are in lens units, which are converted to scene
+/// units with `scale`.
+#[derive(Copy, Clone, Debug)]
+pub struct LensSystem<'a> {
+    interfaces: &'a [LensInterface],
+    vertex_offsets: &'a [f32], // Distance from each interface to the rear interface.
+    film_distances: &'a [f32], // Distance from the rear interface to the film, per time sample.
+    sensor_width: f32,
+    scale: f32,
+    focal_length: f32,
+    dispersive: bool,
+}
+
+impl<'a> LensSystem<'a> {
+    /// Builds a lens system from its interfaces, listed from the front
+    /// (scene side) element to the rear (film side) element.
+    ///
+    /// The film is positioned so that the lens is focused at each of the
+    /// given focus distances, which are in scene units and measured from the
+    /// film plane.  A focus distance of infinity focuses at infinity.
+    pub fn new(
+        arena: &'a MemArena,
+        interfaces: &[LensInterface],
+        focus_distances: &[f32],
+        sensor_width: f32,
+        scale: f32,
+    ) -> Result<LensSystem<'a>, &'static str> {
+        if interfaces.is_empty() {
+            return Err("Lens system has no interfaces.");
+        }
+
+        let mut vertex_offsets = vec![0.0f32; interfaces.len()];
+        for i in (0..(interfaces.len() - 1)).rev() {
+            vertex_offsets[i] = vertex_offsets[i + 1] + interfaces[i].thickness;
+        }
+
+        let mut lens = LensSystem {
+            interfaces: arena.copy_slice(interfaces),
+            vertex_offsets: arena.copy_slice(&vertex_offsets),
+            film_distances: &[],
+            sensor_width: sensor_width,
+            scale: scale,
+            focal_length: 0.0,
+            dispersive: interfaces.iter().any(|i| i.is_dispersive()),
+        };
+
+        // Find the cardinal points of the lens, and use them to focus it.
+        let ((front_principal, _), (rear_principal, rear_focal)) = lens.cardinal_points()?;
+        let focal_length = rear_principal - rear_focal;
+        if focal_length <= 0.0 {
+            return Err("Lens system doesn't converge light, so it can't form an image.");
+        }
+        lens.focal_length = focal_length;
+
+        let mut film_distances = Vec::new();
+        for fd in focus_distances.iter() {
+            // Solve the thick lens equation for the film distance.
+            let b = rear_principal;
+            let infinity_distance = focal_length - b;
+            let d = if fd.is_finite() {
+                let a = (fd / scale) - front_principal;
+                let disc = (a + b) * (a + b - (4.0 * focal_length));
+                if disc < 0.0 {
+                    println!(
+                        "WARNING: lens can't focus as close as {}.  Focusing at infinity instead.",
+                        fd
+                    );
+                    infinity_distance
+                } else {
+                    ((a - b) - disc.sqrt()) * 0.5
+                }
+            } else {
+                infinity_distance
+            };
+
+            if d <= 0.0 {
+                return Err("Lens system focuses in front of its rear element.");
+            }
+
+            film_distances.push(d);
+        }
+        if film_distances.is_empty() {
+            film_distances.push(focal_length - rear_principal);
+        }
+        lens.film_distances = arena.copy_slice(&film_distances);
+
+        Ok(lens)
+    }
+
+    /// The paraxial effective focal length of the lens system, in lens units.
+    pub fn focal_length(&self) -> f32 {
+        self.focal_length
+    }
+
+    /// The horizontal field of view in radians, when focused at infinity.
+    pub fn fov(&self) -> f32 {
+        2.0 * (self.sensor_width * 0.5 / self.focal_length).atan()
+    }
+
+    /// Whether the lens system's index of refraction varies with wavelength.
+    pub fn is_dispersive(&self) -> bool {
+        self.dispersive
+    }
+
+    /// Generates a camera-space ray for the given image plane coordinates,
+    /// where x spans [-1, 1] across the width of the sensor.
+    ///
+    /// `u` and `v` select a point on the rear element to aim at, and are
+    /// in [0, 1].  Returns None if the ray doesn't make it through the lens.
+    pub fn generate_ray(
+        &self,
+        x: f32,
+        y: f32,
+        time: f32,
+        wavelength: f32,
+        u: f32,
+        v: f32,
+    ) -> Option<(Point, Vector)> {
+        let film_distance = lerp_slice(self.film_distances, time);
+
+        // The lens flips the image, so the film is flipped to compensate.
+        let half_width = self.sensor_width * 0.5;
+        let film_point = Vector::new(-x * half_width, -y * half_width, 0.0);
+
+        let rear = self.interfaces.last().unwrap();
+        let rear_point = {
+            let (u, v) = square_to_circle((u * 2.0) - 1.0, (v * 2.0) - 1.0);
+            Vector::new(
+                rear.aperture_radius * u,
+                rear.aperture_radius * v,
+                film_distance,
+            )
+        };
+
+        let (orig, dir) = self.trace_from_film(
+            film_point,
+            (rear_point - film_point).normalized(),
+            wavelength,
+            film_distance,
+        )?;
+
+        Some((
+            Point::new(
+                orig.x() * self.scale,
+                orig.y() * self.scale,
+                orig.z() * self.scale,
+            ),
+            dir,
+        ))
+    }
+
+    /// Traces a ray from the film side of the lens out into the scene.
+    fn trace_from_film(
+        &self,
+        mut orig: Vector,
+        mut dir: Vector,
+        wavelength: f32,
+        film_distance: f32,
+    ) -> Option<(Vector, Vector)> {
+        for i in (0..self.interfaces.len()).rev() {
+            let ior_from = self.interfaces[i].ior_at(wavelength);
+            let ior_to = if i > 0 {
+                self.interfaces[i - 1].ior_at(wavelength)
+            } else {
+                1.0
+            };
+            let (o, d) = self.trace_interface(
+                i,
+                orig,
+                dir,
+                ior_from,
+                ior_to,
+                film_distance,
+            )?;
+            orig = o;
+            dir = d;
+        }
+        Some((orig, dir))
+    }
+
+    /// Traces a ray from the scene side of the lens to the film side.
+    fn trace_from_scene(
+        &self,
+        mut orig: Vector,
+        mut dir: Vector,
+        wavelength: f32,
+        film_distance: f32,
+    ) -> Option<(Vector, Vector)> {
+        for i in 0..self.interfaces.len() {
+            let ior_from = if i > 0 {
+                self.interfaces[i - 1].ior_at(wavelength)
+            } else {
+                1.0
+            };
+            let ior_to = self.interfaces[i].ior_at(wavelength);
+            let (o, d) = self.trace_interface(
+                i,
+                orig,
+                dir,
+                ior_from,
+                ior_to,
+                film_distance,
+            )?;
+            orig = o;
+            dir = d;
+        }
+        Some((orig, dir))
+    }
+
+    /// Intersects and refracts a ray through a single interface.
+    fn trace_interface(
+        &self,
+        index: usize,
+        orig: Vector,
+        dir: Vector,
+        ior_from: f32,
+        ior_to: f32,
+        film_distance: f32,
+    ) -> Option<(Vector, Vector)> {
+        let interface = &self.interfaces[index];
+        let vertex_z = film_distance + self.vertex_offsets[index];
+
+        let (t, nor) = interface.intersect(vertex_z, orig, dir)?;
+        let hit = orig + (dir * t);
+
+        // Blocked by the aperture?
+        let r2 = (hit.x() * hit.x()) + (hit.y() * hit.y());
+        if r2 > (interface.aperture_radius * interface.aperture_radius) {
+            return None;
+        }
+
+        if interface.is_stop || ior_from == ior_to {
+            return Some((hit, dir));
+        }
+
+        // Refract
+        let nor = {
+            let n = nor.normalized();
+            if dot(n, dir) > 0.0 { -n } else { n }
+        };
+        let new_dir = refract(dir, nor, ior_from / ior_to)?;
+
+        Some((hit, new_dir))
+    }
+
+    /// Finds the principal planes and focal points of the lens system via
+    /// paraxial rays, with the rear interface at z = 0.
+    ///
+    /// Returns ((front principal plane, front focal point),
+    /// (rear principal plane, rear focal point)) as z positions.
+    fn cardinal_points(&self) -> Result<((f32, f32), (f32, f32)), &'static str> {
+        let front = &self.interfaces[0];
+        let rear = self.interfaces.last().unwrap();
+        let front_z = self.vertex_offsets[0];
+        let error = "Unable to trace paraxial rays through lens system.";
+
+        // Rear: parallel ray coming from the scene.
+        let h = front.aperture_radius * 0.001;
+        let (o, d) = self.trace_from_scene(
+            Vector::new(h, 0.0, front_z + 1.0),
+            Vector::new(0.0, 0.0, -1.0),
+            WL_D,
+            0.0,
+        ).ok_or(error)?;
+        if d.x() == 0.0 {
+            return Err(error);
+        }
+        let rear_focal = o.z() + (d.z() * (-o.x() / d.x()));
+        let rear_principal = o.z() + (d.z() * ((h - o.x()) / d.x()));
+
+        // Front: parallel ray coming from the film.
+        let h = rear.aperture_radius * 0.001;
+        let (o, d) = self.trace_from_film(
+            Vector::new(h, 0.0, -1.0),
+            Vector::new(0.0, 0.0, 1.0),
+            WL_D,
+            0.0,
+        ).ok_or(error)?;
+        if d.x() == 0.0 {
+            return Err(error);
+        }
+        let front_focal = o.z() + (d.z() * (-o.x() / d.x()));
+        let front_principal = o.z() + (d.z() * ((h - o.x()) / d.x()));
+
+        Ok((
+            (front_principal, front_focal),
+            (rear_principal, rear_focal),
+        ))
+    }
+}
+
+
+/// Refracts a direction through a surface with the given normal, which
+/// should face against the direction.  `eta` is the ratio of the indices
+/// of refraction (from / to).  Returns None on total internal reflection.
+fn refract(dir: Vector, nor: Vector, eta: f32) -> Option<Vector> {
+    let cos_i = -dot(nor, dir);
+    let sin2_t = eta * eta * (1.0 - (cos_i * cos_i)).max(0.0);
+    if sin2_t >= 1.0 {
+        return None;
+    }
+    let cos_t = (1.0 - sin2_t).sqrt();
+    Some(((dir * eta) + (nor * ((eta * cos_i) - cos_t))).normalized())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn biconvex_lens() -> Vec<LensInterface> {
+        let glass = LensInterface {
+            radius: 50.0,
+            thickness: 4.0,
+            ior: 1.5168,
+            abbe: 64.17,
+            aperture_radius: 10.0,
+            conic: 0.0,
+            aspheric: [0.0; 4],
+            is_stop: false,
+        };
+        let back = LensInterface {
+            radius: -50.0,
+            thickness: 0.0,
+            ior: 1.0,
+            abbe: 0.0,
+            ..glass
+        };
+        vec![glass, back]
+    }
+
+    #[test]
+    fn ior_at_d_line() {
+        let lens = biconvex_lens();
+        assert!((lens[0].ior_at(WL_D) - 1.5168).abs() < 1.0e-5);
+    }
+
+    #[test]
+    fn ior_abbe_number() {
+        let lens = biconvex_lens();
+        let abbe = (lens[0].ior_at(WL_D) - 1.0) / (lens[0].ior_at(WL_F) - lens[0].ior_at(WL_C));
+        assert!((abbe - 64.17).abs() < 0.01);
+    }
+
+    #[test]
+    fn thick_lens_focal_length() {
+        let arena = MemArena::new();
+        let lens = LensSystem::new(&arena, &biconvex_lens(), &[], 36.0, 0.001).unwrap();
+
+        // Lensmaker's equation
+        let n = 1.5168;
+        let (r1, r2, d) = (50.0, -50.0, 4.0);
+        let inv_f = (n - 1.0) * ((1.0 / r1) - (1.0 / r2) + (((n - 1.0) * d) / (n * r1 * r2)));
+
+        assert!((lens.focal_length() - (1.0 / inv_f)).abs() < 0.05);
+    }
+
+    #[test]
+    fn focuses_at_focus_distance() {
+        let arena = MemArena::new();
+        let lens = LensSystem::new(&arena, &biconvex_lens(), &[2.0], 36.0, 0.001).unwrap();
+
+        // Paraxial rays leaving the center of the film should converge on
+        // the axis at the focus distance.
+        for &u in &[0.47, 0.48, 0.52, 0.53] {
+            let (o, d) = lens.generate_ray(0.0, 0.0, 0.0, WL_D, u, 0.5).unwrap();
+            let z = o.z() - (o.x() * d.z() / d.x());
+            assert!((z - 2.0).abs() < 0.02);
+        }
+    }
+
+    #[test]
+    fn aperture_blocks_rays() {
+        let mut interfaces = biconvex_lens();
+        interfaces.insert(0, LensInterface::new_stop(2.0, 1.0));
+        let arena = MemArena::new();
+        let lens = LensSystem::new(&arena, &interfaces, &[], 36.0, 0.001).unwrap();
+
+        assert!(lens.generate_ray(0.0, 0.0, 0.0, WL_D, 0.5, 0.5).is_some());
+        assert!(lens.generate_ray(0.0, 0.0, 0.0, WL_D, 0.99, 0.5).is_none());
+    }
+}
@@ -0,0 +1,513 @@
+#![allow(dead_code)]
+
+mod dicing_camera;
+mod distortion;
+mod lens_system;
+mod shutter;
+
+use std::f32;
+
+use mem_arena::MemArena;
+
+use lerp::lerp_slice;
+use math::{Vector, Point, Matrix4x4, Transform, dot};
+use ray::Ray;
+use sampling::square_to_circle;
+
+pub use self::dicing_camera::DicingCamera;
+pub use self::distortion::{DistortionMode, LensDistortion};
+pub use self::lens_system::{LensInterface, LensSystem};
+pub use self::shutter::Shutter;
+
+
+/// A world-space plane that cuts away geometry, for cutaway renders.
+///
+/// Only the side of the plane that its normal points towards is kept.
+#[derive(Copy, Clone, Debug)]
+pub struct ClipPlane {
+    pub nor: Vector,
+    pub offset: f32,
+}
+
+impl ClipPlane {
+    /// Signed distance of a point from the plane (scaled by the length of
+    /// the normal), which is negative on the clipped side.
+    pub fn distance(&self, p: Point) -> f32 {
+        dot(self.nor, p.into_vector()) + self.offset
+    }
+}
+
+
+/// The settings a camera is created from.  Settings that can be animated
+/// have one value per time sample, and are optional unless noted.
+#[derive(Clone, Debug)]
+pub struct CameraParams<'a> {
+    pub name: Option<&'a str>,
+    pub transforms: Vec<Matrix4x4>, // Required
+    pub fovs: Vec<f32>, // Required, unless there's a lens system
+    pub aperture_radii: Vec<f32>,
+    pub focus_distances: Vec<f32>,
+    pub distortions: Vec<LensDistortion>,
+    pub distortion_mode: DistortionMode,
+    pub shifts: Vec<(f32, f32)>,
+    pub tilts: Vec<(f32, f32)>,
+    pub squeezes: Vec<f32>,
+
+    // Replaces the thin lens, and can't be combined with distortion, tilt,
+    // or anamorphic squeeze.
+    pub lens_system: Option<LensSystem<'a>>,
+
+    pub shutter: Shutter,
+    pub clip_range: (f32, f32), // Near and far clip distances
+    pub clip_planes: Vec<ClipPlane>,
+}
+
+impl<'a> Default for CameraParams<'a> {
+    fn default() -> CameraParams<'a> {
+        CameraParams {
+            name: None,
+            transforms: Vec::new(),
+            fovs: Vec::new(),
+            aperture_radii: Vec::new(),
+            focus_distances: Vec::new(),
+            distortions: Vec::new(),
+            distortion_mode: DistortionMode::Distort,
+            shifts: Vec::new(),
+            tilts: Vec::new(),
+            squeezes: Vec::new(),
+            lens_system: None,
+            shutter: Shutter::new(),
+            clip_range: (0.0, f32::INFINITY),
+            clip_planes: Vec::new(),
+        }
+    }
+}
+
+
+#[derive(Copy, Clone, Debug)]
+pub struct Camera<'a> {
+    name: Option<&'a str>,
+    transforms: &'a [Transform], // Camera-to-world, decomposed
+    fovs: &'a [f32],
+    tfovs: &'a [f32],
+    aperture_radii: &'a [f32],
+    focus_distances: &'a [f32],
+    distortions: &'a [LensDistortion],
+    distortion_mode: DistortionMode,
+    shifts: &'a [(f32, f32)],
+    tilts: &'a [(f32, f32)],
+    squeezes: &'a [f32],
+    lens_system: Option<LensSystem<'a>>,
+    shutter: Shutter,
+    clip_range: (f32, f32), // Near and far clip distances
+    clip_planes: &'a [ClipPlane],
+}
+
+impl<'a> Camera<'a> {
+    pub fn new(arena: &'a MemArena, params: CameraParams<'a>) -> Camera<'a> {
+        let CameraParams {
+            name,
+            transforms,
+            fovs,
+            mut aperture_radii,
+            mut focus_distances,
+            mut distortions,
+            distortion_mode,
+            mut shifts,
+            mut tilts,
+            mut squeezes,
+            lens_system,
+            shutter,
+            clip_range,
+            clip_planes,
+        } = params;
+
+        assert!(!transforms.is_empty(), "Camera has no transform(s)!");
+        assert!(
+            lens_system.is_none() ||
+                (distortions.is_empty() && tilts.is_empty() && squeezes.is_empty()),
+            "Camera lens systems can't be combined with distortion, tilt, or squeeze!"
+        );
+
+        // A lens system determines its own fov.
+        let fovs = if let Some(ref lens) = lens_system {
+            vec![lens.fov()]
+        } else {
+            fovs
+        };
+        assert!(!fovs.is_empty(), "Camera has no fov(s)!");
+
+        // Aperture needs focus distance and vice-versa.
+        if aperture_radii.is_empty() || focus_distances.is_empty() {
+            aperture_radii = vec![0.0];
+            focus_distances = vec![1.0];
+
+            if aperture_radii.is_empty() && !focus_distances.is_empty() {
+                println!(
+                    "WARNING: camera has aperture radius but no focus distance.  Disabling \
+                          focal blur."
+                );
+            } else if !aperture_radii.is_empty() && focus_distances.is_empty() {
+                println!(
+                    "WARNING: camera has focus distance but no aperture radius.  Disabling \
+                          focal blur."
+                );
+            }
+        }
+
+        // Can't have focus distance of zero.
+        if focus_distances.iter().any(|d| *d == 0.0) {
+            if aperture_radii.iter().any(|a| *a > 0.0) {
+                println!("WARNING: camera focal distance is zero or less.  Disabling focal blur.");
+            }
+            aperture_radii = vec![0.0];
+            focus_distances = vec![1.0];
+        }
+
+        // Defaults for the image plane adjustments.
+        if distortions.is_empty() {
+            distortions.push(LensDistortion::none());
+        }
+        if shifts.is_empty() {
+            shifts.push((0.0, 0.0));
+        }
+        if tilts.is_empty() {
+            tilts.push((0.0, 0.0));
+        }
+        if squeezes.is_empty() {
+            squeezes.push(1.0);
+        }
+        if squeezes.iter().any(|s| *s <= 0.0) {
+            println!("WARNING: camera anamorphic squeeze is zero or less.  Disabling squeeze.");
+            squeezes = vec![1.0];
+        }
+
+        // Convert angle fov into linear fov.
+        let tfovs: Vec<f32> = fovs.iter()
+            .map(|n| (n / 2.0).sin() / (n / 2.0).cos())
+            .collect();
+
+        // Decompose the transforms, so they can be interpolated quickly.
+        let transforms: Vec<Transform> =
+            transforms.iter().map(|m| Transform::from_matrix(*m)).collect();
+
+        Camera {
+            name: name,
+            transforms: arena.copy_slice(&transforms),
+            fovs: arena.copy_slice(&fovs),
+            tfovs: arena.copy_slice(&tfovs),
+            aperture_radii: arena.copy_slice(&aperture_radii),
+            focus_distances: arena.copy_slice(&focus_distances),
+            distortions: arena.copy_slice(&distortions),
+            distortion_mode: distortion_mode,
+            shifts: arena.copy_slice(&shifts),
+            tilts: arena.copy_slice(&tilts),
+            squeezes: arena.copy_slice(&squeezes),
+            lens_system: lens_system,
+            shutter: shutter,
+            clip_range: clip_range,
+            clip_planes: arena.copy_slice(&clip_planes),
+        }
+    }
+
+    /// The world-space planes that cut away geometry when rendering from
+    /// this camera.
+    pub fn clip_planes(&self) -> &'a [ClipPlane] {
+        self.clip_planes
+    }
+
+    /// Maps a [0, 1) sample to a time within the camera's shutter interval,
+    /// for the given image y coordinate (0.0 at the top, 1.0 at the bottom).
+    pub fn sample_time(&self, n: f32, image_y: f32) -> f32 {
+        self.shutter.sample_time(n, image_y)
+    }
+
+    pub fn name(&self) -> Option<&'a str> {
+        self.name
+    }
+
+    /// Whether the rays generated by the camera depend on wavelength.
+    ///
+    /// Such rays are only valid for the hero wavelength of a spectral sample.
+    pub fn is_dispersive(&self) -> bool {
+        self.lens_system.map_or(false, |lens| lens.is_dispersive())
+    }
+
+    /// Generates a camera ray.  Returns None if the ray is blocked, which can
+    /// happen with lens systems.
+    pub fn generate_ray(
+        &self,
+        x: f32,
+        y: f32,
+        time: f32,
+        wavelength: f32,
+        u: f32,
+        v: f32,
+    ) -> Option<Ray> {
+        // Get time-interpolated camera settings
+        let transform = lerp_slice(self.transforms, time).to_matrix();
+        let shift = lerp_slice(self.shifts, time);
+
+        // Lens shift, in units of the image width.
+        let x = x + (shift.0 * 2.0);
+        let y = y + (shift.1 * 2.0);
+
+        // Trace through the lens system, if there is one
+        if let Some(ref lens) = self.lens_system {
+            return lens.generate_ray(x, y, time, wavelength, u, v).map(
+                |(orig, dir)| {
+                    self.make_clipped_ray(orig, dir, &transform, time, wavelength)
+                },
+            );
+        }
+
+        let tfov = lerp_slice(self.tfovs, time);
+        let aperture_radius = lerp_slice(self.aperture_radii, time);
+        let focus_distance = lerp_slice(self.focus_distances, time);
+        let distortion = lerp_slice(self.distortions, time);
+        let tilt = lerp_slice(self.tilts, time);
+        let squeeze = lerp_slice(self.squeezes, time);
+
+        // Normalized image coordinates, with the anamorphic squeeze widening
+        // the horizontal field of view.
+        let (x, y) = {
+            let co = (x * tfov * squeeze, y * tfov);
+            if distortion.is_none() {
+                co
+            } else {
+                match self.distortion_mode {
+                    DistortionMode::Distort => distortion.undistort(co),
+                    DistortionMode::Undistort => distortion.distort(co),
+                }
+            }
+        };
+
+        // Ray origin, with the anamorphic squeeze also squeezing the aperture.
+        let orig = {
+            let (u, v) = square_to_circle((u * 2.0) - 1.0, (v * 2.0) - 1.0);
+            Point::new(aperture_radius * u / squeeze, aperture_radius * v, 0.0)
+        };
+
+        // Ray direction, aimed at the point in focus on the (possibly tilted)
+        // plane of focus.
+        let dir = {
+            let tilt_denom = 1.0 - (x * tilt.0.tan()) - (y * tilt.1.tan());
+            if tilt_denom > 0.0 {
+                let d = focus_distance / tilt_denom;
+                (Point::new(x * d, y * d, d) - orig).normalized()
+            } else {
+                // The plane of focus is behind the camera in this direction.
+                Vector::new(x, y, 1.0).normalized()
+            }
+        };
+
+        Some(self.make_clipped_ray(orig, dir, &transform, time, wavelength))
+    }
+
+    /// Creates a world-space ray from a camera-space ray, starting it at the
+    /// near clip plane and ending it at the far clip plane.
+    fn make_clipped_ray(
+        &self,
+        orig: Point,
+        dir: Vector,
+        transform: &Matrix4x4,
+        time: f32,
+        wavelength: f32,
+    ) -> Ray {
+        // The clip planes are perpendicular to the view (+z) axis.  Rays
+        // that don't point forward can still come out of lens systems, and
+        // are clipped the same way, to the part of them between the planes.
+        let (near, far) = self.clip_range;
+        let (t_near, t_far) = if dir.z() != 0.0 {
+            let t1 = (near - orig.z()) / dir.z();
+            let t2 = (far - orig.z()) / dir.z();
+            (t1.min(t2).max(0.0), t1.max(t2))
+        } else if orig.z() >= near && orig.z() <= far {
+            (0.0, f32::INFINITY)
+        } else {
+            (0.0, 0.0)
+        };
+        let orig = orig + (dir * t_near);
+        let max_t = (t_far - t_near).max(0.0);
+
+        let mut ray = Ray::new(
+            orig * *transform,
+            dir * *transform,
+            time,
+            wavelength,
+            false,
+        );
+        ray.max_t = max_t;
+        ray
+    }
+}
+
+
+/// Interpolates between two camera transforms, for resampling them.
+///
+/// Unlike the transforms of the rest of the scene, camera transforms are
+/// camera-to-world, so they're decomposed as they are rather than by their
+/// inverse.
+pub fn lerp_camera_transform(a: Matrix4x4, b: Matrix4x4, alpha: f32) -> Matrix4x4 {
+    if alpha == 0.0 || a == b {
+        a
+    } else {
+        Transform::from_matrix(a).lerp(Transform::from_matrix(b), alpha).to_matrix()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn camera<'a>(arena: &'a MemArena, params: CameraParams<'a>) -> Camera<'a> {
+        Camera::new(
+            arena,
+            CameraParams {
+                transforms: vec![Matrix4x4::new()],
+                fovs: vec![f32::consts::FRAC_PI_2],
+                ..params
+            },
+        )
+    }
+
+    fn assert_near(a: Vector, b: Vector) {
+        assert!((a - b).length() < 0.0001, "{:?} != {:?}", a, b);
+    }
+
+    #[test]
+    fn generate_ray_clip_range() {
+        let arena = MemArena::new();
+        let clipped = camera(
+            &arena,
+            CameraParams {
+                clip_range: (2.0, 5.0),
+                ..CameraParams::default()
+            },
+        );
+
+        // The ray starts at the near plane and ends at the far plane, along
+        // the view axis rather than the ray.
+        for &(x, y) in &[(0.0, 0.0), (0.5, -0.3), (-0.9, 0.9)] {
+            let ray = clipped.generate_ray(x, y, 0.0, 550.0, 0.5, 0.5).unwrap();
+            let end = ray.orig + (ray.dir * ray.max_t);
+            assert!((ray.orig.z() - 2.0).abs() < 0.0001);
+            assert!((end.z() - 5.0).abs() < 0.0001);
+        }
+
+        // Unclipped rays are unlimited.
+        let plain = camera(&arena, CameraParams::default());
+        let ray = plain.generate_ray(0.5, -0.3, 0.0, 550.0, 0.5, 0.5).unwrap();
+        assert_eq!(ray.orig.z(), 0.0);
+        assert_eq!(ray.max_t, f32::INFINITY);
+    }
+
+    #[test]
+    fn clip_range_not_forward() {
+        let arena = MemArena::new();
+        let clipped = camera(
+            &arena,
+            CameraParams {
+                clip_range: (2.0, 5.0),
+                ..CameraParams::default()
+            },
+        );
+        let xform = Matrix4x4::new();
+        let clip = |orig: Point, dir: Vector| {
+            clipped.make_clipped_ray(orig, dir.normalized(), &xform, 0.0, 550.0)
+        };
+
+        // Backwards from between the planes, ending at the near plane.
+        let ray = clip(Point::new(0.0, 0.0, 3.0), Vector::new(1.0, 0.0, -1.0));
+        assert_eq!(ray.orig.z(), 3.0);
+        assert!((ray.max_t - 2.0f32.sqrt()).abs() < 0.0001);
+
+        // Backwards from in front of the near plane, which is all clipped.
+        let ray = clip(Point::new(0.0, 0.0, 1.0), Vector::new(1.0, 0.0, -1.0));
+        assert_eq!(ray.max_t, 0.0);
+
+        // Backwards from beyond the far plane, ending at the near plane.
+        let ray = clip(Point::new(0.0, 0.0, 6.0), Vector::new(0.0, 0.0, -1.0));
+        assert!((ray.orig.z() - 5.0).abs() < 0.0001);
+        assert!((ray.max_t - 3.0).abs() < 0.0001);
+
+        // Sideways, between the planes or not.
+        let ray = clip(Point::new(0.0, 0.0, 3.0), Vector::new(1.0, 0.0, 0.0));
+        assert_eq!(ray.max_t, f32::INFINITY);
+        let ray = clip(Point::new(0.0, 0.0, 6.0), Vector::new(1.0, 0.0, 0.0));
+        assert_eq!(ray.max_t, 0.0);
+    }
+
+    #[test]
+    fn generate_ray_shift() {
+        let arena = MemArena::new();
+        let plain = camera(&arena, CameraParams::default());
+        let shifted = camera(
+            &arena,
+            CameraParams {
+                shifts: vec![(0.25, -0.1)],
+                ..CameraParams::default()
+            },
+        );
+
+        // Shifts are in units of the image width, which spans [-1, 1].
+        let ray1 = shifted.generate_ray(0.1, 0.3, 0.0, 550.0, 0.5, 0.5).unwrap();
+        let ray2 = plain.generate_ray(0.6, 0.1, 0.0, 550.0, 0.5, 0.5).unwrap();
+        assert_near(ray1.dir, ray2.dir);
+    }
+
+    #[test]
+    fn generate_ray_squeeze() {
+        let arena = MemArena::new();
+        let plain = camera(&arena, CameraParams::default());
+        let squeezed = camera(
+            &arena,
+            CameraParams {
+                squeezes: vec![2.0],
+                aperture_radii: vec![1.0],
+                focus_distances: vec![10.0],
+                ..CameraParams::default()
+            },
+        );
+
+        // The horizontal field of view is widened by the squeeze.
+        let ray1 = squeezed.generate_ray(0.2, 0.3, 0.0, 550.0, 0.5, 0.5).unwrap();
+        let ray2 = plain.generate_ray(0.4, 0.3, 0.0, 550.0, 0.5, 0.5).unwrap();
+        assert_near(ray1.dir, ray2.dir);
+
+        // And the aperture is squeezed horizontally.
+        let ray = squeezed.generate_ray(0.0, 0.0, 0.0, 550.0, 1.0, 0.5).unwrap();
+        assert!((ray.orig.x() - 0.5).abs() < 0.0001);
+        let ray = squeezed.generate_ray(0.0, 0.0, 0.0, 550.0, 0.5, 1.0).unwrap();
+        assert!((ray.orig.y() - 1.0).abs() < 0.0001);
+    }
+
+    #[test]
+    fn generate_ray_tilt() {
+        let arena = MemArena::new();
+        let tilt = 0.3f32;
+        let tilted = camera(
+            &arena,
+            CameraParams {
+                tilts: vec![(tilt, 0.0)],
+                aperture_radii: vec![1.0],
+                focus_distances: vec![10.0],
+                ..CameraParams::default()
+            },
+        );
+
+        // Rays through different parts of the aperture meet on the tilted
+        // plane of focus, which is further away to the left.
+        for x in &[-0.5f32, 0.0, 0.5] {
+            let expected_z = 10.0 / (1.0 - (x * tilt.tan()));
+            for &(u, v) in &[(0.0, 0.5), (1.0, 0.5), (0.5, 0.0), (0.5, 1.0)] {
+                let ray = tilted.generate_ray(*x, 0.0, 0.0, 550.0, u, v).unwrap();
+                let t = (expected_z - ray.orig.z()) / ray.dir.z();
+                let p = ray.orig + (ray.dir * t);
+                assert!((p.x() - (x * expected_z)).abs() < 0.001);
+                assert!(p.y().abs() < 0.001);
+            }
+        }
+    }
+}
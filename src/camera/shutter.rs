@@ -0,0 +1,116 @@
+#![allow(dead_code)]
+
+use math::clamp;
+
+
+/// Camera shutter, which determines how samples are distributed in time.
+///
+/// Times are in the same [0, 1] range that motion blur data spans.
+#[derive(Copy, Clone, Debug)]
+pub struct Shutter {
+    pub open: f32,
+    pub close: f32,
+
+    // Fraction of the exposure during which the shutter is fully open.  The
+    // rest is split evenly between the shutter opening and closing, giving
+    // a trapezoidal shutter curve.  1.0 is an instant, box-shaped shutter.
+    pub efficiency: f32,
+
+    // Fraction of the shutter interval that the exposure start sweeps
+    // across from the top to the bottom of the image, as with the rolling
+    // shutters of CMOS sensors.  0.0 is a global shutter.
+    pub rolling: f32,
+}
+
+impl Shutter {
+    pub fn new() -> Shutter {
+        Shutter {
+            open: 0.0,
+            close: 1.0,
+            efficiency: 1.0,
+            rolling: 0.0,
+        }
+    }
+
+    /// Maps a [0, 1) sample to a time, for the given image y coordinate
+    /// (0.0 at the top of the image and 1.0 at the bottom).
+    pub fn sample_time(&self, n: f32, image_y: f32) -> f32 {
+        let duration = self.close - self.open;
+        let exposure = duration * (1.0 - self.rolling);
+        let start = self.open + (duration * self.rolling * clamp(image_y, 0.0, 1.0));
+
+        start + (exposure * sample_trapezoid(n, self.efficiency))
+    }
+}
+
+/// Maps a [0, 1) sample to [0, 1) distributed according to a trapezoid
+/// whose flat top covers `plateau` of the range.
+fn sample_trapezoid(n: f32, plateau: f32) -> f32 {
+    let ramp = (1.0 - plateau) * 0.5;
+    if ramp <= 0.0 {
+        return n;
+    }
+
+    let area = plateau + ramp;
+    let a = n * area;
+    if a < (ramp * 0.5) {
+        (2.0 * ramp * a).sqrt()
+    } else if a < ((ramp * 0.5) + plateau) {
+        ramp + (a - (ramp * 0.5))
+    } else {
+        1.0 - (2.0 * ramp * (area - a)).max(0.0).sqrt()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sample_trapezoid_box() {
+        assert_eq!(sample_trapezoid(0.0, 1.0), 0.0);
+        assert_eq!(sample_trapezoid(0.3, 1.0), 0.3);
+    }
+
+    #[test]
+    fn sample_trapezoid_triangle() {
+        assert_eq!(sample_trapezoid(0.0, 0.0), 0.0);
+        assert!((sample_trapezoid(0.5, 0.0) - 0.5).abs() < 0.0001);
+        assert!((sample_trapezoid(0.125, 0.0) - 0.25).abs() < 0.0001);
+        assert!((sample_trapezoid(1.0, 0.0) - 1.0).abs() < 0.0001);
+    }
+
+    #[test]
+    fn sample_trapezoid_monotonic() {
+        let mut prev = 0.0;
+        for i in 0..100 {
+            let t = sample_trapezoid(i as f32 / 100.0, 0.6);
+            assert!(t >= prev);
+            prev = t;
+        }
+    }
+
+    #[test]
+    fn sample_time_open_close() {
+        let shutter = Shutter {
+            open: 0.25,
+            close: 0.75,
+            ..Shutter::new()
+        };
+        assert_eq!(shutter.sample_time(0.0, 0.5), 0.25);
+        assert_eq!(shutter.sample_time(0.5, 0.5), 0.5);
+    }
+
+    #[test]
+    fn sample_time_rolling() {
+        let shutter = Shutter {
+            rolling: 0.5,
+            ..Shutter::new()
+        };
+        assert_eq!(shutter.sample_time(0.0, 0.0), 0.0);
+        assert_eq!(shutter.sample_time(1.0, 0.0), 0.5);
+        assert_eq!(shutter.sample_time(0.0, 1.0), 0.5);
+        assert_eq!(shutter.sample_time(1.0, 1.0), 1.0);
+    }
+}
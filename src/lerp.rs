@@ -1,7 +1,7 @@
 #![allow(dead_code)]
 
 use float4;
-use math3d::{Matrix4x4, Normal, Point, Vector};
+use math3d::{Matrix4x4, Normal, Point, Vector, Transform};
 
 /// Trait for allowing a type to be linearly interpolated.
 pub trait Lerp {
@@ -58,6 +58,63 @@ where
     }
 }
 
+/// Like `lerp_slice()`, but converts the samples with `f` before
+/// interpolating them.
+pub fn lerp_slice_map<T, U, F>(s: &[T], alpha: f32, f: F) -> U
+where
+    T: Copy,
+    U: Lerp,
+    F: Fn(T) -> U,
+{
+    debug_assert!(!s.is_empty());
+    debug_assert!(alpha >= 0.0);
+    debug_assert!(alpha <= 1.0);
+
+    if s.len() == 1 || alpha == 1.0 {
+        f(*s.last().unwrap())
+    } else {
+        let tmp = alpha * ((s.len() - 1) as f32);
+        let i1 = tmp as usize;
+        let i2 = i1 + 1;
+        let alpha2 = tmp - (i1 as f32);
+
+        lerp(f(s[i1]), f(s[i2]), alpha2)
+    }
+}
+
+
+/// Finds the segment of the (increasing) sample `times` that `alpha` falls
+/// in, returning the indices of the samples on either side and how far
+/// between them `alpha` is.  Outside of the times, the first or last sample
+/// is used.
+pub fn time_segment(times: &[f32], alpha: f32) -> (usize, usize, f32) {
+    debug_assert!(!times.is_empty());
+
+    let last = times.len() - 1;
+    if alpha <= times[0] {
+        (0, 0, 0.0)
+    } else if alpha >= times[last] {
+        (last, last, 0.0)
+    } else {
+        let i2 = times.iter().position(|t| *t > alpha).unwrap();
+        let i1 = i2 - 1;
+        (i1, i2, (alpha - times[i1]) / (times[i2] - times[i1]))
+    }
+}
+
+/// Like `lerp_slice_with()`, but with the elements of `s` at the given
+/// sample `times` rather than spread evenly from 0.0 to 1.0.
+pub fn lerp_slice_at_times_with<T, F>(s: &[T], times: &[f32], alpha: f32, f: F) -> T
+where
+    T: Copy,
+    F: Fn(T, T, f32) -> T,
+{
+    debug_assert_eq!(s.len(), times.len());
+
+    let (i1, i2, alpha2) = time_segment(times, alpha);
+    if i1 == i2 { s[i1] } else { f(s[i1], s[i2], alpha2) }
+}
+
 
 impl Lerp for f32 {
     fn lerp(self, other: f32, alpha: f32) -> f32 {
@@ -83,6 +140,12 @@ impl Lerp for float4::Float4 {
     }
 }
 
+impl Lerp for float4::Float8 {
+    fn lerp(self, other: float4::Float8, alpha: f32) -> float4::Float8 {
+        (self * (1.0 - alpha)) + (other * alpha)
+    }
+}
+
 impl Lerp for Matrix4x4 {
     fn lerp(self, other: Matrix4x4, alpha: f32) -> Matrix4x4 {
         let alpha_minus = 1.0 - alpha;
@@ -97,6 +160,20 @@ impl Lerp for Matrix4x4 {
     }
 }
 
+/// Transforms are interpolated linearly for translation and scale, and
+/// spherically for rotation, so rotating motion doesn't shrink or shear.
+impl Lerp for Transform {
+    fn lerp(self, other: Transform, alpha: f32) -> Transform {
+        if alpha == 0.0 || self == other {
+            self
+        } else if alpha == 1.0 {
+            other
+        } else {
+            Transform::lerp(&self, other, alpha)
+        }
+    }
+}
+
 impl Lerp for Normal {
     fn lerp(self, other: Normal, alpha: f32) -> Normal {
         (self * (1.0 - alpha)) + (other * alpha)
@@ -199,6 +276,27 @@ mod tests {
         assert_eq!(2.5, lerp_slice(&s[..], alpha));
     }
 
+    #[test]
+    fn lerp_slice_map1() {
+        let s = [0u16, 10, 20, 30, 40];
+
+        assert_eq!(2.5, lerp_slice_map(&s[..], 0.625, |n| n as f32 * 0.1));
+        assert_eq!(4.0, lerp_slice_map(&s[..], 1.0, |n| n as f32 * 0.1));
+    }
+
+    #[test]
+    fn lerp_slice_at_times1() {
+        let s = [0.0f32, 3.0, 4.0];
+        let times = [0.2f32, 0.5, 1.0];
+        let f = |a: f32, b: f32, alpha: f32| lerp(a, b, alpha);
+
+        assert_eq!(0.0, lerp_slice_at_times_with(&s[..], &times[..], 0.0, f));
+        assert_eq!(1.0, lerp_slice_at_times_with(&s[..], &times[..], 0.3, f));
+        assert_eq!(3.0, lerp_slice_at_times_with(&s[..], &times[..], 0.5, f));
+        assert_eq!(3.5, lerp_slice_at_times_with(&s[..], &times[..], 0.75, f));
+        assert_eq!(4.0, lerp_slice_at_times_with(&s[..], &times[..], 1.0, f));
+    }
+
     #[test]
     fn lerp_matrix() {
         let a = Matrix4x4::new_from_values(
@@ -300,6 +398,63 @@ mod tests {
         assert_eq!(a.lerp(b, 1.0), b);
     }
 
+    #[test]
+    fn lerp_transform() {
+        // Rotation of 90 degrees around the z axis, in the inverse (world
+        // to local) direction, and with a translation.
+        let a = Matrix4x4::new_from_values(
+            1.0,
+            0.0,
+            0.0,
+            -2.0,
+            0.0,
+            1.0,
+            0.0,
+            0.0,
+            0.0,
+            0.0,
+            1.0,
+            0.0,
+            0.0,
+            0.0,
+            0.0,
+            1.0,
+        );
+        let b = Matrix4x4::new_from_values(
+            0.0,
+            1.0,
+            0.0,
+            0.0,
+            -1.0,
+            0.0,
+            0.0,
+            2.0,
+            0.0,
+            0.0,
+            1.0,
+            0.0,
+            0.0,
+            0.0,
+            0.0,
+            1.0,
+        );
+
+        let a = Transform::from_matrix(a.inverse());
+        let b = Transform::from_matrix(b.inverse());
+
+        assert_eq!(a.lerp(b, 0.0), a);
+        assert_eq!(a.lerp(b, 1.0), b);
+
+        // The local origin stays put, and local points rotate around it
+        // without shrinking.
+        let inv = a.lerp(b, 0.5).to_matrix();
+        let origin = Point::new(0.0, 0.0, 0.0) * inv;
+        assert!((origin - Point::new(2.0, 0.0, 0.0)).length() < 0.0001);
+        let p = (Point::new(1.0, 0.0, 0.0) * inv) - origin;
+        assert!((p.length() - 1.0).abs() < 0.0001);
+        assert!((p.x() - p.y()).abs() < 0.0001);
+    }
+
     #[test]
     fn lerp_point_1() {
         let p1 = Point::new(1.0, 2.0, 1.0);
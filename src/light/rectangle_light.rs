@@ -4,12 +4,13 @@ use bbox::BBox;
 use boundable::Boundable;
 use color::{XYZ, SpectralSample, Color};
 use lerp::lerp_slice;
-use math::{Vector, Normal, Point, Matrix4x4, cross};
+use math::{Vector, Normal, Point, Matrix4x4, Transform, cross};
 use ray::{Ray, AccelRay};
 use sampling::{spherical_triangle_solid_angle, uniform_sample_spherical_triangle};
 use shading::surface_closure::{SurfaceClosureUnion, EmitClosure};
 use shading::SurfaceShader;
 use surface::{Surface, SurfaceIntersection, SurfaceIntersectionData, triangle};
+use surface::mesh_attribute::PrimvarValues;
 
 use super::SurfaceLight;
 
@@ -190,24 +191,25 @@ impl<'a> SurfaceLight for RectangleLight<'a> {
 
 
 impl<'a> Surface for RectangleLight<'a> {
-    fn intersect_rays(
-        &self,
+    fn intersect_rays<'b>(
+        &'b self,
         accel_rays: &mut [AccelRay],
         wrays: &[Ray],
-        isects: &mut [SurfaceIntersection],
-        shader: &SurfaceShader,
-        space: &[Matrix4x4],
+        isects: &mut [SurfaceIntersection<'b>],
+        shaders: &[&'b SurfaceShader],
+        space: &[Transform],
     ) {
-        let _ = shader; // Silence 'unused' warning
+        let _ = shaders; // Silence 'unused' warning
 
         for r in accel_rays.iter_mut() {
             let wr = &wrays[r.id as usize];
 
             // Calculate time interpolated values
             let dim = lerp_slice(self.dimensions, r.time);
-            let xform = lerp_slice(space, r.time);
+            let space = lerp_slice(space, r.time);
+            let xform = space.to_inverse_matrix();
 
-            let space_inv = xform.inverse();
+            let space_inv = space.to_matrix();
 
             // Get the four corners of the rectangle, transformed into world space
             let p1 = Point::new(dim.0 * 0.5, dim.1 * 0.5, 0.0) * space_inv;
@@ -233,7 +235,10 @@ impl<'a> Surface for RectangleLight<'a> {
                                 pos_err: pos_err,
                                 nor: normal,
                                 nor_g: normal,
+                                tangent: (p4 - p3).normalized(),
                                 uv: (0.0, 0.0), // TODO
+                                vertex_color: None,
+                                primvars: PrimvarValues::new(),
                                 local_space: xform,
                                 sample_pdf: self.sample_pdf(
                                     &xform,
@@ -246,18 +251,10 @@ impl<'a> Surface for RectangleLight<'a> {
                                 ),
                             };
 
-                            let closure = {
-                                let inv_surface_area = (1.0 / (dim.0 as f64 * dim.1 as f64)) as f32;
-                                let color = lerp_slice(self.colors, r.time).to_spectral_sample(
-                                    wr.wavelength,
-                                ) * inv_surface_area;
-                                SurfaceClosureUnion::EmitClosure(EmitClosure::new(color))
-                            };
-
                             // Fill in intersection
                             isects[r.id as usize] = SurfaceIntersection::Hit {
                                 intersection_data: intersection_data,
-                                closure: closure,
+                                shader: self,
                             };
 
                             // Set ray's max t
@@ -272,6 +269,24 @@ impl<'a> Surface for RectangleLight<'a> {
     }
 }
 
+impl<'a> SurfaceShader for RectangleLight<'a> {
+    fn shade(
+        &self,
+        data: &SurfaceIntersectionData,
+        time: f32,
+        wavelength: f32,
+    ) -> SurfaceClosureUnion {
+        let _ = data; // Silence 'unused' warning
+
+        let dim = lerp_slice(self.dimensions, time);
+        let inv_surface_area = (1.0 / (dim.0 as f64 * dim.1 as f64)) as f32;
+        let color = lerp_slice(self.colors, time).to_spectral_sample(wavelength) *
+            inv_surface_area;
+        SurfaceClosureUnion::EmitClosure(EmitClosure::new(color))
+    }
+}
+
+
 impl<'a> Boundable for RectangleLight<'a> {
     fn bounds(&self) -> &[BBox] {
         self.bounds_
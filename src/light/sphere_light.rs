@@ -6,12 +6,14 @@ use bbox::BBox;
 use boundable::Boundable;
 use color::{XYZ, SpectralSample, Color};
 use lerp::lerp_slice;
-use math::{Vector, Normal, Point, Matrix4x4, dot, coordinate_system_from_vector};
+use math::{Vector, Normal, Point, Matrix4x4, Transform, dot, coordinate_system_from_vector};
 use ray::{Ray, AccelRay};
 use sampling::{uniform_sample_cone, uniform_sample_cone_pdf, uniform_sample_sphere};
 use shading::surface_closure::{SurfaceClosureUnion, EmitClosure};
 use shading::SurfaceShader;
 use surface::{Surface, SurfaceIntersection, SurfaceIntersectionData};
+use surface::mesh_attribute::PrimvarValues;
+use surface::sphere::sphere_tangent;
 
 use super::SurfaceLight;
 
@@ -207,21 +209,21 @@ impl<'a> SurfaceLight for SphereLight<'a> {
 
 
 impl<'a> Surface for SphereLight<'a> {
-    fn intersect_rays(
-        &self,
+    fn intersect_rays<'b>(
+        &'b self,
         accel_rays: &mut [AccelRay],
         wrays: &[Ray],
-        isects: &mut [SurfaceIntersection],
-        shader: &SurfaceShader,
-        space: &[Matrix4x4],
+        isects: &mut [SurfaceIntersection<'b>],
+        shaders: &[&'b SurfaceShader],
+        space: &[Transform],
     ) {
-        let _ = shader; // Silence 'unused' warning
+        let _ = shaders; // Silence 'unused' warning
 
         for r in accel_rays.iter_mut() {
             let wr = &wrays[r.id as usize];
 
             // Get the transform space
-            let xform = lerp_slice(space, r.time);
+            let xform = lerp_slice(space, r.time).to_inverse_matrix();
 
             // Get the radius of the sphere at the ray's time
             let radius = lerp_slice(self.radii, r.time); // Radius of the sphere
@@ -309,7 +311,10 @@ impl<'a> Surface for SphereLight<'a> {
                     pos_err: pos_err,
                     nor: normal,
                     nor_g: normal,
+                    tangent: (sphere_tangent(unit_pos) * inv_xform).normalized(),
                     uv: (0.0, 0.0), // TODO
+                    vertex_color: None,
+                    primvars: PrimvarValues::new(),
                     local_space: xform,
                     sample_pdf: self.sample_pdf(
                         &xform,
@@ -322,19 +327,10 @@ impl<'a> Surface for SphereLight<'a> {
                     ),
                 };
 
-                let closure = {
-                    let inv_surface_area = (1.0 / (4.0 * PI_64 * radius as f64 * radius as f64)) as
-                        f32;
-                    let color = lerp_slice(self.colors, r.time).to_spectral_sample(
-                        wr.wavelength,
-                    ) * inv_surface_area;
-                    SurfaceClosureUnion::EmitClosure(EmitClosure::new(color))
-                };
-
                 // Fill in intersection
                 isects[r.id as usize] = SurfaceIntersection::Hit {
                     intersection_data: intersection_data,
-                    closure: closure,
+                    shader: self,
                 };
 
                 // Set ray's max t
@@ -345,6 +341,24 @@ impl<'a> Surface for SphereLight<'a> {
 }
 
 
+impl<'a> SurfaceShader for SphereLight<'a> {
+    fn shade(
+        &self,
+        data: &SurfaceIntersectionData,
+        time: f32,
+        wavelength: f32,
+    ) -> SurfaceClosureUnion {
+        let _ = data; // Silence 'unused' warning
+
+        let radius = lerp_slice(self.radii, time) as f64;
+        let inv_surface_area = (1.0 / (4.0 * PI_64 * radius * radius)) as f32;
+        let color = lerp_slice(self.colors, time).to_spectral_sample(wavelength) *
+            inv_surface_area;
+        SurfaceClosureUnion::EmitClosure(EmitClosure::new(color))
+    }
+}
+
+
 impl<'a> Boundable for SphereLight<'a> {
     fn bounds(&self) -> &[BBox] {
         self.bounds_
@@ -34,6 +34,7 @@ extern crate lazy_static;
 mod accel;
 mod algorithm;
 mod bbox;
+mod bbox8;
 mod boundable;
 mod camera;
 mod color;
@@ -46,6 +47,7 @@ mod light;
 mod math;
 mod mis;
 mod parse;
+mod quantize;
 mod ray;
 mod renderer;
 mod sampling;
@@ -61,6 +63,7 @@ use std::io;
 use std::io::Read;
 use std::mem;
 use std::path::Path;
+use std::process;
 use std::str::FromStr;
 
 use clap::{App, Arg};
@@ -72,7 +75,7 @@ use ray::{Ray, AccelRay};
 use surface::SurfaceIntersection;
 use renderer::LightPath;
 use bbox::BBox;
-use accel::{BVHNode, BVH4Node};
+use accel::{BVHNode, BVH4Node, BVH8Node, QBVH4Node};
 use timer::Timer;
 
 
@@ -149,8 +152,8 @@ fn main() {
                 .long("threads")
                 .value_name("N")
                 .help(
-                    "Number of threads to render with.  Defaults to the number of logical \
-                       cores on the system.",
+                    "Number of threads to build and render with.  Defaults to the number of \
+                       logical cores on the system.",
                 )
                 .takes_value(true)
                 .validator(|s| {
@@ -160,6 +163,45 @@ fn main() {
                     ))
                 }),
         )
+        .arg(
+            Arg::with_name("camera")
+                .short("c")
+                .long("camera")
+                .value_name("NAME")
+                .help(
+                    "Name of the camera to render with.  Defaults to the first camera \
+                     in the scene.",
+                )
+                .takes_value(true)
+                .conflicts_with("all_cameras"),
+        )
+        .arg(Arg::with_name("all_cameras").long("all_cameras").help(
+            "Render every camera in the scene, appending each camera's name to \
+             the output file name.",
+        ))
+        .arg(Arg::with_name("spatial_splits").long("spatial_splits").help(
+            "Build the BVHs of triangle meshes with spatial splits, unless a \
+             mesh's BVHSplits says otherwise.",
+        ))
+        .arg(Arg::with_name("time_splits").long("time_splits").help(
+            "Build the BVHs of moving objects with time splits, which renders fast \
+             motion blur faster.",
+        ))
+        .arg(
+            Arg::with_name("bvh_quantization")
+                .long("bvh_quantization")
+                .value_name("BITS")
+                .help(
+                    "Quantize the bounds of BVH nodes to 8 or 16 bits, which uses less \
+                     memory but renders a little slower.",
+                )
+                .takes_value(true)
+                .possible_values(&["8", "16"]),
+        )
+        .arg(Arg::with_name("quantize_vertices").long("quantize_vertices").help(
+            "Quantize the vertex positions of triangle meshes to 16 bits, which uses \
+             less memory at some cost in precision.",
+        ))
         .arg(Arg::with_name("stats").long("stats").help(
             "Print additional statistics about rendering",
         ))
@@ -192,6 +234,8 @@ fn main() {
         println!("BBox size: {} bytes", mem::size_of::<BBox>());
         println!("BVHNode size: {} bytes", mem::size_of::<BVHNode>());
         println!("BVH4Node size: {} bytes", mem::size_of::<BVH4Node>());
+        println!("BVH8Node size: {} bytes", mem::size_of::<BVH8Node>());
+        println!("QBVH4Node size: {} bytes", mem::size_of::<QBVH4Node>());
         return;
     }
 
@@ -273,6 +317,23 @@ fn main() {
                     println!("Building scene...");
                 }
 
+                let thread_count = if let Some(threads) = args.value_of("threads") {
+                    u32::from_str(threads).unwrap()
+                } else {
+                    num_cpus::get() as u32
+                };
+                accel::set_build_thread_count(thread_count as usize);
+                accel::set_spatial_splits(args.is_present("spatial_splits"));
+                accel::set_time_splits(args.is_present("time_splits"));
+                accel::set_bvh_quantization(match args.value_of("bvh_quantization") {
+                    Some("8") => accel::BVHQuantization::Bits8,
+                    Some("16") => accel::BVHQuantization::Bits16,
+                    _ => accel::BVHQuantization::Off,
+                });
+                surface::triangle_mesh::set_quantize_vertices(
+                    args.is_present("quantize_vertices"),
+                );
+
                 let arena = MemArena::with_min_block_size((1 << 20) * 4);
                 let mut r = parse_scene(&arena, child).unwrap_or_else(|e| {
                     e.print(&psy_contents);
@@ -293,66 +354,110 @@ fn main() {
                         4096
                     };
 
-                let thread_count = if let Some(threads) = args.value_of("threads") {
-                    u32::from_str(threads).unwrap()
-                } else {
-                    num_cpus::get() as u32
-                };
-
                 if !args.is_present("serialized_output") {
                     println!("\tBuilt scene in {:.3}s", t.tick());
                 }
 
-                if !args.is_present("serialized_output") {
-                    println!("Rendering scene with {} threads...", thread_count);
-                }
-                let (mut image, rstats) = r.render(
-                    max_samples_per_bucket,
-                    crop,
-                    thread_count,
-                    args.is_present("serialized_output"),
-                );
-                // Print render stats
-                if !args.is_present("serialized_output") {
-                    let rtime = t.tick();
-                    let ntime = rtime as f64 / rstats.total_time;
-                    println!("\tRendered scene in {:.3}s", rtime);
-                    println!(
-                        "\t\tTrace:                  {:.3}s",
-                        ntime * rstats.trace_time
-                    );
-                    println!(
-                        "\t\t\tTraversal:            {:.3}s",
-                        ntime * rstats.accel_traversal_time
-                    );
-                    println!("\t\t\tRay/node tests:       {}", rstats.accel_node_visits);
-                    println!(
-                        "\t\tInitial ray generation: {:.3}s",
-                        ntime * rstats.initial_ray_generation_time
-                    );
-                    println!(
-                        "\t\tRay generation:         {:.3}s",
-                        ntime * rstats.ray_generation_time
-                    );
-                    println!(
-                        "\t\tSample writing:         {:.3}s",
-                        ntime * rstats.sample_writing_time
-                    );
-                }
+                // Determine which cameras to render with
+                let cameras: Vec<_> = if args.is_present("all_cameras") {
+                    r.scene.cameras.iter().collect()
+                } else {
+                    let name = args.value_of("camera");
+                    if let Some(camera) = r.scene.camera(name) {
+                        vec![camera]
+                    } else {
+                        let names: Vec<_> = r.scene
+                            .cameras
+                            .iter()
+                            .map(|c| c.name().unwrap_or("(unnamed)"))
+                            .collect();
+                        if let Some(name) = name {
+                            println!("ERROR: the scene has no camera named '{}'.", name);
+                        } else {
+                            println!("ERROR: the scene has no cameras.");
+                        }
+                        if !names.is_empty() {
+                            println!("Available cameras: {}", names.join(", "));
+                        }
+                        process::exit(1);
+                    }
+                };
 
-                // Write to disk
-                if !args.is_present("serialized_output") {
-                    println!("Writing image to disk into '{}'...", r.output_file);
-                    if r.output_file.ends_with(".png") {
-                        image.write_png(Path::new(&r.output_file)).expect(
-                            "Failed to write png...",
-                        );
-                    } else if r.output_file.ends_with(".exr") {
-                        image.write_exr(Path::new(&r.output_file));
+                for (camera_i, camera) in cameras.iter().enumerate() {
+                    let output_file = if args.is_present("all_cameras") {
+                        camera_output_path(&r.output_file, camera.name(), camera_i)
                     } else {
-                        panic!("Unknown output file extension.");
+                        r.output_file.clone()
+                    };
+
+                    if !args.is_present("serialized_output") {
+                        if let Some(name) = camera.name() {
+                            println!(
+                                "Rendering scene from camera '{}' with {} threads...",
+                                name,
+                                thread_count
+                            );
+                        } else {
+                            println!("Rendering scene with {} threads...", thread_count);
+                        }
+                    }
+                    let (mut image, rstats) = r.render(
+                        camera,
+                        max_samples_per_bucket,
+                        crop,
+                        thread_count,
+                        args.is_present("serialized_output"),
+                    );
+                    // Print render stats
+                    if !args.is_present("serialized_output") {
+                        let rtime = t.tick();
+                        let ntime = rtime as f64 / rstats.total_time;
+                        println!("\tRendered scene in {:.3}s", rtime);
+                        println!(
+                            "\t\tTrace:                  {:.3}s",
+                            ntime * rstats.trace_time
+                        );
+                        println!(
+                            "\t\t\tTraversal:            {:.3}s",
+                            ntime * rstats.accel_traversal_time
+                        );
+                        println!("\t\t\tRay/node tests:       {}", rstats.accel_node_visits);
+                        println!(
+                            "\t\tInitial ray generation: {:.3}s",
+                            ntime * rstats.initial_ray_generation_time
+                        );
+                        println!(
+                            "\t\tHit sorting:            {:.3}s",
+                            ntime * rstats.hit_sorting_time
+                        );
+                        println!(
+                            "\t\tShading:                {:.3}s",
+                            ntime * rstats.shading_time
+                        );
+                        println!(
+                            "\t\tRay generation:         {:.3}s",
+                            ntime * rstats.ray_generation_time
+                        );
+                        println!(
+                            "\t\tSample writing:         {:.3}s",
+                            ntime * rstats.sample_writing_time
+                        );
+                    }
+
+                    // Write to disk
+                    if !args.is_present("serialized_output") {
+                        println!("Writing image to disk into '{}'...", output_file);
+                        if output_file.ends_with(".png") {
+                            image.write_png(Path::new(&output_file)).expect(
+                                "Failed to write png...",
+                            );
+                        } else if output_file.ends_with(".exr") {
+                            image.write_exr(Path::new(&output_file));
+                        } else {
+                            panic!("Unknown output file extension.");
+                        }
+                        println!("\tWrote image in {:.3}s", t.tick());
                     }
-                    println!("\tWrote image in {:.3}s", t.tick());
                 }
 
                 // Print memory stats if stats are wanted.
@@ -376,6 +481,21 @@ fn main() {
                     }
 
                     println!("\tTotal blocks:  {}", arena_stats.2);
+
+                    // Memory used by BVHs and vertices, and how much
+                    // quantizing them saved.
+                    let print_memory = |name: &str, (bytes, full_bytes): (usize, usize)| {
+                        let mib = bytes as f64 / 1048576.0;
+                        let saved = if full_bytes > 0 {
+                            100.0 * (1.0 - (bytes as f64 / full_bytes as f64))
+                        } else {
+                            0.0
+                        };
+                        println!("\t{:<14} {:.4} MiB ({:.1}% saved)", name, mib, saved);
+                    };
+                    println!("Geometry memory:");
+                    print_memory("BVH nodes:", accel::bvh_memory());
+                    print_memory("Mesh vertices:", surface::triangle_mesh::vertex_memory());
                 }
             }
         }
@@ -384,3 +504,21 @@ fn main() {
     // End with blank line
     println!("");
 }
+
+/// Inserts a camera's name (or its index, if unnamed) before the extension of
+/// an output file path, e.g. "render.png" becomes "render_hero.png".
+fn camera_output_path(path: &str, camera_name: Option<&str>, camera_index: usize) -> String {
+    let suffix = if let Some(name) = camera_name {
+        name.trim_start_matches('$').to_string()
+    } else {
+        format!("camera{}", camera_index)
+    };
+
+    let file_name_start = path.rfind('/').map_or(0, |i| i + 1);
+    if let Some(i) = path[file_name_start..].rfind('.') {
+        let i = file_name_start + i;
+        format!("{}_{}{}", &path[..i], suffix, &path[i..])
+    } else {
+        format!("{}_{}", path, suffix)
+    }
+}
@@ -2,7 +2,8 @@
 
 use std::f32;
 
-pub use math3d::{Matrix4x4, Normal, Point, Vector, DotProduct, dot, CrossProduct, cross};
+pub use math3d::{Matrix4x4, Normal, Point, Vector, Transform, DotProduct, dot, CrossProduct,
+                 cross};
 
 
 /// Clamps a value between a min and max.
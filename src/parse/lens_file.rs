@@ -0,0 +1,142 @@
+#![allow(dead_code)]
+
+use std::result::Result;
+use std::str::FromStr;
+
+use camera::LensInterface;
+
+
+/// Parses the contents of a lens prescription file.
+///
+/// Lens files list one interface per line, from the front (scene side) of
+/// the lens to the rear (film side), with all distances in millimeters:
+///
+/// ```text
+/// # radius  thickness  ior     abbe   aperture_diameter  [conic a4 a6 a8 a10]
+/// 29.475    3.76       1.67    47.1   25.2
+/// 84.83     0.12       1.0     0      25.2
+/// stop      2.0        15.0
+/// ```
+///
+/// A radius of zero means the surface is flat, and the index of refraction
+/// and Abbe number are those of the medium following the interface (an
+/// Abbe number of zero disables dispersion).  The optional trailing
+/// columns give a conic constant and 4th through 10th order aspheric
+/// coefficients.  Aperture stops are written as `stop thickness diameter`.
+/// Everything after a `#` is a comment.
+pub fn parse_lens_file(contents: &str) -> Result<Vec<LensInterface>, String> {
+    let mut interfaces = Vec::new();
+
+    for (line_i, line) in contents.lines().enumerate() {
+        let line = if let Some(i) = line.find('#') {
+            &line[..i]
+        } else {
+            line
+        };
+        let mut words = line.split_whitespace().peekable();
+        if words.peek().is_none() {
+            continue;
+        }
+
+        let error = |msg: &str| format!("Lens file line {}: {}", line_i + 1, msg);
+
+        if words.peek() == Some(&"stop") {
+            words.next();
+            let ns = parse_numbers(words).ok_or_else(|| {
+                error("Invalid number in aperture stop.")
+            })?;
+            if ns.len() != 2 {
+                return Err(error(
+                    "Aperture stops should be specified as 'stop thickness diameter'.",
+                ));
+            }
+            interfaces.push(LensInterface::new_stop(ns[0], ns[1] * 0.5));
+        } else {
+            let ns = parse_numbers(words).ok_or_else(|| error("Invalid number."))?;
+            if ns.len() != 5 && ns.len() != 6 && ns.len() != 10 {
+                return Err(error(
+                    "Lens interfaces should have 5 values, optionally followed by a conic \
+                     constant and four aspheric coefficients.",
+                ));
+            }
+            if ns[2] < 1.0 {
+                return Err(error("Index of refraction must be at least 1.0."));
+            }
+            let mut aspheric = [0.0f32; 4];
+            if ns.len() == 10 {
+                aspheric.copy_from_slice(&ns[6..10]);
+            }
+            interfaces.push(LensInterface {
+                radius: ns[0],
+                thickness: ns[1],
+                ior: ns[2],
+                abbe: ns[3],
+                aperture_radius: ns[4] * 0.5,
+                conic: if ns.len() > 5 { ns[5] } else { 0.0 },
+                aspheric: aspheric,
+                is_stop: false,
+            });
+        }
+    }
+
+    if interfaces.is_empty() {
+        return Err("Lens file contains no lens interfaces.".to_string());
+    }
+
+    return Ok(interfaces);
+}
+
+fn parse_numbers<'a, I: Iterator<Item = &'a str>>(words: I) -> Option<Vec<f32>> {
+    let mut ns = Vec::new();
+    for word in words {
+        if let Ok(n) = f32::from_str(word) {
+            ns.push(n);
+        } else {
+            return None;
+        }
+    }
+    Some(ns)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_lens_file_1() {
+        let text = "# A lens\n\
+                    29.475 3.76 1.67 47.1 25.2\n\
+                    \n\
+                    stop 2.0 15.0 # The stop\n\
+                    -84.83 0.12 1.0 0 25.2 -1.0\n";
+        let interfaces = parse_lens_file(text).unwrap();
+
+        assert_eq!(interfaces.len(), 3);
+        assert_eq!(interfaces[0].radius, 29.475);
+        assert_eq!(interfaces[0].aperture_radius, 12.6);
+        assert!(!interfaces[0].is_stop);
+        assert!(interfaces[1].is_stop);
+        assert_eq!(interfaces[1].thickness, 2.0);
+        assert_eq!(interfaces[1].aperture_radius, 7.5);
+        assert_eq!(interfaces[2].conic, -1.0);
+    }
+
+    #[test]
+    fn parse_lens_file_aspheric() {
+        let text = "10.0 1.0 1.5 60 8.0 0.5 1.0e-4 2.0e-6 0 0";
+        let interfaces = parse_lens_file(text).unwrap();
+
+        assert_eq!(interfaces[0].conic, 0.5);
+        assert_eq!(interfaces[0].aspheric, [1.0e-4, 2.0e-6, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn parse_lens_file_errors() {
+        assert!(parse_lens_file("").is_err());
+        assert!(parse_lens_file("10.0 1.0 1.5 60").is_err());
+        assert!(parse_lens_file("10.0 1.0 1.5 60 abc").is_err());
+        assert!(parse_lens_file("10.0 1.0 0.5 60 8.0").is_err());
+        assert!(parse_lens_file("stop 1.0").is_err());
+    }
+}
@@ -1,8 +1,16 @@
 mod data_tree;
+mod lens_file;
 mod psy_assembly;
+mod psy_bicubic_patch;
+mod psy_bilinear_patch;
+mod psy_curves;
 mod psy_light;
 mod psy_mesh_surface;
+mod psy_points;
+mod psy_sphere;
+mod psy_subdivision_surface;
 mod psy_surface_shader;
+mod psy_time_samples;
 mod psy;
 pub mod basics;
 
@@ -1,5 +1,7 @@
 #![allow(dead_code)]
 
+use std::fs::File;
+use std::io::Read;
 use std::result::Result;
 use std::f32;
 
@@ -8,18 +10,21 @@ use nom::IResult;
 
 use mem_arena::MemArena;
 
-use camera::Camera;
+use camera::{Camera, CameraParams, ClipPlane, DicingCamera, DistortionMode, LensDistortion,
+             LensSystem, Shutter, lerp_camera_transform};
 use color::{XYZ, rec709_e_to_xyz};
 use light::WorldLightSource;
-use math::Matrix4x4;
+use math::{Matrix4x4, Vector};
 use renderer::Renderer;
 use scene::Scene;
 use scene::World;
 
 use super::basics::{ws_u32, ws_f32};
 use super::DataTree;
+use super::lens_file::parse_lens_file;
 use super::psy_assembly::parse_assembly;
 use super::psy_light::parse_distant_disk_light;
+use super::psy_time_samples::TimeSamples;
 
 
 #[derive(Debug)]
@@ -34,6 +39,7 @@ pub enum PsyParseError {
     IncorrectLeafData(usize, &'static str), // Error message
     WrongNodeCount(usize, &'static str, usize), // Error message, sections found
     InstancedMissingData(usize, &'static str, String), // Error message, data name
+    ExternalFileError(usize, String), // Error message
 }
 
 impl PsyParseError {
@@ -82,6 +88,11 @@ impl PsyParseError {
                 let line = line_count_to_byte_offset(psy_content, offset);
                 println!("Line {}: {} Data name: '{}'", line, error, data_name);
             }
+
+            PsyParseError::ExternalFileError(offset, ref error) => {
+                let line = line_count_to_byte_offset(psy_content, offset);
+                println!("Line {}: {}", line, error);
+            }
         }
     }
 }
@@ -90,6 +101,28 @@ fn line_count_to_byte_offset(text: &str, offset: usize) -> usize {
     text[..offset].matches('\n').count() + 1
 }
 
+/// Parses a file path surrounded by quotes, e.g. '["path/to/file.txt"]'.
+fn parse_quoted_path(contents: &str, byte_offset: usize) -> Result<&str, PsyParseError> {
+    // Trim and validate
+    let tc = contents.trim();
+    if tc.chars().count() < 2 {
+        return Err(PsyParseError::IncorrectLeafData(
+            byte_offset,
+            "File path format is incorrect.",
+        ));
+    }
+    if tc.chars().nth(0).unwrap() != '"' || tc.chars().last().unwrap() != '"' {
+        return Err(PsyParseError::IncorrectLeafData(
+            byte_offset,
+            "File paths must be surrounded by quotes.",
+        ));
+    }
+    let len = tc.len();
+
+    // TODO: proper string escaping
+    return Ok(&tc[1..len - 1]);
+}
+
 
 /// Takes in a `DataTree` representing a Scene node and returns
 pub fn parse_scene<'a>(
@@ -115,11 +148,11 @@ pub fn parse_scene<'a>(
             count,
         ));
     }
-    if tree.iter_children_with_type("Camera").count() != 1 {
+    if tree.iter_children_with_type("Camera").count() < 1 {
         let count = tree.iter_children_with_type("Camera").count();
         return Err(PsyParseError::WrongNodeCount(
             tree.byte_offset(),
-            "Scene should have precisely one Camera \
+            "Scene should have at least one Camera \
                                                   section.",
             count,
         ));
@@ -152,19 +185,31 @@ pub fn parse_scene<'a>(
             .unwrap(),
     )?;
 
-    // Parse camera
-    let camera = parse_camera(
-        arena,
-        tree.iter_children_with_type("Camera").nth(0).unwrap(),
-    )?;
+    // Parse cameras
+    let mut cameras: Vec<Camera> = Vec::new();
+    for child in tree.iter_children_with_type("Camera") {
+        let camera = parse_camera(arena, child)?;
+        if camera.name().is_some() && cameras.iter().any(|c| c.name() == camera.name()) {
+            let count = cameras.iter().filter(|c| c.name() == camera.name()).count() + 1;
+            return Err(PsyParseError::WrongNodeCount(
+                child.byte_offset(),
+                "Scene should not have more than one Camera with the same name.",
+                count,
+            ));
+        }
+        cameras.push(camera);
+    }
 
     // Parse world
     let world = parse_world(arena, tree.iter_children_with_type("World").nth(0).unwrap())?;
 
     // Parse root scene assembly
+    let dicing_camera = DicingCamera::from_cameras(&cameras, (render_settings.0).0 as usize);
     let assembly = parse_assembly(
         arena,
         tree.iter_children_with_type("Assembly").nth(0).unwrap(),
+        &dicing_camera,
+        &[Matrix4x4::new()],
     )?;
 
     // Put scene together
@@ -179,7 +224,7 @@ pub fn parse_scene<'a>(
     };
     let scene = Scene {
         name: scene_name,
-        camera: camera,
+        cameras: cameras,
         world: world,
         root: assembly,
     };
@@ -214,29 +259,8 @@ fn parse_output_info(tree: &DataTree) -> Result<String, PsyParseError> {
                     contents,
                     byte_offset,
                 } if type_name == "Path" => {
-                    // Trim and validate
-                    let tc = contents.trim();
-                    if tc.chars().count() < 2 {
-                        return Err(PsyParseError::IncorrectLeafData(
-                            byte_offset,
-                            "File path format is \
-                                                                     incorrect.",
-                        ));
-                    }
-                    if tc.chars().nth(0).unwrap() != '"' || tc.chars().last().unwrap() != '"' {
-                        return Err(PsyParseError::IncorrectLeafData(
-                            byte_offset,
-                            "File paths must be \
-                                                                     surrounded by quotes.",
-                        ));
-                    }
-                    let len = tc.len();
-                    let tc = &tc[1..len - 1];
-
-                    // Parse
-                    // TODO: proper string escaping
                     found_path = true;
-                    path = tc.to_string();
+                    path = parse_quoted_path(contents, byte_offset)?.to_string();
                 }
 
                 _ => {}
@@ -360,11 +384,27 @@ fn parse_render_settings(tree: &DataTree) -> Result<((u32, u32), u32, u32), PsyP
 
 
 fn parse_camera<'a>(arena: &'a MemArena, tree: &'a DataTree) -> Result<Camera<'a>, PsyParseError> {
-    if let DataTree::Internal { ref children, .. } = *tree {
+    if let DataTree::Internal {
+        ref children,
+        ident,
+        ..
+    } = *tree
+    {
         let mut mats = Vec::new();
         let mut fovs = Vec::new();
         let mut focus_distances = Vec::new();
         let mut aperture_radii = Vec::new();
+        let mut distortions = Vec::new();
+        let mut distortion_mode = DistortionMode::Distort;
+        let mut shifts = Vec::new();
+        let mut tilts = Vec::new();
+        let mut squeezes = Vec::new();
+        let mut lens_file = None;
+        let mut shutter = Shutter::new();
+        let mut clip_range = (0.0, f32::INFINITY);
+        let mut clip_planes = Vec::new();
+        let mut sensor_width = 36.0;
+        let mut lens_scale = 0.001;
 
         // Parse
         for child in children.iter() {
@@ -440,16 +480,311 @@ fn parse_camera<'a>(arena: &'a MemArena, tree: &'a DataTree) -> Result<Camera<'a
                     }
                 }
 
+                // Distortion
+                DataTree::Leaf {
+                    type_name,
+                    contents,
+                    byte_offset,
+                } if type_name == "Distortion" => {
+                    if let IResult::Done(_, (k1, k2, k3, p1, p2)) =
+                        closure!(terminated!(
+                            tuple!(ws_f32, ws_f32, ws_f32, ws_f32, ws_f32),
+                            nom::eof
+                        ))(contents.as_bytes())
+                    {
+                        distortions.push(LensDistortion {
+                            k1: k1,
+                            k2: k2,
+                            k3: k3,
+                            p1: p1,
+                            p2: p2,
+                        });
+                    } else {
+                        // Found Distortion, but its contents is not in the right format
+                        return Err(PsyParseError::IncorrectLeafData(
+                            byte_offset,
+                            "Distortion should be five decimal numbers specified in the \
+                             form '[k1 k2 k3 p1 p2]'.",
+                        ));
+                    }
+                }
+
+                // DistortionMode
+                DataTree::Leaf {
+                    type_name,
+                    contents,
+                    byte_offset,
+                } if type_name == "DistortionMode" => {
+                    distortion_mode = match contents.trim() {
+                        "Distort" => DistortionMode::Distort,
+                        "Undistort" => DistortionMode::Undistort,
+                        _ => {
+                            return Err(PsyParseError::UnknownVariant(
+                                byte_offset,
+                                "DistortionMode should be either Distort or Undistort.",
+                            ))
+                        }
+                    };
+                }
+
+                // LensShift
+                DataTree::Leaf {
+                    type_name,
+                    contents,
+                    byte_offset,
+                } if type_name == "LensShift" => {
+                    if let IResult::Done(_, shift) =
+                        closure!(terminated!(tuple!(ws_f32, ws_f32), nom::eof))(contents.as_bytes())
+                    {
+                        shifts.push(shift);
+                    } else {
+                        // Found LensShift, but its contents is not in the right format
+                        return Err(PsyParseError::IncorrectLeafData(
+                            byte_offset,
+                            "LensShift should be two decimal numbers specified in the \
+                             form '[x y]'.",
+                        ));
+                    }
+                }
+
+                // Tilt
+                DataTree::Leaf {
+                    type_name,
+                    contents,
+                    byte_offset,
+                } if type_name == "Tilt" => {
+                    if let IResult::Done(_, (x, y)) =
+                        closure!(terminated!(tuple!(ws_f32, ws_f32), nom::eof))(contents.as_bytes())
+                    {
+                        tilts.push((
+                            x * (f32::consts::PI / 180.0),
+                            y * (f32::consts::PI / 180.0),
+                        ));
+                    } else {
+                        // Found Tilt, but its contents is not in the right format
+                        return Err(PsyParseError::IncorrectLeafData(
+                            byte_offset,
+                            "Tilt should be two decimal numbers specified in the \
+                             form '[x y]'.",
+                        ));
+                    }
+                }
+
+                // AnamorphicSqueeze
+                DataTree::Leaf {
+                    type_name,
+                    contents,
+                    byte_offset,
+                } if type_name == "AnamorphicSqueeze" => {
+                    if let IResult::Done(_, squeeze) = ws_f32(contents.as_bytes()) {
+                        squeezes.push(squeeze);
+                    } else {
+                        // Found AnamorphicSqueeze, but its contents is not in the right format
+                        return Err(PsyParseError::IncorrectLeafData(
+                            byte_offset,
+                            "AnamorphicSqueeze should be a decimal number specified in \
+                             the form '[squeeze]'.",
+                        ));
+                    }
+                }
+
+                // ClipNear
+                DataTree::Leaf {
+                    type_name,
+                    contents,
+                    byte_offset,
+                } if type_name == "ClipNear" => {
+                    if let IResult::Done(_, near) = ws_f32(contents.as_bytes()) {
+                        clip_range.0 = near;
+                    } else {
+                        // Found ClipNear, but its contents is not in the right format
+                        return Err(PsyParseError::IncorrectLeafData(
+                            byte_offset,
+                            "ClipNear should be a decimal number specified in the \
+                             form '[distance]'.",
+                        ));
+                    }
+                }
+
+                // ClipFar
+                DataTree::Leaf {
+                    type_name,
+                    contents,
+                    byte_offset,
+                } if type_name == "ClipFar" => {
+                    if let IResult::Done(_, far) = ws_f32(contents.as_bytes()) {
+                        clip_range.1 = far;
+                    } else {
+                        // Found ClipFar, but its contents is not in the right format
+                        return Err(PsyParseError::IncorrectLeafData(
+                            byte_offset,
+                            "ClipFar should be a decimal number specified in the \
+                             form '[distance]'.",
+                        ));
+                    }
+                }
+
+                // ClipPlane
+                DataTree::Leaf {
+                    type_name,
+                    contents,
+                    byte_offset,
+                } if type_name == "ClipPlane" => {
+                    if let IResult::Done(_, (a, b, c, d)) =
+                        closure!(terminated!(
+                            tuple!(ws_f32, ws_f32, ws_f32, ws_f32),
+                            nom::eof
+                        ))(contents.as_bytes())
+                    {
+                        clip_planes.push(ClipPlane {
+                            nor: Vector::new(a, b, c),
+                            offset: d,
+                        });
+                    } else {
+                        // Found ClipPlane, but its contents is not in the right format
+                        return Err(PsyParseError::IncorrectLeafData(
+                            byte_offset,
+                            "ClipPlane should be four decimal numbers specified in the \
+                             form '[a b c d]', where geometry with a*x + b*y + c*z + d < 0 \
+                             is clipped.",
+                        ));
+                    }
+                }
+
+                // Shutter
+                DataTree::Internal { type_name, .. } if type_name == "Shutter" => {
+                    shutter = parse_shutter(child)?;
+                }
+
+                // LensFile
+                DataTree::Leaf {
+                    type_name,
+                    contents,
+                    byte_offset,
+                } if type_name == "LensFile" => {
+                    lens_file = Some((parse_quoted_path(contents, byte_offset)?, byte_offset));
+                }
+
+                // SensorWidth
+                DataTree::Leaf {
+                    type_name,
+                    contents,
+                    byte_offset,
+                } if type_name == "SensorWidth" => {
+                    if let IResult::Done(_, sw) = ws_f32(contents.as_bytes()) {
+                        sensor_width = sw;
+                    } else {
+                        // Found SensorWidth, but its contents is not in the right format
+                        return Err(PsyParseError::IncorrectLeafData(
+                            byte_offset,
+                            "SensorWidth should be a decimal number specified in the \
+                             form '[width]'.",
+                        ));
+                    }
+                }
+
+                // LensScale
+                DataTree::Leaf {
+                    type_name,
+                    contents,
+                    byte_offset,
+                } if type_name == "LensScale" => {
+                    if let IResult::Done(_, ls) = ws_f32(contents.as_bytes()) {
+                        lens_scale = ls;
+                    } else {
+                        // Found LensScale, but its contents is not in the right format
+                        return Err(PsyParseError::IncorrectLeafData(
+                            byte_offset,
+                            "LensScale should be a decimal number specified in the \
+                             form '[scale]'.",
+                        ));
+                    }
+                }
+
                 _ => {}
             }
         }
 
+        if clip_range.0 < 0.0 || clip_range.0 > clip_range.1 {
+            return Err(PsyParseError::IncorrectLeafData(
+                tree.byte_offset(),
+                "ClipNear should be at least zero and no more than ClipFar.",
+            ));
+        }
+
+        // Resample motion given at uneven times
+        if let Some(time_samples) = TimeSamples::from_parent(tree)? {
+            mats = time_samples.resample_with(mats, lerp_camera_transform)?;
+            fovs = time_samples.resample(fovs)?;
+            focus_distances = time_samples.resample(focus_distances)?;
+            aperture_radii = time_samples.resample(aperture_radii)?;
+            distortions = time_samples.resample(distortions)?;
+            shifts = time_samples.resample(shifts)?;
+            tilts = time_samples.resample(tilts)?;
+            squeezes = time_samples.resample(squeezes)?;
+        }
+
+        // Load the lens system, if any.  The lens system handles focusing
+        // itself, so the thin lens focal blur is disabled.
+        let lens_system = if let Some((path, byte_offset)) = lens_file {
+            if !distortions.is_empty() || !tilts.is_empty() || !squeezes.is_empty() {
+                return Err(PsyParseError::IncorrectLeafData(
+                    byte_offset,
+                    "LensFile can't be combined with Distortion, Tilt, or \
+                     AnamorphicSqueeze, which only apply to the thin lens camera.",
+                ));
+            }
+
+            let mut text = String::new();
+            if File::open(path)
+                .and_then(|mut f| f.read_to_string(&mut text))
+                .is_err()
+            {
+                return Err(PsyParseError::ExternalFileError(
+                    byte_offset,
+                    format!("Unable to read lens file '{}'.", path),
+                ));
+            }
+            let interfaces = parse_lens_file(&text).map_err(|e| {
+                PsyParseError::ExternalFileError(byte_offset, format!("{}: {}", path, e))
+            })?;
+            if focus_distances.is_empty() {
+                focus_distances.push(f32::INFINITY);
+            }
+            let lens = LensSystem::new(
+                arena,
+                &interfaces,
+                &focus_distances,
+                sensor_width,
+                lens_scale,
+            ).map_err(|e| {
+                PsyParseError::ExternalFileError(byte_offset, format!("{}: {}", path, e))
+            })?;
+            aperture_radii.clear();
+            focus_distances.clear();
+            Some(lens)
+        } else {
+            None
+        };
+
         return Ok(Camera::new(
             arena,
-            mats,
-            fovs,
-            aperture_radii,
-            focus_distances,
+            CameraParams {
+                name: ident,
+                transforms: mats,
+                fovs: fovs,
+                aperture_radii: aperture_radii,
+                focus_distances: focus_distances,
+                distortions: distortions,
+                distortion_mode: distortion_mode,
+                shifts: shifts,
+                tilts: tilts,
+                squeezes: squeezes,
+                lens_system: lens_system,
+                shutter: shutter,
+                clip_range: clip_range,
+                clip_planes: clip_planes,
+            },
         ));
     } else {
         return Err(PsyParseError::ExpectedInternalNode(
@@ -464,6 +799,67 @@ fn parse_camera<'a>(arena: &'a MemArena, tree: &'a DataTree) -> Result<Camera<'a
 
 
 
+fn parse_shutter(tree: &DataTree) -> Result<Shutter, PsyParseError> {
+    let mut shutter = Shutter::new();
+
+    for child in tree.iter_children() {
+        if let DataTree::Leaf {
+            type_name,
+            contents,
+            byte_offset,
+        } = *child
+        {
+            let setting = match type_name {
+                "Open" => &mut shutter.open,
+                "Close" => &mut shutter.close,
+                "Efficiency" => &mut shutter.efficiency,
+                "Rolling" => &mut shutter.rolling,
+                _ => continue,
+            };
+            if let IResult::Done(_, n) = ws_f32(contents.as_bytes()) {
+                *setting = n;
+            } else {
+                // Found a shutter setting, but its contents is not in the right format
+                return Err(PsyParseError::IncorrectLeafData(
+                    byte_offset,
+                    "Shutter settings should be decimal numbers specified in \
+                     the form '[n]'.",
+                ));
+            }
+        }
+    }
+
+    if shutter.open < 0.0 || shutter.close > 1.0 {
+        return Err(PsyParseError::IncorrectLeafData(
+            tree.byte_offset(),
+            "Shutter Open and Close should be between 0.0 and 1.0.",
+        ));
+    }
+    if shutter.open > shutter.close {
+        return Err(PsyParseError::IncorrectLeafData(
+            tree.byte_offset(),
+            "Shutter should open before it closes.",
+        ));
+    }
+    if shutter.efficiency < 0.0 || shutter.efficiency > 1.0 {
+        return Err(PsyParseError::IncorrectLeafData(
+            tree.byte_offset(),
+            "Shutter Efficiency should be between 0.0 and 1.0.",
+        ));
+    }
+    if shutter.rolling < 0.0 || shutter.rolling > 1.0 {
+        return Err(PsyParseError::IncorrectLeafData(
+            tree.byte_offset(),
+            "Shutter Rolling should be between 0.0 and 1.0.",
+        ));
+    }
+
+    return Ok(shutter);
+}
+
+
+
+
 fn parse_world<'a>(arena: &'a MemArena, tree: &'a DataTree) -> Result<World<'a>, PsyParseError> {
     if tree.is_internal() {
         let background_color;
@@ -626,3 +1022,42 @@ pub fn make_transform_format_error(byte_offset: usize) -> PsyParseError {
                                              the form '[# # # # # # # # # # # # # # # #]'.",
     )
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn shutter_from_str(text: &str) -> Result<Shutter, PsyParseError> {
+        let tree = DataTree::from_str(text).unwrap();
+        parse_shutter(tree.iter_children_with_type("Shutter").nth(0).unwrap())
+    }
+
+    #[test]
+    fn parse_shutter_range() {
+        let shutter = shutter_from_str("Shutter { Open [0.25] Close [0.75] }").unwrap();
+        assert_eq!((shutter.open, shutter.close), (0.25, 0.75));
+
+        assert!(shutter_from_str("Shutter { Open [0.0] Close [1.5] }").is_err());
+        assert!(shutter_from_str("Shutter { Open [-0.5] Close [1.0] }").is_err());
+        assert!(shutter_from_str("Shutter { Open [0.75] Close [0.25] }").is_err());
+    }
+
+    #[test]
+    fn parse_camera_clip_range() {
+        let arena = MemArena::new();
+        let parses_with_clip = |clip: &str| {
+            let text = format!(
+                "Camera {{ Fov [90] Transform [1 0 0 0 0 1 0 0 0 0 1 0 0 0 0 1] {} }}",
+                clip
+            );
+            let tree = DataTree::from_str(&text).unwrap();
+            let camera = tree.iter_children_with_type("Camera").nth(0).unwrap();
+            parse_camera(&arena, camera).is_ok()
+        };
+
+        assert!(parses_with_clip("ClipNear [1] ClipFar [10]"));
+        assert!(!parses_with_clip("ClipNear [10] ClipFar [1]"));
+        assert!(!parses_with_clip("ClipNear [-1]"));
+    }
+}
@@ -1,31 +1,92 @@
 #![allow(dead_code)]
 
+use std::collections::HashMap;
 use std::result::Result;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crossbeam;
 
 use mem_arena::MemArena;
 
+use accel::build_threads;
+use camera::DicingCamera;
+use math::Matrix4x4;
 use scene::{Assembly, AssemblyBuilder, Object};
+use surface::triangle_mesh::TriangleMesh;
 
 use super::DataTree;
+use super::psy_bicubic_patch::parse_bicubic_patch;
+use super::psy_bilinear_patch::parse_bilinear_patch;
+use super::psy_curves::parse_curves;
 use super::psy_light::{parse_sphere_light, parse_rectangle_light};
 use super::psy_mesh_surface::parse_mesh_surface;
+use super::psy_points::parse_points;
+use super::psy_sphere::parse_sphere;
+use super::psy_subdivision_surface::parse_subdivision_surface;
 use super::psy_surface_shader::parse_surface_shader;
+use super::psy_time_samples::TimeSamples;
 use super::psy::{parse_matrix, PsyParseError};
 
 
+/// Parses an assembly.
+///
+/// `placements` are the world-to-assembly transforms that the assembly is
+/// instanced with, which are used along with `dicing_camera` to choose
+/// tessellation rates for geometry that is diced at build time.
+///
+/// Triangle meshes and sub-assemblies are parsed and built concurrently,
+/// with the extra threads taken from the shared `build_threads()` budget.
 pub fn parse_assembly<'a>(
     arena: &'a MemArena,
     tree: &'a DataTree,
+    dicing_camera: &DicingCamera,
+    placements: &[Matrix4x4],
 ) -> Result<Assembly<'a>, PsyParseError> {
     let mut builder = AssemblyBuilder::new(arena);
 
     if tree.is_internal() {
-        for child in tree.iter_children() {
+        // Gather the world-to-local transforms of everything instanced in
+        // this assembly.
+        let mut child_placements: HashMap<&str, Vec<Matrix4x4>> = HashMap::new();
+        for child in tree.iter_children_with_type("Instance") {
+            if let Some((_, name, _)) = child.iter_leaf_children_with_type("Data").nth(0) {
+                let xforms = parse_instance_transforms(child)?;
+
+                let child_placement = child_placements.entry(name).or_insert_with(Vec::new);
+                if xforms.is_empty() {
+                    child_placement.extend_from_slice(placements);
+                } else {
+                    for placement in placements {
+                        for xform in &xforms {
+                            child_placement.push(*placement * *xform);
+                        }
+                    }
+                }
+            }
+        }
+        let placements_of = |name: &str| -> Vec<Matrix4x4> {
+            child_placements.get(name).cloned().unwrap_or_else(Vec::new)
+        };
+
+        // Meshes and sub-assemblies are the bulk of the work, so build them
+        // all up front.  They're added to the builder in order below.
+        let mut built = build_concurrently(arena, tree, dicing_camera, &child_placements);
+
+        // The number of shaders that objects with per-face shaders need
+        // bound to them.
+        let mut shader_counts: HashMap<&str, usize> = HashMap::new();
+
+        for (child_i, child) in tree.iter_children().enumerate() {
             match child.type_name() {
                 // Sub-Assembly
                 "Assembly" => {
                     if let DataTree::Internal { ident: Some(ident), .. } = *child {
-                        builder.add_assembly(ident, parse_assembly(arena, child)?);
+                        if let Some(Built::Assembly(assembly)) = built[child_i].take() {
+                            builder.add_assembly(ident, assembly?);
+                        } else {
+                            unreachable!();
+                        }
                     } else {
                         return Err(PsyParseError::UnknownError(child.byte_offset()));
                     }
@@ -46,31 +107,20 @@ pub fn parse_assembly<'a>(
                         child.iter_leaf_children_with_type("Data").nth(0).unwrap().1
                     };
 
-                    // Get surface shader binding, if any.
-                    let surface_shader_name = if child
-                        .iter_leaf_children_with_type("SurfaceShaderBind")
-                        .count() > 0
+                    // Get surface shader bindings, if any.
+                    let mut surface_shader_names = Vec::new();
+                    for (_, contents, _) in
+                        child.iter_leaf_children_with_type("SurfaceShaderBind")
                     {
-                        Some(
-                            child
-                                .iter_leaf_children_with_type("SurfaceShaderBind")
-                                .nth(0)
-                                .unwrap()
-                                .1,
-                        )
-                    } else {
-                        None
-                    };
+                        surface_shader_names.extend(contents.split_whitespace());
+                    }
 
                     // Get xforms
-                    let mut xforms = Vec::new();
-                    for (_, contents, _) in child.iter_leaf_children_with_type("Transform") {
-                        xforms.push(parse_matrix(contents)?);
-                    }
+                    let xforms = parse_instance_transforms(child)?;
 
                     // Add instance
                     if builder.name_exists(name) {
-                        builder.add_instance(name, surface_shader_name, Some(&xforms));
+                        builder.add_instance(name, &surface_shader_names, Some(&xforms));
                     } else {
                         return Err(PsyParseError::InstancedMissingData(
                             child.iter_leaf_children_with_type("Data").nth(0).unwrap().2,
@@ -100,10 +150,15 @@ pub fn parse_assembly<'a>(
                 // MeshSurface
                 "MeshSurface" => {
                     if let DataTree::Internal { ident: Some(ident), .. } = *child {
-                        builder.add_object(
-                            ident,
-                            Object::Surface(arena.alloc(parse_mesh_surface(arena, child)?)),
-                        );
+                        let mesh = if let Some(Built::Mesh(mesh)) = built[child_i].take() {
+                            mesh?
+                        } else {
+                            unreachable!();
+                        };
+                        if mesh.shader_count() > 1 {
+                            shader_counts.insert(ident, mesh.shader_count());
+                        }
+                        builder.add_object(ident, Object::Surface(arena.alloc(mesh)));
                     } else {
                         // TODO: error condition of some kind, because no ident
                         panic!(
@@ -114,6 +169,89 @@ pub fn parse_assembly<'a>(
                     }
                 }
 
+                // Bilinear Patch
+                "BilinearPatch" => {
+                    if let DataTree::Internal { ident: Some(ident), .. } = *child {
+                        builder.add_object(
+                            ident,
+                            Object::Surface(arena.alloc(parse_bilinear_patch(arena, child)?)),
+                        );
+                    } else {
+                        // No ident
+                        return Err(PsyParseError::UnknownError(child.byte_offset()));
+                    }
+                }
+
+                // Bicubic Patch
+                "BicubicPatch" => {
+                    if let DataTree::Internal { ident: Some(ident), .. } = *child {
+                        builder.add_object(
+                            ident,
+                            Object::Surface(arena.alloc(parse_bicubic_patch(arena, child)?)),
+                        );
+                    } else {
+                        // No ident
+                        return Err(PsyParseError::UnknownError(child.byte_offset()));
+                    }
+                }
+
+                // Curves
+                "Curves" => {
+                    if let DataTree::Internal { ident: Some(ident), .. } = *child {
+                        builder.add_object(
+                            ident,
+                            Object::Surface(arena.alloc(parse_curves(arena, child)?)),
+                        );
+                    } else {
+                        // No ident
+                        return Err(PsyParseError::UnknownError(child.byte_offset()));
+                    }
+                }
+
+                // Points
+                "Points" => {
+                    if let DataTree::Internal { ident: Some(ident), .. } = *child {
+                        builder.add_object(
+                            ident,
+                            Object::Surface(arena.alloc(parse_points(arena, child)?)),
+                        );
+                    } else {
+                        // No ident
+                        return Err(PsyParseError::UnknownError(child.byte_offset()));
+                    }
+                }
+
+                // Subdivision Surface
+                "SubdivisionSurface" => {
+                    if let DataTree::Internal { ident: Some(ident), .. } = *child {
+                        builder.add_object(
+                            ident,
+                            Object::Surface(arena.alloc(parse_subdivision_surface(
+                                arena,
+                                child,
+                                dicing_camera,
+                                &placements_of(ident),
+                            )?)),
+                        );
+                    } else {
+                        // No ident
+                        return Err(PsyParseError::UnknownError(child.byte_offset()));
+                    }
+                }
+
+                // Sphere
+                "Sphere" => {
+                    if let DataTree::Internal { ident: Some(ident), .. } = *child {
+                        builder.add_object(
+                            ident,
+                            Object::Surface(arena.alloc(parse_sphere(arena, child)?)),
+                        );
+                    } else {
+                        // No ident
+                        return Err(PsyParseError::UnknownError(child.byte_offset()));
+                    }
+                }
+
                 // Sphere Light
                 "SphereLight" => {
                     if let DataTree::Internal { ident: Some(ident), .. } = *child {
@@ -145,26 +283,25 @@ pub fn parse_assembly<'a>(
                 _ => {
                     // TODO: some kind of error, because not a known type name
                 }
+            }
+        }
 
-                // // Bilinear Patch
-                // "BilinearPatch" => {
-                //     assembly->add_object(child.name, parse_bilinear_patch(child));
-                // }
-                //
-                // // Bicubic Patch
-                // else if (child.type == "BicubicPatch") {
-                //     assembly->add_object(child.name, parse_bicubic_patch(child));
-                // }
-                //
-                // // Subdivision surface
-                // else if (child.type == "SubdivisionSurface") {
-                //     assembly->add_object(child.name, parse_subdivision_surface(child));
-                // }
-                //
-                // // Sphere
-                // else if (child.type == "Sphere") {
-                //     assembly->add_object(child.name, parse_sphere(child));
-                // }
+        // Make sure instances bind all the shaders their data's per-face
+        // shaders refer to, now that all the data has been parsed.
+        for child in tree.iter_children_with_type("Instance") {
+            let name = child.iter_leaf_children_with_type("Data").nth(0).unwrap().1;
+            let bind_count = child
+                .iter_leaf_children_with_type("SurfaceShaderBind")
+                .map(|(_, contents, _)| contents.split_whitespace().count())
+                .sum::<usize>();
+            if let Some(count) = shader_counts.get(name) {
+                if bind_count > 0 && bind_count < *count {
+                    return Err(PsyParseError::IncorrectLeafData(
+                        child.byte_offset(),
+                        "Instance binds fewer surface shaders than its data's \
+                         FaceShaderIndices refer to.",
+                    ));
+                }
             }
         }
     } else {
@@ -173,3 +310,103 @@ pub fn parse_assembly<'a>(
 
     return Ok(builder.build());
 }
+
+
+/// A mesh or sub-assembly built ahead of the rest of its assembly.
+enum Built<'a> {
+    Mesh(Result<TriangleMesh<'a>, PsyParseError>),
+    Assembly(Result<Assembly<'a>, PsyParseError>),
+}
+
+
+/// Parses and builds the `MeshSurface` and `Assembly` children of `tree` on
+/// the current thread plus as many extra threads as it can take from the
+/// shared `build_threads()` budget.  The builds inside it take their threads
+/// from the same budget, so nesting doesn't multiply the thread count.
+///
+/// The results are indexed the same as the children, with `None` for the
+/// children that aren't built here.
+fn build_concurrently<'a>(
+    arena: &'a MemArena,
+    tree: &'a DataTree,
+    dicing_camera: &DicingCamera,
+    child_placements: &HashMap<&str, Vec<Matrix4x4>>,
+) -> Vec<Option<Built<'a>>> {
+    let jobs: Vec<(usize, &'a DataTree, &'a str)> = tree.iter_children()
+        .enumerate()
+        .filter_map(|(i, child)| match *child {
+            DataTree::Internal {
+                type_name: "MeshSurface",
+                ident: Some(ident),
+                ..
+            } |
+            DataTree::Internal {
+                type_name: "Assembly",
+                ident: Some(ident),
+                ..
+            } => Some((i, child, ident)),
+            _ => None,
+        })
+        .collect();
+
+    let build = |child: &'a DataTree, ident: &str| -> Built<'a> {
+        let placements = child_placements.get(ident).map(|p| &p[..]).unwrap_or(&[]);
+        if child.type_name() == "Assembly" {
+            Built::Assembly(parse_assembly(arena, child, dicing_camera, placements))
+        } else {
+            Built::Mesh(parse_mesh_surface(arena, child, dicing_camera, placements))
+        }
+    };
+
+    let mut built: Vec<Option<Built<'a>>> = tree.iter_children().map(|_| None).collect();
+    let extra_threads = if jobs.len() > 1 {
+        build_threads().take(jobs.len() - 1)
+    } else {
+        0
+    };
+    if extra_threads == 0 {
+        for &(i, child, ident) in &jobs {
+            built[i] = Some(build(child, ident));
+        }
+    } else {
+        // Each thread, including this one, takes the next job that hasn't
+        // been taken yet.
+        let next_job = AtomicUsize::new(0);
+        let built_mutex = Mutex::new(built);
+        let work = || loop {
+            let job_i = next_job.fetch_add(1, Ordering::Relaxed);
+            if job_i >= jobs.len() {
+                break;
+            }
+            let (i, child, ident) = jobs[job_i];
+            let result = build(child, ident);
+            built_mutex.lock().unwrap()[i] = Some(result);
+        };
+        crossbeam::scope(|scope| {
+            for _ in 0..extra_threads {
+                scope.spawn(&work);
+            }
+            work();
+        });
+        build_threads().give_back(extra_threads);
+        built = built_mutex.into_inner().unwrap();
+    }
+
+    built
+}
+
+
+/// Parses the world-to-local transforms of an instance, resampled to evenly
+/// spaced time samples if it has `TimeSamples`.
+fn parse_instance_transforms(tree: &DataTree) -> Result<Vec<Matrix4x4>, PsyParseError> {
+    let mut xforms = Vec::new();
+    for (_, contents, _) in tree.iter_leaf_children_with_type("Transform") {
+        xforms.push(parse_matrix(contents)?);
+    }
+
+    if let Some(time_samples) = TimeSamples::from_parent(tree)? {
+        time_samples.resample(xforms)
+    } else {
+        Ok(xforms)
+    }
+}
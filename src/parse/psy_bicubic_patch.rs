@@ -0,0 +1,60 @@
+#![allow(dead_code)]
+
+use std::result::Result;
+
+use nom::IResult;
+
+use mem_arena::MemArena;
+
+use surface::bicubic_patch::BicubicPatchMesh;
+
+use super::basics::ws_usize;
+use super::DataTree;
+use super::psy_mesh_surface::parse_vertex_time_samples;
+use super::psy::PsyParseError;
+
+
+/// Parses a bicubic Bézier patch mesh.
+///
+/// Every 16 vertices in `Vertices` are the control points of one patch.
+/// An optional `DiceRate` overrides the automatically chosen number of
+/// segments the patches are diced into along each parametric direction.
+pub fn parse_bicubic_patch<'a>(
+    arena: &'a MemArena,
+    tree: &'a DataTree,
+) -> Result<BicubicPatchMesh<'a>, PsyParseError> {
+    let mut dice_rate = None;
+
+    // Get verts
+    let verts = parse_vertex_time_samples(tree, "Vertices")?;
+    if verts[0].is_empty() || (verts[0].len() % 16) != 0 {
+        return Err(PsyParseError::IncorrectLeafData(
+            tree.byte_offset(),
+            "Bicubic patches should have exactly 16 control points each.",
+        ));
+    }
+
+    // Get dice rate, if any
+    if let Some((_, text, byte_offset)) = tree.iter_leaf_children_with_type("DiceRate").nth(0) {
+        if let IResult::Done(_, rate) = ws_usize(text.as_bytes()) {
+            if rate == 0 {
+                return Err(PsyParseError::IncorrectLeafData(
+                    byte_offset,
+                    "DiceRate must be at least 1.",
+                ));
+            }
+            dice_rate = Some(rate);
+        } else {
+            return Err(PsyParseError::IncorrectLeafData(
+                byte_offset,
+                "DiceRate should be a single integer.",
+            ));
+        }
+    }
+
+    Ok(BicubicPatchMesh::from_control_points(
+        arena,
+        verts,
+        dice_rate,
+    ))
+}
@@ -0,0 +1,77 @@
+#![allow(dead_code)]
+
+use std::result::Result;
+
+use nom::IResult;
+
+use mem_arena::MemArena;
+
+use surface::bilinear_patch::BilinearPatchMesh;
+
+use super::basics::ws_usize;
+use super::DataTree;
+use super::psy_mesh_surface::parse_vertex_time_samples;
+use super::psy::PsyParseError;
+
+
+/// Parses a bilinear patch mesh.
+///
+/// Patches are given as groups of four vertex indices in `FaceVertIndices`,
+/// in winding order around each patch.  If `FaceVertIndices` is left out,
+/// every four consecutive vertices form a patch.
+pub fn parse_bilinear_patch<'a>(
+    arena: &'a MemArena,
+    tree: &'a DataTree,
+) -> Result<BilinearPatchMesh<'a>, PsyParseError> {
+    let mut face_vert_indices = Vec::new();
+
+    // Get verts
+    let verts = parse_vertex_time_samples(tree, "Vertices")?;
+
+    let vert_count = verts[0].len();
+
+    // Get face vert indices
+    let indices_offset =
+        if let Some((_, text, byte_offset)) = tree.iter_leaf_children_with_type("FaceVertIndices")
+            .nth(0)
+        {
+            let mut raw_text = text.trim().as_bytes();
+
+            while let IResult::Done(remaining, index) = ws_usize(raw_text) {
+                raw_text = remaining;
+
+                face_vert_indices.push(index);
+            }
+
+            byte_offset
+        } else {
+            face_vert_indices.extend(0..vert_count);
+
+            tree.byte_offset()
+        };
+
+    if face_vert_indices.len() % 4 != 0 {
+        return Err(PsyParseError::IncorrectLeafData(
+            indices_offset,
+            "Bilinear patches should have exactly four vertices each.",
+        ));
+    }
+    if face_vert_indices.iter().any(|i| *i >= vert_count) {
+        return Err(PsyParseError::IncorrectLeafData(
+            indices_offset,
+            "Vertex index out of range.",
+        ));
+    }
+
+    // Build patch mesh
+    let patch_vert_indices = face_vert_indices
+        .chunks(4)
+        .map(|c| (c[0], c[1], c[2], c[3]))
+        .collect();
+
+    Ok(BilinearPatchMesh::from_verts_and_indices(
+        arena,
+        verts,
+        patch_vert_indices,
+    ))
+}
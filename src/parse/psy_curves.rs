@@ -0,0 +1,146 @@
+#![allow(dead_code)]
+
+use std::result::Result;
+
+use nom::IResult;
+
+use mem_arena::MemArena;
+
+use surface::curves::{Curves, CurveBasis, CurveMode};
+
+use super::basics::{ws_usize, ws_f32};
+use super::DataTree;
+use super::psy_mesh_surface::parse_vertex_time_samples;
+use super::psy::PsyParseError;
+
+
+/// Parses a set of curves.
+///
+/// `Basis` is one of `Linear`, `Bezier`, or `BSpline` (default), and `Mode`
+/// is either `Ribbon` (default) or `Tube`.  `Widths` has either one width
+/// per vertex or a single width for all of them, and `VertCounts` is the
+/// number of vertices in each curve.
+pub fn parse_curves<'a>(
+    arena: &'a MemArena,
+    tree: &'a DataTree,
+) -> Result<Curves<'a>, PsyParseError> {
+    let mut widths = Vec::new();
+    let mut vert_counts = Vec::new();
+
+    // Get basis
+    let basis = if let Some((_, text, byte_offset)) = tree.iter_leaf_children_with_type("Basis")
+        .nth(0)
+    {
+        match text.trim() {
+            "Linear" => CurveBasis::Linear,
+            "Bezier" => CurveBasis::Bezier,
+            "BSpline" => CurveBasis::BSpline,
+            _ => {
+                return Err(PsyParseError::UnknownVariant(
+                    byte_offset,
+                    "Basis should be one of Linear, Bezier, or BSpline.",
+                ));
+            }
+        }
+    } else {
+        CurveBasis::BSpline
+    };
+
+    // Get mode
+    let mode = if let Some((_, text, byte_offset)) = tree.iter_leaf_children_with_type("Mode")
+        .nth(0)
+    {
+        match text.trim() {
+            "Ribbon" => CurveMode::Ribbon,
+            "Tube" => CurveMode::Tube,
+            _ => {
+                return Err(PsyParseError::UnknownVariant(
+                    byte_offset,
+                    "Mode should be either Ribbon or Tube.",
+                ));
+            }
+        }
+    } else {
+        CurveMode::Ribbon
+    };
+
+    // Get verts
+    let verts = parse_vertex_time_samples(tree, "Vertices")?;
+
+    let vert_count = verts[0].len();
+
+    // Get widths
+    if let Some((_, text, byte_offset)) = tree.iter_leaf_children_with_type("Widths").nth(0) {
+        let mut raw_text = text.trim().as_bytes();
+        while let IResult::Done(remaining, w) = ws_f32(raw_text) {
+            raw_text = remaining;
+            widths.push(w);
+        }
+
+        if widths.len() == 1 {
+            widths = vec![widths[0]; vert_count];
+        } else if widths.len() != vert_count {
+            return Err(PsyParseError::IncorrectLeafData(
+                byte_offset,
+                "Widths should have either one width per vertex or a single width.",
+            ));
+        }
+        if widths.iter().any(|w| *w < 0.0) {
+            return Err(PsyParseError::IncorrectLeafData(
+                byte_offset,
+                "Widths cannot be negative.",
+            ));
+        }
+    } else {
+        return Err(PsyParseError::MissingNode(
+            tree.byte_offset(),
+            "Curves should have a Widths section.",
+        ));
+    }
+
+    // Get vert counts
+    let counts_offset =
+        if let Some((_, text, byte_offset)) = tree.iter_leaf_children_with_type("VertCounts")
+            .nth(0)
+        {
+            let mut raw_text = text.trim().as_bytes();
+            while let IResult::Done(remaining, count) = ws_usize(raw_text) {
+                raw_text = remaining;
+                vert_counts.push(count);
+            }
+            byte_offset
+        } else {
+            return Err(PsyParseError::MissingNode(
+                tree.byte_offset(),
+                "Curves should have a VertCounts section.",
+            ));
+        };
+
+    if vert_counts.iter().sum::<usize>() != vert_count {
+        return Err(PsyParseError::IncorrectLeafData(
+            counts_offset,
+            "VertCounts should add up to the number of vertices.",
+        ));
+    }
+    let valid_count = |c: usize| match basis {
+        CurveBasis::Linear => c >= 2,
+        CurveBasis::Bezier => c >= 4 && (c - 1) % 3 == 0,
+        CurveBasis::BSpline => c >= 4,
+    };
+    if !vert_counts.iter().all(|c| valid_count(*c)) {
+        return Err(PsyParseError::IncorrectLeafData(
+            counts_offset,
+            "Curve vertex count is invalid for the curve basis: linear curves need at least \
+             2 vertices, B-splines at least 4, and Bézier curves 3n + 1.",
+        ));
+    }
+
+    Ok(Curves::new(
+        arena,
+        basis,
+        mode,
+        verts,
+        widths,
+        vert_counts,
+    ))
+}
@@ -12,6 +12,7 @@ use light::{DistantDiskLight, SphereLight, RectangleLight};
 
 use super::basics::ws_f32;
 use super::DataTree;
+use super::psy_time_samples::TimeSamples;
 use super::psy::PsyParseError;
 
 
@@ -80,6 +81,13 @@ pub fn parse_distant_disk_light<'a>(
             }
         }
 
+        // Resample motion given at uneven times
+        if let Some(time_samples) = TimeSamples::from_parent(tree)? {
+            radii = time_samples.resample(radii)?;
+            directions = time_samples.resample(directions)?;
+            colors = time_samples.resample(colors)?;
+        }
+
         return Ok(DistantDiskLight::new(arena, radii, directions, colors));
     } else {
         return Err(PsyParseError::UnknownError(tree.byte_offset()));
@@ -135,6 +143,12 @@ pub fn parse_sphere_light<'a>(
             }
         }
 
+        // Resample motion given at uneven times
+        if let Some(time_samples) = TimeSamples::from_parent(tree)? {
+            radii = time_samples.resample(radii)?;
+            colors = time_samples.resample(colors)?;
+        }
+
         return Ok(SphereLight::new(arena, radii, colors));
     } else {
         return Err(PsyParseError::UnknownError(tree.byte_offset()));
@@ -191,6 +205,12 @@ pub fn parse_rectangle_light<'a>(
             }
         }
 
+        // Resample motion given at uneven times
+        if let Some(time_samples) = TimeSamples::from_parent(tree)? {
+            dimensions = time_samples.resample(dimensions)?;
+            colors = time_samples.resample(colors)?;
+        }
+
         return Ok(RectangleLight::new(arena, dimensions, colors));
     } else {
         return Err(PsyParseError::UnknownError(tree.byte_offset()));
@@ -6,14 +6,32 @@ use nom::IResult;
 
 use mem_arena::MemArena;
 
-use math::{Point, Normal};
+use accel;
+use bbox::BBox;
+use camera::DicingCamera;
+use color::rec709_e_to_xyz;
+use math::{Point, Normal, Vector, Matrix4x4};
+use surface::displacement::{Displacement, DisplacementPattern, dice_and_displace};
+use surface::mesh_attribute::{AttributeList, MeshAttributes, MAX_ATTRIBUTE_SIZE,
+                              MAX_PRIMVAR_VALUES};
+use surface::smooth_normals::{NormalWeighting, smooth_normals};
 use surface::triangle_mesh::TriangleMesh;
 
-use super::basics::{ws_usize, ws_f32};
+use super::basics::{ws_u32, ws_usize, ws_f32};
 use super::DataTree;
+use super::psy_time_samples::TimeSamples;
 use super::psy::PsyParseError;
 
 
+// Target length of the edges of displaced micropolygons, in pixels.
+const DICE_PIXELS: f32 = 1.0;
+
+// Maximum dice rate of a triangle, and the maximum number of
+// micropolygons a single displaced mesh is diced into.
+const MAX_DICE_RATE: usize = 64;
+const MAX_MICROPOLYGONS: usize = 1 << 24;
+
+
 // pub struct TriangleMesh {
 //    time_samples: usize,
 //    geo: Vec<(Point, Point, Point)>,
@@ -21,11 +39,36 @@ use super::psy::PsyParseError;
 //    accel: BVH,
 // }
 
+/// Parses a polygon mesh into a triangle mesh.
+///
+/// Without `Normals`, `SmoothNormals` (either `Area` or `Angle` weighted)
+/// generates smooth normals, which are creased across edges sharper than
+/// `CreaseAngle` degrees and along the vertex pairs in `HardEdges`.
+/// Displaced meshes always get smooth normals from their displaced surface,
+/// and can't use any of those.
+///
+/// `UVs`, `VertexColors`, and the `Values` of `Primvar` sections are either
+/// per-vertex or face-varying (one per entry in `FaceVertIndices`),
+/// depending on how many values they have.  `FaceShaderIndices` assigns
+/// each face one of the shaders bound to the mesh's instances.
+///
+/// `Velocities` and `Accelerations` have one vector per vertex, and move
+/// the vertices along a path over the course of the shutter, for motion
+/// blur of meshes whose topology changes from frame to frame.  They're in
+/// units per shutter time, where the shutter spans from 0.0 to 1.0.
+///
+/// `BVHSplits` is either `Object` or `Spatial`, and overrides the global
+/// choice of whether the mesh's BVH is built with spatial splits.
+///
+/// Meshes with a `Displacement` section are diced into micropolygons and
+/// displaced, with `placements` and `dicing_camera` determining how finely
+/// each triangle is diced unless the section has an explicit `DiceRate`.
 pub fn parse_mesh_surface<'a>(
     arena: &'a MemArena,
     tree: &'a DataTree,
+    dicing_camera: &DicingCamera,
+    placements: &[Matrix4x4],
 ) -> Result<TriangleMesh<'a>, PsyParseError> {
-    let mut verts = Vec::new(); // Vec of vecs, one for each time sample
     let mut normals = Vec::new(); // Vec of vecs, on for each time sample
     let mut face_vert_counts = Vec::new();
     let mut face_vert_indices = Vec::new();
@@ -34,26 +77,8 @@ pub fn parse_mesh_surface<'a>(
     // and other validation.
 
     // Get verts
-    for (_, text, _) in tree.iter_leaf_children_with_type("Vertices") {
-        let mut raw_text = text.trim().as_bytes();
-
-        // Collect verts for this time sample
-        let mut tverts = Vec::new();
-        while let IResult::Done(remaining, vert) =
-            closure!(tuple!(ws_f32, ws_f32, ws_f32))(raw_text)
-        {
-            raw_text = remaining;
-
-            tverts.push(Point::new(vert.0, vert.1, vert.2));
-        }
-        verts.push(tverts);
-    }
-
-    // Make sure all time samples have same vert count
+    let verts = parse_vertex_time_samples(tree, "Vertices")?;
     let vert_count = verts[0].len();
-    for vs in &verts {
-        assert_eq!(vert_count, vs.len());
-    }
 
     // Get normals, if they exist
     for (_, text, _) in tree.iter_leaf_children_with_type("Normals") {
@@ -71,6 +96,11 @@ pub fn parse_mesh_surface<'a>(
         normals.push(tnormals);
     }
 
+    // Resample motion given at uneven times, like the vertices
+    if let Some(time_samples) = TimeSamples::from_parent(tree)? {
+        normals = time_samples.resample_vecs(normals)?;
+    }
+
     // Make sure normal's time samples and vert count match the vertices
     if !normals.is_empty() {
         assert_eq!(normals.len(), verts.len());
@@ -101,10 +131,101 @@ pub fn parse_mesh_surface<'a>(
         }
     }
 
+    // Get attributes
+    let face_vert_count = face_vert_indices.len();
+    let mut attributes = MeshAttributes::new();
+    if let Some((_, text, byte_offset)) = tree.iter_leaf_children_with_type("UVs").nth(0) {
+        attributes.uvs = Some(parse_attribute(
+            text,
+            byte_offset,
+            2,
+            vert_count,
+            face_vert_count,
+        )?);
+    }
+    if let Some((_, text, byte_offset)) = tree.iter_leaf_children_with_type("VertexColors").nth(0) {
+        let mut colors = parse_attribute(text, byte_offset, 3, vert_count, face_vert_count)?;
+        for c in colors.values.chunks_mut(3) {
+            let xyz = rec709_e_to_xyz((c[0], c[1], c[2]));
+            c[0] = xyz.0;
+            c[1] = xyz.1;
+            c[2] = xyz.2;
+        }
+        attributes.colors = Some(colors);
+    }
+    for child in tree.iter_children_with_type("Primvar") {
+        attributes.primvars.push(parse_primvar(
+            child,
+            vert_count,
+            face_vert_count,
+        )?);
+    }
+    if let Some((_, text, byte_offset)) = tree.iter_leaf_children_with_type("Velocities").nth(0) {
+        attributes.velocities = Some(parse_vertex_vectors(
+            text,
+            byte_offset,
+            vert_count,
+            "Velocities should have one vector per vertex.",
+        )?);
+    }
+    if let Some((_, text, byte_offset)) = tree.iter_leaf_children_with_type("Accelerations")
+        .nth(0)
+    {
+        attributes.accelerations = Some(parse_vertex_vectors(
+            text,
+            byte_offset,
+            vert_count,
+            "Accelerations should have one vector per vertex.",
+        )?);
+    }
+    if attributes.primvars.iter().map(|pv| pv.size).sum::<usize>() > MAX_PRIMVAR_VALUES {
+        return Err(PsyParseError::IncorrectLeafData(
+            tree.byte_offset(),
+            "The sizes of a mesh's primvars can add up to at most 8.",
+        ));
+    }
+
+    // Get per-face shader indices, if any
+    let mut face_shader_indices = Vec::new();
+    if let Some((_, text, byte_offset)) = tree.iter_leaf_children_with_type("FaceShaderIndices")
+        .nth(0)
+    {
+        let mut raw_text = text.trim().as_bytes();
+        while let IResult::Done(remaining, index) = ws_usize(raw_text) {
+            raw_text = remaining;
+            face_shader_indices.push(index);
+        }
+
+        if face_shader_indices.len() != face_vert_counts.len() {
+            return Err(PsyParseError::IncorrectLeafData(
+                byte_offset,
+                "FaceShaderIndices should have one shader index per face.",
+            ));
+        }
+    }
+
+    // Get the BVH splitting method, if specified
+    let spatial_splits = if let Some((_, text, byte_offset)) =
+        tree.iter_leaf_children_with_type("BVHSplits").nth(0)
+    {
+        match text.trim() {
+            "Object" => false,
+            "Spatial" => true,
+            _ => {
+                return Err(PsyParseError::UnknownVariant(
+                    byte_offset,
+                    "BVHSplits should be either Object or Spatial.",
+                ));
+            }
+        }
+    } else {
+        accel::spatial_splits()
+    };
+
     // Build triangle mesh
     let mut tri_vert_indices = Vec::new();
     let mut ii = 0;
-    for fvc in &face_vert_counts {
+    for (fi, fvc) in face_vert_counts.iter().enumerate() {
         if *fvc >= 3 {
             // Store the polygon, split up into triangles if >3 verts
             let v1 = ii;
@@ -114,6 +235,10 @@ pub fn parse_mesh_surface<'a>(
                     face_vert_indices[v1 + vi + 1],
                     face_vert_indices[v1 + vi + 2],
                 ));
+                attributes.tri_corners.push((v1, v1 + vi + 1, v1 + vi + 2));
+                if !face_shader_indices.is_empty() {
+                    attributes.face_shader_indices.push(face_shader_indices[fi]);
+                }
             }
         } else {
             // TODO: proper error
@@ -123,7 +248,157 @@ pub fn parse_mesh_surface<'a>(
         ii += *fvc;
     }
 
-    Ok(TriangleMesh::from_verts_and_indices(
+    // Dice and displace, if there's displacement
+    if let Some(disp_tree) = tree.iter_children_with_type("Displacement").nth(0) {
+        for name in &["SmoothNormals", "CreaseAngle", "HardEdges"] {
+            if let Some((_, _, byte_offset)) = tree.iter_leaf_children_with_type(name).nth(0) {
+                return Err(PsyParseError::IncorrectLeafData(
+                    byte_offset,
+                    "SmoothNormals, CreaseAngle, and HardEdges cannot be used on displaced \
+                     meshes, which get smooth normals from their displaced surface.",
+                ));
+            }
+        }
+
+        let (displacement, dice_rate) = parse_displacement(disp_tree)?;
+        let mut rates = if let Some(rate) = dice_rate {
+            if rate > MAX_DICE_RATE {
+                println!(
+                    "WARNING: DiceRate {} is more than the maximum of {}.  Using {} instead.",
+                    rate,
+                    MAX_DICE_RATE,
+                    MAX_DICE_RATE
+                );
+            }
+            vec![rate.min(MAX_DICE_RATE); tri_vert_indices.len()]
+        } else {
+            screen_space_dice_rates(&verts, &tri_vert_indices, dicing_camera, placements)
+        };
+        fit_dice_rates_to_budget(&mut rates);
+        let (verts, normals, tri_vert_indices, attributes) = dice_and_displace(
+            &verts,
+            if normals.is_empty() {
+                None
+            } else {
+                Some(&normals[..])
+            },
+            &tri_vert_indices,
+            &attributes,
+            &rates,
+            &displacement,
+        );
+        return Ok(TriangleMesh::from_verts_indices_and_attributes(
+            arena,
+            verts,
+            Some(normals),
+            tri_vert_indices,
+            attributes,
+            spatial_splits,
+        ));
+    }
+
+    // Generate smooth normals, if requested
+    if let Some((_, text, byte_offset)) = tree.iter_leaf_children_with_type("SmoothNormals")
+        .nth(0)
+    {
+        let weighting = match text.trim() {
+            "Area" => NormalWeighting::Area,
+            "Angle" => NormalWeighting::Angle,
+            _ => {
+                return Err(PsyParseError::UnknownVariant(
+                    byte_offset,
+                    "SmoothNormals should be either Area or Angle.",
+                ));
+            }
+        };
+        if !normals.is_empty() {
+            return Err(PsyParseError::IncorrectLeafData(
+                byte_offset,
+                "SmoothNormals cannot be used together with Normals.",
+            ));
+        }
+
+        let crease_angle = if let Some((_, text, byte_offset)) =
+            tree.iter_leaf_children_with_type("CreaseAngle").nth(0)
+        {
+            if let IResult::Done(_, angle) = ws_f32(text.as_bytes()) {
+                angle.to_radians()
+            } else {
+                return Err(PsyParseError::IncorrectLeafData(
+                    byte_offset,
+                    "CreaseAngle should be a single number, in degrees.",
+                ));
+            }
+        } else {
+            180.0f32.to_radians()
+        };
+
+        let mut hard_edges = Vec::new();
+        if let Some((_, text, byte_offset)) = tree.iter_leaf_children_with_type("HardEdges")
+            .nth(0)
+        {
+            let mut raw_text = text.trim().as_bytes();
+            while let IResult::Done(remaining, edge) =
+                closure!(tuple!(ws_usize, ws_usize))(raw_text)
+            {
+                raw_text = remaining;
+                hard_edges.push(edge);
+            }
+            if !raw_text.is_empty() ||
+                hard_edges.iter().any(|e| e.0 >= vert_count || e.1 >= vert_count)
+            {
+                return Err(PsyParseError::IncorrectLeafData(
+                    byte_offset,
+                    "HardEdges should be pairs of valid vertex indices.",
+                ));
+            }
+        }
+
+        let (vert_sources, tri_vert_indices, normals) =
+            smooth_normals(&verts, &tri_vert_indices, weighting, crease_angle, &hard_edges);
+
+        // Remap the vertices and per-vertex attributes to the split vertices
+        let verts = verts
+            .iter()
+            .map(|tverts| vert_sources.iter().map(|v| tverts[*v]).collect())
+            .collect();
+        let mut attributes = attributes;
+        {
+            let remap = |list: &mut AttributeList| if !list.face_varying {
+                let s = list.size;
+                list.values = vert_sources
+                    .iter()
+                    .flat_map(|v| list.values[(v * s)..((v + 1) * s)].to_vec())
+                    .collect();
+            };
+            if let Some(ref mut uvs) = attributes.uvs {
+                remap(uvs);
+            }
+            if let Some(ref mut colors) = attributes.colors {
+                remap(colors);
+            }
+            for pv in &mut attributes.primvars {
+                remap(pv);
+            }
+            if let Some(ref mut velocities) = attributes.velocities {
+                remap(velocities);
+            }
+            if let Some(ref mut accelerations) = attributes.accelerations {
+                remap(accelerations);
+            }
+        }
+
+        return Ok(TriangleMesh::from_verts_indices_and_attributes(
+            arena,
+            verts,
+            Some(normals),
+            tri_vert_indices,
+            attributes,
+            spatial_splits,
+        ));
+    }
+
+    Ok(TriangleMesh::from_verts_indices_and_attributes(
         arena,
         verts,
         if normals.is_empty() {
@@ -132,5 +407,348 @@ pub fn parse_mesh_surface<'a>(
             Some(normals)
         },
         tri_vert_indices,
+        attributes,
+        spatial_splits,
     ))
 }
+
+/// Parses the vertex positions in the `node_name` leaves of `tree`, one leaf
+/// for each time sample, resampling them to evenly spaced time samples if
+/// `tree` has `TimeSamples`.
+///
+/// This is shared by all the surfaces made of vertices.
+pub fn parse_vertex_time_samples(
+    tree: &DataTree,
+    node_name: &'static str,
+) -> Result<Vec<Vec<Point>>, PsyParseError> {
+    let mut verts: Vec<Vec<Point>> = Vec::new(); // Vec of vecs, one for each time sample
+
+    for (_, text, byte_offset) in tree.iter_leaf_children_with_type(node_name) {
+        let mut raw_text = text.trim().as_bytes();
+
+        // Collect verts for this time sample
+        let mut tverts = Vec::new();
+        while let IResult::Done(remaining, vert) =
+            closure!(tuple!(ws_f32, ws_f32, ws_f32))(raw_text)
+        {
+            raw_text = remaining;
+
+            tverts.push(Point::new(vert.0, vert.1, vert.2));
+        }
+
+        // Make sure all time samples have same vert count
+        if !verts.is_empty() && tverts.len() != verts[0].len() {
+            return Err(PsyParseError::IncorrectLeafData(
+                byte_offset,
+                "All time samples of vertices should have the same number of vertices.",
+            ));
+        }
+        verts.push(tverts);
+    }
+
+    if verts.is_empty() {
+        return Err(PsyParseError::MissingNode(
+            tree.byte_offset(),
+            "Surfaces made of vertices should have at least one section of vertices.",
+        ));
+    }
+
+    // Resample motion given at uneven times
+    if let Some(time_samples) = TimeSamples::from_parent(tree)? {
+        verts = time_samples.resample_vecs(verts)?;
+    }
+
+    Ok(verts)
+}
+
+/// Parses a list of attribute values with `size` components each, which
+/// are face-varying if there's one per face-vertex rather than one per
+/// vertex.
+fn parse_attribute(
+    text: &str,
+    byte_offset: usize,
+    size: usize,
+    vert_count: usize,
+    face_vert_count: usize,
+) -> Result<AttributeList, PsyParseError> {
+    let mut raw_text = text.trim().as_bytes();
+    let mut values = Vec::new();
+    while let IResult::Done(remaining, v) = ws_f32(raw_text) {
+        raw_text = remaining;
+        values.push(v);
+    }
+
+    let face_varying = if values.len() == vert_count * size {
+        false
+    } else if values.len() == face_vert_count * size {
+        true
+    } else {
+        return Err(PsyParseError::IncorrectLeafData(
+            byte_offset,
+            "Attributes should have one value per vertex or one value per face-vertex.",
+        ));
+    };
+
+    Ok(AttributeList {
+        size: size,
+        face_varying: face_varying,
+        values: values,
+    })
+}
+
+/// Parses a list of vectors with one per vertex, such as velocities.
+fn parse_vertex_vectors(
+    text: &str,
+    byte_offset: usize,
+    vert_count: usize,
+    error: &'static str,
+) -> Result<AttributeList, PsyParseError> {
+    let mut raw_text = text.trim().as_bytes();
+    let mut values = Vec::new();
+    while let IResult::Done(remaining, v) = ws_f32(raw_text) {
+        raw_text = remaining;
+        values.push(v);
+    }
+
+    if values.len() != vert_count * 3 {
+        return Err(PsyParseError::IncorrectLeafData(byte_offset, error));
+    }
+
+    Ok(AttributeList {
+        size: 3,
+        face_varying: false,
+        values: values,
+    })
+}
+
+/// Parses a `Primvar` section, which has a `Size` (number of components,
+/// from 1 to 4) and `Values`.
+fn parse_primvar(
+    tree: &DataTree,
+    vert_count: usize,
+    face_vert_count: usize,
+) -> Result<AttributeList, PsyParseError> {
+    if !tree.is_internal() {
+        return Err(PsyParseError::ExpectedInternalNode(
+            tree.byte_offset(),
+            "Primvar should be an internal node.",
+        ));
+    }
+
+    let size = if let Some((_, text, byte_offset)) =
+        tree.iter_leaf_children_with_type("Size").nth(0)
+    {
+        match ws_usize(text.as_bytes()) {
+            IResult::Done(_, size) if size > 0 && size <= MAX_ATTRIBUTE_SIZE => size,
+            _ => {
+                return Err(PsyParseError::IncorrectLeafData(
+                    byte_offset,
+                    "Primvar Size should be a single integer from 1 to 4.",
+                ));
+            }
+        }
+    } else {
+        1
+    };
+
+    if let Some((_, text, byte_offset)) = tree.iter_leaf_children_with_type("Values").nth(0) {
+        parse_attribute(text, byte_offset, size, vert_count, face_vert_count)
+    } else {
+        Err(PsyParseError::MissingNode(
+            tree.byte_offset(),
+            "Expected a Values field in Primvar.",
+        ))
+    }
+}
+
+/// Parses a `Displacement` section, returning the displacement and its
+/// dice rate, if specified.
+fn parse_displacement(tree: &DataTree) -> Result<(Displacement, Option<usize>), PsyParseError> {
+    if !tree.is_internal() {
+        return Err(PsyParseError::ExpectedInternalNode(
+            tree.byte_offset(),
+            "Displacement should be an internal node.",
+        ));
+    }
+
+    let type_name = if let Some((_, text, _)) = tree.iter_leaf_children_with_type("Type").nth(0) {
+        text.trim()
+    } else {
+        return Err(PsyParseError::MissingNode(
+            tree.byte_offset(),
+            "Expected a Type field in Displacement.",
+        ));
+    };
+
+    let pattern = match type_name {
+        "Constant" => DisplacementPattern::Constant,
+
+        "Waves" => {
+            if let Some((_, text, byte_offset)) =
+                tree.iter_leaf_children_with_type("Frequency").nth(0)
+            {
+                if let IResult::Done(_, f) = closure!(tuple!(ws_f32, ws_f32, ws_f32))(
+                    text.as_bytes(),
+                )
+                {
+                    DisplacementPattern::Waves { frequency: Vector::new(f.0, f.1, f.2) }
+                } else {
+                    return Err(PsyParseError::IncorrectLeafData(
+                        byte_offset,
+                        "Frequency should be three numbers for Waves displacement.",
+                    ));
+                }
+            } else {
+                return Err(PsyParseError::MissingNode(
+                    tree.byte_offset(),
+                    "Expected a Frequency field in Waves displacement.",
+                ));
+            }
+        }
+
+        "Noise" => {
+            let frequency = if let Some((_, text, byte_offset)) =
+                tree.iter_leaf_children_with_type("Frequency").nth(0)
+            {
+                if let IResult::Done(_, f) = ws_f32(text.as_bytes()) {
+                    f
+                } else {
+                    return Err(PsyParseError::IncorrectLeafData(
+                        byte_offset,
+                        "Frequency should be a single number for Noise displacement.",
+                    ));
+                }
+            } else {
+                1.0
+            };
+            let octaves = if let Some((_, text, byte_offset)) =
+                tree.iter_leaf_children_with_type("Octaves").nth(0)
+            {
+                if let IResult::Done(_, o) = ws_u32(text.as_bytes()) {
+                    o
+                } else {
+                    return Err(PsyParseError::IncorrectLeafData(
+                        byte_offset,
+                        "Octaves should be a single integer.",
+                    ));
+                }
+            } else {
+                1
+            };
+            DisplacementPattern::Noise {
+                frequency: frequency,
+                octaves: octaves,
+            }
+        }
+
+        _ => {
+            return Err(PsyParseError::UnknownVariant(
+                tree.byte_offset(),
+                "Displacement Type should be one of Constant, Waves, or Noise.",
+            ));
+        }
+    };
+
+    let amount = if let Some((_, text, byte_offset)) =
+        tree.iter_leaf_children_with_type("Amount").nth(0)
+    {
+        if let IResult::Done(_, a) = ws_f32(text.as_bytes()) {
+            a
+        } else {
+            return Err(PsyParseError::IncorrectLeafData(
+                byte_offset,
+                "Amount should be a single number.",
+            ));
+        }
+    } else {
+        return Err(PsyParseError::MissingNode(
+            tree.byte_offset(),
+            "Expected an Amount field in Displacement.",
+        ));
+    };
+
+    let direction = if let Some((_, text, byte_offset)) =
+        tree.iter_leaf_children_with_type("Direction").nth(0)
+    {
+        if let IResult::Done(_, d) = closure!(tuple!(ws_f32, ws_f32, ws_f32))(text.as_bytes()) {
+            Some(Vector::new(d.0, d.1, d.2))
+        } else {
+            return Err(PsyParseError::IncorrectLeafData(
+                byte_offset,
+                "Direction should be three numbers.",
+            ));
+        }
+    } else {
+        None
+    };
+
+    let dice_rate = if let Some((_, text, byte_offset)) =
+        tree.iter_leaf_children_with_type("DiceRate").nth(0)
+    {
+        match ws_usize(text.as_bytes()) {
+            IResult::Done(_, rate) if rate > 0 => Some(rate),
+            _ => {
+                return Err(PsyParseError::IncorrectLeafData(
+                    byte_offset,
+                    "DiceRate should be a single integer of at least 1.",
+                ));
+            }
+        }
+    } else {
+        None
+    };
+
+    Ok((
+        Displacement {
+            pattern: pattern,
+            amount: amount,
+            direction: direction,
+        },
+        dice_rate,
+    ))
+}
+
+/// Picks the dice rate of each triangle at which its micropolygons become
+/// `DICE_PIXELS` across on screen, wherever it's placed in the scene.
+fn screen_space_dice_rates(
+    verts: &[Vec<Point>],
+    tri_indices: &[(usize, usize, usize)],
+    dicing_camera: &DicingCamera,
+    placements: &[Matrix4x4],
+) -> Vec<usize> {
+    let mut rates = vec![1; tri_indices.len()];
+    for placement in placements {
+        let xform = placement.inverse();
+        for tverts in verts {
+            for (tri, rate) in tri_indices.iter().zip(rates.iter_mut()) {
+                let p0 = tverts[tri.0] * xform;
+                let p1 = tverts[tri.1] * xform;
+                let p2 = tverts[tri.2] * xform;
+                let bounds = BBox::from_points(p0.min(p1.min(p2)), p0.max(p1.max(p2)));
+                let longest_edge = (p1 - p0).length().max((p2 - p1).length()).max(
+                    (p0 - p2).length(),
+                );
+
+                let pixel_size = dicing_camera.pixel_size(&bounds, longest_edge) * DICE_PIXELS;
+                if pixel_size > 0.0 {
+                    let r = (longest_edge / pixel_size).ceil() as usize;
+                    *rate = (*rate).max(r.min(MAX_DICE_RATE));
+                }
+            }
+        }
+    }
+
+    rates
+}
+
+/// Scales the dice rates down evenly, if needed, to keep the number of
+/// micropolygons within `MAX_MICROPOLYGONS`.
+fn fit_dice_rates_to_budget(rates: &mut [usize]) {
+    let count: usize = rates.iter().map(|r| r * r).sum();
+    if count > MAX_MICROPOLYGONS {
+        let scale = (MAX_MICROPOLYGONS as f64 / count as f64).sqrt();
+        for rate in rates.iter_mut() {
+            *rate = ((*rate as f64 * scale) as usize).max(1);
+        }
+    }
+}
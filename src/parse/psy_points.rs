@@ -0,0 +1,108 @@
+#![allow(dead_code)]
+
+use std::result::Result;
+
+use nom::IResult;
+
+use mem_arena::MemArena;
+
+use color::{XYZ, rec709_e_to_xyz};
+use surface::points::{Points, PointMode};
+
+use super::basics::ws_f32;
+use super::DataTree;
+use super::psy_mesh_surface::parse_vertex_time_samples;
+use super::psy::PsyParseError;
+
+
+/// Parses a set of points.
+///
+/// `Mode` is either `Sphere` (default) or `Disk`, the latter being flat
+/// disks that face the incoming ray.  `Radii` has either one radius per
+/// point or a single radius for all of them, and the optional `Colors` has
+/// one rec709 color per point.
+pub fn parse_points<'a>(
+    arena: &'a MemArena,
+    tree: &'a DataTree,
+) -> Result<Points<'a>, PsyParseError> {
+    let mut radii = Vec::new();
+
+    // Get mode
+    let mode = if let Some((_, text, byte_offset)) = tree.iter_leaf_children_with_type("Mode")
+        .nth(0)
+    {
+        match text.trim() {
+            "Sphere" => PointMode::Sphere,
+            "Disk" => PointMode::Disk,
+            _ => {
+                return Err(PsyParseError::UnknownVariant(
+                    byte_offset,
+                    "Mode should be either Sphere or Disk.",
+                ));
+            }
+        }
+    } else {
+        PointMode::Sphere
+    };
+
+    // Get positions
+    let positions = parse_vertex_time_samples(tree, "Vertices")?;
+
+    let point_count = positions[0].len();
+
+    // Get radii
+    if let Some((_, text, byte_offset)) = tree.iter_leaf_children_with_type("Radii").nth(0) {
+        let mut raw_text = text.trim().as_bytes();
+        while let IResult::Done(remaining, r) = ws_f32(raw_text) {
+            raw_text = remaining;
+            radii.push(r);
+        }
+
+        if radii.len() == 1 {
+            radii = vec![radii[0]; point_count];
+        } else if radii.len() != point_count {
+            return Err(PsyParseError::IncorrectLeafData(
+                byte_offset,
+                "Radii should have either one radius per point or a single radius.",
+            ));
+        }
+        if radii.iter().any(|r| *r <= 0.0) {
+            return Err(PsyParseError::IncorrectLeafData(
+                byte_offset,
+                "Radii should be greater than zero.",
+            ));
+        }
+    } else {
+        return Err(PsyParseError::MissingNode(
+            tree.byte_offset(),
+            "Points should have a Radii section.",
+        ));
+    }
+
+    // Get colors
+    let colors = if let Some((_, text, byte_offset)) = tree.iter_leaf_children_with_type("Colors")
+        .nth(0)
+    {
+        let mut raw_text = text.trim().as_bytes();
+        let mut colors = Vec::new();
+        while let IResult::Done(remaining, col) =
+            closure!(tuple!(ws_f32, ws_f32, ws_f32))(raw_text)
+        {
+            raw_text = remaining;
+
+            colors.push(XYZ::from_tuple(rec709_e_to_xyz(col)));
+        }
+
+        if colors.len() != point_count {
+            return Err(PsyParseError::IncorrectLeafData(
+                byte_offset,
+                "Colors should have one color per point.",
+            ));
+        }
+        Some(colors)
+    } else {
+        None
+    };
+
+    Ok(Points::new(arena, mode, positions, radii, colors))
+}
@@ -0,0 +1,70 @@
+#![allow(dead_code)]
+
+use std::result::Result;
+
+use nom::IResult;
+
+use mem_arena::MemArena;
+
+use surface::sphere::Sphere;
+
+use super::basics::ws_f32;
+use super::DataTree;
+use super::psy_time_samples::TimeSamples;
+use super::psy::PsyParseError;
+
+
+pub fn parse_sphere<'a>(
+    arena: &'a MemArena,
+    tree: &'a DataTree,
+) -> Result<Sphere<'a>, PsyParseError> {
+    if let DataTree::Internal { ref children, .. } = *tree {
+        let mut radii = Vec::new();
+
+        // Parse
+        for child in children.iter() {
+            match *child {
+                // Radius
+                DataTree::Leaf {
+                    type_name,
+                    contents,
+                    byte_offset,
+                } if type_name == "Radius" => {
+                    if let IResult::Done(_, radius) = ws_f32(contents.as_bytes()) {
+                        if radius <= 0.0 {
+                            return Err(PsyParseError::IncorrectLeafData(
+                                byte_offset,
+                                "Sphere radius must be greater than zero.",
+                            ));
+                        }
+                        radii.push(radius);
+                    } else {
+                        // Found radius, but its contents is not in the right format
+                        return Err(PsyParseError::IncorrectLeafData(
+                            byte_offset,
+                            "Radius should be a single number.",
+                        ));
+                    }
+                }
+
+                _ => {}
+            }
+        }
+
+        if radii.is_empty() {
+            return Err(PsyParseError::MissingNode(
+                tree.byte_offset(),
+                "Sphere should have at least one Radius.",
+            ));
+        }
+
+        // Resample motion given at uneven times
+        if let Some(time_samples) = TimeSamples::from_parent(tree)? {
+            radii = time_samples.resample(radii)?;
+        }
+
+        return Ok(Sphere::new(arena, radii));
+    } else {
+        return Err(PsyParseError::UnknownError(tree.byte_offset()));
+    }
+}
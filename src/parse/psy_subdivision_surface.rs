@@ -0,0 +1,226 @@
+#![allow(dead_code)]
+
+use std::result::Result;
+
+use nom::IResult;
+
+use mem_arena::MemArena;
+
+use bbox::BBox;
+use camera::DicingCamera;
+use math::{Point, Matrix4x4};
+use surface::subdivision::SubdivisionMesh;
+use surface::triangle_mesh::TriangleMesh;
+
+use super::basics::{ws_usize, ws_f32};
+use super::DataTree;
+use super::psy_mesh_surface::parse_vertex_time_samples;
+use super::psy::PsyParseError;
+
+
+// Target length of the subdivided edges, in pixels.
+const DICE_PIXELS: f32 = 1.0;
+
+// Maximum subdivision level.  Each level quadruples the number of faces, so
+// this keeps close cameras or large explicit levels from blowing up memory
+// usage.
+const MAX_LEVEL: usize = 6;
+
+
+/// Parses a Catmull-Clark subdivision surface, and subdivides it into a
+/// triangle mesh.
+///
+/// `placements` are the world-to-object transforms the surface is instanced
+/// with, which together with `dicing_camera` determine how finely it is
+/// subdivided.  An explicit `Level` overrides that.
+pub fn parse_subdivision_surface<'a>(
+    arena: &'a MemArena,
+    tree: &'a DataTree,
+    dicing_camera: &DicingCamera,
+    placements: &[Matrix4x4],
+) -> Result<TriangleMesh<'a>, PsyParseError> {
+    let mut face_vert_counts = Vec::new();
+    let mut face_vert_indices = Vec::new();
+
+    // Get verts
+    let verts = parse_vertex_time_samples(tree, "Vertices")?;
+
+    let vert_count = verts[0].len();
+
+    // Get face vert counts
+    if let Some((_, text, _)) = tree.iter_leaf_children_with_type("FaceVertCounts").nth(0) {
+        face_vert_counts = parse_usizes(text);
+    }
+
+    // Get face vert indices
+    let indices_offset =
+        if let Some((_, text, byte_offset)) = tree.iter_leaf_children_with_type("FaceVertIndices")
+            .nth(0)
+        {
+            face_vert_indices = parse_usizes(text);
+            byte_offset
+        } else {
+            tree.byte_offset()
+        };
+
+    if face_vert_counts.iter().any(|c| *c < 3) {
+        return Err(PsyParseError::IncorrectLeafData(
+            indices_offset,
+            "Cannot handle polygons with less than three vertices.",
+        ));
+    }
+    if face_vert_counts.iter().sum::<usize>() != face_vert_indices.len() {
+        return Err(PsyParseError::IncorrectLeafData(
+            indices_offset,
+            "FaceVertIndices should have as many indices as FaceVertCounts adds up to.",
+        ));
+    }
+    if face_vert_indices.iter().any(|i| *i >= vert_count) {
+        return Err(PsyParseError::IncorrectLeafData(
+            indices_offset,
+            "Vertex index out of range.",
+        ));
+    }
+
+    // Build control mesh
+    let faces = {
+        let mut faces = Vec::with_capacity(face_vert_counts.len());
+        let mut ii = 0;
+        for fvc in &face_vert_counts {
+            faces.push(face_vert_indices[ii..(ii + fvc)].to_vec());
+            ii += *fvc;
+        }
+        faces
+    };
+    let mut mesh = SubdivisionMesh::new(verts, faces);
+
+    // Get creases, if any
+    if let Some((_, text, byte_offset)) = tree.iter_leaf_children_with_type("CreaseIndices")
+        .nth(0)
+    {
+        let indices = parse_usizes(text);
+        let sharpnesses = if let Some((_, text, _)) =
+            tree.iter_leaf_children_with_type("CreaseSharpness").nth(0)
+        {
+            parse_f32s(text)
+        } else {
+            Vec::new()
+        };
+
+        if indices.len() % 2 != 0 || indices.len() / 2 != sharpnesses.len() {
+            return Err(PsyParseError::IncorrectLeafData(
+                byte_offset,
+                "CreaseIndices should be pairs of vertex indices, with one CreaseSharpness \
+                 value for each pair.",
+            ));
+        }
+        if indices.iter().any(|i| *i >= vert_count) {
+            return Err(PsyParseError::IncorrectLeafData(
+                byte_offset,
+                "Crease vertex index out of range.",
+            ));
+        }
+        for (edge, sharpness) in indices.chunks(2).zip(sharpnesses.iter()) {
+            mesh.set_crease(edge[0], edge[1], *sharpness);
+        }
+    }
+
+    // Get holes, if any
+    if let Some((_, text, byte_offset)) = tree.iter_leaf_children_with_type("HoleFaces").nth(0) {
+        for fi in parse_usizes(text) {
+            if fi >= mesh.faces.len() {
+                return Err(PsyParseError::IncorrectLeafData(
+                    byte_offset,
+                    "Hole face index out of range.",
+                ));
+            }
+            mesh.holes[fi] = true;
+        }
+    }
+
+    // Get level, either explicit or based on the size of the mesh on screen
+    let level = if let Some((_, text, byte_offset)) =
+        tree.iter_leaf_children_with_type("Level").nth(0)
+    {
+        if let IResult::Done(_, level) = ws_usize(text.as_bytes()) {
+            if level > MAX_LEVEL {
+                println!(
+                    "WARNING: subdivision surface Level {} is more than the maximum of {}.  \
+                     Using {} instead.",
+                    level,
+                    MAX_LEVEL,
+                    MAX_LEVEL
+                );
+            }
+            level.min(MAX_LEVEL)
+        } else {
+            return Err(PsyParseError::IncorrectLeafData(
+                byte_offset,
+                "Level should be a single integer.",
+            ));
+        }
+    } else {
+        screen_space_level(&mesh, dicing_camera, placements)
+    };
+
+    // Subdivide
+    for _ in 0..level {
+        mesh = mesh.subdivide();
+    }
+
+    Ok(mesh.to_triangle_mesh(arena))
+}
+
+/// Picks the subdivision level at which the longest edge of each face
+/// becomes `DICE_PIXELS` long on screen where the face is, wherever the mesh
+/// is placed in the scene.
+fn screen_space_level(
+    mesh: &SubdivisionMesh,
+    dicing_camera: &DicingCamera,
+    placements: &[Matrix4x4],
+) -> usize {
+    let mut level = 0;
+    for placement in placements {
+        let xform = placement.inverse();
+        for verts in &mesh.verts {
+            let world_verts: Vec<Point> = verts.iter().map(|v| *v * xform).collect();
+
+            for face in &mesh.faces {
+                let bounds = face.iter().fold(BBox::new(), |b, vi| b | world_verts[*vi]);
+                let mut longest_edge: f32 = 0.0;
+                for i in 0..face.len() {
+                    let edge = world_verts[face[(i + 1) % face.len()]] - world_verts[face[i]];
+                    longest_edge = longest_edge.max(edge.length());
+                }
+
+                let pixel_size = dicing_camera.pixel_size(&bounds, longest_edge) * DICE_PIXELS;
+                if pixel_size > 0.0 {
+                    let l = (longest_edge / pixel_size).log2().ceil().max(0.0) as usize;
+                    level = level.max(l.min(MAX_LEVEL));
+                }
+            }
+        }
+    }
+
+    level
+}
+
+fn parse_usizes(text: &str) -> Vec<usize> {
+    let mut raw_text = text.trim().as_bytes();
+    let mut ns = Vec::new();
+    while let IResult::Done(remaining, n) = ws_usize(raw_text) {
+        raw_text = remaining;
+        ns.push(n);
+    }
+    ns
+}
+
+fn parse_f32s(text: &str) -> Vec<f32> {
+    let mut raw_text = text.trim().as_bytes();
+    let mut ns = Vec::new();
+    while let IResult::Done(remaining, n) = ws_f32(raw_text) {
+        raw_text = remaining;
+        ns.push(n);
+    }
+    ns
+}
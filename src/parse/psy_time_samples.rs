@@ -0,0 +1,212 @@
+#![allow(dead_code)]
+
+use std::result::Result;
+
+use nom::IResult;
+
+use lerp::{Lerp, lerp, lerp_slice_at_times_with};
+
+use super::basics::ws_f32;
+use super::DataTree;
+use super::psy::PsyParseError;
+
+
+// Maximum number of evenly spaced time samples that motion data is
+// resampled to.
+const MAX_RESAMPLED_COUNT: usize = 64;
+
+
+/// The times of an item's motion samples, from its `TimeSamples` section.
+///
+/// The times are in the same units as the camera's shutter, with the scene's
+/// motion spanning 0.0 to 1.0.  The rest of the renderer expects motion
+/// samples spread evenly over that span, so motion data given at other times
+/// is resampled to evenly spaced samples.  Where possible those include every
+/// one of the given times, so that no motion is lost.  Large data such as
+/// vertex positions isn't resampled, and has to be given at evenly spaced
+/// times.
+#[derive(Debug, Clone)]
+pub struct TimeSamples {
+    times: Vec<f32>,
+    even_count: usize, // Number of evenly spaced samples to resample to
+    byte_offset: usize,
+}
+
+impl TimeSamples {
+    /// Parses the `TimeSamples` section of `tree`, if it has one.
+    pub fn from_parent(tree: &DataTree) -> Result<Option<TimeSamples>, PsyParseError> {
+        let (text, byte_offset) = if let Some((_, text, byte_offset)) =
+            tree.iter_leaf_children_with_type("TimeSamples").nth(0)
+        {
+            (text, byte_offset)
+        } else {
+            return Ok(None);
+        };
+
+        let mut raw_text = text.trim().as_bytes();
+        let mut times = Vec::new();
+        while let IResult::Done(remaining, t) = ws_f32(raw_text) {
+            raw_text = remaining;
+            times.push(t);
+        }
+
+        if times.is_empty() || !raw_text.is_empty() {
+            return Err(PsyParseError::IncorrectLeafData(
+                byte_offset,
+                "TimeSamples should be one or more decimal numbers.",
+            ));
+        }
+        if times.iter().any(|t| *t < 0.0 || *t > 1.0) ||
+            times.windows(2).any(|w| w[0] >= w[1])
+        {
+            return Err(PsyParseError::IncorrectLeafData(
+                byte_offset,
+                "TimeSamples should be increasing times between 0.0 and 1.0.",
+            ));
+        }
+
+        let even_count = even_sample_count(&times);
+        Ok(Some(TimeSamples {
+            times: times,
+            even_count: even_count,
+            byte_offset: byte_offset,
+        }))
+    }
+
+    /// Resamples motion data given at the sample times to evenly spaced
+    /// time samples.  Data with a single sample doesn't move, and is
+    /// returned as-is.
+    pub fn resample<T: Lerp + Copy>(&self, samples: Vec<T>) -> Result<Vec<T>, PsyParseError> {
+        self.resample_with(samples, lerp)
+    }
+
+    /// Like `resample()`, but interpolating with `f`.
+    pub fn resample_with<T, F>(&self, samples: Vec<T>, f: F) -> Result<Vec<T>, PsyParseError>
+    where
+        T: Copy,
+        F: Fn(T, T, f32) -> T,
+    {
+        if samples.len() <= 1 {
+            return Ok(samples);
+        }
+        self.check_count(samples.len())?;
+
+        Ok(
+            (0..self.even_count)
+                .map(|i| {
+                    let time = even_time(i, self.even_count);
+                    lerp_slice_at_times_with(&samples, &self.times, time, &f)
+                })
+                .collect(),
+        )
+    }
+
+    /// Like `resample()`, but for data such as vertex positions, with a Vec
+    /// of values for each time sample.
+    ///
+    /// Resampling such data to more samples would multiply its size, and
+    /// resampling it to fewer would lose some of its motion, so it has to be
+    /// given at evenly spaced times already, and is returned as-is.  Other
+    /// times are an error.
+    pub fn resample_vecs<T>(&self, samples: Vec<Vec<T>>) -> Result<Vec<Vec<T>>, PsyParseError> {
+        if samples.len() <= 1 {
+            return Ok(samples);
+        }
+        self.check_count(samples.len())?;
+
+        if self.even_count != samples.len() {
+            return Err(PsyParseError::IncorrectLeafData(
+                self.byte_offset,
+                "TimeSamples of vertex data should be evenly spaced from 0.0 to 1.0, \
+                 since it can't be resampled without losing some of its motion.",
+            ));
+        }
+
+        Ok(samples)
+    }
+
+    fn check_count(&self, count: usize) -> Result<(), PsyParseError> {
+        if count == self.times.len() {
+            Ok(())
+        } else {
+            Err(PsyParseError::IncorrectLeafData(
+                self.byte_offset,
+                "Motion data should have either a single sample or one sample per \
+                 time in TimeSamples.",
+            ))
+        }
+    }
+}
+
+
+/// The time of sample `i` of `count` evenly spaced time samples.
+fn even_time(i: usize, count: usize) -> f32 {
+    if count > 1 {
+        i as f32 / (count - 1) as f32
+    } else {
+        0.0
+    }
+}
+
+/// Picks how many evenly spaced time samples to resample to: the fewest that
+/// have a sample at each of `times`, if that's within `MAX_RESAMPLED_COUNT`.
+fn even_sample_count(times: &[f32]) -> usize {
+    if times.len() <= 1 {
+        return 1;
+    }
+
+    for segments in 1..MAX_RESAMPLED_COUNT {
+        let s = segments as f32;
+        if times.iter().all(|t| ((t * s) - (t * s).round()).abs() < 0.001) {
+            return segments + 1;
+        }
+    }
+    MAX_RESAMPLED_COUNT
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn even_sample_count_1() {
+        assert_eq!(even_sample_count(&[0.5]), 1);
+        assert_eq!(even_sample_count(&[0.0, 1.0]), 2);
+        assert_eq!(even_sample_count(&[0.0, 0.3, 1.0]), 11);
+        assert_eq!(even_sample_count(&[0.25, 0.5, 1.0]), 5);
+        assert_eq!(even_sample_count(&[0.0, 0.123456, 1.0]), MAX_RESAMPLED_COUNT);
+    }
+
+    #[test]
+    fn resample_uneven() {
+        let ts = TimeSamples {
+            times: vec![0.25, 0.5, 1.0],
+            even_count: 5,
+            byte_offset: 0,
+        };
+        let resampled = ts.resample(vec![1.0f32, 2.0, 4.0]).unwrap();
+        assert_eq!(resampled, vec![1.0, 1.0, 2.0, 3.0, 4.0]);
+        assert_eq!(ts.resample(vec![3.0f32]).unwrap(), vec![3.0]);
+        assert!(ts.resample(vec![3.0f32, 4.0]).is_err());
+    }
+
+    #[test]
+    fn resample_vecs_even_only() {
+        let ts = TimeSamples {
+            times: vec![0.0, 0.25, 1.0],
+            even_count: 5,
+            byte_offset: 0,
+        };
+        assert!(ts.resample_vecs(vec![vec![1.0f32], vec![2.0], vec![5.0]]).is_err());
+        assert_eq!(ts.resample_vecs(vec![vec![3.0f32]]).unwrap(), vec![vec![3.0]]);
+
+        let ts = TimeSamples {
+            times: vec![0.0, 0.5, 1.0],
+            even_count: 3,
+            byte_offset: 0,
+        };
+        let resampled = ts.resample_vecs(vec![vec![1.0f32], vec![2.0], vec![4.0]]).unwrap();
+        assert_eq!(resampled, vec![vec![1.0], vec![2.0], vec![4.0]]);
+    }
+}
@@ -0,0 +1,246 @@
+#![allow(dead_code)]
+
+use bbox::BBox;
+use math::Point;
+
+
+/// A grid of evenly spaced points spanning a bounding box, with
+/// `2^bits - 1` steps along each axis, that coordinates can be quantized
+/// to.
+#[derive(Debug, Copy, Clone)]
+pub struct QuantGrid {
+    min: [f32; 3],
+    step: [f32; 3],
+    max_q: u32,
+    empty: bool,
+}
+
+impl QuantGrid {
+    pub fn new(bounds: BBox, bits: u32) -> QuantGrid {
+        debug_assert!(bits > 0 && bits <= 16);
+        let max_q = (1u32 << bits) - 1;
+
+        let mut grid = QuantGrid {
+            min: [0.0; 3],
+            step: [0.0; 3],
+            max_q: max_q,
+            empty: bounds.is_empty(),
+        };
+        if grid.empty {
+            return grid;
+        }
+
+        for axis in 0..3 {
+            let min = bounds.min.get_n(axis);
+            let max = bounds.max.get_n(axis);
+            let mut step = (max - min) / max_q as f32;
+
+            // Make sure the last step reaches the max despite rounding, so
+            // that everything in the bounds can be rounded outward.
+            while step.is_finite() && min + (max_q as f32 * step) < max {
+                step = f32::from_bits(step.to_bits() + 1);
+            }
+
+            grid.min[axis] = min;
+            grid.step[axis] = step;
+        }
+
+        grid
+    }
+
+    /// The number of bits each quantized coordinate needs.
+    pub fn bits(&self) -> u32 {
+        32 - self.max_q.leading_zeros()
+    }
+
+    pub fn dequantize(&self, q: [u32; 3]) -> Point {
+        Point::new(
+            self.dequantize_axis(q[0], 0),
+            self.dequantize_axis(q[1], 1),
+            self.dequantize_axis(q[2], 2),
+        )
+    }
+
+    /// Quantizes a point to the nearest point on the grid.
+    pub fn quantize(&self, p: Point) -> [u32; 3] {
+        let mut q = [0; 3];
+        for axis in 0..3 {
+            q[axis] = self.quantize_axis(p.get_n(axis), axis, f32::round);
+        }
+        q
+    }
+
+    /// Quantizes a bounding box to `[min_x, min_y, min_z, max_x, max_y,
+    /// max_z]`, rounding outward so that the dequantized box encloses the
+    /// original.  Empty boxes stay empty.
+    ///
+    /// Parts of the box outside of the grid are clamped to it, so the box
+    /// should be within the grid's bounds.
+    pub fn quantize_bbox(&self, bb: BBox) -> [u32; 6] {
+        if self.empty || bb.is_empty() {
+            return [self.max_q, self.max_q, self.max_q, 0, 0, 0];
+        }
+
+        let mut q = [0; 6];
+        for axis in 0..3 {
+            let min = bb.min.get_n(axis);
+            let max = bb.max.get_n(axis);
+
+            // Round down the min and up the max, and then correct for
+            // floating point error in the divide.
+            let mut q_min = self.quantize_axis(min, axis, f32::floor);
+            while q_min > 0 && self.dequantize_axis(q_min, axis) > min {
+                q_min -= 1;
+            }
+            let mut q_max = self.quantize_axis(max, axis, f32::ceil);
+            while q_max < self.max_q && self.dequantize_axis(q_max, axis) < max {
+                q_max += 1;
+            }
+
+            q[axis] = q_min;
+            q[axis + 3] = q_max;
+        }
+        q
+    }
+
+    pub fn dequantize_bbox(&self, q: [u32; 6]) -> BBox {
+        if q[0] > q[3] || q[1] > q[4] || q[2] > q[5] {
+            BBox::new()
+        } else {
+            BBox::from_points(
+                self.dequantize([q[0], q[1], q[2]]),
+                self.dequantize([q[3], q[4], q[5]]),
+            )
+        }
+    }
+
+    fn dequantize_axis(&self, q: u32, axis: usize) -> f32 {
+        self.min[axis] + (q as f32 * self.step[axis])
+    }
+
+    // Rounds `n` to a step along the axis with `round`, clamped to the grid.
+    fn quantize_axis<F: Fn(f32) -> f32>(&self, n: f32, axis: usize, round: F) -> u32 {
+        if self.step[axis] <= 0.0 {
+            return 0;
+        }
+        let q = round((n - self.min[axis]) / self.step[axis]);
+        if q >= self.max_q as f32 {
+            self.max_q
+        } else if q > 0.0 {
+            q as u32
+        } else {
+            0
+        }
+    }
+}
+
+impl Default for QuantGrid {
+    fn default() -> QuantGrid {
+        QuantGrid::new(BBox::new(), 1)
+    }
+}
+
+
+/// Appends quantized values to `out`, using one byte per value for up to
+/// 8 bits, and two (little endian) otherwise.
+pub fn write_quantized(values: &[u32], bits: u32, out: &mut Vec<u8>) {
+    for v in values {
+        if bits <= 8 {
+            out.push(*v as u8);
+        } else {
+            out.push(*v as u8);
+            out.push((*v >> 8) as u8);
+        }
+    }
+}
+
+/// Reads the `i`th value written by `write_quantized()`.
+#[inline(always)]
+pub fn read_quantized(bytes: &[u8], bits: u32, i: usize) -> u32 {
+    if bits <= 8 {
+        bytes[i] as u32
+    } else {
+        bytes[i * 2] as u32 | ((bytes[i * 2 + 1] as u32) << 8)
+    }
+}
+
+/// The number of bytes `write_quantized()` uses per value.
+pub fn quantized_size(bits: u32) -> usize {
+    if bits <= 8 { 1 } else { 2 }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bbox(min: (f32, f32, f32), max: (f32, f32, f32)) -> BBox {
+        BBox::from_points(Point::new(min.0, min.1, min.2), Point::new(max.0, max.1, max.2))
+    }
+
+    fn encloses(outer: BBox, inner: BBox) -> bool {
+        (0..3).all(|axis| {
+            outer.min.get_n(axis) <= inner.min.get_n(axis) &&
+                outer.max.get_n(axis) >= inner.max.get_n(axis)
+        })
+    }
+
+    #[test]
+    fn quantize_bbox_encloses() {
+        let frame = bbox((-1.3, 0.1, 1000.0), (2.7, 0.3, 1000.5));
+        for bits in &[8, 16] {
+            let grid = QuantGrid::new(frame, *bits);
+            for i in 0..100 {
+                let a = i as f32 / 100.0;
+                let b = (i as f32 * 0.37).fract();
+                let bb = bbox(
+                    (-1.3 + a, 0.1 + (0.2 * a * b), 1000.0 + (0.5 * a * a)),
+                    (
+                        -1.3 + a + (b * (2.7 - (-1.3 + a))),
+                        0.1 + (0.2 * a),
+                        1000.0 + (0.5 * a),
+                    ),
+                );
+                let dq = grid.dequantize_bbox(grid.quantize_bbox(bb));
+                assert!(encloses(dq, bb));
+            }
+
+            // The whole frame
+            let dq = grid.dequantize_bbox(grid.quantize_bbox(frame));
+            assert!(encloses(dq, frame));
+        }
+    }
+
+    #[test]
+    fn quantize_bbox_empty() {
+        let grid = QuantGrid::new(bbox((0.0, 0.0, 0.0), (1.0, 1.0, 1.0)), 8);
+        assert!(grid.dequantize_bbox(grid.quantize_bbox(BBox::new())).is_empty());
+
+        let grid = QuantGrid::new(BBox::new(), 8);
+        let bb = bbox((0.0, 0.0, 0.0), (1.0, 1.0, 1.0));
+        assert!(grid.dequantize_bbox(grid.quantize_bbox(bb)).is_empty());
+    }
+
+    #[test]
+    fn quantize_point_nearest() {
+        let grid = QuantGrid::new(bbox((0.0, 0.0, 0.0), (255.0, 2.55, 25500.0)), 8);
+        let q = grid.quantize(Point::new(10.4, 0.106, 3.0));
+        assert_eq!(q, [10, 11, 0]);
+        let p = grid.dequantize(q);
+        assert!((p.x() - 10.0).abs() < 0.0001);
+        assert!((p.y() - 0.11).abs() < 0.0001);
+    }
+
+    #[test]
+    fn read_write_quantized() {
+        for bits in &[8, 16] {
+            let values = [0, 1, 200, 255];
+            let mut bytes = Vec::new();
+            write_quantized(&values, *bits, &mut bytes);
+            assert_eq!(bytes.len(), values.len() * quantized_size(*bits));
+            for (i, v) in values.iter().enumerate() {
+                assert_eq!(read_quantized(&bytes, *bits, i), *v);
+            }
+        }
+    }
+}
@@ -11,7 +11,8 @@ use scoped_threadpool::Pool;
 use halton;
 
 use accel::{ACCEL_TRAV_TIME, ACCEL_NODE_RAY_TESTS};
-use algorithm::partition_pair;
+use algorithm::{partition_pair, permute_pair};
+use camera::Camera;
 use color::{Color, XYZ, SpectralSample, map_0_1_to_wavelength};
 use float4::Float4;
 use fp_utils::robust_ray_origin;
@@ -22,6 +23,8 @@ use math::{fast_logit, upper_power_of_two};
 use mis::power_heuristic;
 use ray::Ray;
 use scene::{Scene, SceneLightSample};
+use shading::SurfaceShader;
+use shading::surface_closure::SurfaceClosureUnion;
 use surface;
 use timer::Timer;
 use tracer::Tracer;
@@ -43,6 +46,8 @@ pub struct RenderStats {
     pub accel_traversal_time: f64,
     pub accel_node_visits: u64,
     pub initial_ray_generation_time: f64,
+    pub hit_sorting_time: f64,
+    pub shading_time: f64,
     pub ray_generation_time: f64,
     pub sample_writing_time: f64,
     pub total_time: f64,
@@ -55,6 +60,8 @@ impl RenderStats {
             accel_traversal_time: 0.0,
             accel_node_visits: 0,
             initial_ray_generation_time: 0.0,
+            hit_sorting_time: 0.0,
+            shading_time: 0.0,
             ray_generation_time: 0.0,
             sample_writing_time: 0.0,
             total_time: 0.0,
@@ -66,6 +73,8 @@ impl RenderStats {
         self.accel_traversal_time += other.accel_traversal_time;
         self.accel_node_visits += other.accel_node_visits;
         self.initial_ray_generation_time += other.initial_ray_generation_time;
+        self.hit_sorting_time += other.hit_sorting_time;
+        self.shading_time += other.shading_time;
         self.ray_generation_time += other.ray_generation_time;
         self.sample_writing_time += other.sample_writing_time;
         self.total_time += other.total_time;
@@ -73,8 +82,10 @@ impl RenderStats {
 }
 
 impl<'a> Renderer<'a> {
+    /// Renders the scene from the given camera.
     pub fn render(
         &self,
+        camera: &Camera,
         max_samples_per_bucket: u32,
         crop: Option<(u32, u32, u32, u32)>,
         thread_count: u32,
@@ -118,6 +129,7 @@ impl<'a> Renderer<'a> {
                 let cstats = &collective_stats;
                 scope.execute(move || {
                     self.render_job(
+                        camera,
                         jq,
                         ajq,
                         img,
@@ -195,6 +207,7 @@ impl<'a> Renderer<'a> {
     /// Waits for buckets in the job queue to render and renders them when available.
     fn render_job(
         &self,
+        camera: &Camera,
         job_queue: &MsQueue<BucketJob>,
         all_jobs_queued: &RwLock<bool>,
         image: &Image,
@@ -209,7 +222,10 @@ impl<'a> Renderer<'a> {
 
         let mut paths = Vec::new();
         let mut rays = Vec::new();
-        let mut tracer = Tracer::from_assembly(&self.scene.root);
+        let mut closures = Vec::new();
+        let mut hit_order = Vec::new();
+        let mut sorted_isects = Vec::new();
+        let mut tracer = Tracer::from_assembly(&self.scene.root, camera.clip_planes());
         let mut xform_stack = TransformStack::new();
 
         // Pre-calculate some useful values related to the image plane
@@ -245,29 +261,36 @@ impl<'a> Renderer<'a> {
                     let offset = hash_u32(((x as u32) << 16) ^ (y as u32), self.seed);
                     for si in 0..self.spp {
                         // Calculate image plane x and y coordinates
-                        let (img_x, img_y) = {
+                        let (samp_x, samp_y) = {
                             let filter_x = fast_logit(get_sample(4, offset + si as u32), 1.5) + 0.5;
                             let filter_y = fast_logit(get_sample(5, offset + si as u32), 1.5) + 0.5;
-                            let samp_x = (filter_x + x as f32) * cmpx;
-                            let samp_y = (filter_y + y as f32) * cmpy;
-                            ((samp_x - 0.5) * x_extent, (0.5 - samp_y) * y_extent)
+                            ((filter_x + x as f32) * cmpx, (filter_y + y as f32) * cmpy)
                         };
+                        let (img_x, img_y) =
+                            ((samp_x - 0.5) * x_extent, (0.5 - samp_y) * y_extent);
 
-                        // Create the light path and initial ray for this sample
-                        let (path, ray) = LightPath::new(
-                            &self.scene,
+                        // Calculate the sample's time, which depends on the
+                        // image y coordinate for rolling shutters.
+                        let time = camera.sample_time(get_sample(2, offset + si as u32), samp_y);
+
+                        // Create the light path and initial ray for this sample.
+                        // Samples whose camera ray is blocked contribute nothing.
+                        if let Some((path, ray)) = LightPath::new(
+                            camera,
                             (x, y),
                             (img_x, img_y),
                             (
                                 get_sample(0, offset + si as u32),
                                 get_sample(1, offset + si as u32),
                             ),
-                            get_sample(2, offset + si as u32),
+                            time,
                             map_0_1_to_wavelength(get_sample(3, offset + si as u32)),
                             offset + si as u32,
-                        );
-                        paths.push(path);
-                        rays.push(ray);
+                        )
+                        {
+                            paths.push(path);
+                            rays.push(ray);
+                        }
                     }
                 }
             }
@@ -277,12 +300,44 @@ impl<'a> Renderer<'a> {
             let mut pi = paths.len();
             while pi > 0 {
                 // Test rays against scene
-                let isects = tracer.trace(&rays);
+                let (isects, hit_instances) = tracer.trace(&rays);
                 stats.trace_time += timer.tick() as f64;
 
+                // Sort the active paths by the shader and instance they hit,
+                // so that shading and closure sampling are done in coherent
+                // batches.
+                hit_order.clear();
+                hit_order.extend(0..pi);
+                hit_order.sort_by_key(|i| shading_key(&isects[*i], hit_instances[*i]));
+                sorted_isects.clear();
+                sorted_isects.extend(hit_order.iter().map(|i| isects[*i]));
+                permute_pair(&mut paths[..pi], &mut rays[..pi], &mut hit_order[..]);
+                stats.hit_sorting_time += timer.tick() as f64;
+
+                // Shade the hits of the active paths
+                closures.clear();
+                closures.extend(sorted_isects.iter().zip(rays[..pi].iter()).map(
+                    |(isect, ray)| if let surface::SurfaceIntersection::Hit {
+                        intersection_data: ref idata,
+                        shader,
+                    } = *isect
+                    {
+                        Some(shader.shade(idata, ray.time, ray.wavelength))
+                    } else {
+                        None
+                    },
+                ));
+                stats.shading_time += timer.tick() as f64;
+
                 // Determine next rays to shoot based on result
                 pi = partition_pair(&mut paths[..pi], &mut rays[..pi], |i, path, ray| {
-                    path.next(&mut xform_stack, &self.scene, &isects[i], &mut *ray)
+                    path.next(
+                        &mut xform_stack,
+                        &self.scene,
+                        &sorted_isects[i],
+                        closures[i].as_ref(),
+                        &mut *ray,
+                    )
                 });
                 stats.ray_generation_time += timer.tick() as f64;
             }
@@ -383,15 +438,32 @@ pub struct LightPath {
 
 impl LightPath {
     fn new(
-        scene: &Scene,
+        camera: &Camera,
         pixel_co: (u32, u32),
         image_plane_co: (f32, f32),
         lens_uv: (f32, f32),
         time: f32,
         wavelength: f32,
         lds_offset: u32,
-    ) -> (LightPath, Ray) {
-        (
+    ) -> Option<(LightPath, Ray)> {
+        let ray = camera.generate_ray(
+            image_plane_co.0,
+            image_plane_co.1,
+            time,
+            wavelength,
+            lens_uv.0,
+            lens_uv.1,
+        )?;
+
+        // Wavelength-dependent camera rays are only valid for the hero
+        // wavelength, so the other wavelengths are dropped.
+        let light_attenuation = if camera.is_dispersive() {
+            Float4::new(4.0, 0.0, 0.0, 0.0)
+        } else {
+            Float4::splat(1.0)
+        };
+
+        Some((
             LightPath {
                 event: LightPathEvent::CameraRay,
                 bounce_count: 0,
@@ -406,20 +478,12 @@ impl LightPath {
                 next_attenuation_fac: Float4::splat(1.0),
 
                 closure_sample_pdf: 1.0,
-                light_attenuation: Float4::splat(1.0),
+                light_attenuation: light_attenuation,
                 pending_color_addition: Float4::splat(0.0),
                 color: Float4::splat(0.0),
             },
-
-            scene.camera.generate_ray(
-                image_plane_co.0,
-                image_plane_co.1,
-                time,
-                wavelength,
-                lens_uv.0,
-                lens_uv.1,
-            ),
-        )
+            ray,
+        ))
     }
 
     fn next_lds_samp(&self) -> f32 {
@@ -433,6 +497,7 @@ impl LightPath {
         xform_stack: &mut TransformStack,
         scene: &Scene,
         isect: &surface::SurfaceIntersection,
+        closure: Option<&SurfaceClosureUnion>,
         ray: &mut Ray,
     ) -> bool {
         match self.event {
@@ -440,20 +505,19 @@ impl LightPath {
             // Result of Camera or bounce ray, prepare next bounce and light rays
             LightPathEvent::CameraRay |
             LightPathEvent::BounceRay => {
-                if let surface::SurfaceIntersection::Hit {
-                    intersection_data: ref idata,
-                    ref closure,
-                } = *isect
+                if let (&surface::SurfaceIntersection::Hit {
+                            intersection_data: ref idata, ..
+                        },
+                        Some(closure)) = (isect, closure)
                 {
                     // Hit something!  Do the stuff
 
                     // If it's an emission closure, handle specially:
                     // - Collect light from the emission.
                     // - Terminate the path.
-                    use shading::surface_closure::SurfaceClosureUnion;
                     if let &SurfaceClosureUnion::EmitClosure(ref clsr) = closure {
                         if let LightPathEvent::CameraRay = self.event {
-                            self.color += clsr.emitted_color().e;
+                            self.color += clsr.emitted_color().e * self.light_attenuation;
                         } else {
                             let mis_pdf =
                                 power_heuristic(self.closure_sample_pdf, idata.sample_pdf);
@@ -481,6 +545,7 @@ impl LightPath {
                         self.wavelength,
                         self.time,
                         isect,
+                        closure,
                     );
                     let found_light = if light_info.is_none() || light_info.pdf() <= 0.0 ||
                         light_info.selection_pdf() <= 0.0
@@ -659,6 +724,16 @@ impl LightPath {
     }
 }
 
+/// Gets the key that hits are sorted by before shading, grouping them by
+/// shader and then by instance.  Misses all have the same key.
+fn shading_key(isect: &surface::SurfaceIntersection, instance: usize) -> (usize, usize) {
+    if let surface::SurfaceIntersection::Hit { shader, .. } = *isect {
+        (shader as *const SurfaceShader as *const u8 as usize, instance)
+    } else {
+        (0, 0)
+    }
+}
+
 /// Gets a sample, using LDS samples for lower dimensions,
 /// and switching to random samples at higher dimensions where
 /// LDS samples aren't available.
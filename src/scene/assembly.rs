@@ -2,16 +2,18 @@ use std::collections::HashMap;
 
 use mem_arena::MemArena;
 
+use accel;
 use accel::{LightAccel, LightTree};
-use accel::BVH4;
+use accel::WideBVH;
 use bbox::{BBox, transform_bbox_slice_from};
 use boundable::Boundable;
 use color::SpectralSample;
 use lerp::lerp_slice;
 use light::SurfaceLight;
-use math::{Matrix4x4, Normal, Point};
+use math::{Matrix4x4, Normal, Point, Transform};
 use surface::{Surface, SurfaceIntersection};
 use shading::SurfaceShader;
+use shading::surface_closure::SurfaceClosureUnion;
 use transform_stack::TransformStack;
 
 
@@ -20,10 +22,10 @@ pub struct Assembly<'a> {
     // Instance list
     pub instances: &'a [Instance],
     pub light_instances: &'a [Instance],
-    pub xforms: &'a [Matrix4x4],
+    pub xforms: &'a [Transform], // Local-to-world, decomposed
 
-    // Surface shader list
-    pub surface_shaders: &'a [&'a SurfaceShader],
+    // Surface shader list, with the shaders bound to each instance
+    pub surface_shader_binds: &'a [&'a SurfaceShader],
 
     // Object list
     pub objects: &'a [Object<'a>],
@@ -32,7 +34,7 @@ pub struct Assembly<'a> {
     pub assemblies: &'a [Assembly<'a>],
 
     // Object accel
-    pub object_accel: BVH4<'a>,
+    pub object_accel: WideBVH<'a>,
 
     // Light accel
     pub light_accel: LightTree<'a>,
@@ -48,14 +50,12 @@ impl<'a> Assembly<'a> {
         wavelength: f32,
         time: f32,
         intr: &SurfaceIntersection,
+        closure: &SurfaceClosureUnion,
     ) -> Option<(SpectralSample, (Point, Normal, f32), f32, f32)> {
-        if let SurfaceIntersection::Hit {
-            intersection_data: idata,
-            closure,
-        } = *intr
+        if let SurfaceIntersection::Hit { intersection_data: idata, .. } = *intr
         {
             let sel_xform = if !xform_stack.top().is_empty() {
-                lerp_slice(xform_stack.top(), time)
+                lerp_slice(xform_stack.top(), time).to_inverse_matrix()
             } else {
                 Matrix4x4::new()
             };
@@ -79,16 +79,17 @@ impl<'a> Assembly<'a> {
                                 // Get the world-to-object space transform of the light
                                 let xform = if let Some((a, b)) = inst.transform_indices {
                                     let pxforms = xform_stack.top();
-                                    let xform = lerp_slice(&self.xforms[a..b], time);
+                                    let xform =
+                                        lerp_slice(&self.xforms[a..b], time).to_inverse_matrix();
                                     if !pxforms.is_empty() {
-                                        lerp_slice(pxforms, time) * xform
+                                        lerp_slice(pxforms, time).to_inverse_matrix() * xform
                                     } else {
                                         xform
                                     }
                                 } else {
                                     let pxforms = xform_stack.top();
                                     if !pxforms.is_empty() {
-                                        lerp_slice(pxforms, time)
+                                        lerp_slice(pxforms, time).to_inverse_matrix()
                                     } else {
                                         Matrix4x4::new()
                                     }
@@ -125,6 +126,7 @@ impl<'a> Assembly<'a> {
                             wavelength,
                             time,
                             intr,
+                            closure,
                         );
 
                         // Pop the assembly's transforms off the transform stack.
@@ -158,11 +160,12 @@ pub struct AssemblyBuilder<'a> {
 
     // Instance list
     instances: Vec<Instance>,
-    xforms: Vec<Matrix4x4>,
+    xforms: Vec<Transform>,
 
     // Shader list
     surface_shaders: Vec<&'a SurfaceShader>,
     surface_shader_map: HashMap<String, usize>, // map Name -> Index
+    surface_shader_binds: Vec<&'a SurfaceShader>,
 
     // Object list
     objects: Vec<Object<'a>>,
@@ -182,6 +185,7 @@ impl<'a> AssemblyBuilder<'a> {
             xforms: Vec::new(),
             surface_shaders: Vec::new(),
             surface_shader_map: HashMap::new(),
+            surface_shader_binds: Vec::new(),
             objects: Vec::new(),
             object_map: HashMap::new(),
             assemblies: Vec::new(),
@@ -234,7 +238,7 @@ impl<'a> AssemblyBuilder<'a> {
     pub fn add_instance(
         &mut self,
         name: &str,
-        surface_shader_name: Option<&str>,
+        surface_shader_names: &[&str],
         xforms: Option<&[Matrix4x4]>,
     ) {
         // Make sure name exists
@@ -249,17 +253,20 @@ impl<'a> AssemblyBuilder<'a> {
             None
         };
 
+        // Map zero-length shader lists to None
+        let shader_indices = if !surface_shader_names.is_empty() {
+            let start = self.surface_shader_binds.len();
+            Some((start, start + surface_shader_names.len()))
+        } else {
+            None
+        };
+
         // Create instance
         let instance = if self.object_map.contains_key(name) {
             Instance {
                 instance_type: InstanceType::Object,
                 data_index: self.object_map[name],
-                surface_shader_index: surface_shader_name.map(|name| {
-                    *self.surface_shader_map.get(name).expect(&format!(
-                        "Unknown surface shader '{}'.",
-                        name
-                    ))
-                }),
+                surface_shader_indices: shader_indices,
                 id: self.instances.len(),
                 transform_indices: xforms.map(
                     |xf| (self.xforms.len(), self.xforms.len() + xf.len()),
@@ -269,12 +276,7 @@ impl<'a> AssemblyBuilder<'a> {
             Instance {
                 instance_type: InstanceType::Assembly,
                 data_index: self.assembly_map[name],
-                surface_shader_index: surface_shader_name.map(|name| {
-                    *self.surface_shader_map.get(name).expect(&format!(
-                        "Unknown surface shader '{}'.",
-                        name
-                    ))
-                }),
+                surface_shader_indices: shader_indices,
                 id: self.instances.len(),
                 transform_indices: xforms.map(
                     |xf| (self.xforms.len(), self.xforms.len() + xf.len()),
@@ -284,9 +286,17 @@ impl<'a> AssemblyBuilder<'a> {
 
         self.instances.push(instance);
 
-        // Store transforms
+        // Store shaders
+        for name in surface_shader_names {
+            let shader = self.surface_shaders[*self.surface_shader_map.get(*name).expect(
+                &format!("Unknown surface shader '{}'.", name),
+            )];
+            self.surface_shader_binds.push(shader);
+        }
+
+        // Store transforms, decomposed so they can be interpolated quickly
         if let Some(xf) = xforms {
-            self.xforms.extend(xf);
+            self.xforms.extend(xf.iter().map(|m| Transform::from_matrix(m.inverse())));
         }
     }
 
@@ -298,10 +308,20 @@ impl<'a> AssemblyBuilder<'a> {
         // Calculate instance bounds, used for building object accel and light accel.
         let (bis, bbs) = self.instance_bounds();
 
-        // Build object accel
-        let object_accel = BVH4::from_objects(self.arena, &mut self.instances[..], 1, |inst| {
-            &bbs[bis[inst.id]..bis[inst.id + 1]]
-        });
+        // Build object accel.  With time splits, instances can be referenced
+        // from more than one leaf, so the accel gets its own list of them.
+        let is_moving = bbs.len() > self.instances.len();
+        let (object_accel, accel_instances) = if accel::time_splits() && is_moving {
+            WideBVH::from_objects_with_time_splits(self.arena, &self.instances[..], 1, |inst| {
+                &bbs[bis[inst.id]..bis[inst.id + 1]]
+            })
+        } else {
+            let object_accel =
+                WideBVH::from_objects(self.arena, &mut self.instances[..], 1, |inst| {
+                    &bbs[bis[inst.id]..bis[inst.id + 1]]
+                });
+            (object_accel, self.instances.clone())
+        };
 
         // Get list of instances that are for light sources or assemblies that contain light
         // sources.
@@ -325,12 +345,14 @@ impl<'a> AssemblyBuilder<'a> {
             .cloned()
             .collect();
 
-        // Build light accel
+        // Build light accel.  Only the parts of the builder that can be
+        // shared between build threads are borrowed.
+        let (objects, assemblies) = (&self.objects, &self.assemblies);
         let light_accel = LightTree::from_objects(self.arena, &mut light_instances[..], |inst| {
             let bounds = &bbs[bis[inst.id]..bis[inst.id + 1]];
             let energy = match inst.instance_type {
                 InstanceType::Object => {
-                    if let Object::SurfaceLight(light) = self.objects[inst.data_index] {
+                    if let Object::SurfaceLight(light) = objects[inst.data_index] {
                         light.approximate_energy()
                     } else {
                         0.0
@@ -338,19 +360,17 @@ impl<'a> AssemblyBuilder<'a> {
                 }
 
                 InstanceType::Assembly => {
-                    self.assemblies[inst.data_index]
-                        .light_accel
-                        .approximate_energy()
+                    assemblies[inst.data_index].light_accel.approximate_energy()
                 }
             };
             (bounds, energy)
         });
 
         Assembly {
-            instances: self.arena.copy_slice(&self.instances),
+            instances: self.arena.copy_slice(&accel_instances),
             light_instances: self.arena.copy_slice(&light_instances),
             xforms: self.arena.copy_slice(&self.xforms),
-            surface_shaders: self.arena.copy_slice(&self.surface_shaders),
+            surface_shader_binds: self.arena.copy_slice(&self.surface_shader_binds),
             objects: self.arena.copy_slice(&self.objects),
             assemblies: self.arena.copy_slice(&self.assemblies),
             object_accel: object_accel,
@@ -360,7 +380,7 @@ impl<'a> AssemblyBuilder<'a> {
 
 
     /// Returns a pair of vectors with the bounds of all instances.
-    /// This is used for building the assembly's BVH4.
+    /// This is used for building the assembly's BVH.
     fn instance_bounds(&self) -> (Vec<usize>, Vec<BBox>) {
         let mut indices = vec![0];
         let mut bounds = Vec::new();
@@ -418,7 +438,7 @@ pub enum Object<'a> {
 pub struct Instance {
     pub instance_type: InstanceType,
     pub data_index: usize,
-    pub surface_shader_index: Option<usize>,
+    pub surface_shader_indices: Option<(usize, usize)>,
     pub id: usize,
     pub transform_indices: Option<(usize, usize)>,
 }
@@ -2,6 +2,6 @@ mod assembly;
 mod scene;
 mod world;
 
-pub use self::assembly::{Assembly, AssemblyBuilder, Object, InstanceType};
+pub use self::assembly::{Assembly, AssemblyBuilder, Object, Instance, InstanceType};
 pub use self::scene::{Scene, SceneLightSample};
 pub use self::world::World;
@@ -3,6 +3,7 @@ use algorithm::weighted_choice;
 use camera::Camera;
 use color::SpectralSample;
 use math::{Vector, Normal, Point};
+use shading::surface_closure::SurfaceClosureUnion;
 use surface::SurfaceIntersection;
 use transform_stack::TransformStack;
 
@@ -13,12 +14,25 @@ use super::World;
 #[derive(Debug)]
 pub struct Scene<'a> {
     pub name: Option<String>,
-    pub camera: Camera<'a>,
+    pub cameras: Vec<Camera<'a>>,
     pub world: World<'a>,
     pub root: Assembly<'a>,
 }
 
 impl<'a> Scene<'a> {
+    /// Returns the camera with the given name, or the scene's first camera
+    /// if no name is given.  The leading '$' of the name is optional.
+    pub fn camera(&self, name: Option<&str>) -> Option<&Camera<'a>> {
+        if let Some(name) = name {
+            let name = name.trim_start_matches('$');
+            self.cameras.iter().find(|c| {
+                c.name().map(|n| n.trim_start_matches('$')) == Some(name)
+            })
+        } else {
+            self.cameras.first()
+        }
+    }
+
     pub fn sample_lights(
         &self,
         xform_stack: &mut TransformStack,
@@ -27,6 +41,7 @@ impl<'a> Scene<'a> {
         wavelength: f32,
         time: f32,
         intr: &SurfaceIntersection,
+        closure: &SurfaceClosureUnion,
     ) -> SceneLightSample {
         // TODO: this just selects between world lights and local lights
         // with a 50/50 chance.  We should do something more sophisticated
@@ -80,6 +95,7 @@ impl<'a> Scene<'a> {
                         wavelength,
                         time,
                         intr,
+                        closure,
                     )
                 {
                     return SceneLightSample::Surface {
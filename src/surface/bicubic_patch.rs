@@ -0,0 +1,482 @@
+#![allow(dead_code)]
+
+use mem_arena::MemArena;
+
+use accel::WideBVH;
+use bbox::BBox;
+use boundable::Boundable;
+use lerp::lerp_slice;
+use math::{Point, Vector, Normal, Matrix4x4, Transform, dot, cross};
+use ray::{Ray, AccelRay};
+use shading::SurfaceShader;
+
+use super::{Surface, SurfaceIntersection, SurfaceIntersectionData};
+use super::mesh_attribute::PrimvarValues;
+use super::triangle;
+
+
+// Maximum allowed deviation of the diced micro-triangles from the true
+// surface, as a fraction of the patch's size.
+const DICE_TOLERANCE: f32 = 0.001;
+
+// Limits on the number of segments a patch is diced into along each
+// parametric direction.
+const MAX_DICE_RATE: usize = 64;
+
+// How many times to look for a normal further inside the patch, where the
+// normal at a point is degenerate.
+const NORMAL_RETRIES: usize = 4;
+
+
+/// A mesh of bicubic Bézier patches.
+///
+/// Each patch has 16 control points, in rows of increasing v with each row
+/// ordered by increasing u.  The patches are diced into micro-triangles at
+/// build time, with the shading normals and uvs of the micro-triangle
+/// vertices evaluated analytically from the patches.
+#[derive(Copy, Clone, Debug)]
+pub struct BicubicPatchMesh<'a> {
+    time_sample_count: usize,
+    vertices: &'a [Point], // Vertices, with the time samples for each vertex stored contiguously
+    normals: &'a [Normal], // Vertex normals, organized the same as `vertices`
+    uvs: &'a [(f32, f32)], // Vertex uvs, one per vertex
+    indices: &'a [(u32, u32, u32, u32)], // (v0_idx, v1_idx, v2_idx, original_tri_idx)
+    accel: WideBVH<'a>,
+}
+
+impl<'a> BicubicPatchMesh<'a> {
+    /// Creates a new bicubic patch mesh.
+    ///
+    /// `control_points` has one Vec per time sample, each with 16 control
+    /// points per patch.  If `dice_rate` is `None`, the number of segments
+    /// each patch is diced into is chosen from its curvature.
+    pub fn from_control_points<'b>(
+        arena: &'b MemArena,
+        control_points: Vec<Vec<Point>>,
+        dice_rate: Option<usize>,
+    ) -> BicubicPatchMesh<'b> {
+        let time_sample_count = control_points.len();
+        let patch_count = control_points[0].len() / 16;
+
+        // Dice the patches into grids of micro-triangles
+        let mut verts = Vec::new(); // Time samples for each vertex stored contiguously
+        let mut nors = Vec::new(); // Organized the same as `verts`
+        let mut uvs = Vec::new();
+        let mut tri_indices = Vec::new();
+        for pi in 0..patch_count {
+            let patches: Vec<&[Point]> = control_points
+                .iter()
+                .map(|cps| &cps[(pi * 16)..((pi + 1) * 16)])
+                .collect();
+
+            let rate = dice_rate.unwrap_or_else(|| {
+                patches.iter().map(|p| estimate_dice_rate(p)).max().unwrap()
+            });
+            let rate = rate.max(1).min(MAX_DICE_RATE);
+
+            // Vertices
+            let first_vert = uvs.len();
+            for vi in 0..(rate + 1) {
+                for ui in 0..(rate + 1) {
+                    let u = ui as f32 / rate as f32;
+                    let v = vi as f32 / rate as f32;
+                    uvs.push((u, v));
+                    for patch in &patches {
+                        let (p, n) = eval_patch(patch, u, v);
+                        verts.push(p);
+                        nors.push(n);
+                    }
+                }
+            }
+
+            // Micro-triangles
+            for vi in 0..rate {
+                for ui in 0..rate {
+                    let v00 = first_vert + (vi * (rate + 1)) + ui;
+                    let v10 = v00 + 1;
+                    let v01 = v00 + rate + 1;
+                    let v11 = v01 + 1;
+                    tri_indices.push((v00, v10, v11));
+                    tri_indices.push((v00, v11, v01));
+                }
+            }
+        }
+
+        // Copy triangle vertex indices over, appending the triangle index itself to the tuple
+        let indices = {
+            let indices = unsafe { arena.alloc_array_uninitialized(tri_indices.len()) };
+            for (i, tri_i) in tri_indices.iter().enumerate() {
+                indices[i] = (tri_i.0 as u32, tri_i.2 as u32, tri_i.1 as u32, i as u32);
+            }
+            indices
+        };
+
+        // Create bounds array for use during BVH construction
+        let bounds = {
+            let mut bounds = Vec::with_capacity(indices.len() * time_sample_count);
+            for tri in &tri_indices {
+                for ti in 0..time_sample_count {
+                    let p0 = verts[(tri.0 * time_sample_count) + ti];
+                    let p1 = verts[(tri.1 * time_sample_count) + ti];
+                    let p2 = verts[(tri.2 * time_sample_count) + ti];
+                    let minimum = p0.min(p1.min(p2));
+                    let maximum = p0.max(p1.max(p2));
+                    bounds.push(BBox::from_points(minimum, maximum));
+                }
+            }
+            bounds
+        };
+
+        // Build BVH
+        let accel = WideBVH::from_objects(arena, &mut indices[..], 3, |tri| {
+            &bounds[(tri.3 as usize * time_sample_count)..
+                        ((tri.3 as usize + 1) * time_sample_count)]
+        });
+
+        BicubicPatchMesh {
+            time_sample_count: time_sample_count,
+            vertices: arena.copy_slice(&verts),
+            normals: arena.copy_slice(&nors),
+            uvs: arena.copy_slice(&uvs),
+            indices: indices,
+            accel: accel,
+        }
+    }
+}
+
+impl<'a> Boundable for BicubicPatchMesh<'a> {
+    fn bounds(&self) -> &[BBox] {
+        self.accel.bounds()
+    }
+}
+
+
+impl<'a> Surface for BicubicPatchMesh<'a> {
+    fn intersect_rays<'b>(
+        &'b self,
+        accel_rays: &mut [AccelRay],
+        wrays: &[Ray],
+        isects: &mut [SurfaceIntersection<'b>],
+        shaders: &[&'b SurfaceShader],
+        space: &[Transform],
+    ) {
+        let shader = shaders[0];
+
+        // Precalculate transform for non-motion blur cases
+        let static_mat_space = if space.len() == 1 {
+            lerp_slice(space, 0.0).to_matrix()
+        } else {
+            Matrix4x4::new()
+        };
+
+        let tsc = self.time_sample_count;
+
+        self.accel.traverse(
+            &mut accel_rays[..],
+            self.indices,
+            |tri_indices, rs| {
+                let (i0, i1, i2) = (
+                    tri_indices.0 as usize,
+                    tri_indices.1 as usize,
+                    tri_indices.2 as usize,
+                );
+
+                for r in rs {
+                    let wr = &wrays[r.id as usize];
+
+                    // Get triangle
+                    let tri = (
+                        lerp_slice(&self.vertices[(i0 * tsc)..((i0 + 1) * tsc)], wr.time),
+                        lerp_slice(&self.vertices[(i1 * tsc)..((i1 + 1) * tsc)], wr.time),
+                        lerp_slice(&self.vertices[(i2 * tsc)..((i2 + 1) * tsc)], wr.time),
+                    );
+
+                    // Transform triangle as necessary, and get transform
+                    // space.
+                    let (mat_space, tri) = if !space.is_empty() {
+                        let mat_space = if space.len() > 1 {
+                            // Per-ray transform, for motion blur
+                            lerp_slice(space, wr.time).to_matrix()
+                        } else {
+                            // Same transform for all rays
+                            static_mat_space
+                        };
+                        (mat_space, (tri.0 * mat_space, tri.1 * mat_space, tri.2 * mat_space))
+                    } else {
+                        // No transforms
+                        (Matrix4x4::new(), tri)
+                    };
+
+                    // Test ray against triangle
+                    if let Some((t, b0, b1, b2)) = triangle::intersect_ray(wr, tri) {
+                        if t < r.max_t {
+                            if r.is_occlusion() {
+                                isects[r.id as usize] = SurfaceIntersection::Occlude;
+                                r.mark_done();
+                            } else {
+                                // Calculate intersection point and error magnitudes
+                                let (pos, pos_err) = triangle::surface_point(tri, (b0, b1, b2));
+
+                                // Calculate the interpolated surface normal
+                                let shading_normal = {
+                                    let ns = self.normals;
+                                    let n0 = lerp_slice(&ns[(i0 * tsc)..((i0 + 1) * tsc)], wr.time);
+                                    let n1 = lerp_slice(&ns[(i1 * tsc)..((i1 + 1) * tsc)], wr.time);
+                                    let n2 = lerp_slice(&ns[(i2 * tsc)..((i2 + 1) * tsc)], wr.time);
+                                    ((n0 * b0) + (n1 * b1) + (n2 * b2)) * mat_space
+                                };
+
+                                // Calculate geometric surface normal, oriented
+                                // to match the patch.
+                                let geo_normal = {
+                                    let n = cross(tri.0 - tri.1, tri.0 - tri.2).into_normal();
+                                    if dot(n, shading_normal) >= 0.0 { n } else { -n }
+                                };
+
+                                // Calculate the interpolated uv
+                                let uv = {
+                                    let uv0 = self.uvs[i0];
+                                    let uv1 = self.uvs[i1];
+                                    let uv2 = self.uvs[i2];
+                                    (
+                                        (uv0.0 * b0) + (uv1.0 * b1) + (uv2.0 * b2),
+                                        (uv0.1 * b0) + (uv1.1 * b1) + (uv2.1 * b2),
+                                    )
+                                };
+
+                                // Calculate the tangent along u from the
+                                // triangle's uv gradient.
+                                let tangent = {
+                                    let (uv0, uv1, uv2) =
+                                        (self.uvs[i0], self.uvs[i1], self.uvs[i2]);
+                                    let (du1, dv1) = (uv1.0 - uv0.0, uv1.1 - uv0.1);
+                                    let (du2, dv2) = (uv2.0 - uv0.0, uv2.1 - uv0.1);
+                                    let det = (du1 * dv2) - (dv1 * du2);
+                                    let dpdu = if det != 0.0 {
+                                        (((tri.1 - tri.0) * dv2) - ((tri.2 - tri.0) * dv1)) / det
+                                    } else {
+                                        tri.1 - tri.0
+                                    };
+                                    dpdu.normalized()
+                                };
+
+                                let intersection_data = SurfaceIntersectionData {
+                                    incoming: wr.dir,
+                                    t: t,
+                                    pos: pos,
+                                    pos_err: pos_err,
+                                    nor: shading_normal,
+                                    nor_g: geo_normal,
+                                    tangent: tangent,
+                                    uv: uv,
+                                    vertex_color: None,
+                                    primvars: PrimvarValues::new(),
+                                    local_space: mat_space,
+                                    sample_pdf: 0.0,
+                                };
+
+                                // Fill in intersection data
+                                isects[r.id as usize] = SurfaceIntersection::Hit {
+                                    intersection_data: intersection_data,
+                                    shader: shader,
+                                };
+                                r.max_t = t;
+                            }
+                        }
+                    }
+                }
+            },
+        );
+    }
+}
+
+
+/// Evaluates the cubic Bernstein basis functions and their derivatives at
+/// `t`.
+fn bernstein(t: f32) -> ([f32; 4], [f32; 4]) {
+    let s = 1.0 - t;
+    (
+        [s * s * s, 3.0 * t * s * s, 3.0 * t * t * s, t * t * t],
+        [
+            -3.0 * s * s,
+            (3.0 * s * s) - (6.0 * t * s),
+            (6.0 * t * s) - (3.0 * t * t),
+            3.0 * t * t,
+        ],
+    )
+}
+
+/// Evaluates the position and normal of a bicubic Bézier patch at the
+/// given uv coordinates.
+pub fn eval_patch(patch: &[Point], u: f32, v: f32) -> (Point, Normal) {
+    let (pos, mut nor) = eval_patch_cross(patch, u, v);
+
+    // Degenerate edge, e.g. where a row of control points collapses to a
+    // pole.  Use the normal from slightly inside the patch instead, going
+    // further in if that's degenerate too.
+    let mut e = 0.0001;
+    for _ in 0..NORMAL_RETRIES {
+        if nor.length2() > 0.0 {
+            break;
+        }
+        let nu = if u < 0.5 { u + e } else { u - e };
+        let nv = if v < 0.5 { v + e } else { v - e };
+        nor = eval_patch_cross(patch, nu, nv).1;
+        e *= 10.0;
+    }
+
+    // The whole patch is degenerate, e.g. all of its control points are
+    // the same.  Fall back to the normal of its corners, or any normal.
+    if nor.length2() == 0.0 {
+        nor = cross(patch[15] - patch[0], patch[12] - patch[3]);
+        if nor.length2() == 0.0 {
+            nor = Vector::new(0.0, 0.0, 1.0);
+        }
+    }
+
+    (pos.into_point(), nor.normalized().into_normal())
+}
+
+/// Evaluates the position of a bicubic Bézier patch at the given uv
+/// coordinates, and the (unnormalized) cross product of its derivatives.
+fn eval_patch_cross(patch: &[Point], u: f32, v: f32) -> (Vector, Vector) {
+    let (bu, dbu) = bernstein(u);
+    let (bv, dbv) = bernstein(v);
+
+    let mut pos = Vector::new(0.0, 0.0, 0.0);
+    let mut dpdu = Vector::new(0.0, 0.0, 0.0);
+    let mut dpdv = Vector::new(0.0, 0.0, 0.0);
+    for j in 0..4 {
+        for i in 0..4 {
+            let p = patch[(j * 4) + i].into_vector();
+            pos = pos + (p * (bu[i] * bv[j]));
+            dpdu = dpdu + (p * (dbu[i] * bv[j]));
+            dpdv = dpdv + (p * (bu[i] * dbv[j]));
+        }
+    }
+
+    (pos, cross(dpdu, dpdv))
+}
+
+/// Estimates the number of segments a patch needs to be diced into along
+/// each parametric direction for its micro-triangles to stay within
+/// `DICE_TOLERANCE` of the true surface.
+///
+/// This uses the largest second difference of the control points along the
+/// rows and columns, which bounds the second derivative of the patch.  The
+/// error of linearly interpolating a function over `n` segments is at most
+/// `f'' / (8 * n^2)`.
+pub fn estimate_dice_rate(patch: &[Point]) -> usize {
+    let mut max_diff2: f32 = 0.0;
+    let mut minimum = patch[0];
+    let mut maximum = patch[0];
+    for i in 0..4 {
+        for k in 0..2 {
+            // Along u
+            let (a, b, c) = (
+                patch[(i * 4) + k],
+                patch[(i * 4) + k + 1],
+                patch[(i * 4) + k + 2],
+            );
+            max_diff2 = max_diff2.max(((a - b) - (b - c)).length());
+
+            // Along v
+            let (a, b, c) = (
+                patch[(k * 4) + i],
+                patch[((k + 1) * 4) + i],
+                patch[((k + 2) * 4) + i],
+            );
+            max_diff2 = max_diff2.max(((a - b) - (b - c)).length());
+        }
+    }
+    for p in patch {
+        minimum = minimum.min(*p);
+        maximum = maximum.max(*p);
+    }
+
+    // The second derivative of a cubic Bézier curve is at most 6 times the
+    // largest second difference of its control points.
+    let tolerance = (maximum - minimum).length() * DICE_TOLERANCE;
+    if tolerance <= 0.0 || max_diff2 <= 0.0 {
+        return 1;
+    }
+    let rate = ((6.0 * max_diff2) / (8.0 * tolerance)).sqrt().ceil();
+
+    (rate as usize).max(1).min(MAX_DICE_RATE)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn flat_patch() -> Vec<Point> {
+        let mut patch = Vec::new();
+        for j in 0..4 {
+            for i in 0..4 {
+                patch.push(Point::new(i as f32, j as f32, 0.0));
+            }
+        }
+        patch
+    }
+
+    fn bumpy_patch() -> Vec<Point> {
+        let mut patch = flat_patch();
+        patch[5] = Point::new(1.0, 1.0, 2.0);
+        patch[6] = Point::new(2.0, 1.0, 2.0);
+        patch[9] = Point::new(1.0, 2.0, 2.0);
+        patch[10] = Point::new(2.0, 2.0, 2.0);
+        patch
+    }
+
+    #[test]
+    fn eval_patch_corners() {
+        let patch = bumpy_patch();
+        assert_eq!(eval_patch(&patch, 0.0, 0.0).0, patch[0]);
+        assert_eq!(eval_patch(&patch, 1.0, 0.0).0, patch[3]);
+        assert_eq!(eval_patch(&patch, 0.0, 1.0).0, patch[12]);
+        assert_eq!(eval_patch(&patch, 1.0, 1.0).0, patch[15]);
+    }
+
+    #[test]
+    fn eval_patch_degenerate() {
+        // All of the control points are the same.
+        let patch = vec![Point::new(1.0, 2.0, 3.0); 16];
+        for &(u, v) in &[(0.0, 0.0), (0.5, 0.5), (1.0, 0.3)] {
+            let (p, n) = eval_patch(&patch, u, v);
+            assert!((p - patch[0]).length() < 0.00001);
+            assert!((n.length() - 1.0).abs() < 0.00001);
+        }
+
+        // All of the control points are on a line.
+        let patch: Vec<_> = (0..16).map(|i| Point::new(i as f32, 0.0, 0.0)).collect();
+        let (_, n) = eval_patch(&patch, 0.0, 0.0);
+        assert!((n.length() - 1.0).abs() < 0.00001);
+    }
+
+    #[test]
+    fn eval_patch_flat() {
+        let patch = flat_patch();
+        let (p, n) = eval_patch(&patch, 0.25, 0.5);
+        assert!((p.x() - 0.75).abs() < 0.00001);
+        assert!((p.y() - 1.5).abs() < 0.00001);
+        assert_eq!(p.z(), 0.0);
+        assert!((n.z() - 1.0).abs() < 0.00001);
+    }
+
+    #[test]
+    fn eval_patch_bumpy() {
+        let patch = bumpy_patch();
+        let (p, n) = eval_patch(&patch, 0.5, 0.5);
+        // 2 * (3/4)^2 from the four raised control points
+        assert!((p.z() - 1.125).abs() < 0.00001);
+        // Top of the bump is flat
+        assert!((n.z() - 1.0).abs() < 0.00001);
+    }
+
+    #[test]
+    fn dice_rate() {
+        assert_eq!(estimate_dice_rate(&flat_patch()), 1);
+        assert!(estimate_dice_rate(&bumpy_patch()) > 8);
+    }
+}
@@ -0,0 +1,419 @@
+#![allow(dead_code)]
+
+use mem_arena::MemArena;
+
+use accel::WideBVH;
+use bbox::BBox;
+use boundable::Boundable;
+use fp_utils::fp_gamma;
+use lerp::{lerp, lerp_slice};
+use math::{Point, Matrix4x4, Transform, dot, cross};
+use ray::{Ray, AccelRay};
+use shading::SurfaceShader;
+
+use super::{Surface, SurfaceIntersection, SurfaceIntersectionData};
+use super::mesh_attribute::PrimvarValues;
+
+
+/// A mesh of bilinear patches.
+///
+/// Each patch is given by four vertices in winding order around its
+/// edge, which become the patch's (0,0), (1,0), (1,1), and (0,1) corners
+/// in uv space, respectively.  Unlike splitting a quad into two
+/// triangles, this gives a smooth and unambiguous surface for non-planar
+/// quads.
+#[derive(Copy, Clone, Debug)]
+pub struct BilinearPatchMesh<'a> {
+    time_sample_count: usize,
+    vertices: &'a [Point], // Vertices, with the time samples for each vertex stored contiguously
+    indices: &'a [(u32, u32, u32, u32, u32)], // (v00, v10, v11, v01, original_patch_idx)
+    accel: WideBVH<'a>,
+}
+
+impl<'a> BilinearPatchMesh<'a> {
+    pub fn from_verts_and_indices<'b>(
+        arena: &'b MemArena,
+        verts: Vec<Vec<Point>>,
+        patch_indices: Vec<(usize, usize, usize, usize)>,
+    ) -> BilinearPatchMesh<'b> {
+        let vert_count = verts[0].len();
+        let time_sample_count = verts.len();
+
+        // Copy verts over to a contiguous area of memory, reorganizing them
+        // so that each vertices' time samples are contiguous in memory.
+        let vertices = {
+            let vertices =
+                unsafe { arena.alloc_array_uninitialized(vert_count * time_sample_count) };
+
+            for vi in 0..vert_count {
+                for ti in 0..time_sample_count {
+                    vertices[(vi * time_sample_count) + ti] = verts[ti][vi];
+                }
+            }
+
+            vertices
+        };
+
+        // Copy patch vertex indices over, appending the patch index itself to the tuple
+        let indices = {
+            let indices = unsafe { arena.alloc_array_uninitialized(patch_indices.len()) };
+            for (i, patch_i) in patch_indices.iter().enumerate() {
+                indices[i] = (
+                    patch_i.0 as u32,
+                    patch_i.1 as u32,
+                    patch_i.2 as u32,
+                    patch_i.3 as u32,
+                    i as u32,
+                );
+            }
+            indices
+        };
+
+        // Create bounds array for use during BVH construction.  A bilinear
+        // patch is always contained within the bounds of its corners.
+        let bounds = {
+            let mut bounds = Vec::with_capacity(indices.len() * time_sample_count);
+            for patch in &patch_indices {
+                for ti in 0..time_sample_count {
+                    let p0 = verts[ti][patch.0];
+                    let p1 = verts[ti][patch.1];
+                    let p2 = verts[ti][patch.2];
+                    let p3 = verts[ti][patch.3];
+                    let minimum = p0.min(p1.min(p2.min(p3)));
+                    let maximum = p0.max(p1.max(p2.max(p3)));
+                    bounds.push(BBox::from_points(minimum, maximum));
+                }
+            }
+            bounds
+        };
+
+        // Build BVH
+        let accel = WideBVH::from_objects(arena, &mut indices[..], 3, |patch| {
+            &bounds[(patch.4 as usize * time_sample_count)..
+                        ((patch.4 as usize + 1) * time_sample_count)]
+        });
+
+        BilinearPatchMesh {
+            time_sample_count: time_sample_count,
+            vertices: vertices,
+            indices: indices,
+            accel: accel,
+        }
+    }
+
+    fn vertex(&self, index: u32, time: f32) -> Point {
+        let i = index as usize * self.time_sample_count;
+        lerp_slice(&self.vertices[i..(i + self.time_sample_count)], time)
+    }
+}
+
+impl<'a> Boundable for BilinearPatchMesh<'a> {
+    fn bounds(&self) -> &[BBox] {
+        self.accel.bounds()
+    }
+}
+
+
+impl<'a> Surface for BilinearPatchMesh<'a> {
+    fn intersect_rays<'b>(
+        &'b self,
+        accel_rays: &mut [AccelRay],
+        wrays: &[Ray],
+        isects: &mut [SurfaceIntersection<'b>],
+        shaders: &[&'b SurfaceShader],
+        space: &[Transform],
+    ) {
+        let shader = shaders[0];
+
+        // Precalculate transform for non-motion blur cases
+        let static_mat_space = if space.len() == 1 {
+            lerp_slice(space, 0.0).to_matrix()
+        } else {
+            Matrix4x4::new()
+        };
+
+        self.accel.traverse(
+            &mut accel_rays[..],
+            self.indices,
+            |patch_indices, rs| {
+                for r in rs {
+                    let wr = &wrays[r.id as usize];
+
+                    // Get patch
+                    let patch = (
+                        self.vertex(patch_indices.0, wr.time),
+                        self.vertex(patch_indices.1, wr.time),
+                        self.vertex(patch_indices.2, wr.time),
+                        self.vertex(patch_indices.3, wr.time),
+                    );
+
+                    // Transform patch as necessary, and get transform
+                    // space.
+                    let (mat_space, patch) = if !space.is_empty() {
+                        let mat_space = if space.len() > 1 {
+                            // Per-ray transform, for motion blur
+                            lerp_slice(space, wr.time).to_matrix()
+                        } else {
+                            // Same transform for all rays
+                            static_mat_space
+                        };
+                        (mat_space, (
+                            patch.0 * mat_space,
+                            patch.1 * mat_space,
+                            patch.2 * mat_space,
+                            patch.3 * mat_space,
+                        ))
+                    } else {
+                        // No transforms
+                        (Matrix4x4::new(), patch)
+                    };
+
+                    // Test ray against patch
+                    if let Some((t, u, v)) = intersect_ray(wr, patch, r.max_t) {
+                        if r.is_occlusion() {
+                            isects[r.id as usize] = SurfaceIntersection::Occlude;
+                            r.mark_done();
+                        } else {
+                            // Calculate intersection point and error magnitudes
+                            let (pos, pos_err) = surface_point(patch, (u, v));
+
+                            // Calculate geometric surface normal from the
+                            // patch's partial derivatives.
+                            let dpdu = lerp(patch.1 - patch.0, patch.2 - patch.3, v);
+                            let normal = {
+                                let dpdv = lerp(patch.3 - patch.0, patch.2 - patch.1, u);
+                                let n = cross(dpdu, dpdv);
+                                if n.length2() > 0.0 {
+                                    n.into_normal()
+                                } else {
+                                    // Degenerate point on the patch, so
+                                    // fall back to the diagonals.
+                                    cross(patch.2 - patch.0, patch.3 - patch.1).into_normal()
+                                }
+                            };
+
+                            let intersection_data = SurfaceIntersectionData {
+                                incoming: wr.dir,
+                                t: t,
+                                pos: pos,
+                                pos_err: pos_err,
+                                nor: normal,
+                                nor_g: normal,
+                                tangent: if dpdu.length2() > 0.0 {
+                                    dpdu.normalized()
+                                } else {
+                                    (patch.2 - patch.0).normalized()
+                                },
+                                uv: (u, v),
+                                vertex_color: None,
+                                primvars: PrimvarValues::new(),
+                                local_space: mat_space,
+                                sample_pdf: 0.0,
+                            };
+
+                            // Fill in intersection data
+                            isects[r.id as usize] = SurfaceIntersection::Hit {
+                                intersection_data: intersection_data,
+                                shader: shader,
+                            };
+                            r.max_t = t;
+                        }
+                    }
+                }
+            },
+        );
+    }
+}
+
+
+/// Intersects `ray` with the bilinear patch `patch`, returning
+/// `Some((t, u, v))` for the nearest hit with t in (0, `max_t`), or `None`
+/// if no intersection.
+///
+/// The patch corners are given in the order (0,0), (1,0), (1,1), (0,1).
+///
+/// Uses the ray-patch test from "Cool Patches: A Geometric Approach to
+/// Ray/Bilinear Patch Intersections" by Reshetov, which works directly with
+/// the patch's edges and is robust for both nearly planar and strongly
+/// twisted patches.  The ray direction does not need to be normalized.
+pub fn intersect_ray(
+    ray: &Ray,
+    patch: (Point, Point, Point, Point),
+    max_t: f32,
+) -> Option<(f32, f32, f32)> {
+    let dir = ray.dir;
+
+    // Patch edges, and the normal of the patch's "average" plane.
+    let e10 = patch.1 - patch.0;
+    let e11 = patch.2 - patch.1;
+    let e00 = patch.3 - patch.0;
+    let qn = cross(e10, patch.3 - patch.2);
+
+    // Corners relative to the ray origin.
+    let q00 = patch.0 - ray.orig;
+    let q10 = patch.1 - ray.orig;
+
+    // The u coordinates of the hits are the roots of a + bu + cu^2.
+    let a = dot(cross(q00, dir), e00);
+    let c = dot(qn, dir);
+    let b = dot(cross(q10, dir), e11) - (a + c);
+
+    let discriminant = (b * b) - (4.0 * a * c);
+    if discriminant < 0.0 {
+        return None;
+    }
+    let discriminant = discriminant.sqrt();
+
+    let (u1, u2) = if c == 0.0 {
+        // The patch is a trapezoid, so the equation is linear.
+        (-a / b, -1.0)
+    } else {
+        let q = if b < 0.0 {
+            -0.5 * (b - discriminant)
+        } else {
+            -0.5 * (b + discriminant)
+        };
+        (q / c, a / q)
+    };
+
+    // For each root, intersect the ray with the line across the patch at
+    // that u coordinate.
+    let mut hit = None;
+    let mut nearest_t = max_t;
+    for &u in &[u1, u2] {
+        if !(u >= 0.0 && u <= 1.0) {
+            continue;
+        }
+
+        let pa = lerp(q00, q10, u);
+        let pb = lerp(e00, e11, u);
+        let n = cross(dir, pb);
+        let det = dot(n, n);
+        if det == 0.0 {
+            continue;
+        }
+        let n = cross(n, pa);
+        let t = dot(n, pb) / det;
+        let v = dot(n, dir) / det;
+
+        if t > 0.0 && t < nearest_t && v >= 0.0 && v <= 1.0 {
+            nearest_t = t;
+            hit = Some((t, u, v));
+        }
+    }
+
+    hit
+}
+
+/// Calculates a point on a bilinear patch's surface at the given uv
+/// coordinates.
+///
+/// Returns the point and the error magnitude of the point.
+pub fn surface_point(patch: (Point, Point, Point, Point), uv: (f32, f32)) -> (Point, f32) {
+    let (u, v) = uv;
+    let w = (
+        (1.0 - u) * (1.0 - v),
+        u * (1.0 - v),
+        u * v,
+        (1.0 - u) * v,
+    );
+
+    let pos = ((patch.0.into_vector() * w.0) + (patch.1.into_vector() * w.1) +
+                   (patch.2.into_vector() * w.2) + (patch.3.into_vector() * w.3))
+        .into_point();
+
+    let pos_err = (((patch.0.into_vector().abs() * w.0) + (patch.1.into_vector().abs() * w.1) +
+                        (patch.2.into_vector().abs() * w.2) +
+                        (patch.3.into_vector().abs() * w.3)) * fp_gamma(9)).co
+        .h_max();
+
+    (pos, pos_err)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use math::Vector;
+
+    fn ray(orig: (f32, f32, f32), dir: (f32, f32, f32)) -> Ray {
+        Ray::new(
+            Point::new(orig.0, orig.1, orig.2),
+            Vector::new(dir.0, dir.1, dir.2),
+            0.0,
+            0.0,
+            false,
+        )
+    }
+
+    fn planar_patch() -> (Point, Point, Point, Point) {
+        (
+            Point::new(0.0, 0.0, 0.0),
+            Point::new(2.0, 0.0, 0.0),
+            Point::new(2.0, 2.0, 0.0),
+            Point::new(0.0, 2.0, 0.0),
+        )
+    }
+
+    #[test]
+    fn intersect_ray_planar() {
+        let r = ray((0.5, 1.5, 2.0), (0.0, 0.0, -2.0));
+        let (t, u, v) = intersect_ray(&r, planar_patch(), 100.0).unwrap();
+        assert!((t - 1.0).abs() < 0.00001);
+        assert!((u - 0.25).abs() < 0.00001);
+        assert!((v - 0.75).abs() < 0.00001);
+
+        // Limited by max_t
+        assert_eq!(intersect_ray(&r, planar_patch(), 0.5), None);
+
+        // Missing
+        let r = ray((2.5, 1.0, 2.0), (0.0, 0.0, -1.0));
+        assert_eq!(intersect_ray(&r, planar_patch(), 100.0), None);
+    }
+
+    #[test]
+    fn intersect_ray_twisted() {
+        // Hyperbolic paraboloid z = (2u - 1)(2v - 1) over the unit square.
+        let patch = (
+            Point::new(0.0, 0.0, 1.0),
+            Point::new(1.0, 0.0, -1.0),
+            Point::new(1.0, 1.0, 1.0),
+            Point::new(0.0, 1.0, -1.0),
+        );
+        for &(x, y) in &[(0.5, 0.5), (0.1, 0.8), (0.9, 0.3), (0.25, 0.25)] {
+            let r = ray((x, y, 5.0), (0.0, 0.0, -1.0));
+            let (t, u, v) = intersect_ray(&r, patch, 100.0).unwrap();
+            let z = ((2.0 * x) - 1.0) * ((2.0 * y) - 1.0);
+            assert!((u - x).abs() < 0.0001);
+            assert!((v - y).abs() < 0.0001);
+            assert!((t - (5.0 - z)).abs() < 0.0001);
+
+            let (pos, _) = surface_point(patch, (u, v));
+            assert!((pos.z() - z).abs() < 0.0001);
+        }
+    }
+
+    #[test]
+    fn intersect_ray_nearest_of_two() {
+        // Same saddle as above, which is flat along u = 0.5 and v = 0.5.  A
+        // ray lying in the z = 0 plane crosses both of those lines.
+        let patch = (
+            Point::new(0.0, 0.0, 1.0),
+            Point::new(1.0, 0.0, -1.0),
+            Point::new(1.0, 1.0, 1.0),
+            Point::new(0.0, 1.0, -1.0),
+        );
+        let r = ray((-1.0, -0.3, 0.0), (1.0, 0.6, 0.0));
+        let (t, u, v) = intersect_ray(&r, patch, 100.0).unwrap();
+        assert!((t - (4.0 / 3.0)).abs() < 0.0001);
+        assert!((u - (1.0 / 3.0)).abs() < 0.0001);
+        assert!((v - 0.5).abs() < 0.0001);
+
+        // And the farther hit is found when the nearer one is excluded.
+        let r = ray((-1.0 + (1.4 * 1.0), -0.3 + (1.4 * 0.6), 0.0), (1.0, 0.6, 0.0));
+        let (t, u, v) = intersect_ray(&r, patch, 100.0).unwrap();
+        assert!((t - 0.1).abs() < 0.0001);
+        assert!((u - 0.5).abs() < 0.0001);
+        assert!((v - 0.6).abs() < 0.0001);
+    }
+}
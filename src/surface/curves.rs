@@ -0,0 +1,630 @@
+#![allow(dead_code)]
+
+use mem_arena::MemArena;
+
+use accel::WideBVH;
+use bbox::BBox;
+use boundable::Boundable;
+use fp_utils::fp_gamma;
+use lerp::{lerp, lerp_slice};
+use math::{Point, Vector, Matrix4x4, Transform, dot, cross, coordinate_system_from_vector};
+use ray::{Ray, AccelRay};
+use shading::SurfaceShader;
+
+use super::{Surface, SurfaceIntersection, SurfaceIntersectionData};
+use super::mesh_attribute::PrimvarValues;
+
+
+// Maximum number of times a curve segment is split in half during
+// intersection testing.
+const MAX_SPLIT_DEPTH: i32 = 10;
+
+
+/// How the control points of curves are interpreted.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum CurveBasis {
+    Linear, // Polylines through the control points
+    Bezier, // Cubic Bézier segments, sharing their end points
+    BSpline, // Uniform cubic B-splines
+}
+
+/// The shape of curves' cross sections.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum CurveMode {
+    Ribbon, // Flat ribbons that always face the incoming ray
+    Tube, // Round tubes
+}
+
+
+/// A set of curves with varying widths, for hair, fur, grass, etc.
+///
+/// All curves are converted to cubic Bézier segments, and the segments are
+/// what the BVH is built over.
+#[derive(Copy, Clone, Debug)]
+pub struct Curves<'a> {
+    mode: CurveMode,
+    time_sample_count: usize,
+    points: &'a [Point], // Four control points per segment, each with all its time samples
+    widths: &'a [f32], // Four width control values per segment
+    ranges: &'a [(f32, f32)], // The parameter range of each segment along its curve
+    indices: &'a [u32], // Segment indices
+    accel: WideBVH<'a>,
+}
+
+impl<'a> Curves<'a> {
+    /// Creates a new set of curves.
+    ///
+    /// `verts` has one Vec per time sample, and `widths` has one width for
+    /// each vertex.  `curve_vert_counts` is the number of vertices in each
+    /// curve, which should be valid for `basis`.
+    pub fn new<'b>(
+        arena: &'b MemArena,
+        basis: CurveBasis,
+        mode: CurveMode,
+        verts: Vec<Vec<Point>>,
+        widths: Vec<f32>,
+        curve_vert_counts: Vec<usize>,
+    ) -> Curves<'b> {
+        let time_sample_count = verts.len();
+
+        // Convert curves to Bézier segments
+        let mut points = Vec::new();
+        let mut seg_widths = Vec::new();
+        let mut ranges = Vec::new();
+        let mut first_vert = 0;
+        for count in &curve_vert_counts {
+            let vi = |i: usize| first_vert + i;
+            let (seg_count, step, matrix) = match basis {
+                CurveBasis::Linear => (count - 1, 1, &LINEAR_TO_BEZIER),
+                CurveBasis::Bezier => ((count - 1) / 3, 3, &BEZIER_TO_BEZIER),
+                CurveBasis::BSpline => (count - 3, 1, &BSPLINE_TO_BEZIER),
+            };
+
+            for si in 0..seg_count {
+                let cvs = [
+                    vi(si * step),
+                    vi((si * step) + 1).min(first_vert + count - 1),
+                    vi((si * step) + 2).min(first_vert + count - 1),
+                    vi((si * step) + 3).min(first_vert + count - 1),
+                ];
+
+                for row in matrix.iter() {
+                    for tverts in &verts {
+                        let mut p = Vector::new(0.0, 0.0, 0.0);
+                        for (i, cv) in cvs.iter().enumerate() {
+                            p = p + (tverts[*cv].into_vector() * row[i]);
+                        }
+                        points.push(p.into_point());
+                    }
+                }
+                for row in matrix.iter() {
+                    let mut w = 0.0;
+                    for (i, cv) in cvs.iter().enumerate() {
+                        w += widths[*cv] * row[i];
+                    }
+                    seg_widths.push(w);
+                }
+                ranges.push((
+                    si as f32 / seg_count as f32,
+                    (si + 1) as f32 / seg_count as f32,
+                ));
+            }
+
+            first_vert += *count;
+        }
+        let seg_count = ranges.len();
+
+        // Create bounds array for use during BVH construction
+        let bounds = {
+            let mut bounds = Vec::with_capacity(seg_count * time_sample_count);
+            for si in 0..seg_count {
+                let radius = seg_widths[(si * 4)..((si + 1) * 4)]
+                    .iter()
+                    .fold(0.0f32, |a, b| a.max(*b)) * 0.5;
+                let pad = Vector::new(radius, radius, radius);
+                for ti in 0..time_sample_count {
+                    let bb = (0..4).fold(BBox::new(), |b, i| {
+                        b | points[(((si * 4) + i) * time_sample_count) + ti]
+                    });
+                    bounds.push(BBox::from_points(bb.min - pad, bb.max + pad));
+                }
+            }
+            bounds
+        };
+
+        // Build BVH
+        let indices = {
+            let indices: Vec<u32> = (0..seg_count as u32).collect();
+            arena.copy_slice(&indices)
+        };
+        let accel = WideBVH::from_objects(arena, &mut indices[..], 3, |seg| {
+            &bounds[(*seg as usize * time_sample_count)..
+                        ((*seg as usize + 1) * time_sample_count)]
+        });
+
+        Curves {
+            mode: mode,
+            time_sample_count: time_sample_count,
+            points: arena.copy_slice(&points),
+            widths: arena.copy_slice(&seg_widths),
+            ranges: arena.copy_slice(&ranges),
+            indices: indices,
+            accel: accel,
+        }
+    }
+}
+
+impl<'a> Boundable for Curves<'a> {
+    fn bounds(&self) -> &[BBox] {
+        self.accel.bounds()
+    }
+}
+
+
+impl<'a> Surface for Curves<'a> {
+    fn intersect_rays<'b>(
+        &'b self,
+        accel_rays: &mut [AccelRay],
+        wrays: &[Ray],
+        isects: &mut [SurfaceIntersection<'b>],
+        shaders: &[&'b SurfaceShader],
+        space: &[Transform],
+    ) {
+        let shader = shaders[0];
+
+        let tsc = self.time_sample_count;
+
+        self.accel.traverse(
+            &mut accel_rays[..],
+            self.indices,
+            |seg_i, rs| {
+                let si = *seg_i as usize;
+                let widths = &self.widths[(si * 4)..((si + 1) * 4)];
+
+                for r in rs {
+                    let wr = &wrays[r.id as usize];
+
+                    // Get the transform space
+                    let xform = if space.is_empty() {
+                        Matrix4x4::new()
+                    } else {
+                        lerp_slice(space, wr.time).to_inverse_matrix()
+                    };
+
+                    // Get the control points of the segment at the ray's
+                    // time
+                    let cps = {
+                        let mut cps = [Vector::new(0.0, 0.0, 0.0); 4];
+                        for (i, cp) in cps.iter_mut().enumerate() {
+                            let start = ((si * 4) + i) * tsc;
+                            *cp = lerp_slice(&self.points[start..(start + tsc)], wr.time)
+                                .into_vector();
+                        }
+                        cps
+                    };
+
+                    // Get the ray origin and direction in local space
+                    let orig = r.orig.into_vector();
+                    let dir = wr.dir * xform;
+
+                    // Find the hit on the ray-facing ribbon
+                    let (t, u, v, hit_width) =
+                        if let Some(hit) = intersect_ribbon(orig, dir, &cps, widths, r.max_t) {
+                            hit
+                        } else {
+                            continue;
+                        };
+
+                    // Curve position and tangent at the hit
+                    let (curve_pos, tangent) = {
+                        let (p, d) = eval_bezier(&cps, u);
+                        (p, if d.length2() > 0.0 {
+                            d.normalized()
+                        } else {
+                            (cps[3] - cps[0]).normalized()
+                        })
+                    };
+
+                    // For tubes, find the hit on the surface of the tube
+                    // at the ribbon hit.
+                    let (t, local_pos, local_nor) = match self.mode {
+                        CurveMode::Ribbon => {
+                            let pos = orig + (dir * t);
+                            let nor = cross(tangent, cross(dir, tangent));
+                            (t, pos, nor)
+                        }
+
+                        CurveMode::Tube => {
+                            if let Some(t) = intersect_tube(
+                                orig,
+                                dir,
+                                curve_pos,
+                                tangent,
+                                hit_width * 0.5,
+                                r.max_t,
+                            )
+                            {
+                                let pos = orig + (dir * t);
+                                let offset = pos - curve_pos;
+                                let nor = offset - (tangent * dot(offset, tangent));
+                                (t, pos, nor)
+                            } else {
+                                continue;
+                            }
+                        }
+                    };
+
+                    // We hit the curve, so calculate intersection info.
+                    if r.is_occlusion() {
+                        isects[r.id as usize] = SurfaceIntersection::Occlude;
+                        r.mark_done();
+                    } else {
+                        let inv_xform = xform.inverse();
+
+                        let pos = local_pos.into_point() * inv_xform;
+
+                        // The ribbons and tubes only approximate the true
+                        // curve surface, so the error is relative to the
+                        // curve's width.
+                        let pos_err = {
+                            let err = hit_width * 2.0;
+                            let xerr = (Vector::new(err, 0.0, 0.0) * inv_xform).abs();
+                            let yerr = (Vector::new(0.0, err, 0.0) * inv_xform).abs();
+                            let zerr = (Vector::new(0.0, 0.0, err) * inv_xform).abs();
+                            let p = pos.into_vector().abs();
+                            (xerr + yerr + zerr).co.h_max() +
+                                (p.x().max(p.y()).max(p.z()) * fp_gamma(3))
+                        };
+
+                        let normal = if local_nor.length2() > 0.0 {
+                            local_nor.into_normal() * inv_xform
+                        } else {
+                            (-dir).into_normal() * inv_xform
+                        };
+
+                        let uv = {
+                            let range = self.ranges[si];
+                            (lerp(range.0, range.1, u), v)
+                        };
+
+                        let intersection_data = SurfaceIntersectionData {
+                            incoming: wr.dir,
+                            t: t,
+                            pos: pos,
+                            pos_err: pos_err,
+                            nor: normal,
+                            nor_g: normal,
+                            tangent: (tangent * inv_xform).normalized(),
+                            uv: uv,
+                            vertex_color: None,
+                            primvars: PrimvarValues::new(),
+                            local_space: xform,
+                            sample_pdf: 0.0,
+                        };
+
+                        // Fill in intersection
+                        isects[r.id as usize] = SurfaceIntersection::Hit {
+                            intersection_data: intersection_data,
+                            shader: shader,
+                        };
+
+                        // Set ray's max t
+                        r.max_t = t;
+                    }
+                }
+            },
+        );
+    }
+}
+
+
+// Basis conversion matrices to cubic Bézier control points.
+const LINEAR_TO_BEZIER: [[f32; 4]; 4] = [
+    [1.0, 0.0, 0.0, 0.0],
+    [2.0 / 3.0, 1.0 / 3.0, 0.0, 0.0],
+    [1.0 / 3.0, 2.0 / 3.0, 0.0, 0.0],
+    [0.0, 1.0, 0.0, 0.0],
+];
+const BEZIER_TO_BEZIER: [[f32; 4]; 4] = [
+    [1.0, 0.0, 0.0, 0.0],
+    [0.0, 1.0, 0.0, 0.0],
+    [0.0, 0.0, 1.0, 0.0],
+    [0.0, 0.0, 0.0, 1.0],
+];
+const BSPLINE_TO_BEZIER: [[f32; 4]; 4] = [
+    [1.0 / 6.0, 4.0 / 6.0, 1.0 / 6.0, 0.0],
+    [0.0, 4.0 / 6.0, 2.0 / 6.0, 0.0],
+    [0.0, 2.0 / 6.0, 4.0 / 6.0, 0.0],
+    [0.0, 1.0 / 6.0, 4.0 / 6.0, 1.0 / 6.0],
+];
+
+
+/// Evaluates a cubic Bézier curve and its derivative at `t`.
+fn eval_bezier(cps: &[Vector; 4], t: f32) -> (Vector, Vector) {
+    let a = [lerp(cps[0], cps[1], t), lerp(cps[1], cps[2], t), lerp(cps[2], cps[3], t)];
+    let b = [lerp(a[0], a[1], t), lerp(a[1], a[2], t)];
+    (lerp(b[0], b[1], t), (b[1] - b[0]) * 3.0)
+}
+
+fn eval_bezier_f32(cps: &[f32], t: f32) -> f32 {
+    let s = 1.0 - t;
+    (cps[0] * s * s * s) + (cps[1] * 3.0 * t * s * s) + (cps[2] * 3.0 * t * t * s) +
+        (cps[3] * t * t * t)
+}
+
+/// Splits a cubic Bézier curve in half.
+fn split_bezier(cps: &[Vector; 4]) -> ([Vector; 4], [Vector; 4]) {
+    let a = [
+        (cps[0] + cps[1]) * 0.5,
+        (cps[1] + cps[2]) * 0.5,
+        (cps[2] + cps[3]) * 0.5,
+    ];
+    let b = [(a[0] + a[1]) * 0.5, (a[1] + a[2]) * 0.5];
+    let c = (b[0] + b[1]) * 0.5;
+    ([cps[0], a[0], b[0], c], [c, b[1], a[2], cps[3]])
+}
+
+/// Intersects a ray with a ray-facing ribbon along a cubic Bézier curve
+/// segment, returning `Some((t, u, v, width))` for the nearest hit with t in
+/// (0, `max_t`).
+///
+/// `u` is the curve parameter at the hit, `v` is the position across the
+/// ribbon, and `width` is the curve's width at the hit.
+///
+/// Uses the recursive subdivision approach of "Physically Based Rendering"
+/// 3rd edition by Pharr et al., which in turn is based on "Ray Tracing for
+/// Curves Primitive" by Nakamaru and Ohno.
+pub fn intersect_ribbon(
+    orig: Vector,
+    dir: Vector,
+    cps: &[Vector; 4],
+    widths: &[f32],
+    max_t: f32,
+) -> Option<(f32, f32, f32, f32)> {
+    let dir_len2 = dir.length2();
+    if dir_len2 == 0.0 {
+        return None;
+    }
+
+    // Transform the control points into a space where the ray starts at
+    // the origin and runs along +z, with z measured in ray t.
+    let (_, x, y) = coordinate_system_from_vector(dir.normalized());
+    let (x, y) = (x.normalized(), y.normalized());
+    let to_ray_space = |p: Vector| {
+        let p = p - orig;
+        Vector::new(dot(p, x), dot(p, y), dot(p, dir) / dir_len2)
+    };
+    let rcps = [
+        to_ray_space(cps[0]),
+        to_ray_space(cps[1]),
+        to_ray_space(cps[2]),
+        to_ray_space(cps[3]),
+    ];
+
+    // Figure out how many times to split the curve so it's approximately
+    // straight at the finest level.
+    let max_depth = {
+        let mut l0: f32 = 0.0;
+        for i in 0..2 {
+            let d = rcps[i] - (rcps[i + 1] * 2.0) + rcps[i + 2];
+            l0 = l0.max(d.x().abs()).max(d.y().abs());
+        }
+        let eps = widths.iter().fold(0.0f32, |a, b| a.max(*b)) * 0.05;
+        if eps > 0.0 && l0 > 0.0 {
+            let r0 = ((1.41421356 * 6.0 * l0) / (8.0 * eps)).log2() * 0.5;
+            (r0 as i32).max(0).min(MAX_SPLIT_DEPTH)
+        } else {
+            0
+        }
+    };
+
+    recursive_intersect(&rcps, widths, (0.0, 1.0), max_depth, dir_len2.sqrt(), max_t)
+}
+
+fn recursive_intersect(
+    cps: &[Vector; 4],
+    widths: &[f32],
+    u_range: (f32, f32),
+    depth: i32,
+    dir_len: f32,
+    max_t: f32,
+) -> Option<(f32, f32, f32, f32)> {
+    // Check against the bounds of the segment
+    let max_radius = widths.iter().fold(0.0f32, |a, b| a.max(*b)) * 0.5;
+    let (minimum, maximum) = cps.iter().fold(
+        (cps[0], cps[0]),
+        |(mn, mx), p| {
+            (
+                Vector::new(mn.x().min(p.x()), mn.y().min(p.y()), mn.z().min(p.z())),
+                Vector::new(mx.x().max(p.x()), mx.y().max(p.y()), mx.z().max(p.z())),
+            )
+        },
+    );
+    let z_pad = max_radius / dir_len;
+    if (minimum.x() - max_radius) > 0.0 || (maximum.x() + max_radius) < 0.0 ||
+        (minimum.y() - max_radius) > 0.0 || (maximum.y() + max_radius) < 0.0 ||
+        (maximum.z() + z_pad) < 0.0 || (minimum.z() - z_pad) > max_t
+    {
+        return None;
+    }
+
+    if depth > 0 {
+        // Split the curve and test the halves
+        let (cps1, cps2) = split_bezier(cps);
+        let u_mid = (u_range.0 + u_range.1) * 0.5;
+        let hit1 =
+            recursive_intersect(&cps1, widths, (u_range.0, u_mid), depth - 1, dir_len, max_t);
+        let max_t = hit1.map(|h| h.0).unwrap_or(max_t);
+        let hit2 =
+            recursive_intersect(&cps2, widths, (u_mid, u_range.1), depth - 1, dir_len, max_t);
+        return hit2.or(hit1);
+    }
+
+    // Test the ray against the end caps, which are perpendicular to the
+    // curve's tangent at its ends.
+    let edge = ((cps[1].y() - cps[0].y()) * -cps[0].y()) +
+        (cps[0].x() * (cps[0].x() - cps[1].x()));
+    if edge < 0.0 {
+        return None;
+    }
+    let edge = ((cps[2].y() - cps[3].y()) * -cps[3].y()) +
+        (cps[3].x() * (cps[3].x() - cps[2].x()));
+    if edge < 0.0 {
+        return None;
+    }
+
+    // Find the closest point on the now approximately straight segment
+    let (seg_x, seg_y) = (cps[3].x() - cps[0].x(), cps[3].y() - cps[0].y());
+    let denom = (seg_x * seg_x) + (seg_y * seg_y);
+    if denom == 0.0 {
+        return None;
+    }
+    let w = ((-cps[0].x() * seg_x) + (-cps[0].y() * seg_y)) / denom;
+    let w = w.max(0.0).min(1.0);
+    let u = lerp(u_range.0, u_range.1, w).max(u_range.0).min(u_range.1);
+    let hit_width = eval_bezier_f32(widths, u);
+
+    // Check if the ray passes within the curve's width of that point
+    let (pc, dpc) = eval_bezier(cps, w);
+    let dist2 = (pc.x() * pc.x()) + (pc.y() * pc.y());
+    if dist2 > (hit_width * hit_width * 0.25) {
+        return None;
+    }
+    let t = pc.z();
+    if t <= 0.0 || t >= max_t {
+        return None;
+    }
+
+    // Figure out which side of the curve the ray passes on, for v
+    let dist = dist2.sqrt();
+    let v = if ((dpc.x() * -pc.y()) - (-pc.x() * dpc.y())) > 0.0 {
+        0.5 + (dist / hit_width)
+    } else {
+        0.5 - (dist / hit_width)
+    };
+
+    Some((t, u, v, hit_width))
+}
+
+/// Intersects a ray with an infinite cylinder, returning the nearest t in
+/// (0, `max_t`), if any.
+fn intersect_tube(
+    orig: Vector,
+    dir: Vector,
+    center: Vector,
+    axis: Vector,
+    radius: f32,
+    max_t: f32,
+) -> Option<f32> {
+    let o = orig - center;
+    let o = o - (axis * dot(o, axis));
+    let d = dir - (axis * dot(dir, axis));
+
+    let a = dot(d, d);
+    let b = 2.0 * dot(o, d);
+    let c = dot(o, o) - (radius * radius);
+    if a == 0.0 {
+        return None;
+    }
+
+    let discriminant = (b * b) - (4.0 * a * c);
+    if discriminant < 0.0 {
+        return None;
+    }
+    let discriminant = discriminant.sqrt();
+    let q = if b < 0.0 {
+        -0.5 * (b - discriminant)
+    } else {
+        -0.5 * (b + discriminant)
+    };
+
+    let mut t0 = q / a;
+    let mut t1 = if q != 0.0 { c / q } else { t0 };
+    if t0 > t1 {
+        use std::mem::swap;
+        swap(&mut t0, &mut t1);
+    }
+
+    if t0 > 0.0 && t0 < max_t {
+        Some(t0)
+    } else if t1 > 0.0 && t1 < max_t {
+        Some(t1)
+    } else {
+        None
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn straight_curve() -> [Vector; 4] {
+        [
+            Vector::new(-1.0, 0.0, 0.0),
+            Vector::new(-1.0 / 3.0, 0.0, 0.0),
+            Vector::new(1.0 / 3.0, 0.0, 0.0),
+            Vector::new(1.0, 0.0, 0.0),
+        ]
+    }
+
+    #[test]
+    fn split_bezier_matches_eval() {
+        let cps = [
+            Vector::new(0.0, 0.0, 0.0),
+            Vector::new(1.0, 2.0, 0.0),
+            Vector::new(2.0, -1.0, 1.0),
+            Vector::new(3.0, 0.0, 0.0),
+        ];
+        let (a, b) = split_bezier(&cps);
+        let p = eval_bezier(&cps, 0.25).0;
+        let pa = eval_bezier(&a, 0.5).0;
+        assert!((p - pa).length() < 0.00001);
+        let p = eval_bezier(&cps, 0.75).0;
+        let pb = eval_bezier(&b, 0.5).0;
+        assert!((p - pb).length() < 0.00001);
+    }
+
+    #[test]
+    fn intersect_ribbon_straight() {
+        let widths = [0.2, 0.2, 0.2, 0.2];
+        let orig = Vector::new(0.5, 0.05, 5.0);
+        let dir = Vector::new(0.0, 0.0, -2.0);
+        let (t, u, _, w) = intersect_ribbon(orig, dir, &straight_curve(), &widths, 100.0)
+            .unwrap();
+        assert!((t - 2.5).abs() < 0.0001);
+        assert!((u - 0.75).abs() < 0.0001);
+        assert!((w - 0.2).abs() < 0.0001);
+
+        // Outside the width
+        let orig = Vector::new(0.5, 0.15, 5.0);
+        assert!(intersect_ribbon(orig, dir, &straight_curve(), &widths, 100.0).is_none());
+
+        // Past the end
+        let orig = Vector::new(1.05, 0.0, 5.0);
+        assert!(intersect_ribbon(orig, dir, &straight_curve(), &widths, 100.0).is_none());
+    }
+
+    #[test]
+    fn intersect_ribbon_tapered() {
+        let widths = [0.4, 0.4, 0.0, 0.0];
+        let dir = Vector::new(0.0, 0.0, -1.0);
+        let orig = Vector::new(-0.9, 0.15, 5.0);
+        assert!(intersect_ribbon(orig, dir, &straight_curve(), &widths, 100.0).is_some());
+        let orig = Vector::new(0.9, 0.15, 5.0);
+        assert!(intersect_ribbon(orig, dir, &straight_curve(), &widths, 100.0).is_none());
+    }
+
+    #[test]
+    fn intersect_tube_side() {
+        let t = intersect_tube(
+            Vector::new(0.0, 0.0, 5.0),
+            Vector::new(0.0, 0.0, -1.0),
+            Vector::new(0.0, 0.0, 0.0),
+            Vector::new(1.0, 0.0, 0.0),
+            0.5,
+            100.0,
+        ).unwrap();
+        assert!((t - 4.5).abs() < 0.0001);
+    }
+}
@@ -0,0 +1,495 @@
+#![allow(dead_code)]
+
+use std::collections::HashMap;
+use std::f32::consts::PI;
+
+use hash::hash_u32;
+use math::{Point, Normal, Vector, dot, cross};
+
+use super::mesh_attribute::{AttributeList, MeshAttributes, MAX_ATTRIBUTE_SIZE};
+
+
+/// A procedural pattern that drives displacement.  All patterns return
+/// values in [-1, 1].
+#[derive(Copy, Clone, Debug)]
+pub enum DisplacementPattern {
+    Constant,
+    // A plane wave, with `frequency` cycles per unit of distance along the
+    // vector's direction.
+    Waves { frequency: Vector },
+    // Fractal gradient noise.
+    Noise { frequency: f32, octaves: u32 },
+}
+
+impl DisplacementPattern {
+    pub fn eval(&self, p: Point) -> f32 {
+        match *self {
+            DisplacementPattern::Constant => 1.0,
+
+            DisplacementPattern::Waves { frequency } => {
+                (dot(p.into_vector(), frequency) * 2.0 * PI).sin()
+            }
+
+            DisplacementPattern::Noise { frequency, octaves } => {
+                let mut sum = 0.0;
+                let mut total_amplitude = 0.0;
+                let mut amplitude = 1.0;
+                let mut p = p.into_vector() * frequency;
+                for _ in 0..octaves.max(1) {
+                    sum += gradient_noise(p) * amplitude;
+                    total_amplitude += amplitude;
+                    amplitude *= 0.5;
+                    p = p * 2.0;
+                }
+                (sum / total_amplitude).max(-1.0).min(1.0)
+            }
+        }
+    }
+}
+
+
+/// Displacement of a surface by a pattern.
+///
+/// Without a `direction` the surface is displaced along its normals
+/// (scalar displacement), and otherwise along `direction` in object space
+/// (vector displacement).
+#[derive(Copy, Clone, Debug)]
+pub struct Displacement {
+    pub pattern: DisplacementPattern,
+    pub amount: f32,
+    pub direction: Option<Vector>,
+}
+
+
+/// Dices a triangle mesh into micropolygons and displaces them.
+///
+/// Every triangle is split into `rate * rate` micro-triangles, where
+/// `rates` has the rate of each triangle.  Edges are diced at the lowest
+/// rate of the triangles sharing them, with the micro-vertices of finer
+/// triangles snapped onto the edge's dicing, and the micro-vertices along
+/// shared edges are shared as well, so the displaced surface stays
+/// watertight.  `verts` and `normals` have one Vec per time sample.  If
+/// `normals` is `None`, smooth normals are computed to displace along.  The
+/// pattern is evaluated at the positions of the first time sample, so
+/// deforming meshes carry their displacement along with them.
+///
+/// Returns the displaced vertices, shading normals recomputed from the
+/// displaced surface, the micro-triangles, and `attributes` interpolated
+/// onto the micro-triangles.
+pub fn dice_and_displace(
+    verts: &[Vec<Point>],
+    normals: Option<&[Vec<Normal>]>,
+    tri_indices: &[(usize, usize, usize)],
+    attributes: &MeshAttributes,
+    rates: &[usize],
+    displacement: &Displacement,
+) -> (Vec<Vec<Point>>, Vec<Vec<Normal>>, Vec<(usize, usize, usize)>, MeshAttributes) {
+    assert_eq!(rates.len(), tri_indices.len());
+    let base_normals = if let Some(normals) = normals {
+        normals.to_vec()
+    } else {
+        verts
+            .iter()
+            .map(|tverts| vertex_normals(tverts, tri_indices))
+            .collect()
+    };
+
+    // Find the rate each edge is diced at.
+    let edge_key = |a: usize, b: usize| if a < b { (a, b) } else { (b, a) };
+    let mut edge_rates = HashMap::new();
+    for (tri, rate) in tri_indices.iter().zip(rates.iter()) {
+        let rate = (*rate).max(1);
+        for &(a, b) in &[(tri.0, tri.1), (tri.1, tri.2), (tri.2, tri.0)] {
+            let edge_rate = edge_rates.entry(edge_key(a, b)).or_insert(rate);
+            *edge_rate = (*edge_rate).min(rate);
+        }
+    }
+
+    // Build the micro-vertices as weighted sums of the base vertices,
+    // along with the micro-triangles.
+    let mut recipes: Vec<[(usize, f32); 3]> = Vec::new();
+    let mut micro_tris = Vec::with_capacity(rates.iter().map(|r| r * r).sum());
+    let mut micro_corners = Vec::new(); // (base triangle, barycentrics of corners)
+    let mut micro_face_shaders = Vec::new();
+    let has_face_varying = attributes.has_face_varying();
+    {
+        let mut shared = HashMap::new();
+        let mut grid = Vec::new(); // (micro-vertex index, barycentrics)
+        for (ti, tri) in tri_indices.iter().enumerate() {
+            let rate = rates[ti].max(1);
+            let corners = [tri.0, tri.1, tri.2];
+
+            // Get the micro-vertex index at each grid coordinate.
+            grid.clear();
+            for j in 0..(rate + 1) {
+                for i in 0..(rate + 1 - j) {
+                    let k = rate - i - j;
+                    let steps = [k, i, j];
+                    let mut bary = [
+                        k as f32 / rate as f32,
+                        i as f32 / rate as f32,
+                        j as f32 / rate as f32,
+                    ];
+
+                    // Find out which base vertices the grid point lies
+                    // between, so that points on corners and edges can be
+                    // shared with adjacent triangles.  Points on edges are
+                    // snapped to the nearest point of the edge's dicing.
+                    let on: Vec<usize> = (0..3).filter(|n| steps[*n] > 0).collect();
+                    let key = match on.len() {
+                        1 => Some((corners[on[0]], corners[on[0]], 0, 0.0)),
+                        2 => {
+                            // Always measure along the edge from the lower
+                            // index, so both sides compute the same point.
+                            let (lo, hi) = if corners[on[0]] < corners[on[1]] {
+                                (on[0], on[1])
+                            } else {
+                                (on[1], on[0])
+                            };
+                            let edge_rate = edge_rates[&edge_key(corners[lo], corners[hi])];
+                            let s = ((steps[hi] * edge_rate) + (rate / 2)) / rate;
+                            let w = s as f32 / edge_rate as f32;
+                            bary[lo] = 1.0 - w;
+                            bary[hi] = w;
+                            if s == 0 {
+                                Some((corners[lo], corners[lo], 0, 0.0))
+                            } else if s == edge_rate {
+                                Some((corners[hi], corners[hi], 0, 0.0))
+                            } else {
+                                Some((corners[lo], corners[hi], s, w))
+                            }
+                        }
+                        _ => None,
+                    };
+
+                    let vi = if let Some((a, b, s, w)) = key {
+                        let next = recipes.len();
+                        let vi = *shared.entry((a, b, s)).or_insert(next);
+                        if vi == next {
+                            recipes.push([(a, 1.0 - w), (b, w), (b, 0.0)]);
+                        }
+                        vi
+                    } else {
+                        recipes.push([
+                            (corners[0], bary[0]),
+                            (corners[1], bary[1]),
+                            (corners[2], bary[2]),
+                        ]);
+                        recipes.len() - 1
+                    };
+                    grid.push((vi, (bary[0], bary[1], bary[2])));
+                }
+            }
+
+            // Build the micro-triangles, with the same winding as the base
+            // triangle.  Snapping points onto coarser edges collapses some
+            // of them, and those are skipped.
+            let row_start = |j: usize| (j * (rate + 1)) - ((j * j.saturating_sub(1)) / 2);
+            let at = |i: usize, j: usize| grid[row_start(j) + i];
+            let mut push_tri = |a: (usize, (f32, f32, f32)),
+                                b: (usize, (f32, f32, f32)),
+                                c: (usize, (f32, f32, f32))| {
+                if a.0 == b.0 || b.0 == c.0 || c.0 == a.0 {
+                    return;
+                }
+                micro_tris.push((a.0, b.0, c.0));
+                if has_face_varying {
+                    micro_corners.push((ti, [a.1, b.1, c.1]));
+                }
+                if !attributes.face_shader_indices.is_empty() {
+                    micro_face_shaders.push(attributes.face_shader_indices[ti]);
+                }
+            };
+            for j in 0..rate {
+                for i in 0..(rate - j) {
+                    push_tri(at(i, j), at(i + 1, j), at(i, j + 1));
+                    if (i + j + 1) < rate {
+                        push_tri(at(i + 1, j), at(i + 1, j + 1), at(i, j + 1));
+                    }
+                }
+            }
+        }
+    }
+
+    // Evaluate the displacement pattern at the micro-vertices.
+    let amounts: Vec<f32> = recipes
+        .iter()
+        .map(|recipe| {
+            let p = recipe.iter().fold(Vector::new(0.0, 0.0, 0.0), |p, &(vi, w)| {
+                p + (verts[0][vi].into_vector() * w)
+            });
+            displacement.pattern.eval(p.into_point()) * displacement.amount
+        })
+        .collect();
+
+    // Displace the micro-vertices for each time sample
+    let micro_verts: Vec<Vec<Point>> = verts
+        .iter()
+        .zip(base_normals.iter())
+        .map(|(tverts, tnormals)| {
+            recipes
+                .iter()
+                .zip(amounts.iter())
+                .map(|(recipe, amount)| {
+                    let mut p = Vector::new(0.0, 0.0, 0.0);
+                    let mut n = Vector::new(0.0, 0.0, 0.0);
+                    for &(vi, w) in recipe.iter() {
+                        p = p + (tverts[vi].into_vector() * w);
+                        n = n + (tnormals[vi].into_vector() * w);
+                    }
+                    let offset = match displacement.direction {
+                        Some(dir) => dir * *amount,
+                        None if n.length2() > 0.0 => n.normalized() * *amount,
+                        None => Vector::new(0.0, 0.0, 0.0),
+                    };
+                    (p + offset).into_point()
+                })
+                .collect()
+        })
+        .collect();
+
+    // Recompute shading normals from the displaced surface
+    let micro_normals = micro_verts
+        .iter()
+        .map(|tverts| vertex_normals(tverts, &micro_tris))
+        .collect();
+
+    // Interpolate attributes onto the micro-triangles.  Face-varying
+    // attributes get a value for every micro-triangle corner.
+    let dice_attribute = |list: &AttributeList| -> AttributeList {
+        let s = list.size;
+        let mut values = Vec::new();
+        if list.face_varying {
+            for &(ti, ref barys) in &micro_corners {
+                let c = attributes.tri_corners[ti];
+                for b in barys.iter() {
+                    for comp in 0..s {
+                        values.push(
+                            (list.values[(c.0 * s) + comp] * b.0) +
+                                (list.values[(c.1 * s) + comp] * b.1) +
+                                (list.values[(c.2 * s) + comp] * b.2),
+                        );
+                    }
+                }
+            }
+        } else {
+            for recipe in &recipes {
+                let mut value = [0.0; MAX_ATTRIBUTE_SIZE];
+                for &(vi, w) in recipe.iter() {
+                    for comp in 0..s {
+                        value[comp] += list.values[(vi * s) + comp] * w;
+                    }
+                }
+                values.extend_from_slice(&value[..s]);
+            }
+        }
+        AttributeList {
+            size: s,
+            face_varying: list.face_varying,
+            values: values,
+        }
+    };
+    let micro_attributes = MeshAttributes {
+        uvs: attributes.uvs.as_ref().map(&dice_attribute),
+        colors: attributes.colors.as_ref().map(&dice_attribute),
+        primvars: attributes.primvars.iter().map(&dice_attribute).collect(),
+        velocities: attributes.velocities.as_ref().map(&dice_attribute),
+        accelerations: attributes.accelerations.as_ref().map(&dice_attribute),
+        tri_corners: if has_face_varying {
+            (0..micro_tris.len())
+                .map(|i| (i * 3, (i * 3) + 1, (i * 3) + 2))
+                .collect()
+        } else {
+            Vec::new()
+        },
+        face_shader_indices: micro_face_shaders,
+    };
+
+    (micro_verts, micro_normals, micro_tris, micro_attributes)
+}
+
+/// Computes area-weighted vertex normals of a triangle mesh.
+fn vertex_normals(verts: &[Point], tri_indices: &[(usize, usize, usize)]) -> Vec<Normal> {
+    let mut normals = vec![Vector::new(0.0, 0.0, 0.0); verts.len()];
+    for tri in tri_indices {
+        let n = cross(verts[tri.1] - verts[tri.0], verts[tri.2] - verts[tri.0]);
+        normals[tri.0] = normals[tri.0] + n;
+        normals[tri.1] = normals[tri.1] + n;
+        normals[tri.2] = normals[tri.2] + n;
+    }
+    normals
+        .iter()
+        .map(|n| if n.length2() > 0.0 {
+            n.normalized().into_normal()
+        } else {
+            Normal::new(0.0, 0.0, 1.0)
+        })
+        .collect()
+}
+
+
+/// Gradient noise, after Ken Perlin's "Improving Noise", with a hash in
+/// place of the permutation table.  Returns values in roughly [-1, 1].
+fn gradient_noise(p: Vector) -> f32 {
+    let (fx, fy, fz) = (p.x().floor(), p.y().floor(), p.z().floor());
+    let (ix, iy, iz) = (fx as i32, fy as i32, fz as i32);
+    let (x, y, z) = (p.x() - fx, p.y() - fy, p.z() - fz);
+    let (u, v, w) = (fade(x), fade(y), fade(z));
+
+    let corner = |dx: i32, dy: i32, dz: i32| {
+        let h = hash_u32(
+            (ix.wrapping_add(dx) as u32) ^
+                hash_u32(
+                    (iy.wrapping_add(dy) as u32) ^ hash_u32(iz.wrapping_add(dz) as u32, 0),
+                    0,
+                ),
+            0,
+        );
+        grad(h, x - dx as f32, y - dy as f32, z - dz as f32)
+    };
+
+    let lerp = |a: f32, b: f32, t: f32| a + ((b - a) * t);
+    lerp(
+        lerp(
+            lerp(corner(0, 0, 0), corner(1, 0, 0), u),
+            lerp(corner(0, 1, 0), corner(1, 1, 0), u),
+            v,
+        ),
+        lerp(
+            lerp(corner(0, 0, 1), corner(1, 0, 1), u),
+            lerp(corner(0, 1, 1), corner(1, 1, 1), u),
+            v,
+        ),
+        w,
+    )
+}
+
+fn fade(t: f32) -> f32 {
+    t * t * t * ((t * ((t * 6.0) - 15.0)) + 10.0)
+}
+
+fn grad(hash: u32, x: f32, y: f32, z: f32) -> f32 {
+    let h = hash & 15;
+    let u = if h < 8 { x } else { y };
+    let v = if h < 4 {
+        y
+    } else if h == 12 || h == 14 {
+        x
+    } else {
+        z
+    };
+    (if (h & 1) == 0 { u } else { -u }) + (if (h & 2) == 0 { v } else { -v })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn quad() -> (Vec<Vec<Point>>, Vec<(usize, usize, usize)>) {
+        (
+            vec![
+                vec![
+                    Point::new(0.0, 0.0, 0.0),
+                    Point::new(1.0, 0.0, 0.0),
+                    Point::new(1.0, 1.0, 0.0),
+                    Point::new(0.0, 1.0, 0.0),
+                ],
+            ],
+            vec![(0, 1, 2), (0, 2, 3)],
+        )
+    }
+
+    #[test]
+    fn dice_shares_edge_vertices() {
+        let (verts, tris) = quad();
+        let disp = Displacement {
+            pattern: DisplacementPattern::Constant,
+            amount: 0.0,
+            direction: None,
+        };
+        let (mverts, mnors, mtris, _) =
+            dice_and_displace(&verts, None, &tris, &MeshAttributes::new(), &[4, 4], &disp);
+
+        // 15 grid points per triangle, minus the 5 on the shared diagonal.
+        assert_eq!(mverts[0].len(), 25);
+        assert_eq!(mnors[0].len(), 25);
+        assert_eq!(mtris.len(), 32);
+    }
+
+    #[test]
+    fn dice_mixed_rates_watertight() {
+        let (verts, tris) = quad();
+        let disp = Displacement {
+            pattern: DisplacementPattern::Constant,
+            amount: 0.0,
+            direction: None,
+        };
+        let (mverts, _, mtris, _) =
+            dice_and_displace(&verts, None, &tris, &MeshAttributes::new(), &[4, 2], &disp);
+
+        // Every edge is used by two micro-triangles, except for the ones
+        // along the outside of the quad.
+        let mut edges = HashMap::new();
+        for tri in &mtris {
+            for &(a, b) in &[(tri.0, tri.1), (tri.1, tri.2), (tri.2, tri.0)] {
+                *edges.entry(if a < b { (a, b) } else { (b, a) }).or_insert(0) += 1;
+            }
+        }
+        assert!(edges.values().all(|c| *c <= 2));
+        assert_eq!(edges.values().filter(|c| **c == 1).count(), 4 + 4 + 2 + 2);
+
+        // And none of the micro-triangles are degenerate.
+        for tri in &mtris {
+            let v = &mverts[0];
+            let n = cross(v[tri.1] - v[tri.0], v[tri.2] - v[tri.0]);
+            assert!(n.z() > 0.0);
+        }
+    }
+
+    #[test]
+    fn dice_keeps_winding() {
+        let (verts, tris) = quad();
+        let disp = Displacement {
+            pattern: DisplacementPattern::Constant,
+            amount: 0.0,
+            direction: None,
+        };
+        let (mverts, _, mtris, _) =
+            dice_and_displace(&verts, None, &tris, &MeshAttributes::new(), &[3, 3], &disp);
+        for tri in &mtris {
+            let v = &mverts[0];
+            let n = cross(v[tri.1] - v[tri.0], v[tri.2] - v[tri.0]);
+            assert!(n.z() > 0.0);
+        }
+    }
+
+    #[test]
+    fn constant_displacement_along_normal() {
+        let (verts, tris) = quad();
+        let disp = Displacement {
+            pattern: DisplacementPattern::Constant,
+            amount: 0.5,
+            direction: None,
+        };
+        let (mverts, mnors, _, _) =
+            dice_and_displace(&verts, None, &tris, &MeshAttributes::new(), &[2, 2], &disp);
+        for (v, n) in mverts[0].iter().zip(mnors[0].iter()) {
+            assert!((v.z() - 0.5).abs() < 0.00001);
+            assert!((n.z() - 1.0).abs() < 0.00001);
+        }
+    }
+
+    #[test]
+    fn noise_in_range() {
+        let pattern = DisplacementPattern::Noise {
+            frequency: 3.7,
+            octaves: 4,
+        };
+        for i in 0..1000 {
+            let f = i as f32 * 0.137;
+            let n = pattern.eval(Point::new(f, f * 0.5 - 3.0, -f * 0.25));
+            assert!(n >= -1.0 && n <= 1.0);
+        }
+    }
+}
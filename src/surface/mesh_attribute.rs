@@ -0,0 +1,170 @@
+#![allow(dead_code)]
+
+use mem_arena::MemArena;
+
+
+/// Maximum number of components of a single attribute.
+pub const MAX_ATTRIBUTE_SIZE: usize = 4;
+
+/// Maximum total number of primvar components a mesh can have, which is
+/// how many are carried in the intersection data.
+pub const MAX_PRIMVAR_VALUES: usize = 8;
+
+
+/// An attribute of a mesh (uvs, colors, etc.) as parsed, before it's copied
+/// into the mesh.
+#[derive(Clone, Debug)]
+pub struct AttributeList {
+    pub size: usize, // Number of components per value
+    pub face_varying: bool, // Indexed by face-vertex rather than by vertex
+    pub values: Vec<f32>,
+}
+
+/// The optional attributes of a triangle mesh.
+#[derive(Clone, Debug)]
+pub struct MeshAttributes {
+    pub uvs: Option<AttributeList>,
+    pub colors: Option<AttributeList>,
+    pub primvars: Vec<AttributeList>,
+
+    // Per-vertex velocities and accelerations, which move the vertices
+    // along a path over time.  Never face-varying.
+    pub velocities: Option<AttributeList>,
+    pub accelerations: Option<AttributeList>,
+
+    // The face-vertex index of each triangle corner, for looking up
+    // face-varying attributes.  Only needed if there are any.
+    pub tri_corners: Vec<(usize, usize, usize)>,
+
+    // The index of each triangle's shader in the list of shaders bound to
+    // the mesh's instances, if shaders are assigned per face.
+    pub face_shader_indices: Vec<usize>,
+}
+
+impl MeshAttributes {
+    pub fn new() -> MeshAttributes {
+        MeshAttributes {
+            uvs: None,
+            colors: None,
+            primvars: Vec::new(),
+            velocities: None,
+            accelerations: None,
+            tri_corners: Vec::new(),
+            face_shader_indices: Vec::new(),
+        }
+    }
+
+    pub fn has_face_varying(&self) -> bool {
+        self.uvs.iter().chain(self.colors.iter()).chain(self.primvars.iter()).any(
+            |a| a.face_varying,
+        )
+    }
+}
+
+
+/// An attribute stored in a mesh.
+#[derive(Copy, Clone, Debug)]
+pub struct MeshAttribute<'a> {
+    size: usize,
+    face_varying: bool,
+    values: &'a [f32],
+}
+
+impl<'a> MeshAttribute<'a> {
+    pub fn from_list<'b>(arena: &'b MemArena, list: &AttributeList) -> MeshAttribute<'b> {
+        assert!(list.size > 0 && list.size <= MAX_ATTRIBUTE_SIZE);
+        MeshAttribute {
+            size: list.size,
+            face_varying: list.face_varying,
+            values: arena.copy_slice(&list.values),
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Interpolates the attribute across a triangle with barycentric
+    /// coordinates `b`, given the triangle's vertex indices and face-vertex
+    /// indices.  Only the first `size()` components of the result are used.
+    pub fn interpolate(
+        &self,
+        verts: (usize, usize, usize),
+        corners: (usize, usize, usize),
+        b: (f32, f32, f32),
+    ) -> [f32; MAX_ATTRIBUTE_SIZE] {
+        let (i0, i1, i2) = if self.face_varying { corners } else { verts };
+        let s = self.size;
+        let mut result = [0.0; MAX_ATTRIBUTE_SIZE];
+        for (c, r) in result.iter_mut().enumerate().take(s) {
+            *r = (self.values[(i0 * s) + c] * b.0) + (self.values[(i1 * s) + c] * b.1) +
+                (self.values[(i2 * s) + c] * b.2);
+        }
+        result
+    }
+}
+
+
+/// Interpolated primvar values at a surface point, in the order the
+/// primvars are declared on the mesh.
+#[derive(Copy, Clone, Debug)]
+pub struct PrimvarValues {
+    values: [f32; MAX_PRIMVAR_VALUES],
+    len: usize,
+}
+
+impl PrimvarValues {
+    pub fn new() -> PrimvarValues {
+        PrimvarValues {
+            values: [0.0; MAX_PRIMVAR_VALUES],
+            len: 0,
+        }
+    }
+
+    pub fn push(&mut self, values: &[f32]) {
+        assert!(self.len + values.len() <= MAX_PRIMVAR_VALUES);
+        self.values[self.len..(self.len + values.len())].copy_from_slice(values);
+        self.len += values.len();
+    }
+
+    pub fn as_slice(&self) -> &[f32] {
+        &self.values[..self.len]
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interpolate_vertex_and_face_varying() {
+        let arena = MemArena::new();
+        let list = AttributeList {
+            size: 2,
+            face_varying: false,
+            values: vec![0.0, 0.0, 1.0, 0.0, 0.0, 1.0],
+        };
+        let attr = MeshAttribute::from_list(&arena, &list);
+        let v = attr.interpolate((0, 1, 2), (5, 5, 5), (0.5, 0.25, 0.25));
+        assert_eq!(v[0], 0.25);
+        assert_eq!(v[1], 0.25);
+
+        let list = AttributeList {
+            size: 1,
+            face_varying: true,
+            values: vec![1.0, 2.0, 3.0, 4.0],
+        };
+        let attr = MeshAttribute::from_list(&arena, &list);
+        let v = attr.interpolate((0, 0, 0), (1, 2, 3), (1.0, 0.0, 0.0));
+        assert_eq!(v[0], 2.0);
+    }
+
+    #[test]
+    fn primvar_values_push() {
+        let mut pv = PrimvarValues::new();
+        pv.push(&[1.0, 2.0]);
+        pv.push(&[3.0]);
+        assert_eq!(pv.as_slice(), &[1.0, 2.0, 3.0]);
+    }
+}
@@ -1,36 +1,59 @@
 #![allow(dead_code)]
 
+pub mod bicubic_patch;
+pub mod bilinear_patch;
+pub mod curves;
+pub mod displacement;
+pub mod mesh_attribute;
+pub mod points;
+pub mod smooth_normals;
+pub mod sphere;
+pub mod subdivision;
 pub mod triangle;
 pub mod triangle_mesh;
 
 use std::fmt::Debug;
 
 use boundable::Boundable;
-use math::{Point, Vector, Normal, Matrix4x4};
+use color::XYZ;
+use math::{Point, Vector, Normal, Matrix4x4, Transform};
 use ray::{Ray, AccelRay};
-use shading::surface_closure::SurfaceClosureUnion;
 use shading::SurfaceShader;
 
+use self::mesh_attribute::PrimvarValues;
+
 
 pub trait Surface: Boundable + Debug + Sync {
-    fn intersect_rays(
-        &self,
+    /// Intersects rays with the surface.
+    ///
+    /// `shaders` are the shaders bound to the instance being traced, of
+    /// which there is always at least one.  Surfaces use the first one,
+    /// unless they assign shaders per face.
+    ///
+    /// `space` has the instance's local-to-world transform motion samples,
+    /// decomposed so they can be interpolated without being decomposed
+    /// again for every ray.  It's empty for untransformed instances.
+    ///
+    /// Hits only record the shader to use, and are shaded later by the
+    /// renderer, once the closest hit of each ray is known.
+    fn intersect_rays<'a>(
+        &'a self,
         accel_rays: &mut [AccelRay],
         wrays: &[Ray],
-        isects: &mut [SurfaceIntersection],
-        shader: &SurfaceShader,
-        space: &[Matrix4x4],
+        isects: &mut [SurfaceIntersection<'a>],
+        shaders: &[&'a SurfaceShader],
+        space: &[Transform],
     );
 }
 
 
 #[derive(Debug, Copy, Clone)]
-pub enum SurfaceIntersection {
+pub enum SurfaceIntersection<'a> {
     Miss,
     Occlude,
     Hit {
         intersection_data: SurfaceIntersectionData,
-        closure: SurfaceClosureUnion,
+        shader: &'a SurfaceShader,
     },
 }
 
@@ -43,8 +66,11 @@ pub struct SurfaceIntersectionData {
     // a cube centered around `pos` with dimensions of `2 * pos_err`.
     pub nor: Normal, // Shading normal
     pub nor_g: Normal, // True geometric normal
+    pub tangent: Vector, // Unit surface tangent, e.g. along a curve or dp/du
     pub local_space: Matrix4x4, // Matrix from global space to local space
     pub t: f32, // Ray t-value at the intersection point
     pub uv: (f32, f32), // 2d surface parameters
+    pub vertex_color: Option<XYZ>, // Interpolated vertex color, if any
+    pub primvars: PrimvarValues, // Interpolated primvars, if any
     pub sample_pdf: f32, // The PDF of getting this point by explicitly sampling the surface
 }
@@ -0,0 +1,286 @@
+#![allow(dead_code)]
+
+use mem_arena::MemArena;
+
+use accel::WideBVH;
+use bbox::BBox;
+use boundable::Boundable;
+use color::XYZ;
+use fp_utils::fp_gamma;
+use lerp::lerp_slice;
+use math::{Point, Vector, Matrix4x4, Transform, dot, coordinate_system_from_vector};
+use ray::{Ray, AccelRay};
+use shading::SurfaceShader;
+
+use super::{Surface, SurfaceIntersection, SurfaceIntersectionData};
+use super::mesh_attribute::PrimvarValues;
+use super::sphere;
+
+
+/// The shape points are rendered as.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum PointMode {
+    Sphere,
+    Disk, // Flat disks that always face the incoming ray
+}
+
+
+/// A cloud of points with radii and optional colors, for particles, sand,
+/// spray, star fields, etc.
+#[derive(Copy, Clone, Debug)]
+pub struct Points<'a> {
+    mode: PointMode,
+    time_sample_count: usize,
+    positions: &'a [Point], // The time samples of each point are stored contiguously
+    radii: &'a [f32],
+    colors: Option<&'a [XYZ]>,
+    indices: &'a [u32],
+    accel: WideBVH<'a>,
+}
+
+impl<'a> Points<'a> {
+    /// Creates a new point cloud.
+    ///
+    /// `positions` has one Vec per time sample, and `radii` and `colors`
+    /// have one entry per point.
+    pub fn new<'b>(
+        arena: &'b MemArena,
+        mode: PointMode,
+        positions: Vec<Vec<Point>>,
+        radii: Vec<f32>,
+        colors: Option<Vec<XYZ>>,
+    ) -> Points<'b> {
+        let point_count = positions[0].len();
+        let time_sample_count = positions.len();
+
+        // Copy positions, with each point's time samples contiguous in
+        // memory.
+        let pos = {
+            let mut pos = Vec::with_capacity(point_count * time_sample_count);
+            for pi in 0..point_count {
+                for tpositions in &positions {
+                    pos.push(tpositions[pi]);
+                }
+            }
+            pos
+        };
+
+        // Create bounds array for use during BVH construction
+        let bounds = {
+            let mut bounds = Vec::with_capacity(point_count * time_sample_count);
+            for (pi, radius) in radii.iter().enumerate() {
+                let r = Vector::new(*radius, *radius, *radius);
+                for p in &pos[(pi * time_sample_count)..((pi + 1) * time_sample_count)] {
+                    bounds.push(BBox::from_points(*p - r, *p + r));
+                }
+            }
+            bounds
+        };
+
+        // Build BVH
+        let indices = {
+            let indices: Vec<u32> = (0..point_count as u32).collect();
+            arena.copy_slice(&indices)
+        };
+        let accel = WideBVH::from_objects(arena, &mut indices[..], 3, |pi| {
+            &bounds[(*pi as usize * time_sample_count)..
+                        ((*pi as usize + 1) * time_sample_count)]
+        });
+
+        Points {
+            mode: mode,
+            time_sample_count: time_sample_count,
+            positions: arena.copy_slice(&pos),
+            radii: arena.copy_slice(&radii),
+            colors: colors.map(|c| &arena.copy_slice(&c)[..]),
+            indices: indices,
+            accel: accel,
+        }
+    }
+}
+
+impl<'a> Boundable for Points<'a> {
+    fn bounds(&self) -> &[BBox] {
+        self.accel.bounds()
+    }
+}
+
+
+impl<'a> Surface for Points<'a> {
+    fn intersect_rays<'b>(
+        &'b self,
+        accel_rays: &mut [AccelRay],
+        wrays: &[Ray],
+        isects: &mut [SurfaceIntersection<'b>],
+        shaders: &[&'b SurfaceShader],
+        space: &[Transform],
+    ) {
+        let shader = shaders[0];
+        let tsc = self.time_sample_count;
+
+        self.accel.traverse(
+            &mut accel_rays[..],
+            self.indices,
+            |point_i, rs| {
+                let pi = *point_i as usize;
+                let radius = self.radii[pi];
+
+                for r in rs {
+                    let wr = &wrays[r.id as usize];
+
+                    // Get the transform space
+                    let xform = if space.is_empty() {
+                        Matrix4x4::new()
+                    } else {
+                        lerp_slice(space, wr.time).to_inverse_matrix()
+                    };
+
+                    // Get the ray origin and direction in local space,
+                    // relative to the point's center.
+                    let center =
+                        lerp_slice(&self.positions[(pi * tsc)..((pi + 1) * tsc)], wr.time)
+                            .into_vector();
+                    let orig = r.orig.into_vector() - center;
+                    let dir = wr.dir * xform;
+
+                    // Test ray against the point's shape
+                    let t = match self.mode {
+                        PointMode::Sphere => {
+                            if let Some(t) = sphere::intersect_ray(orig, dir, radius, r.max_t) {
+                                t
+                            } else {
+                                continue;
+                            }
+                        }
+
+                        PointMode::Disk => {
+                            if let Some(t) = intersect_disk(orig, dir, radius, r.max_t) {
+                                t
+                            } else {
+                                continue;
+                            }
+                        }
+                    };
+
+                    // We hit the point, so calculate intersection info.
+                    if r.is_occlusion() {
+                        isects[r.id as usize] = SurfaceIntersection::Occlude;
+                        r.mark_done();
+                    } else {
+                        let inv_xform = xform.inverse();
+
+                        // Position, normal, tangent, and uv relative to the
+                        // point's center.
+                        let p = orig + (dir * t);
+                        let (rel_pos, nor, tangent, uv) = match self.mode {
+                            PointMode::Sphere => {
+                                let unit_p = p / p.length();
+                                (
+                                    unit_p * radius,
+                                    unit_p,
+                                    sphere::sphere_tangent(unit_p),
+                                    sphere::sphere_uv(unit_p),
+                                )
+                            }
+
+                            PointMode::Disk => {
+                                let nor = -dir.normalized();
+                                let (_, tangent, _) = coordinate_system_from_vector(nor);
+                                (p, nor, tangent.normalized(), (p.length() / radius, 0.0))
+                            }
+                        };
+                        let local_pos = rel_pos + center;
+                        let local_err = max_abs_3(local_pos) * fp_gamma(5);
+
+                        // Bring the position and its error bounds to world
+                        // space.
+                        let pos = local_pos.into_point() * inv_xform;
+                        let pos_err = {
+                            let xerr = (Vector::new(local_err, 0.0, 0.0) * inv_xform).abs();
+                            let yerr = (Vector::new(0.0, local_err, 0.0) * inv_xform).abs();
+                            let zerr = (Vector::new(0.0, 0.0, local_err) * inv_xform).abs();
+                            max_abs_3(xerr + yerr + zerr) +
+                                (max_abs_3(pos.into_vector()) * fp_gamma(3))
+                        };
+
+                        let normal = nor.into_normal() * inv_xform;
+
+                        let intersection_data = SurfaceIntersectionData {
+                            incoming: wr.dir,
+                            t: t,
+                            pos: pos,
+                            pos_err: pos_err,
+                            nor: normal,
+                            nor_g: normal,
+                            tangent: (tangent * inv_xform).normalized(),
+                            uv: uv,
+                            vertex_color: self.colors.map(|c| c[pi]),
+                            primvars: PrimvarValues::new(),
+                            local_space: xform,
+                            sample_pdf: 0.0,
+                        };
+
+                        // Fill in intersection
+                        isects[r.id as usize] = SurfaceIntersection::Hit {
+                            intersection_data: intersection_data,
+                            shader: shader,
+                        };
+
+                        // Set ray's max t
+                        r.max_t = t;
+                    }
+                }
+            },
+        );
+    }
+}
+
+
+/// Intersects a ray with a disk of the given radius centered at the origin
+/// and facing the ray, returning its t if it's in (0, `max_t`).
+fn intersect_disk(orig: Vector, dir: Vector, radius: f32, max_t: f32) -> Option<f32> {
+    let dir_len2 = dir.length2();
+    if dir_len2 == 0.0 {
+        return None;
+    }
+
+    let t = -dot(orig, dir) / dir_len2;
+    if t <= 0.0 || t >= max_t {
+        return None;
+    }
+
+    let p = orig + (dir * t);
+    if p.length2() > (radius * radius) {
+        return None;
+    }
+
+    Some(t)
+}
+
+fn max_abs_3(v: Vector) -> f32 {
+    v.x().abs().max(v.y().abs()).max(v.z().abs())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn intersect_disk_hit_and_miss() {
+        let dir = Vector::new(0.0, 0.0, -2.0);
+        let t = intersect_disk(Vector::new(0.5, 0.0, 4.0), dir, 1.0, 100.0).unwrap();
+        assert!((t - 2.0).abs() < 0.00001);
+        assert!(intersect_disk(Vector::new(1.5, 0.0, 4.0), dir, 1.0, 100.0).is_none());
+        assert!(intersect_disk(Vector::new(0.5, 0.0, -4.0), dir, 1.0, 100.0).is_none());
+        assert!(intersect_disk(Vector::new(0.5, 0.0, 4.0), dir, 1.0, 1.0).is_none());
+    }
+
+    #[test]
+    fn intersect_disk_faces_ray() {
+        // Same distance from the center, regardless of the ray's direction.
+        let dir = Vector::new(1.0, 1.0, 0.0);
+        let t = intersect_disk(Vector::new(-3.0, -3.0, 0.5), dir, 1.0, 100.0).unwrap();
+        assert!((t - 3.0).abs() < 0.00001);
+    }
+}
@@ -0,0 +1,257 @@
+#![allow(dead_code)]
+
+use std::collections::{HashMap, HashSet};
+
+use math::{Point, Normal, Vector, dot, cross};
+
+
+/// How face normals are weighted when averaging them into vertex normals.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum NormalWeighting {
+    Area, // By the area of the faces
+    Angle, // By the angle of the faces' corners at the vertex
+}
+
+
+/// Computes smooth vertex normals for a triangle mesh.
+///
+/// Faces are only smoothed across edges where the angle between them is at
+/// most `crease_angle` (in radians) and that aren't in `hard_edges`.  To
+/// allow that, vertices are split where the surface has a crease, so the
+/// mesh is rebuilt along the way.  Where to split is decided on the first
+/// time sample, so that all time samples share the same topology.
+///
+/// Returns the original vertex index of each new vertex, the triangles
+/// using the new vertex indices, and the normals of the new vertices for
+/// each time sample.
+pub fn smooth_normals(
+    verts: &[Vec<Point>],
+    tri_indices: &[(usize, usize, usize)],
+    weighting: NormalWeighting,
+    crease_angle: f32,
+    hard_edges: &[(usize, usize)],
+) -> (Vec<usize>, Vec<(usize, usize, usize)>, Vec<Vec<Normal>>) {
+    let cos_crease = crease_angle.min(::std::f32::consts::PI).cos();
+    let hard_edges: HashSet<(usize, usize)> =
+        hard_edges.iter().map(|e| edge_key(e.0, e.1)).collect();
+
+    let tri_verts = |t: usize| [tri_indices[t].0, tri_indices[t].1, tri_indices[t].2];
+    let face_normals: Vec<Vector> = (0..tri_indices.len())
+        .map(|t| {
+            let v = tri_verts(t);
+            let n = cross(verts[0][v[1]] - verts[0][v[0]], verts[0][v[2]] - verts[0][v[0]]);
+            if n.length2() > 0.0 { n.normalized() } else { n }
+        })
+        .collect();
+
+    // Find the triangles on each edge
+    let mut edge_tris: HashMap<(usize, usize), Vec<usize>> = HashMap::new();
+    for t in 0..tri_indices.len() {
+        let v = tri_verts(t);
+        for c in 0..3 {
+            edge_tris
+                .entry(edge_key(v[c], v[(c + 1) % 3]))
+                .or_insert_with(Vec::new)
+                .push(t);
+        }
+    }
+
+    // Join the triangle corners that share a normal, i.e. the corners at
+    // the same vertex of triangles joined by a smooth edge.
+    let mut corner_sets: Vec<usize> = (0..(tri_indices.len() * 3)).collect();
+    for (edge, tris) in &edge_tris {
+        if hard_edges.contains(edge) {
+            continue;
+        }
+        for (i, t1) in tris.iter().enumerate() {
+            for t2 in &tris[(i + 1)..] {
+                let (n1, n2) = (face_normals[*t1], face_normals[*t2]);
+                let degenerate = n1.length2() == 0.0 || n2.length2() == 0.0;
+                if !degenerate && dot(n1, n2) < cos_crease {
+                    continue;
+                }
+                for v in &[edge.0, edge.1] {
+                    let c1 = corner_of(&tri_verts(*t1), *v);
+                    let c2 = corner_of(&tri_verts(*t2), *v);
+                    if let (Some(c1), Some(c2)) = (c1, c2) {
+                        join(&mut corner_sets, (t1 * 3) + c1, (t2 * 3) + c2);
+                    }
+                }
+            }
+        }
+    }
+
+    // Create a new vertex for each set of joined corners
+    let mut vert_sources = Vec::new();
+    let mut new_tris = Vec::with_capacity(tri_indices.len());
+    let mut corner_verts = Vec::with_capacity(tri_indices.len() * 3);
+    {
+        let mut set_verts = HashMap::new();
+        for t in 0..tri_indices.len() {
+            let v = tri_verts(t);
+            let mut new_v = [0; 3];
+            for c in 0..3 {
+                let set = find(&mut corner_sets, (t * 3) + c);
+                new_v[c] = *set_verts.entry(set).or_insert_with(|| {
+                    vert_sources.push(v[c]);
+                    vert_sources.len() - 1
+                });
+                corner_verts.push(new_v[c]);
+            }
+            new_tris.push((new_v[0], new_v[1], new_v[2]));
+        }
+    }
+
+    // Calculate the normals for each time sample
+    let normals = verts
+        .iter()
+        .map(|tverts| {
+            let mut normals = vec![Vector::new(0.0, 0.0, 0.0); vert_sources.len()];
+            for t in 0..tri_indices.len() {
+                let v = tri_verts(t);
+                let n = cross(tverts[v[1]] - tverts[v[0]], tverts[v[2]] - tverts[v[0]]);
+                for c in 0..3 {
+                    let weighted_n = match weighting {
+                        NormalWeighting::Area => n,
+                        NormalWeighting::Angle => {
+                            let e1 = tverts[v[(c + 1) % 3]] - tverts[v[c]];
+                            let e2 = tverts[v[(c + 2) % 3]] - tverts[v[c]];
+                            if n.length2() > 0.0 && e1.length2() > 0.0 && e2.length2() > 0.0 {
+                                let cos_angle = dot(e1.normalized(), e2.normalized());
+                                n.normalized() * cos_angle.max(-1.0).min(1.0).acos()
+                            } else {
+                                Vector::new(0.0, 0.0, 0.0)
+                            }
+                        }
+                    };
+                    let nv = corner_verts[(t * 3) + c];
+                    normals[nv] = normals[nv] + weighted_n;
+                }
+            }
+            normals
+                .iter()
+                .map(|n| if n.length2() > 0.0 {
+                    n.normalized().into_normal()
+                } else {
+                    Normal::new(0.0, 0.0, 1.0)
+                })
+                .collect()
+        })
+        .collect();
+
+    (vert_sources, new_tris, normals)
+}
+
+fn edge_key(v1: usize, v2: usize) -> (usize, usize) {
+    if v1 < v2 { (v1, v2) } else { (v2, v1) }
+}
+
+fn corner_of(tri: &[usize; 3], v: usize) -> Option<usize> {
+    tri.iter().position(|tv| *tv == v)
+}
+
+// Union-find over triangle corners.
+fn find(sets: &mut [usize], i: usize) -> usize {
+    let mut root = i;
+    while sets[root] != root {
+        root = sets[root];
+    }
+    let mut i = i;
+    while sets[i] != root {
+        let next = sets[i];
+        sets[i] = root;
+        i = next;
+    }
+    root
+}
+
+fn join(sets: &mut [usize], a: usize, b: usize) {
+    let (ra, rb) = (find(sets, a), find(sets, b));
+    if ra != rb {
+        sets[ra.max(rb)] = ra.min(rb);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f32::consts::PI;
+
+    fn cube() -> (Vec<Vec<Point>>, Vec<(usize, usize, usize)>) {
+        let verts = vec![
+            vec![
+                Point::new(-1.0, -1.0, -1.0),
+                Point::new(1.0, -1.0, -1.0),
+                Point::new(1.0, 1.0, -1.0),
+                Point::new(-1.0, 1.0, -1.0),
+                Point::new(-1.0, -1.0, 1.0),
+                Point::new(1.0, -1.0, 1.0),
+                Point::new(1.0, 1.0, 1.0),
+                Point::new(-1.0, 1.0, 1.0),
+            ],
+        ];
+        let quads = [
+            [0, 3, 2, 1],
+            [4, 5, 6, 7],
+            [0, 1, 5, 4],
+            [1, 2, 6, 5],
+            [2, 3, 7, 6],
+            [3, 0, 4, 7],
+        ];
+        let mut tris = Vec::new();
+        for q in &quads {
+            tris.push((q[0], q[1], q[2]));
+            tris.push((q[0], q[2], q[3]));
+        }
+        (verts, tris)
+    }
+
+    #[test]
+    fn fully_smooth_cube() {
+        let (verts, tris) = cube();
+        let (sources, new_tris, normals) =
+            smooth_normals(&verts, &tris, NormalWeighting::Angle, PI, &[]);
+        assert_eq!(sources.len(), 8);
+        assert_eq!(new_tris.len(), 12);
+
+        // Corner normals point diagonally outward
+        for (v, n) in sources.iter().zip(normals[0].iter()) {
+            let p = verts[0][*v].into_vector().normalized();
+            assert!(dot(p, n.into_vector()) > 0.999);
+        }
+    }
+
+    #[test]
+    fn creased_cube() {
+        let (verts, tris) = cube();
+        let (sources, _, normals) =
+            smooth_normals(&verts, &tris, NormalWeighting::Area, PI / 3.0, &[]);
+
+        // Every corner is split three ways, and the normals are flat
+        assert_eq!(sources.len(), 24);
+        for n in &normals[0] {
+            let n = n.into_vector();
+            assert!(n.x().abs().max(n.y().abs()).max(n.z().abs()) > 0.999);
+        }
+    }
+
+    #[test]
+    fn hard_edge() {
+        // Two coplanar triangles, split by a hard edge along their diagonal
+        let verts = vec![
+            vec![
+                Point::new(0.0, 0.0, 0.0),
+                Point::new(1.0, 0.0, 0.0),
+                Point::new(1.0, 1.0, 0.0),
+                Point::new(0.0, 1.0, 0.0),
+            ],
+        ];
+        let tris = vec![(0, 1, 2), (0, 2, 3)];
+        let (sources, _, _) = smooth_normals(&verts, &tris, NormalWeighting::Area, PI, &[]);
+        assert_eq!(sources.len(), 4);
+        let (sources, _, _) =
+            smooth_normals(&verts, &tris, NormalWeighting::Area, PI, &[(2, 0)]);
+        assert_eq!(sources.len(), 6);
+    }
+}
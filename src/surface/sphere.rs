@@ -0,0 +1,303 @@
+#![allow(dead_code)]
+
+use std::f32::consts::PI;
+
+use mem_arena::MemArena;
+
+use bbox::BBox;
+use boundable::Boundable;
+use fp_utils::fp_gamma;
+use lerp::lerp_slice;
+use math::{Point, Vector, Matrix4x4, Transform};
+use ray::{Ray, AccelRay};
+use shading::SurfaceShader;
+
+use super::{Surface, SurfaceIntersection, SurfaceIntersectionData};
+use super::mesh_attribute::PrimvarValues;
+
+
+/// A sphere centered at the origin of its local space.
+///
+/// The radius can be motion blurred.  Position and orientation come from
+/// the instance's transforms.
+#[derive(Copy, Clone, Debug)]
+pub struct Sphere<'a> {
+    radii: &'a [f32],
+    bounds_: &'a [BBox],
+}
+
+impl<'a> Sphere<'a> {
+    pub fn new<'b>(arena: &'b MemArena, radii: Vec<f32>) -> Sphere<'b> {
+        let bbs: Vec<_> = radii
+            .iter()
+            .map(|r| {
+                BBox {
+                    min: Point::new(-*r, -*r, -*r),
+                    max: Point::new(*r, *r, *r),
+                }
+            })
+            .collect();
+        Sphere {
+            radii: arena.copy_slice(&radii),
+            bounds_: arena.copy_slice(&bbs),
+        }
+    }
+}
+
+impl<'a> Boundable for Sphere<'a> {
+    fn bounds(&self) -> &[BBox] {
+        self.bounds_
+    }
+}
+
+
+impl<'a> Surface for Sphere<'a> {
+    fn intersect_rays<'b>(
+        &'b self,
+        accel_rays: &mut [AccelRay],
+        wrays: &[Ray],
+        isects: &mut [SurfaceIntersection<'b>],
+        shaders: &[&'b SurfaceShader],
+        space: &[Transform],
+    ) {
+        let shader = shaders[0];
+
+        for r in accel_rays.iter_mut() {
+            if r.is_done() {
+                continue;
+            }
+
+            let wr = &wrays[r.id as usize];
+
+            // Get the transform space
+            let xform = if space.is_empty() {
+                Matrix4x4::new()
+            } else {
+                lerp_slice(space, r.time).to_inverse_matrix()
+            };
+
+            // Get the radius of the sphere at the ray's time
+            let radius = lerp_slice(self.radii, r.time);
+            if radius <= 0.0 {
+                continue;
+            }
+
+            // Get the ray origin and direction in local space
+            let orig = r.orig.into_vector();
+            let dir = wr.dir * xform;
+
+            let t = if let Some(t) = intersect_ray(orig, dir, radius, r.max_t) {
+                t
+            } else {
+                continue;
+            };
+
+            // We hit the sphere, so calculate intersection info.
+            if r.is_occlusion() {
+                isects[r.id as usize] = SurfaceIntersection::Occlude;
+                r.mark_done();
+            } else {
+                let inv_xform = xform.inverse();
+
+                // Position is calculated from the local-space ray and t, and
+                // then re-projected onto the surface of the sphere, which
+                // keeps the error of the local position within gamma(5) of
+                // its magnitude.
+                let (unit_pos, local_pos) = {
+                    let p = orig + (dir * t);
+                    let unit_p = p / p.length();
+                    (unit_p, unit_p * radius)
+                };
+                let local_err = max_abs_3(local_pos) * fp_gamma(5);
+
+                // Bring the position and its error bounds to world space.
+                let pos = local_pos.into_point() * inv_xform;
+                let pos_err = {
+                    let xerr = (Vector::new(local_err, 0.0, 0.0) * inv_xform).abs();
+                    let yerr = (Vector::new(0.0, local_err, 0.0) * inv_xform).abs();
+                    let zerr = (Vector::new(0.0, 0.0, local_err) * inv_xform).abs();
+                    max_abs_3(xerr + yerr + zerr) +
+                        (max_abs_3(pos.into_vector()) * fp_gamma(3))
+                };
+
+                let normal = unit_pos.into_normal() * inv_xform;
+
+                let intersection_data = SurfaceIntersectionData {
+                    incoming: wr.dir,
+                    t: t,
+                    pos: pos,
+                    pos_err: pos_err,
+                    nor: normal,
+                    nor_g: normal,
+                    tangent: (sphere_tangent(unit_pos) * inv_xform).normalized(),
+                    uv: sphere_uv(unit_pos),
+                    vertex_color: None,
+                    primvars: PrimvarValues::new(),
+                    local_space: xform,
+                    sample_pdf: 0.0,
+                };
+
+                // Fill in intersection
+                isects[r.id as usize] = SurfaceIntersection::Hit {
+                    intersection_data: intersection_data,
+                    shader: shader,
+                };
+
+                // Set ray's max t
+                r.max_t = t;
+            }
+        }
+    }
+}
+
+
+/// Intersects a ray with a sphere of the given radius centered at the
+/// origin, returning the nearest t in (0, `max_t`), if any.
+///
+/// Computed in double precision, and with the discriminant calculated from
+/// the ray's closest approach to the center, as described in "Precision
+/// Improvements for Ray/Sphere Intersection" by Haines et al.  This keeps
+/// the intersection accurate even for small spheres that are far from the
+/// ray origin.
+pub fn intersect_ray(orig: Vector, dir: Vector, radius: f32, max_t: f32) -> Option<f32> {
+    let (ox, oy, oz) = (orig.x() as f64, orig.y() as f64, orig.z() as f64);
+    let (dx, dy, dz) = (dir.x() as f64, dir.y() as f64, dir.z() as f64);
+    let radius = radius as f64;
+
+    // Calculate quadratic coeffs
+    let a = (dx * dx) + (dy * dy) + (dz * dz);
+    let b = 2.0 * ((dx * ox) + (dy * oy) + (dz * oz));
+    let c = (ox * ox) + (oy * oy) + (oz * oz) - (radius * radius);
+    if a == 0.0 {
+        return None;
+    }
+
+    // Calculate the discriminant from the vector between the sphere center
+    // and the point of closest approach, which avoids catastrophic
+    // cancellation in b^2 - 4ac.
+    let discriminant = {
+        let s = b / (2.0 * a);
+        let (lx, ly, lz) = (ox - (dx * s), oy - (dy * s), oz - (dz * s));
+        4.0 * a * ((radius * radius) - ((lx * lx) + (ly * ly) + (lz * lz)))
+    };
+    if discriminant < 0.0 {
+        // Discriminant less than zero?  No solution => no intersection.
+        return None;
+    }
+    let discriminant = discriminant.sqrt();
+
+    // Compute a more stable form of our param t (t0 = q/a, t1 = c/q)
+    let q = if b < 0.0 {
+        -0.5 * (b - discriminant)
+    } else {
+        -0.5 * (b + discriminant)
+    };
+
+    // Get our final parametric values
+    let mut t0 = q / a;
+    let mut t1 = if q != 0.0 { c / q } else { t0 };
+
+    // Swap them so they are ordered right
+    if t0 > t1 {
+        use std::mem::swap;
+        swap(&mut t0, &mut t1);
+    }
+
+    let (t0, t1) = (t0 as f32, t1 as f32);
+    if t0 > 0.0 && t0 < max_t {
+        Some(t0)
+    } else if t1 > 0.0 && t1 < max_t {
+        Some(t1)
+    } else {
+        None
+    }
+}
+
+/// Calculates the uv coordinates of a point on the unit sphere.
+///
+/// U goes counter-clockwise around the z axis starting from +x, and v goes
+/// from the bottom pole to the top pole.
+pub fn sphere_uv(unit_pos: Vector) -> (f32, f32) {
+    let phi = {
+        let phi = unit_pos.y().atan2(unit_pos.x());
+        if phi < 0.0 { phi + (2.0 * PI) } else { phi }
+    };
+    let theta = unit_pos.z().max(-1.0).min(1.0).acos();
+
+    (phi / (2.0 * PI), 1.0 - (theta / PI))
+}
+
+/// Returns the unit tangent of a sphere along increasing u, at the given
+/// position on the unit sphere.
+pub fn sphere_tangent(unit_pos: Vector) -> Vector {
+    let tangent = Vector::new(-unit_pos.y(), unit_pos.x(), 0.0);
+    if tangent.length2() > 0.0 {
+        tangent.normalized()
+    } else {
+        // At the poles, where u is undefined.
+        Vector::new(1.0, 0.0, 0.0)
+    }
+}
+
+fn max_abs_3(v: Vector) -> f32 {
+    v.x().abs().max(v.y().abs()).max(v.z().abs())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn intersect_ray_outside() {
+        let orig = Vector::new(0.0, 0.0, -5.0);
+        let dir = Vector::new(0.0, 0.0, 2.0);
+        let t = intersect_ray(orig, dir, 1.0, 100.0).unwrap();
+        assert!((t - 2.0).abs() < 0.00001);
+
+        // Limited by max_t
+        assert_eq!(intersect_ray(orig, dir, 1.0, 1.5), None);
+
+        // Pointing away
+        assert_eq!(intersect_ray(orig, -dir, 1.0, 100.0), None);
+
+        // Missing
+        assert_eq!(
+            intersect_ray(Vector::new(1.5, 0.0, -5.0), dir, 1.0, 100.0),
+            None
+        );
+    }
+
+    #[test]
+    fn intersect_ray_inside() {
+        let orig = Vector::new(0.0, 0.0, 0.0);
+        let dir = Vector::new(1.0, 0.0, 0.0);
+        let t = intersect_ray(orig, dir, 2.0, 100.0).unwrap();
+        assert!((t - 2.0).abs() < 0.00001);
+    }
+
+    #[test]
+    fn intersect_ray_small_and_distant() {
+        let orig = Vector::new(0.0, 0.0, -10000.0);
+        let dir = Vector::new(0.0, 0.0, 1.0);
+        let t = intersect_ray(orig, dir, 0.001, 100000.0).unwrap();
+        assert!((t - 9999.999).abs() < 0.001);
+    }
+
+    #[test]
+    fn sphere_tangent_follows_u() {
+        let t = sphere_tangent(Vector::new(1.0, 0.0, 0.0));
+        assert!((t - Vector::new(0.0, 1.0, 0.0)).length() < 0.00001);
+        let t = sphere_tangent(Vector::new(0.0, 0.0, 1.0));
+        assert!((t.length() - 1.0).abs() < 0.00001);
+    }
+
+    #[test]
+    fn sphere_uv_poles_and_equator() {
+        assert_eq!(sphere_uv(Vector::new(0.0, 0.0, 1.0)).1, 1.0);
+        assert_eq!(sphere_uv(Vector::new(0.0, 0.0, -1.0)).1, 0.0);
+        let (u, v) = sphere_uv(Vector::new(0.0, -1.0, 0.0));
+        assert!((u - 0.75).abs() < 0.00001);
+        assert!((v - 0.5).abs() < 0.00001);
+    }
+}
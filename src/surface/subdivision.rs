@@ -0,0 +1,374 @@
+#![allow(dead_code)]
+
+use std::collections::HashMap;
+use std::f32;
+
+use mem_arena::MemArena;
+
+use lerp::lerp;
+use math::{Point, Vector, Normal, cross};
+
+use super::triangle_mesh::TriangleMesh;
+
+
+/// A polygon control mesh for Catmull-Clark subdivision.
+///
+/// Boundary edges are treated as infinitely sharp creases, and boundary
+/// vertices with only one adjacent face are kept as corners.  Hole faces
+/// are subdivided along with the rest of the mesh, so they still shape
+/// the surface around them, but are left out of the final tessellation.
+#[derive(Clone, Debug)]
+pub struct SubdivisionMesh {
+    pub verts: Vec<Vec<Point>>, // Vec of vecs, one for each time sample
+    pub faces: Vec<Vec<usize>>,
+    pub holes: Vec<bool>, // Whether each face is a hole
+    pub creases: HashMap<(usize, usize), f32>, // Crease sharpness of edges, by vertex indices
+}
+
+impl SubdivisionMesh {
+    pub fn new(verts: Vec<Vec<Point>>, faces: Vec<Vec<usize>>) -> SubdivisionMesh {
+        let face_count = faces.len();
+        SubdivisionMesh {
+            verts: verts,
+            faces: faces,
+            holes: vec![false; face_count],
+            creases: HashMap::new(),
+        }
+    }
+
+    /// Sets the crease sharpness of the edge between two vertices.
+    pub fn set_crease(&mut self, v1: usize, v2: usize, sharpness: f32) {
+        self.creases.insert(edge_key(v1, v2), sharpness);
+    }
+
+    /// Applies one level of Catmull-Clark subdivision, returning the
+    /// resulting mesh.
+    ///
+    /// Creases follow the semi-sharp crease rules of "Subdivision Surfaces
+    /// in Character Animation" by DeRose et al.: their sharpness drops by
+    /// one with each level, and fractional sharpness blends between the
+    /// smooth and sharp rules.
+    pub fn subdivide(&self) -> SubdivisionMesh {
+        let vert_count = self.verts[0].len();
+        let face_count = self.faces.len();
+
+        // Gather edges and their connectivity
+        let mut edge_map: HashMap<(usize, usize), usize> = HashMap::new();
+        let mut edges: Vec<(usize, usize)> = Vec::new();
+        let mut edge_faces: Vec<Vec<usize>> = Vec::new();
+        let mut face_edges: Vec<Vec<usize>> = Vec::with_capacity(face_count);
+        for (fi, face) in self.faces.iter().enumerate() {
+            let mut fedges = Vec::with_capacity(face.len());
+            for i in 0..face.len() {
+                let key = edge_key(face[i], face[(i + 1) % face.len()]);
+                let ei = *edge_map.entry(key).or_insert_with(|| {
+                    edges.push(key);
+                    edge_faces.push(Vec::new());
+                    edges.len() - 1
+                });
+                edge_faces[ei].push(fi);
+                fedges.push(ei);
+            }
+            face_edges.push(fedges);
+        }
+        let edge_count = edges.len();
+
+        // Boundary and non-manifold edges are infinitely sharp
+        let edge_sharpness: Vec<f32> = edges
+            .iter()
+            .enumerate()
+            .map(|(ei, e)| if edge_faces[ei].len() != 2 {
+                f32::INFINITY
+            } else {
+                self.creases.get(e).cloned().unwrap_or(0.0)
+            })
+            .collect();
+
+        // Gather vertex connectivity
+        let mut vert_faces = vec![Vec::new(); vert_count];
+        let mut vert_edges = vec![Vec::new(); vert_count];
+        for (fi, face) in self.faces.iter().enumerate() {
+            for vi in face {
+                vert_faces[*vi].push(fi);
+            }
+        }
+        for (ei, e) in edges.iter().enumerate() {
+            vert_edges[e.0].push(ei);
+            vert_edges[e.1].push(ei);
+        }
+
+        // Calculate the new vertex positions for each time sample.  They
+        // are laid out as the vertex points, then the edge points, and then
+        // the face points.
+        let mut new_verts = Vec::with_capacity(self.verts.len());
+        for verts in &self.verts {
+            let v = |i: usize| verts[i].into_vector();
+
+            let face_points: Vec<Vector> = self.faces
+                .iter()
+                .map(|face| {
+                    face.iter().fold(Vector::new(0.0, 0.0, 0.0), |a, i| a + v(*i)) /
+                        face.len() as f32
+                })
+                .collect();
+
+            let edge_points: Vec<Vector> = edges
+                .iter()
+                .enumerate()
+                .map(|(ei, e)| {
+                    let mid = (v(e.0) + v(e.1)) * 0.5;
+                    let sharpness = edge_sharpness[ei];
+                    if sharpness >= 1.0 {
+                        mid
+                    } else {
+                        let smooth = (v(e.0) + v(e.1) + face_points[edge_faces[ei][0]] +
+                                          face_points[edge_faces[ei][1]]) *
+                            0.25;
+                        lerp(smooth, mid, sharpness)
+                    }
+                })
+                .collect();
+
+            let vert_points: Vec<Vector> = (0..vert_count)
+                .map(|vi| {
+                    let p = v(vi);
+                    let valence = vert_edges[vi].len();
+                    if valence == 0 || vert_faces[vi].is_empty() {
+                        return p;
+                    }
+
+                    let smooth = {
+                        let n = valence as f32;
+                        let f = vert_faces[vi].iter().fold(
+                            Vector::new(0.0, 0.0, 0.0),
+                            |a, fi| a + face_points[*fi],
+                        ) / vert_faces[vi].len() as f32;
+                        let r = vert_edges[vi].iter().fold(Vector::new(0.0, 0.0, 0.0), |a, ei| {
+                            a + ((v(edges[*ei].0) + v(edges[*ei].1)) * 0.5)
+                        }) / n;
+                        (f + (r * 2.0) + (p * (n - 3.0))) / n
+                    };
+
+                    let sharp_edges: Vec<usize> = vert_edges[vi]
+                        .iter()
+                        .cloned()
+                        .filter(|ei| edge_sharpness[*ei] > 0.0)
+                        .collect();
+                    let sharpness = sharp_edges.iter().fold(
+                        0.0,
+                        |a, ei| a + edge_sharpness[*ei],
+                    ) / sharp_edges.len().max(1) as f32;
+
+                    let sharp = if sharp_edges.len() < 2 {
+                        // Dart, which uses the smooth rule
+                        return smooth;
+                    } else if sharp_edges.len() == 2 && vert_faces[vi].len() > 1 {
+                        // Crease
+                        let other = |ei: usize| if edges[ei].0 == vi {
+                            v(edges[ei].1)
+                        } else {
+                            v(edges[ei].0)
+                        };
+                        (other(sharp_edges[0]) + (p * 6.0) + other(sharp_edges[1])) * 0.125
+                    } else {
+                        // Corner
+                        p
+                    };
+
+                    if sharpness >= 1.0 {
+                        sharp
+                    } else {
+                        lerp(smooth, sharp, sharpness)
+                    }
+                })
+                .collect();
+
+            new_verts.push(
+                vert_points
+                    .into_iter()
+                    .chain(edge_points.into_iter())
+                    .chain(face_points.into_iter())
+                    .map(|p| p.into_point())
+                    .collect(),
+            );
+        }
+
+        // Split each face into quads
+        let mut new_faces = Vec::new();
+        let mut new_holes = Vec::new();
+        for (fi, face) in self.faces.iter().enumerate() {
+            let n = face.len();
+            for i in 0..n {
+                let next_edge = face_edges[fi][i];
+                let prev_edge = face_edges[fi][(i + n - 1) % n];
+                new_faces.push(vec![
+                    face[i],
+                    vert_count + next_edge,
+                    vert_count + edge_count + fi,
+                    vert_count + prev_edge,
+                ]);
+                new_holes.push(self.holes[fi]);
+            }
+        }
+
+        // Creases carry over to the child edges, one level less sharp
+        let mut new_creases = HashMap::new();
+        for (ei, e) in edges.iter().enumerate() {
+            let sharpness = edge_sharpness[ei] - 1.0;
+            if edge_faces[ei].len() == 2 && sharpness > 0.0 {
+                new_creases.insert(edge_key(e.0, vert_count + ei), sharpness);
+                new_creases.insert(edge_key(e.1, vert_count + ei), sharpness);
+            }
+        }
+
+        SubdivisionMesh {
+            verts: new_verts,
+            faces: new_faces,
+            holes: new_holes,
+            creases: new_creases,
+        }
+    }
+
+    /// Builds a smooth-shaded triangle mesh from the non-hole faces of the
+    /// mesh.
+    pub fn to_triangle_mesh<'a>(&self, arena: &'a MemArena) -> TriangleMesh<'a> {
+        let mut tri_indices = Vec::new();
+        for (fi, face) in self.faces.iter().enumerate() {
+            if !self.holes[fi] {
+                for i in 1..(face.len() - 1) {
+                    tri_indices.push((face[0], face[i], face[i + 1]));
+                }
+            }
+        }
+
+        // Area-weighted vertex normals
+        let normals = self.verts
+            .iter()
+            .map(|verts| {
+                let mut normals = vec![Vector::new(0.0, 0.0, 0.0); verts.len()];
+                for tri in &tri_indices {
+                    let n = cross(verts[tri.1] - verts[tri.0], verts[tri.2] - verts[tri.0]);
+                    normals[tri.0] = normals[tri.0] + n;
+                    normals[tri.1] = normals[tri.1] + n;
+                    normals[tri.2] = normals[tri.2] + n;
+                }
+                normals
+                    .iter()
+                    .map(|n| if n.length2() > 0.0 {
+                        n.normalized().into_normal()
+                    } else {
+                        Normal::new(0.0, 0.0, 1.0)
+                    })
+                    .collect()
+            })
+            .collect();
+
+        TriangleMesh::from_verts_and_indices(arena, self.verts.clone(), Some(normals), tri_indices)
+    }
+}
+
+fn edge_key(v1: usize, v2: usize) -> (usize, usize) {
+    if v1 < v2 { (v1, v2) } else { (v2, v1) }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cube() -> SubdivisionMesh {
+        let verts = vec![
+            vec![
+                Point::new(-1.0, -1.0, -1.0),
+                Point::new(1.0, -1.0, -1.0),
+                Point::new(1.0, 1.0, -1.0),
+                Point::new(-1.0, 1.0, -1.0),
+                Point::new(-1.0, -1.0, 1.0),
+                Point::new(1.0, -1.0, 1.0),
+                Point::new(1.0, 1.0, 1.0),
+                Point::new(-1.0, 1.0, 1.0),
+            ],
+        ];
+        let faces = vec![
+            vec![0, 3, 2, 1],
+            vec![4, 5, 6, 7],
+            vec![0, 1, 5, 4],
+            vec![1, 2, 6, 5],
+            vec![2, 3, 7, 6],
+            vec![3, 0, 4, 7],
+        ];
+        SubdivisionMesh::new(verts, faces)
+    }
+
+    fn square() -> SubdivisionMesh {
+        let verts = vec![
+            vec![
+                Point::new(0.0, 0.0, 0.0),
+                Point::new(1.0, 0.0, 0.0),
+                Point::new(1.0, 1.0, 0.0),
+                Point::new(0.0, 1.0, 0.0),
+            ],
+        ];
+        SubdivisionMesh::new(verts, vec![vec![0, 1, 2, 3]])
+    }
+
+    #[test]
+    fn subdivide_cube_counts() {
+        let mesh = cube().subdivide();
+        assert_eq!(mesh.verts[0].len(), 8 + 12 + 6);
+        assert_eq!(mesh.faces.len(), 24);
+        assert!(mesh.faces.iter().all(|f| f.len() == 4));
+    }
+
+    #[test]
+    fn subdivide_cube_shrinks() {
+        let mesh = cube().subdivide().subdivide();
+
+        // Catmull-Clark corners of a cube move inwards towards the limit
+        // surface, but nothing moves outside of the cube.
+        let corner = mesh.verts[0][6];
+        assert!(corner.x() < 1.0 && corner.x() > 0.5);
+        assert!((corner.x() - corner.y()).abs() < 0.00001);
+        assert!((corner.y() - corner.z()).abs() < 0.00001);
+        for p in &mesh.verts[0] {
+            assert!(p.x().abs() <= 1.0 && p.y().abs() <= 1.0 && p.z().abs() <= 1.0);
+        }
+    }
+
+    #[test]
+    fn subdivide_sharp_crease() {
+        let mut mesh = cube();
+        for &(a, b) in &[(4, 5), (5, 6), (6, 7), (7, 4)] {
+            mesh.set_crease(a, b, 10.0);
+        }
+        let mesh = mesh.subdivide().subdivide();
+
+        // The creased top face stays flat.
+        for face in &mesh.faces[(4 * 4)..(4 * 4 * 2)] {
+            for vi in face {
+                assert_eq!(mesh.verts[0][*vi].z(), 1.0);
+            }
+        }
+    }
+
+    #[test]
+    fn subdivide_boundary() {
+        let mesh = square().subdivide();
+
+        // The corners of an open mesh stay put, and the boundary stays
+        // straight.
+        assert_eq!(mesh.verts[0][0], Point::new(0.0, 0.0, 0.0));
+        assert_eq!(mesh.verts[0][2], Point::new(1.0, 1.0, 0.0));
+        assert_eq!(mesh.verts[0][4], Point::new(0.5, 0.0, 0.0));
+        assert_eq!(mesh.verts[0][8], Point::new(0.5, 0.5, 0.0));
+    }
+
+    #[test]
+    fn subdivide_holes() {
+        let mut mesh = cube();
+        mesh.holes[1] = true;
+        let mesh = mesh.subdivide();
+        assert_eq!(mesh.holes.iter().filter(|h| **h).count(), 4);
+        assert!(mesh.holes[4..8].iter().all(|h| *h));
+    }
+}
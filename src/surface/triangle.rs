@@ -1,6 +1,8 @@
 #![allow(dead_code)]
 
+use bbox::BBox;
 use fp_utils::fp_gamma;
+use lerp::lerp;
 use math::Point;
 use ray::Ray;
 
@@ -144,6 +146,35 @@ pub fn surface_point(tri: (Point, Point, Point), bary: (f32, f32, f32)) -> (Poin
     (pos, pos_err)
 }
 
+/// Returns the bounds of the parts of a triangle on either side of the
+/// plane at `pos` along `axis`.  Either can be empty.
+pub fn clip_bounds(tri: (Point, Point, Point), axis: usize, pos: f32) -> (BBox, BBox) {
+    let verts = [tri.0, tri.1, tri.2];
+    let (mut left, mut right) = (BBox::new(), BBox::new());
+    for i in 0..3 {
+        let v1 = verts[i];
+        let v2 = verts[(i + 1) % 3];
+        let (d1, d2) = (v1.get_n(axis), v2.get_n(axis));
+
+        if d1 <= pos {
+            left |= v1;
+        }
+        if d1 >= pos {
+            right |= v1;
+        }
+
+        // Where the edge crosses the plane, the crossing point is on both
+        // sides.
+        if (d1 < pos && d2 > pos) || (d1 > pos && d2 < pos) {
+            let t = (pos - d1) / (d2 - d1);
+            let p = lerp(v1, v2, t.max(0.0).min(1.0));
+            left |= p;
+            right |= p;
+        }
+    }
+    (left, right)
+}
+
 fn max_abs_3(a: f32, b: f32, c: f32) -> f32 {
     let a = a.abs();
     let b = b.abs();
@@ -1,26 +1,70 @@
 #![allow(dead_code)]
 
+use std::mem;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
 use mem_arena::MemArena;
 
-use accel::BVH4;
+use accel::{self, WideBVH};
 use bbox::BBox;
 use boundable::Boundable;
-use lerp::lerp_slice;
-use math::{Point, Normal, Matrix4x4, dot, cross};
+use lerp::{lerp_slice, lerp_slice_map};
+use color::XYZ;
+use math::{Point, Normal, Vector, Matrix4x4, Transform, dot, cross};
+use quantize::QuantGrid;
 use ray::{Ray, AccelRay};
 use shading::SurfaceShader;
 
 use super::{Surface, SurfaceIntersection, SurfaceIntersectionData};
+use super::mesh_attribute::{AttributeList, MeshAttributes, MeshAttribute, PrimvarValues};
 use super::triangle;
 
 
+// Whether triangle meshes quantize their vertex positions.
+static QUANTIZE_VERTICES: AtomicBool = AtomicBool::new(false);
+
+/// Sets whether triangle meshes quantize their vertex positions to 16 bits
+/// within the bounds of each mesh, trading some precision for memory.
+pub fn set_quantize_vertices(enabled: bool) {
+    QUANTIZE_VERTICES.store(enabled, Ordering::Relaxed);
+}
+
+pub fn quantize_vertices() -> bool {
+    QUANTIZE_VERTICES.load(Ordering::Relaxed)
+}
+
+// The memory used by the vertex positions of all meshes built so far, and
+// the memory they would have used without quantization, for reporting.
+static VERTEX_BYTES: AtomicUsize = AtomicUsize::new(0);
+static VERTEX_FULL_BYTES: AtomicUsize = AtomicUsize::new(0);
+
+/// Returns the bytes used by the vertex positions of all meshes built so
+/// far, and the bytes they would have used without quantization.
+pub fn vertex_memory() -> (usize, usize) {
+    (VERTEX_BYTES.load(Ordering::Relaxed), VERTEX_FULL_BYTES.load(Ordering::Relaxed))
+}
+
+
 #[derive(Copy, Clone, Debug)]
 pub struct TriangleMesh<'a> {
     time_sample_count: usize,
-    vertices: &'a [Point], // Vertices, with the time samples for each vertex stored contiguously
+    vertices: Vertices<'a>, // With the time samples for each vertex stored contiguously
     normals: Option<&'a [Normal]>, // Vertex normals, organized the same as `vertices`
+    velocities: Option<&'a [Vector]>, // Per-vertex, added on top of the time samples
+    accelerations: Option<&'a [Vector]>, // Per-vertex, added on top of the time samples
     indices: &'a [(u32, u32, u32, u32)], // (v0_idx, v1_idx, v2_idx, original_tri_idx)
-    accel: BVH4<'a>,
+    corners: &'a [(u32, u32, u32)], // Face-vertex indices, ordered like `indices`
+    uvs: Option<MeshAttribute<'a>>,
+    colors: Option<MeshAttribute<'a>>, // In XYZ
+    primvars: &'a [MeshAttribute<'a>],
+    face_shaders: &'a [u32], // Per-triangle shader indices, ordered like the original triangles
+    accel: WideBVH<'a>,
+}
+
+#[derive(Copy, Clone, Debug)]
+enum Vertices<'a> {
+    Full(&'a [Point]),
+    Quantized(&'a [[u16; 3]], QuantGrid),
 }
 
 impl<'a> TriangleMesh<'a> {
@@ -29,23 +73,82 @@ impl<'a> TriangleMesh<'a> {
         verts: Vec<Vec<Point>>,
         vert_normals: Option<Vec<Vec<Normal>>>,
         tri_indices: Vec<(usize, usize, usize)>,
+    ) -> TriangleMesh<'b> {
+        TriangleMesh::from_verts_indices_and_attributes(
+            arena,
+            verts,
+            vert_normals,
+            tri_indices,
+            MeshAttributes::new(),
+            accel::spatial_splits(),
+        )
+    }
+
+    /// Like `from_verts_and_indices()`, but also with uvs, vertex colors,
+    /// and/or primvars.
+    ///
+    /// If `spatial_splits` is true, the mesh's BVH is built with spatial
+    /// splits, which handles long, thin, and overlapping triangles much
+    /// better at the cost of some extra memory.  This is only done for
+    /// meshes that don't move.
+    pub fn from_verts_indices_and_attributes<'b>(
+        arena: &'b MemArena,
+        mut verts: Vec<Vec<Point>>,
+        vert_normals: Option<Vec<Vec<Normal>>>,
+        tri_indices: Vec<(usize, usize, usize)>,
+        attributes: MeshAttributes,
+        spatial_splits: bool,
     ) -> TriangleMesh<'b> {
         let vert_count = verts[0].len();
         let time_sample_count = verts.len();
 
+        // Quantize verts if requested, snapping them to the quantized
+        // positions so that everything below sees the same vertices as
+        // rendering does.
+        let grid = if quantize_vertices() {
+            let mut bb = BBox::new();
+            for v in verts.iter().flat_map(|tv| tv.iter()) {
+                bb |= *v;
+            }
+            let grid = QuantGrid::new(bb, 16);
+            for v in verts.iter_mut().flat_map(|tv| tv.iter_mut()) {
+                *v = grid.dequantize(grid.quantize(*v));
+            }
+            Some(grid)
+        } else {
+            None
+        };
+
         // Copy verts over to a contiguous area of memory, reorganizing them
         // so that each vertices' time samples are contiguous in memory.
         let vertices = {
-            let mut vertices =
-                unsafe { arena.alloc_array_uninitialized(vert_count * time_sample_count) };
+            let full_bytes = vert_count * time_sample_count * mem::size_of::<Point>();
+            VERTEX_FULL_BYTES.fetch_add(full_bytes, Ordering::Relaxed);
 
-            for vi in 0..vert_count {
-                for ti in 0..time_sample_count {
-                    vertices[(vi * time_sample_count) + ti] = verts[ti][vi];
+            if let Some(grid) = grid {
+                let vertices = unsafe {
+                    arena.alloc_array_uninitialized::<[u16; 3]>(vert_count * time_sample_count)
+                };
+                for vi in 0..vert_count {
+                    for ti in 0..time_sample_count {
+                        let q = grid.quantize(verts[ti][vi]);
+                        vertices[(vi * time_sample_count) + ti] =
+                            [q[0] as u16, q[1] as u16, q[2] as u16];
+                    }
+                }
+                VERTEX_BYTES.fetch_add(mem::size_of_val(vertices), Ordering::Relaxed);
+                Vertices::Quantized(vertices, grid)
+            } else {
+                let vertices =
+                    unsafe { arena.alloc_array_uninitialized(vert_count * time_sample_count) };
+                for vi in 0..vert_count {
+                    for ti in 0..time_sample_count {
+                        vertices[(vi * time_sample_count) + ti] = verts[ti][vi];
+                    }
                 }
+                VERTEX_BYTES.fetch_add(full_bytes, Ordering::Relaxed);
+                Vertices::Full(vertices)
             }
-
-            vertices
         };
 
         // Copy vertex normals, if any, organizing them the same as vertices
@@ -67,45 +170,176 @@ impl<'a> TriangleMesh<'a> {
             None => None,
         };
 
+        // Copy per-vertex velocities and accelerations, if any
+        let to_vectors = |list: &AttributeList| -> &'b [Vector] {
+            assert!(list.size == 3 && !list.face_varying);
+            let vectors: Vec<_> = list.values
+                .chunks(3)
+                .map(|v| Vector::new(v[0], v[1], v[2]))
+                .collect();
+            arena.copy_slice(&vectors)
+        };
+        let velocities = attributes.velocities.as_ref().map(&to_vectors);
+        let accelerations = attributes.accelerations.as_ref().map(&to_vectors);
+
         // Copy triangle vertex indices over, appending the triangle index itself to the tuple
-        let mut indices = {
-            let mut indices = unsafe { arena.alloc_array_uninitialized(tri_indices.len()) };
-            for (i, tri_i) in tri_indices.iter().enumerate() {
-                indices[i] = (tri_i.0 as u32, tri_i.2 as u32, tri_i.1 as u32, i as u32);
+        let indices: Vec<_> = tri_indices
+            .iter()
+            .enumerate()
+            .map(|(i, tri_i)| (tri_i.0 as u32, tri_i.2 as u32, tri_i.1 as u32, i as u32))
+            .collect();
+
+        // Copy the triangles' face-vertex indices, if there are any
+        // face-varying attributes, ordered the same as the vertex indices
+        // above.
+        let corners = if attributes.has_face_varying() {
+            let corners = unsafe { arena.alloc_array_uninitialized(tri_indices.len()) };
+            for (i, c) in attributes.tri_corners.iter().enumerate() {
+                corners[i] = (c.0 as u32, c.2 as u32, c.1 as u32);
             }
-            indices
+            &corners[..]
+        } else {
+            &[]
         };
 
-        // Create bounds array for use during BVH construction
+        // Copy attributes
+        let uvs = attributes.uvs.as_ref().map(|l| MeshAttribute::from_list(arena, l));
+        let colors = attributes.colors.as_ref().map(|l| MeshAttribute::from_list(arena, l));
+        let primvars = {
+            let primvars: Vec<_> = attributes
+                .primvars
+                .iter()
+                .map(|l| MeshAttribute::from_list(arena, l))
+                .collect();
+            arena.copy_slice(&primvars)
+        };
+
+        // Copy per-face shader indices
+        let face_shaders = {
+            let face_shaders: Vec<u32> = attributes
+                .face_shader_indices
+                .iter()
+                .map(|i| *i as u32)
+                .collect();
+            arena.copy_slice(&face_shaders)
+        };
+
+        // Create bounds array for use during BVH construction.  Moving
+        // vertices get at least two bounds samples, each padded by how far
+        // the curved path of accelerating vertices can stray from the linear
+        // interpolation between samples.
+        let bounds_sample_count = if velocities.is_some() || accelerations.is_some() {
+            time_sample_count.max(2)
+        } else {
+            time_sample_count
+        };
         let bounds = {
-            let mut bounds = Vec::with_capacity(indices.len() * time_sample_count);
+            let mut tverts = Vec::with_capacity(time_sample_count);
+            let mut bounds = Vec::with_capacity(indices.len() * bounds_sample_count);
             for tri in &tri_indices {
-                for ti in 0..time_sample_count {
-                    let p0 = verts[ti][tri.0];
-                    let p1 = verts[ti][tri.1];
-                    let p2 = verts[ti][tri.2];
-                    let minimum = p0.min(p1.min(p2));
-                    let maximum = p0.max(p1.max(p2));
-                    bounds.push(BBox::from_points(minimum, maximum));
+                for bi in 0..bounds_sample_count {
+                    let time = if bounds_sample_count > 1 {
+                        bi as f32 / (bounds_sample_count - 1) as f32
+                    } else {
+                        0.0
+                    };
+                    let mut bb = BBox::new();
+                    for vi in &[tri.0, tri.1, tri.2] {
+                        tverts.clear();
+                        tverts.extend(verts.iter().map(|tv| tv[*vi]));
+                        let p = moved_vertex(
+                            lerp_slice(&tverts, time),
+                            velocities.map(|v| v[*vi]),
+                            accelerations.map(|a| a[*vi]),
+                            time,
+                        );
+                        let pad = accelerations.map_or(
+                            Vector::new(0.0, 0.0, 0.0),
+                            |a| a[*vi].abs() * 0.125,
+                        );
+                        bb |= BBox::from_points(p - pad, p + pad);
+                    }
+                    bounds.push(bb);
                 }
             }
             bounds
         };
 
-        // Build BVH
-        let accel = BVH4::from_objects(arena, &mut indices[..], 3, |tri| {
-            &bounds[(tri.3 as usize * time_sample_count)..
-                        ((tri.3 as usize + 1) * time_sample_count)]
-        });
+        // Build BVH.  With spatial or time splits, triangles can be
+        // referenced from more than one leaf, so the indices are replaced by
+        // the BVH's references.
+        let (accel, indices) = if spatial_splits && bounds_sample_count == 1 {
+            let (accel, refs) = WideBVH::from_objects_with_spatial_splits(
+                arena,
+                &indices,
+                3,
+                |tri| bounds[tri.3 as usize],
+                |tri, axis, pos| {
+                    let tri_verts = (
+                        verts[0][tri.0 as usize],
+                        verts[0][tri.1 as usize],
+                        verts[0][tri.2 as usize],
+                    );
+                    triangle::clip_bounds(tri_verts, axis, pos)
+                },
+            );
+            (accel, &arena.copy_slice(&refs)[..])
+        } else if accel::time_splits() && bounds_sample_count > 1 {
+            let (accel, refs) = WideBVH::from_objects_with_time_splits(arena, &indices, 3, |tri| {
+                &bounds[(tri.3 as usize * bounds_sample_count)..
+                            ((tri.3 as usize + 1) * bounds_sample_count)]
+            });
+            (accel, &arena.copy_slice(&refs)[..])
+        } else {
+            let indices = arena.copy_slice(&indices);
+            let accel = WideBVH::from_objects(arena, &mut indices[..], 3, |tri| {
+                &bounds[(tri.3 as usize * bounds_sample_count)..
+                            ((tri.3 as usize + 1) * bounds_sample_count)]
+            });
+            (accel, &indices[..])
+        };
 
         TriangleMesh {
             time_sample_count: time_sample_count,
             vertices: vertices,
             normals: normals,
+            velocities: velocities,
+            accelerations: accelerations,
             indices: indices,
+            corners: corners,
+            uvs: uvs,
+            colors: colors,
+            primvars: primvars,
+            face_shaders: face_shaders,
             accel: accel,
         }
     }
+
+    /// Returns the position of vertex `vi` at `time`, given its time
+    /// samples.
+    fn vertex_at_time(&self, vi: u32, time: f32) -> Point {
+        let samples = (vi as usize * self.time_sample_count)..
+            ((vi as usize + 1) * self.time_sample_count);
+        let p = match self.vertices {
+            Vertices::Full(verts) => lerp_slice(&verts[samples], time),
+            Vertices::Quantized(verts, ref grid) => {
+                lerp_slice_map(&verts[samples], time, |q| {
+                    grid.dequantize([q[0] as u32, q[1] as u32, q[2] as u32])
+                })
+            }
+        };
+        moved_vertex(
+            p,
+            self.velocities.map(|v| v[vi as usize]),
+            self.accelerations.map(|a| a[vi as usize]),
+            time,
+        )
+    }
+
+    /// The number of shaders the mesh's instances need to bind.
+    pub fn shader_count(&self) -> usize {
+        self.face_shaders.iter().fold(0, |a, b| a.max(*b as usize + 1)).max(1)
+    }
 }
 
 impl<'a> Boundable for TriangleMesh<'a> {
@@ -115,18 +349,32 @@ impl<'a> Boundable for TriangleMesh<'a> {
 }
 
 
+/// Moves a vertex from position `p` along the path given by its velocity
+/// and acceleration, if any.
+fn moved_vertex(p: Point, vel: Option<Vector>, acc: Option<Vector>, time: f32) -> Point {
+    let mut p = p;
+    if let Some(v) = vel {
+        p = p + (v * time);
+    }
+    if let Some(a) = acc {
+        p = p + (a * (0.5 * time * time));
+    }
+    p
+}
+
+
 impl<'a> Surface for TriangleMesh<'a> {
-    fn intersect_rays(
-        &self,
+    fn intersect_rays<'b>(
+        &'b self,
         accel_rays: &mut [AccelRay],
         wrays: &[Ray],
-        isects: &mut [SurfaceIntersection],
-        shader: &SurfaceShader,
-        space: &[Matrix4x4],
+        isects: &mut [SurfaceIntersection<'b>],
+        shaders: &[&'b SurfaceShader],
+        space: &[Transform],
     ) {
         // Precalculate transform for non-motion blur cases
         let static_mat_space = if space.len() == 1 {
-            lerp_slice(space, 0.0).inverse()
+            lerp_slice(space, 0.0).to_matrix()
         } else {
             Matrix4x4::new()
         };
@@ -135,37 +383,32 @@ impl<'a> Surface for TriangleMesh<'a> {
             &mut accel_rays[..],
             self.indices,
             |tri_indices, rs| {
+                // Get the triangle's shader.  Instances that bind no
+                // shaders get just the unassigned shader, which is used for
+                // every face, and otherwise parsing makes sure all the
+                // per-face shaders are bound.
+                let shader = if self.face_shaders.is_empty() || shaders.len() == 1 {
+                    shaders[0]
+                } else {
+                    shaders[self.face_shaders[tri_indices.3 as usize] as usize]
+                };
+
                 for r in rs {
                     let wr = &wrays[r.id as usize];
 
                     // Get triangle
-                    let tri = {
-                        let p0_slice = &self.vertices[(tri_indices.0 as usize *
-                                                           self.time_sample_count)..
-                                                          ((tri_indices.0 as usize + 1) *
-                                                               self.time_sample_count)];
-                        let p1_slice = &self.vertices[(tri_indices.1 as usize *
-                                                           self.time_sample_count)..
-                                                          ((tri_indices.1 as usize + 1) *
-                                                               self.time_sample_count)];
-                        let p2_slice = &self.vertices[(tri_indices.2 as usize *
-                                                           self.time_sample_count)..
-                                                          ((tri_indices.2 as usize + 1) *
-                                                               self.time_sample_count)];
-
-                        let p0 = lerp_slice(p0_slice, wr.time);
-                        let p1 = lerp_slice(p1_slice, wr.time);
-                        let p2 = lerp_slice(p2_slice, wr.time);
-
-                        (p0, p1, p2)
-                    };
+                    let tri = (
+                        self.vertex_at_time(tri_indices.0, wr.time),
+                        self.vertex_at_time(tri_indices.1, wr.time),
+                        self.vertex_at_time(tri_indices.2, wr.time),
+                    );
 
                     // Transform triangle as necessary, and get transform
                     // space.
                     let (mat_space, tri) = if !space.is_empty() {
                         if space.len() > 1 {
                             // Per-ray transform, for motion blur
-                            let mat_space = lerp_slice(space, wr.time).inverse();
+                            let mat_space = lerp_slice(space, wr.time).to_matrix();
                             (mat_space, (
                                 tri.0 * mat_space,
                                 tri.1 * mat_space,
@@ -226,6 +469,34 @@ impl<'a> Surface for TriangleMesh<'a> {
                                     geo_normal
                                 };
 
+                                // Interpolate attributes
+                                let verts = (
+                                    tri_indices.0 as usize,
+                                    tri_indices.1 as usize,
+                                    tri_indices.2 as usize,
+                                );
+                                let corners = if self.corners.is_empty() {
+                                    (0, 0, 0)
+                                } else {
+                                    let c = self.corners[tri_indices.3 as usize];
+                                    (c.0 as usize, c.1 as usize, c.2 as usize)
+                                };
+                                let uv = if let Some(ref uvs) = self.uvs {
+                                    let uv = uvs.interpolate(verts, corners, (b0, b1, b2));
+                                    (uv[0], uv[1])
+                                } else {
+                                    (0.0, 0.0)
+                                };
+                                let vertex_color = self.colors.as_ref().map(|colors| {
+                                    let c = colors.interpolate(verts, corners, (b0, b1, b2));
+                                    XYZ::new(c[0], c[1], c[2])
+                                });
+                                let mut primvars = PrimvarValues::new();
+                                for pv in self.primvars {
+                                    let v = pv.interpolate(verts, corners, (b0, b1, b2));
+                                    primvars.push(&v[..pv.size()]);
+                                }
+
                                 let intersection_data = SurfaceIntersectionData {
                                     incoming: wr.dir,
                                     t: t,
@@ -233,7 +504,10 @@ impl<'a> Surface for TriangleMesh<'a> {
                                     pos_err: pos_err,
                                     nor: shading_normal,
                                     nor_g: geo_normal,
-                                    uv: (0.0, 0.0), // TODO
+                                    tangent: (tri.1 - tri.0).normalized(),
+                                    uv: uv,
+                                    vertex_color: vertex_color,
+                                    primvars: primvars,
                                     local_space: mat_space,
                                     sample_pdf: 0.0,
                                 };
@@ -241,11 +515,7 @@ impl<'a> Surface for TriangleMesh<'a> {
                                 // Fill in intersection data
                                 isects[r.id as usize] = SurfaceIntersection::Hit {
                                     intersection_data: intersection_data,
-                                    closure: shader.shade(
-                                        &intersection_data,
-                                        wr.time,
-                                        wr.wavelength,
-                                    ),
+                                    shader: shader,
                                 };
                                 r.max_t = t;
                             }
@@ -256,3 +526,53 @@ impl<'a> Surface for TriangleMesh<'a> {
         );
     }
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn moving_vertices_stay_in_bounds() {
+        let arena = MemArena::new();
+        let mut attributes = MeshAttributes::new();
+        attributes.velocities = Some(AttributeList {
+            size: 3,
+            face_varying: false,
+            values: vec![2.0, 0.0, 0.0, 2.0, 0.0, 0.0, 0.0, 1.0, 0.0],
+        });
+        attributes.accelerations = Some(AttributeList {
+            size: 3,
+            face_varying: false,
+            values: vec![-4.0, 0.0, 0.0, -4.0, 0.0, 0.0, 0.0, 0.0, 3.0],
+        });
+        let verts = vec![
+            Point::new(0.0, 0.0, 0.0),
+            Point::new(1.0, 0.0, 0.0),
+            Point::new(0.0, 1.0, 0.0),
+        ];
+        let mesh = TriangleMesh::from_verts_indices_and_attributes(
+            &arena,
+            vec![verts.clone()],
+            None,
+            vec![(0, 1, 2)],
+            attributes,
+            false,
+        );
+
+        for i in 0..11 {
+            let time = i as f32 / 10.0;
+            let bb = lerp_slice(mesh.bounds(), time);
+            for vi in 0..verts.len() {
+                let p = mesh.vertex_at_time(vi as u32, time);
+                assert!(bb.min.x() <= p.x() && p.x() <= bb.max.x());
+                assert!(bb.min.y() <= p.y() && p.y() <= bb.max.y());
+                assert!(bb.min.z() <= p.z() && p.z() <= bb.max.z());
+            }
+        }
+
+        // Halfway through, the first vertex has moved 2 * 0.5 - 0.5 * 4 * 0.25.
+        let p = mesh.vertex_at_time(0, 0.5);
+        assert!((p.x() - 0.5).abs() < 0.00001);
+    }
+}
@@ -1,33 +1,73 @@
 use std::iter;
 
 use algorithm::partition;
+use camera::ClipPlane;
 use lerp::lerp_slice;
+use math::dot;
 use ray::{Ray, AccelRay};
-use scene::{Assembly, Object, InstanceType};
+use scene::{Assembly, Object, Instance, InstanceType};
 use surface::SurfaceIntersection;
 use transform_stack::TransformStack;
 use shading::{SurfaceShader, SimpleSurfaceShader};
 use color::{XYZ, rec709_to_xyz};
 
 
+lazy_static! {
+    // Used for surfaces that don't have a shader assigned, in a color that
+    // stands out.
+    static ref UNASSIGNED_SHADER: SimpleSurfaceShader = SimpleSurfaceShader::Emit {
+        color: XYZ::from_tuple(rec709_to_xyz((1.0, 0.0, 1.0))),
+    };
+}
+
+
 pub struct Tracer<'a> {
     rays: Vec<AccelRay>,
+    clip_planes: &'a [ClipPlane],
+    clipped_rays: Vec<Ray>,
     inner: TracerInner<'a>,
 }
 
 impl<'a> Tracer<'a> {
-    pub fn from_assembly(assembly: &'a Assembly) -> Tracer<'a> {
+    /// Creates a tracer for the given assembly.  Geometry on the clipped side
+    /// of any of the given clip planes is ignored.
+    pub fn from_assembly(assembly: &'a Assembly, clip_planes: &'a [ClipPlane]) -> Tracer<'a> {
         Tracer {
             rays: Vec::new(),
+            clip_planes: clip_planes,
+            clipped_rays: Vec::new(),
             inner: TracerInner {
                 root: assembly,
                 xform_stack: TransformStack::new(),
                 isects: Vec::new(),
+                hit_instances: Vec::new(),
+                max_ts: Vec::new(),
             },
         }
     }
 
-    pub fn trace<'b>(&'b mut self, wrays: &[Ray]) -> &'b [SurfaceIntersection] {
+    /// Traces the rays, returning the closest hit (if any) for each of them,
+    /// along with a key identifying the object instance that was hit.
+    ///
+    /// The hits aren't shaded: that's left to the caller, so that each ray
+    /// is only shaded once.  The instance keys are only meaningful for
+    /// grouping hits, e.g. to shade hits on the same instance together.
+    pub fn trace<'b>(
+        &'b mut self,
+        wrays: &[Ray],
+    ) -> (&'b [SurfaceIntersection<'a>], &'b [usize]) {
+        // Restrict the rays to the part of the scene that isn't clipped.
+        let wrays = if self.clip_planes.is_empty() {
+            wrays
+        } else {
+            self.clipped_rays.clear();
+            let clip_planes = self.clip_planes;
+            self.clipped_rays.extend(
+                wrays.iter().map(|wr| clip_ray(wr, clip_planes)),
+            );
+            &self.clipped_rays[..]
+        };
+
         self.rays.clear();
         self.rays.reserve(wrays.len());
         let mut ids = 0..(wrays.len() as u32);
@@ -42,11 +82,17 @@ impl<'a> Tracer<'a> {
 struct TracerInner<'a> {
     root: &'a Assembly<'a>,
     xform_stack: TransformStack,
-    isects: Vec<SurfaceIntersection>,
+    isects: Vec<SurfaceIntersection<'a>>,
+    hit_instances: Vec<usize>,
+    max_ts: Vec<f32>,
 }
 
 impl<'a> TracerInner<'a> {
-    fn trace<'b>(&'b mut self, wrays: &[Ray], rays: &mut [AccelRay]) -> &'b [SurfaceIntersection] {
+    fn trace<'b>(
+        &'b mut self,
+        wrays: &[Ray],
+        rays: &mut [AccelRay],
+    ) -> (&'b [SurfaceIntersection<'a>], &'b [usize]) {
         // Ready the isects
         self.isects.clear();
         self.isects.reserve(wrays.len());
@@ -56,18 +102,20 @@ impl<'a> TracerInner<'a> {
                     .len(),
             ),
         );
+        self.hit_instances.clear();
+        self.hit_instances.extend(iter::repeat(0).take(wrays.len()));
 
         let mut ray_sets = split_rays_by_direction(&mut rays[..]);
         for ray_set in ray_sets.iter_mut().filter(|ray_set| !ray_set.is_empty()) {
             self.trace_assembly(self.root, wrays, ray_set);
         }
 
-        &self.isects
+        (&self.isects, &self.hit_instances)
     }
 
     fn trace_assembly<'b>(
         &'b mut self,
-        assembly: &Assembly,
+        assembly: &'a Assembly<'a>,
         wrays: &[Ray],
         accel_rays: &mut [AccelRay],
     ) {
@@ -87,7 +135,7 @@ impl<'a> TracerInner<'a> {
                         let t = ray.time;
                         ray.update_from_xformed_world_ray(
                             &wrays[id as usize],
-                            &lerp_slice(xforms, t),
+                            &lerp_slice(xforms, t).to_inverse_matrix(),
                         );
                     }
                 }
@@ -130,8 +178,9 @@ impl<'a> TracerInner<'a> {
                             InstanceType::Object => {
                                 self.trace_object(
                                     &assembly.objects[inst.data_index],
-                                    inst.surface_shader_index.map(
-                                        |i| assembly.surface_shaders[i],
+                                    inst,
+                                    inst.surface_shader_indices.map(
+                                        |(start, end)| &assembly.surface_shader_binds[start..end],
                                     ),
                                     wrays,
                                     ray_set,
@@ -162,7 +211,7 @@ impl<'a> TracerInner<'a> {
                             let t = ray.time;
                             ray.update_from_xformed_world_ray(
                                 &wrays[id as usize],
-                                &lerp_slice(xforms, t),
+                                &lerp_slice(xforms, t).to_inverse_matrix(),
                             );
                         }
                     } else {
@@ -178,43 +227,88 @@ impl<'a> TracerInner<'a> {
 
     fn trace_object<'b>(
         &'b mut self,
-        obj: &Object,
-        surface_shader: Option<&SurfaceShader>,
+        obj: &'a Object<'a>,
+        inst: &Instance,
+        surface_shaders: Option<&'a [&'a SurfaceShader]>,
         wrays: &[Ray],
         rays: &mut [AccelRay],
     ) {
+        // Note the rays' closest hits so far, to tell which rays hit the
+        // object.
+        self.max_ts.clear();
+        self.max_ts.extend(rays.iter().map(|r| r.max_t));
+
         match *obj {
             Object::Surface(surface) => {
-                let unassigned_shader = SimpleSurfaceShader::Emit {
-                    color: XYZ::from_tuple(rec709_to_xyz((1.0, 0.0, 1.0))),
-                };
-                let shader = surface_shader.unwrap_or(&unassigned_shader);
+                let unassigned_shaders = [&*UNASSIGNED_SHADER as &SurfaceShader];
+                let shaders = surface_shaders.unwrap_or(&unassigned_shaders);
 
                 surface.intersect_rays(
                     rays,
                     wrays,
                     &mut self.isects,
-                    shader,
+                    shaders,
                     self.xform_stack.top(),
                 );
             }
 
             Object::SurfaceLight(surface) => {
-                // Lights don't use shaders
-                let bogus_shader = SimpleSurfaceShader::Emit {
-                    color: XYZ::from_tuple(rec709_to_xyz((1.0, 0.0, 1.0))),
-                };
-
+                // Lights shade themselves, and don't use shaders
                 surface.intersect_rays(
                     rays,
                     wrays,
                     &mut self.isects,
-                    &bogus_shader,
+                    &[&*UNASSIGNED_SHADER],
                     self.xform_stack.top(),
                 );
             }
         }
+
+        // Record the instance of the rays that hit the object
+        for (r, max_t) in rays.iter().zip(self.max_ts.iter()) {
+            if r.max_t < *max_t {
+                self.hit_instances[r.id as usize] = inst as *const Instance as usize;
+            }
+        }
+    }
+}
+
+
+/// Clips a ray to the region kept by all of the given clip planes.
+///
+/// Since that region is convex, the result is a single ray segment.  Hits
+/// are reported relative to the new ray origin, but in the same parametric
+/// units as the original ray.
+fn clip_ray(ray: &Ray, clip_planes: &[ClipPlane]) -> Ray {
+    let mut t_near = 0.0f32;
+    let mut t_far = ray.max_t;
+    for plane in clip_planes {
+        let dist = plane.distance(ray.orig);
+        let rate = dot(plane.nor, ray.dir);
+        if rate == 0.0 {
+            if dist < 0.0 {
+                t_far = 0.0;
+            }
+        } else {
+            let t = -dist / rate;
+            if rate > 0.0 {
+                t_near = t_near.max(t);
+            } else {
+                t_far = t_far.min(t);
+            }
+        }
+    }
+
+    let mut clipped = *ray;
+    if t_near >= t_far {
+        clipped.max_t = 0.0;
+    } else if t_near > 0.0 {
+        clipped.orig = ray.orig + (ray.dir * t_near);
+        clipped.max_t = t_far - t_near;
+    } else {
+        clipped.max_t = t_far;
     }
+    clipped
 }
 
 
@@ -1,11 +1,9 @@
-use std::cmp;
-
-use algorithm::merge_slices_to;
-use math::Matrix4x4;
+use algorithm::{merge_slices_to, merged_len};
+use math::Transform;
 
 
 pub struct TransformStack {
-    stack: Vec<Matrix4x4>,
+    stack: Vec<Transform>,
     stack_indices: Vec<usize>,
 }
 
@@ -29,7 +27,7 @@ impl TransformStack {
         self.stack_indices.push(0);
     }
 
-    pub fn push(&mut self, xforms: &[Matrix4x4]) {
+    pub fn push(&mut self, xforms: &[Transform]) {
         assert!(!xforms.is_empty());
 
         if self.stack.is_empty() {
@@ -42,13 +40,16 @@ impl TransformStack {
             // Note this leaves exposed uninitialized memory.  The subsequent call to
             // merge_slices_to() fills that memory in.
             {
-                let maxlen = cmp::max(xforms.len(), i2 - i1);
+                let maxlen = merged_len(i2 - i1, xforms.len());
                 self.stack.reserve(maxlen);
                 let l = self.stack.len();
                 unsafe { self.stack.set_len(l + maxlen) };
             }
             let (xfs1, xfs2) = self.stack.split_at_mut(i2);
-            merge_slices_to(&xfs1[i1..i2], xforms, xfs2, |xf1, xf2| *xf1 * *xf2);
+            // The transforms are local-to-world, so the child's transforms
+            // come first.  They're composed directly, since decomposing the
+            // merged matrices again would be far slower.
+            merge_slices_to(&xfs1[i1..i2], xforms, xfs2, |xf1, xf2| *xf2 * *xf1);
         }
 
         self.stack_indices.push(self.stack.len());
@@ -66,7 +67,7 @@ impl TransformStack {
         self.stack_indices.pop();
     }
 
-    pub fn top(&self) -> &[Matrix4x4] {
+    pub fn top(&self) -> &[Transform] {
         let sil = self.stack_indices.len();
         let i1 = self.stack_indices[sil - 2];
         let i2 = self.stack_indices[sil - 1];
@@ -102,4 +102,81 @@ fn main() {
     }
 
     f.write_all("\n];".as_bytes()).unwrap();
+
+    // Build the tables for eight-wide BVHs.  A BVH8 node is made from up to
+    // three levels of BVH2 nodes, which are numbered like a binary heap:
+    // 0 is the top node, 1 and 2 its children, and 3-6 their children.  The
+    // node's shape is a bitmask of which of those are internal nodes, and
+    // each possible shape gets a traversal code.
+    let shapes: Vec<u8> = (0..128u8)
+        .filter(|mask| {
+            (mask & 1) != 0 && ((mask & 0b0011000) == 0 || (mask & 0b10) != 0) &&
+                ((mask & 0b1100000) == 0 || (mask & 0b100) != 0)
+        })
+        .collect();
+
+    let mut traversal_codes = [255u8; 128];
+    for (code, mask) in shapes.iter().enumerate() {
+        traversal_codes[*mask as usize] = code as u8;
+    }
+
+    // For each shape and each combination of BVH2 nodes whose children are
+    // visited in reverse order, the order to visit the BVH8 node's children
+    // in, four bits per child.
+    let mut traversal_table_8 = Vec::new();
+    for mask in shapes.iter() {
+        let mut sub_table = Vec::new();
+        for swaps in 0..128u8 {
+            let mut order = Vec::new();
+            let mut next_child = 0;
+            visit_order_8(*mask, swaps, 0, &mut next_child, &mut order);
+            sub_table.push(
+                order
+                    .iter()
+                    .enumerate()
+                    .fold(0u32, |code, (i, child)| code | (child << (i * 4))),
+            );
+        }
+        traversal_table_8.push(sub_table);
+    }
+
+    // Write the eight-wide tables to Rust file
+    f.write_all(
+        format!(
+            "\n\npub static TRAVERSAL_CODE_8: [u8; 128] = {:?};",
+            &traversal_codes[..]
+        ).as_bytes(),
+    ).unwrap();
+    f.write_all(
+        format!(
+            "\n\npub static TRAVERSAL_TABLE_8: [[u32; 128]; {}] = [",
+            shapes.len()
+        ).as_bytes(),
+    ).unwrap();
+    for sub_table in traversal_table_8.iter() {
+        f.write_all(format!("\n    {:?},", sub_table).as_bytes())
+            .unwrap();
+    }
+    f.write_all("\n];".as_bytes()).unwrap();
+}
+
+/// Appends the children of BVH2 node `node` to `order` in the order they're
+/// visited, given the BVH8 node's shape and which BVH2 nodes are visited in
+/// reverse.  The children are numbered left to right, as assigned by
+/// `next_child`.
+fn visit_order_8(shape: u8, swaps: u8, node: usize, next_child: &mut u32, order: &mut Vec<u32>) {
+    if node < 7 && (shape & (1 << node)) != 0 {
+        let mut left = Vec::new();
+        let mut right = Vec::new();
+        visit_order_8(shape, swaps, (node * 2) + 1, next_child, &mut left);
+        visit_order_8(shape, swaps, (node * 2) + 2, next_child, &mut right);
+        if (swaps & (1 << node)) != 0 {
+            order.extend(right.iter().chain(left.iter()));
+        } else {
+            order.extend(left.iter().chain(right.iter()));
+        }
+    } else {
+        order.push(*next_child);
+        *next_child += 1;
+    }
 }
@@ -1,6 +1,7 @@
 #![allow(dead_code)]
 
-// Include TRAVERSAL_TABLE generated by the build.rs script
+// Include TRAVERSAL_TABLE, TRAVERSAL_CODE_8, and TRAVERSAL_TABLE_8 generated
+// by the build.rs script
 include!(concat!(env!("OUT_DIR"), "/table_inc.rs"));
 
 /// Represents the split axes of the BVH2 node(s) that a BVH4 node was created
@@ -35,3 +36,55 @@ pub fn calc_traversal_code(split: SplitAxes) -> u8 {
         SplitAxes::TopOnly(top) => top + (27 + 9 + 9),
     }
 }
+
+
+/// Calculates the traversal code for a BVH8 node based on the topology of
+/// the BVH2 nodes it was created from.
+///
+/// A BVH8 node is created from up to three levels of BVH2 nodes, which are
+/// numbered like a binary heap: 0 is the top node, 1 and 2 are its left and
+/// right children, 3 and 4 are the children of 1, and 5 and 6 are the
+/// children of 2.  `internal_nodes` has bit `n` set if BVH2 node `n` is an
+/// internal node whose children are part of the BVH8 node.  The BVH8 node's
+/// children are the leaves of that tree, numbered left to right.
+#[inline(always)]
+pub fn calc_traversal_code_8(internal_nodes: u8) -> u8 {
+    let code = TRAVERSAL_CODE_8[internal_nodes as usize & 0x7f];
+    debug_assert!(code != 255, "Invalid BVH8 node topology.");
+    code
+}
+
+/// Returns the order to visit a BVH8 node's children in, four bits per
+/// child with the first child to visit in the lowest bits.
+///
+/// `reversed` has bit `n` set if the children of BVH2 node `n` (numbered as
+/// for `calc_traversal_code_8()`) should be visited right-to-left, which is
+/// normally when the ray is traveling in the negative direction on its split
+/// axis.
+#[inline(always)]
+pub fn traversal_order_8(traversal_code: u8, reversed: u8) -> u32 {
+    TRAVERSAL_TABLE_8[traversal_code as usize][reversed as usize & 0x7f]
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn traversal_order_8_full() {
+        let code = calc_traversal_code_8(0b111_1111);
+        assert_eq!(traversal_order_8(code, 0), 0x7654_3210);
+        assert_eq!(traversal_order_8(code, 0b000_0001), 0x3210_7654);
+        assert_eq!(traversal_order_8(code, 0b111_1111), 0x0123_4567);
+    }
+
+    #[test]
+    fn traversal_order_8_partial() {
+        // Top node with a left child split in two: three children
+        let code = calc_traversal_code_8(0b000_0011);
+        assert_eq!(traversal_order_8(code, 0), 0x210);
+        assert_eq!(traversal_order_8(code, 0b000_0001), 0x102);
+        assert_eq!(traversal_order_8(code, 0b000_0010), 0x201);
+    }
+}
@@ -0,0 +1,360 @@
+#![allow(dead_code)]
+
+use std::cmp::PartialEq;
+use std::ops::{Add, Sub, Mul, Div, BitAnd, BitOr};
+
+#[cfg(all(feature = "simd_perf", target_feature = "avx"))]
+use simd::x86::avx::{f32x8, bool32fx8, AvxF32x8};
+
+/// Essentially a tuple of eight floats, which will use AVX operations
+/// where possible on a platform.
+#[cfg(all(feature = "simd_perf", target_feature = "avx"))]
+#[derive(Debug, Copy, Clone)]
+pub struct Float8 {
+    data: f32x8,
+}
+
+#[cfg(not(all(feature = "simd_perf", target_feature = "avx")))]
+#[derive(Debug, Copy, Clone)]
+pub struct Float8 {
+    data: [f32; 8],
+}
+
+impl Float8 {
+    #[cfg(all(feature = "simd_perf", target_feature = "avx"))]
+    #[inline(always)]
+    pub fn from_array(a: [f32; 8]) -> Float8 {
+        Float8 { data: f32x8::new(a[0], a[1], a[2], a[3], a[4], a[5], a[6], a[7]) }
+    }
+    #[cfg(not(all(feature = "simd_perf", target_feature = "avx")))]
+    #[inline(always)]
+    pub fn from_array(a: [f32; 8]) -> Float8 {
+        Float8 { data: a }
+    }
+
+    #[cfg(all(feature = "simd_perf", target_feature = "avx"))]
+    #[inline(always)]
+    pub fn splat(n: f32) -> Float8 {
+        Float8 { data: f32x8::splat(n) }
+    }
+    #[cfg(not(all(feature = "simd_perf", target_feature = "avx")))]
+    #[inline(always)]
+    pub fn splat(n: f32) -> Float8 {
+        Float8 { data: [n; 8] }
+    }
+
+    #[inline]
+    pub fn h_min(&self) -> f32 {
+        (1..8).fold(self.get_n(0), |m, i| {
+            let n = self.get_n(i);
+            if n < m { n } else { m }
+        })
+    }
+
+    #[inline]
+    pub fn h_max(&self) -> f32 {
+        (1..8).fold(self.get_n(0), |m, i| {
+            let n = self.get_n(i);
+            if n > m { n } else { m }
+        })
+    }
+
+    #[cfg(all(feature = "simd_perf", target_feature = "avx"))]
+    #[inline(always)]
+    pub fn v_min(&self, other: Float8) -> Float8 {
+        Float8 { data: self.data.min(other.data) }
+    }
+    #[cfg(not(all(feature = "simd_perf", target_feature = "avx")))]
+    #[inline(always)]
+    pub fn v_min(&self, other: Float8) -> Float8 {
+        let mut r = *self;
+        for (a, b) in r.data.iter_mut().zip(other.data.iter()) {
+            if *b < *a {
+                *a = *b;
+            }
+        }
+        r
+    }
+
+    #[cfg(all(feature = "simd_perf", target_feature = "avx"))]
+    #[inline(always)]
+    pub fn v_max(&self, other: Float8) -> Float8 {
+        Float8 { data: self.data.max(other.data) }
+    }
+    #[cfg(not(all(feature = "simd_perf", target_feature = "avx")))]
+    #[inline(always)]
+    pub fn v_max(&self, other: Float8) -> Float8 {
+        let mut r = *self;
+        for (a, b) in r.data.iter_mut().zip(other.data.iter()) {
+            if *b > *a {
+                *a = *b;
+            }
+        }
+        r
+    }
+
+    #[cfg(all(feature = "simd_perf", target_feature = "avx"))]
+    #[inline(always)]
+    pub fn lt(&self, other: Float8) -> Bool8 {
+        Bool8 { data: self.data.lt(other.data) }
+    }
+    #[cfg(not(all(feature = "simd_perf", target_feature = "avx")))]
+    #[inline(always)]
+    pub fn lt(&self, other: Float8) -> Bool8 {
+        self.compare(other, |a, b| a < b)
+    }
+
+    #[cfg(all(feature = "simd_perf", target_feature = "avx"))]
+    #[inline(always)]
+    pub fn lte(&self, other: Float8) -> Bool8 {
+        Bool8 { data: self.data.le(other.data) }
+    }
+    #[cfg(not(all(feature = "simd_perf", target_feature = "avx")))]
+    #[inline(always)]
+    pub fn lte(&self, other: Float8) -> Bool8 {
+        self.compare(other, |a, b| a <= b)
+    }
+
+    #[cfg(all(feature = "simd_perf", target_feature = "avx"))]
+    #[inline(always)]
+    pub fn gt(&self, other: Float8) -> Bool8 {
+        Bool8 { data: self.data.gt(other.data) }
+    }
+    #[cfg(not(all(feature = "simd_perf", target_feature = "avx")))]
+    #[inline(always)]
+    pub fn gt(&self, other: Float8) -> Bool8 {
+        self.compare(other, |a, b| a > b)
+    }
+
+    #[cfg(all(feature = "simd_perf", target_feature = "avx"))]
+    #[inline(always)]
+    pub fn gte(&self, other: Float8) -> Bool8 {
+        Bool8 { data: self.data.ge(other.data) }
+    }
+    #[cfg(not(all(feature = "simd_perf", target_feature = "avx")))]
+    #[inline(always)]
+    pub fn gte(&self, other: Float8) -> Bool8 {
+        self.compare(other, |a, b| a >= b)
+    }
+
+    #[cfg(not(all(feature = "simd_perf", target_feature = "avx")))]
+    #[inline(always)]
+    fn compare<F: Fn(f32, f32) -> bool>(&self, other: Float8, f: F) -> Bool8 {
+        let mut r = Bool8 { data: [false; 8] };
+        for (d, (a, b)) in r.data.iter_mut().zip(self.data.iter().zip(other.data.iter())) {
+            *d = f(*a, *b);
+        }
+        r
+    }
+
+    /// Set the nth element to the given value.
+    #[cfg(all(feature = "simd_perf", target_feature = "avx"))]
+    #[inline(always)]
+    pub fn set_n(&mut self, n: usize, v: f32) {
+        assert!(n < 8, "Attempted to set element of Float8 outside of bounds.");
+        self.data = self.data.replace(n as u32, v);
+    }
+    #[cfg(not(all(feature = "simd_perf", target_feature = "avx")))]
+    #[inline(always)]
+    pub fn set_n(&mut self, n: usize, v: f32) {
+        assert!(n < 8, "Attempted to set element of Float8 outside of bounds.");
+        self.data[n] = v;
+    }
+
+    /// Returns the value of the nth element.
+    #[cfg(all(feature = "simd_perf", target_feature = "avx"))]
+    #[inline(always)]
+    pub fn get_n(&self, n: usize) -> f32 {
+        assert!(n < 8, "Attempted to access element of Float8 outside of bounds.");
+        self.data.extract(n as u32)
+    }
+    #[cfg(not(all(feature = "simd_perf", target_feature = "avx")))]
+    #[inline(always)]
+    pub fn get_n(&self, n: usize) -> f32 {
+        assert!(n < 8, "Attempted to access element of Float8 outside of bounds.");
+        self.data[n]
+    }
+}
+
+
+impl PartialEq for Float8 {
+    #[inline]
+    fn eq(&self, other: &Float8) -> bool {
+        (0..8).all(|i| self.get_n(i) == other.get_n(i))
+    }
+}
+
+
+// Implements a component-wise binary operator for `Float8`, both with
+// another `Float8` and with an `f32` applied to every component.
+macro_rules! float8_op {
+    ($trait_name:ident, $fn_name:ident, $op:tt) => {
+        impl $trait_name for Float8 {
+            type Output = Float8;
+
+            #[cfg(all(feature = "simd_perf", target_feature = "avx"))]
+            #[inline(always)]
+            fn $fn_name(self, other: Float8) -> Float8 {
+                Float8 { data: self.data $op other.data }
+            }
+            #[cfg(not(all(feature = "simd_perf", target_feature = "avx")))]
+            #[inline(always)]
+            fn $fn_name(self, other: Float8) -> Float8 {
+                let mut r = self;
+                for (d, (a, b)) in r.data.iter_mut().zip(self.data.iter().zip(other.data.iter())) {
+                    *d = *a $op *b;
+                }
+                r
+            }
+        }
+
+        impl $trait_name<f32> for Float8 {
+            type Output = Float8;
+
+            #[inline(always)]
+            fn $fn_name(self, other: f32) -> Float8 {
+                self $op Float8::splat(other)
+            }
+        }
+    }
+}
+
+float8_op!(Add, add, +);
+float8_op!(Sub, sub, -);
+float8_op!(Mul, mul, *);
+float8_op!(Div, div, /);
+
+
+/// Essentially a tuple of eight bools, which will use AVX operations
+/// where possible on a platform.
+#[cfg(all(feature = "simd_perf", target_feature = "avx"))]
+#[derive(Debug, Copy, Clone)]
+pub struct Bool8 {
+    data: bool32fx8,
+}
+
+#[cfg(not(all(feature = "simd_perf", target_feature = "avx")))]
+#[derive(Debug, Copy, Clone)]
+pub struct Bool8 {
+    data: [bool; 8],
+}
+
+impl Bool8 {
+    /// Returns the value of the nth element.
+    #[cfg(all(feature = "simd_perf", target_feature = "avx"))]
+    #[inline(always)]
+    pub fn get_n(&self, n: usize) -> bool {
+        assert!(n < 8, "Attempted to access element of Bool8 outside of bounds.");
+        self.data.extract(n as u32)
+    }
+    #[cfg(not(all(feature = "simd_perf", target_feature = "avx")))]
+    #[inline(always)]
+    pub fn get_n(&self, n: usize) -> bool {
+        assert!(n < 8, "Attempted to access element of Bool8 outside of bounds.");
+        self.data[n]
+    }
+
+    /// Returns the elements packed into the bits of a byte, with the 0th
+    /// element as the lowest bit.
+    #[inline]
+    pub fn to_bitmask(&self) -> u8 {
+        (0..8).fold(0, |mask, i| mask | ((self.get_n(i) as u8) << i))
+    }
+}
+
+impl BitAnd for Bool8 {
+    type Output = Bool8;
+
+    #[cfg(all(feature = "simd_perf", target_feature = "avx"))]
+    #[inline(always)]
+    fn bitand(self, rhs: Bool8) -> Bool8 {
+        Bool8 { data: self.data & rhs.data }
+    }
+    #[cfg(not(all(feature = "simd_perf", target_feature = "avx")))]
+    #[inline(always)]
+    fn bitand(self, rhs: Bool8) -> Bool8 {
+        let mut r = self;
+        for (a, b) in r.data.iter_mut().zip(rhs.data.iter()) {
+            *a = *a && *b;
+        }
+        r
+    }
+}
+
+impl BitOr for Bool8 {
+    type Output = Bool8;
+
+    #[cfg(all(feature = "simd_perf", target_feature = "avx"))]
+    #[inline(always)]
+    fn bitor(self, rhs: Bool8) -> Bool8 {
+        Bool8 { data: self.data | rhs.data }
+    }
+    #[cfg(not(all(feature = "simd_perf", target_feature = "avx")))]
+    #[inline(always)]
+    fn bitor(self, rhs: Bool8) -> Bool8 {
+        let mut r = self;
+        for (a, b) in r.data.iter_mut().zip(rhs.data.iter()) {
+            *a = *a || *b;
+        }
+        r
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn f8() -> Float8 {
+        Float8::from_array([1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0, 8.0])
+    }
+
+    #[test]
+    fn get_set_n() {
+        let mut f = f8();
+        assert_eq!(f.get_n(0), 1.0);
+        assert_eq!(f.get_n(7), 8.0);
+        f.set_n(5, 10.0);
+        assert_eq!(f.get_n(5), 10.0);
+    }
+
+    #[test]
+    fn h_min_max() {
+        let f = Float8::from_array([3.0, 2.0, 8.0, 4.0, -1.0, 6.0, 7.0, 5.0]);
+        assert_eq!(f.h_min(), -1.0);
+        assert_eq!(f.h_max(), 8.0);
+    }
+
+    #[test]
+    fn v_min_max() {
+        let f1 = f8();
+        let f2 = Float8::splat(4.5);
+        assert_eq!(
+            f1.v_min(f2),
+            Float8::from_array([1.0, 2.0, 3.0, 4.0, 4.5, 4.5, 4.5, 4.5])
+        );
+        assert_eq!(
+            f1.v_max(f2),
+            Float8::from_array([4.5, 4.5, 4.5, 4.5, 5.0, 6.0, 7.0, 8.0])
+        );
+    }
+
+    #[test]
+    fn arithmetic() {
+        let f = f8();
+        assert_eq!(f + f, f * 2.0);
+        assert_eq!(f - f, Float8::splat(0.0));
+        assert_eq!((f * f) / f, f);
+    }
+
+    #[test]
+    fn compare_to_bitmask() {
+        let f = f8();
+        let g = Float8::splat(4.0);
+        assert_eq!(f.lt(g).to_bitmask(), 0b0000_0111);
+        assert_eq!(f.lte(g).to_bitmask(), 0b0000_1111);
+        assert_eq!(f.gt(g).to_bitmask(), 0b1111_0000);
+        assert_eq!((f.gte(g) & f.lt(Float8::splat(7.0))).to_bitmask(), 0b0011_1000);
+        assert_eq!((f.lt(g) | f.gt(g)).to_bitmask(), 0b1111_0111);
+    }
+}
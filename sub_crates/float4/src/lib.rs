@@ -9,6 +9,10 @@ use std::ops::{Add, AddAssign, Sub, SubAssign, Mul, MulAssign, Div, DivAssign, B
 #[cfg(feature = "simd_perf")]
 use simd::{f32x4, bool32fx4};
 
+mod float8;
+
+pub use float8::{Float8, Bool8};
+
 /// Essentially a tuple of four floats, which will use SIMD operations
 /// where possible on a platform.
 #[cfg(feature = "simd_perf")]
@@ -5,11 +5,15 @@ extern crate float4;
 mod matrix;
 mod normal;
 mod point;
+mod quaternion;
+mod transform;
 mod vector;
 
 pub use self::matrix::Matrix4x4;
 pub use self::normal::Normal;
 pub use self::point::Point;
+pub use self::quaternion::Quaternion;
+pub use self::transform::Transform;
 pub use self::vector::Vector;
 
 /// Trait for calculating dot products.
@@ -0,0 +1,200 @@
+#![allow(dead_code)]
+
+
+/// A unit quaternion, used to represent rotations.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Quaternion {
+    pub x: f32,
+    pub y: f32,
+    pub z: f32,
+    pub w: f32,
+}
+
+
+impl Quaternion {
+    /// Creates a new identity rotation
+    #[inline]
+    pub fn new() -> Quaternion {
+        Quaternion {
+            x: 0.0,
+            y: 0.0,
+            z: 0.0,
+            w: 1.0,
+        }
+    }
+
+    /// Creates a rotation of `angle` radians around the given axis.
+    pub fn from_axis_angle(axis: (f32, f32, f32), angle: f32) -> Quaternion {
+        let len = ((axis.0 * axis.0) + (axis.1 * axis.1) + (axis.2 * axis.2)).sqrt();
+        let s = (angle * 0.5).sin() / len;
+        Quaternion {
+            x: axis.0 * s,
+            y: axis.1 * s,
+            z: axis.2 * s,
+            w: (angle * 0.5).cos(),
+        }
+    }
+
+    /// Creates a quaternion from a 3x3 rotation matrix, stored in row-major
+    /// order.  The matrix must be a proper rotation, without any scaling or
+    /// reflection.
+    pub fn from_rotation_matrix(m: &[[f32; 3]; 3]) -> Quaternion {
+        // Based on "Quaternion Calculus and Fast Animation" by Ken Shoemake,
+        // picking the largest component to divide by for stability.
+        let trace = m[0][0] + m[1][1] + m[2][2];
+        let q = if trace > 0.0 {
+            let s = (trace + 1.0).sqrt() * 2.0;
+            Quaternion {
+                x: (m[2][1] - m[1][2]) / s,
+                y: (m[0][2] - m[2][0]) / s,
+                z: (m[1][0] - m[0][1]) / s,
+                w: 0.25 * s,
+            }
+        } else if m[0][0] > m[1][1] && m[0][0] > m[2][2] {
+            let s = (1.0 + m[0][0] - m[1][1] - m[2][2]).sqrt() * 2.0;
+            Quaternion {
+                x: 0.25 * s,
+                y: (m[0][1] + m[1][0]) / s,
+                z: (m[0][2] + m[2][0]) / s,
+                w: (m[2][1] - m[1][2]) / s,
+            }
+        } else if m[1][1] > m[2][2] {
+            let s = (1.0 + m[1][1] - m[0][0] - m[2][2]).sqrt() * 2.0;
+            Quaternion {
+                x: (m[0][1] + m[1][0]) / s,
+                y: 0.25 * s,
+                z: (m[1][2] + m[2][1]) / s,
+                w: (m[0][2] - m[2][0]) / s,
+            }
+        } else {
+            let s = (1.0 + m[2][2] - m[0][0] - m[1][1]).sqrt() * 2.0;
+            Quaternion {
+                x: (m[0][2] + m[2][0]) / s,
+                y: (m[1][2] + m[2][1]) / s,
+                z: 0.25 * s,
+                w: (m[1][0] - m[0][1]) / s,
+            }
+        };
+
+        q.normalized()
+    }
+
+    /// Returns the 3x3 rotation matrix of the quaternion, in row-major order.
+    pub fn to_rotation_matrix(&self) -> [[f32; 3]; 3] {
+        let (x, y, z, w) = (self.x, self.y, self.z, self.w);
+        [
+            [
+                1.0 - (2.0 * ((y * y) + (z * z))),
+                2.0 * ((x * y) - (z * w)),
+                2.0 * ((x * z) + (y * w)),
+            ],
+            [
+                2.0 * ((x * y) + (z * w)),
+                1.0 - (2.0 * ((x * x) + (z * z))),
+                2.0 * ((y * z) - (x * w)),
+            ],
+            [
+                2.0 * ((x * z) - (y * w)),
+                2.0 * ((y * z) + (x * w)),
+                1.0 - (2.0 * ((x * x) + (y * y))),
+            ],
+        ]
+    }
+
+    #[inline]
+    pub fn dot(&self, other: Quaternion) -> f32 {
+        (self.x * other.x) + (self.y * other.y) + (self.z * other.z) + (self.w * other.w)
+    }
+
+    #[inline]
+    pub fn normalized(&self) -> Quaternion {
+        let len = self.dot(*self).sqrt();
+        Quaternion {
+            x: self.x / len,
+            y: self.y / len,
+            z: self.z / len,
+            w: self.w / len,
+        }
+    }
+
+    /// Returns the angle in radians of the smallest rotation between this
+    /// rotation and `other`.
+    pub fn angle_to(&self, other: Quaternion) -> f32 {
+        let d = self.dot(other).abs().min(1.0);
+        2.0 * d.acos()
+    }
+
+    /// Spherical linear interpolation between two rotations, taking the
+    /// shortest path between them.
+    pub fn slerp(&self, other: Quaternion, alpha: f32) -> Quaternion {
+        // Flip to the same hemisphere, so that the interpolation takes the
+        // shortest path.
+        let (other, d) = {
+            let d = self.dot(other);
+            if d < 0.0 {
+                (
+                    Quaternion {
+                        x: -other.x,
+                        y: -other.y,
+                        z: -other.z,
+                        w: -other.w,
+                    },
+                    -d,
+                )
+            } else {
+                (other, d)
+            }
+        };
+
+        let (fac1, fac2) = if d > 0.9995 {
+            // Close enough that plain linear interpolation works, and avoids
+            // dividing by (almost) zero.
+            (1.0 - alpha, alpha)
+        } else {
+            let angle = d.acos();
+            let sin_angle = angle.sin();
+            (
+                ((1.0 - alpha) * angle).sin() / sin_angle,
+                (alpha * angle).sin() / sin_angle,
+            )
+        };
+
+        Quaternion {
+            x: (self.x * fac1) + (other.x * fac2),
+            y: (self.y * fac1) + (other.y * fac2),
+            z: (self.z * fac1) + (other.z * fac2),
+            w: (self.w * fac1) + (other.w * fac2),
+        }.normalized()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f32::consts::PI;
+
+    #[test]
+    fn rotation_matrix_round_trip() {
+        let q = Quaternion::from_axis_angle((1.0, 2.0, -0.5), 2.5);
+        let q2 = Quaternion::from_rotation_matrix(&q.to_rotation_matrix());
+        assert!(q.dot(q2).abs() > 0.99999);
+    }
+
+    #[test]
+    fn slerp_halfway() {
+        let q1 = Quaternion::new();
+        let q2 = Quaternion::from_axis_angle((0.0, 0.0, 1.0), PI * 0.5);
+        let q3 = Quaternion::from_axis_angle((0.0, 0.0, 1.0), PI * 0.25);
+        assert!(q1.slerp(q2, 0.5).dot(q3) > 0.99999);
+        assert!((q1.angle_to(q2) - (PI * 0.5)).abs() < 0.0001);
+    }
+
+    #[test]
+    fn slerp_shortest_path() {
+        let q1 = Quaternion::from_axis_angle((0.0, 0.0, 1.0), PI * 0.9);
+        let q2 = Quaternion::from_axis_angle((0.0, 0.0, 1.0), PI * -0.9);
+        let q3 = Quaternion::from_axis_angle((0.0, 0.0, 1.0), PI);
+        assert!(q1.slerp(q2, 0.5).dot(q3).abs() > 0.99999);
+    }
+}
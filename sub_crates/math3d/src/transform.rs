@@ -0,0 +1,449 @@
+#![allow(dead_code)]
+
+use std::ops::Mul;
+
+use float4::Float4;
+
+use super::{Matrix4x4, Quaternion, Vector};
+
+
+/// An affine transform decomposed into translation, rotation, and scale.
+///
+/// The transformation it represents is scale, then rotation, then
+/// translation.  Scale is a full matrix, so that it can also hold any
+/// shearing, and is symmetric for transforms decomposed from a matrix.
+/// Unlike matrices, decomposed transforms can be interpolated without the
+/// rotation shrinking or shearing the result.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Transform {
+    pub translation: Vector,
+    pub rotation: Quaternion,
+    pub scale: [[f32; 3]; 3], // Row-major
+}
+
+
+impl Transform {
+    /// Creates a new identity transform
+    pub fn new() -> Transform {
+        Transform {
+            translation: Vector::new(0.0, 0.0, 0.0),
+            rotation: Quaternion::new(),
+            scale: [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]],
+        }
+    }
+
+    /// Decomposes an affine matrix.  The bottom row of the matrix is
+    /// ignored.
+    pub fn from_matrix(mat: Matrix4x4) -> Transform {
+        let translation = Vector::new(mat[0].get_3(), mat[1].get_3(), mat[2].get_3());
+
+        // Polar decomposition of the upper-left 3x3 part of the matrix
+        // into rotation * scale, as described in "Matrix Animation and Polar
+        // Decomposition" by Shoemake and Duff.  The rotation is found by
+        // repeatedly averaging the matrix with its inverse transpose.
+        let m = {
+            let mut m = [[0.0f64; 3]; 3];
+            for (y, row) in m.iter_mut().enumerate() {
+                for (x, v) in row.iter_mut().enumerate() {
+                    *v = mat[y].get_n(x) as f64;
+                }
+            }
+            m
+        };
+        let mut rot = m;
+        for _ in 0..100 {
+            let inv_t = match inverse_3x3(&rot) {
+                Some(inv) => transpose_3x3(&inv),
+                None => break, // Degenerate matrix, so there's no rotation to find
+            };
+
+            let mut diff = 0.0f64;
+            for y in 0..3 {
+                for x in 0..3 {
+                    let v = (rot[y][x] + inv_t[y][x]) * 0.5;
+                    diff = diff.max((v - rot[y][x]).abs());
+                    rot[y][x] = v;
+                }
+            }
+
+            if diff < 1.0e-12 {
+                break;
+            }
+        }
+
+        // Make sure the rotation doesn't contain a reflection, leaving that
+        // to the scale instead.
+        if determinant_3x3(&rot) < 0.0 {
+            for row in &mut rot {
+                for v in row.iter_mut() {
+                    *v = -*v;
+                }
+            }
+        }
+
+        // Scale = rotation^-1 * matrix
+        let scale = mul_3x3(&transpose_3x3(&rot), &m);
+
+        Transform {
+            translation: translation,
+            rotation: Quaternion::from_rotation_matrix(&to_f32_3x3(&rot)),
+            scale: to_f32_3x3(&scale),
+        }
+    }
+
+    /// Recomposes the transform into a matrix.
+    pub fn to_matrix(&self) -> Matrix4x4 {
+        let rot = self.rotation.to_rotation_matrix();
+        let mut m = [[0.0f32; 3]; 3];
+        for (y, row) in m.iter_mut().enumerate() {
+            for (x, v) in row.iter_mut().enumerate() {
+                *v = (rot[y][0] * self.scale[0][x]) + (rot[y][1] * self.scale[1][x]) +
+                    (rot[y][2] * self.scale[2][x]);
+            }
+        }
+
+        Matrix4x4 {
+            values: [
+                Float4::new(m[0][0], m[0][1], m[0][2], self.translation.x()),
+                Float4::new(m[1][0], m[1][1], m[1][2], self.translation.y()),
+                Float4::new(m[2][0], m[2][1], m[2][2], self.translation.z()),
+                Float4::new(0.0, 0.0, 0.0, 1.0),
+            ],
+        }
+    }
+
+    /// Recomposes the inverse of the transform into a matrix, which is
+    /// cheaper than inverting the result of `to_matrix()`.
+    pub fn to_inverse_matrix(&self) -> Matrix4x4 {
+        // The inverse of the scale, by way of its adjugate
+        let s = &self.scale;
+        let inv_s = {
+            let adj = [
+                [
+                    (s[1][1] * s[2][2]) - (s[1][2] * s[2][1]),
+                    (s[0][2] * s[2][1]) - (s[0][1] * s[2][2]),
+                    (s[0][1] * s[1][2]) - (s[0][2] * s[1][1]),
+                ],
+                [
+                    (s[1][2] * s[2][0]) - (s[1][0] * s[2][2]),
+                    (s[0][0] * s[2][2]) - (s[0][2] * s[2][0]),
+                    (s[0][2] * s[1][0]) - (s[0][0] * s[1][2]),
+                ],
+                [
+                    (s[1][0] * s[2][1]) - (s[1][1] * s[2][0]),
+                    (s[0][1] * s[2][0]) - (s[0][0] * s[2][1]),
+                    (s[0][0] * s[1][1]) - (s[0][1] * s[1][0]),
+                ],
+            ];
+            let inv_det = 1.0 /
+                ((s[0][0] * adj[0][0]) + (s[0][1] * adj[1][0]) + (s[0][2] * adj[2][0]));
+            let mut inv_s = adj;
+            for row in &mut inv_s {
+                for v in row.iter_mut() {
+                    *v *= inv_det;
+                }
+            }
+            inv_s
+        };
+
+        // (rotation * scale)^-1 = scale^-1 * rotation^T
+        let rot = self.rotation.to_rotation_matrix();
+        let mut m = [[0.0f32; 3]; 3];
+        for (y, row) in m.iter_mut().enumerate() {
+            for (x, v) in row.iter_mut().enumerate() {
+                *v = (inv_s[y][0] * rot[x][0]) + (inv_s[y][1] * rot[x][1]) +
+                    (inv_s[y][2] * rot[x][2]);
+            }
+        }
+
+        let t = self.translation;
+        let translation = |y: usize| -((m[y][0] * t.x()) + (m[y][1] * t.y()) + (m[y][2] * t.z()));
+        Matrix4x4 {
+            values: [
+                Float4::new(m[0][0], m[0][1], m[0][2], translation(0)),
+                Float4::new(m[1][0], m[1][1], m[1][2], translation(1)),
+                Float4::new(m[2][0], m[2][1], m[2][2], translation(2)),
+                Float4::new(0.0, 0.0, 0.0, 1.0),
+            ],
+        }
+    }
+
+    /// Interpolates between two transforms, linearly for translation and
+    /// scale, and spherically for rotation.
+    pub fn lerp(&self, other: Transform, alpha: f32) -> Transform {
+        let alpha_minus = 1.0 - alpha;
+        let mut scale = [[0.0f32; 3]; 3];
+        for (y, row) in scale.iter_mut().enumerate() {
+            for (x, v) in row.iter_mut().enumerate() {
+                *v = (self.scale[y][x] * alpha_minus) + (other.scale[y][x] * alpha);
+            }
+        }
+
+        Transform {
+            translation: (self.translation * alpha_minus) + (other.translation * alpha),
+            rotation: self.rotation.slerp(other.rotation, alpha),
+            scale: scale,
+        }
+    }
+}
+
+
+/// Composes two transforms without decomposing the result again, with the
+/// same order as multiplying their matrices: `a * b` applies `a` first.
+impl Mul<Transform> for Transform {
+    type Output = Transform;
+
+    fn mul(self, other: Transform) -> Transform {
+        compose(&self, &other)
+    }
+}
+
+
+/// Composes `first` followed by `second`.
+fn compose(first: &Transform, second: &Transform) -> Transform {
+    // T2 R2 S2 T1 R1 S1 = T(t2 + R2 S2 t1) (R2 R1) (R1^T S2 R1 S1)
+    let rot1 = first.rotation.to_rotation_matrix();
+    let rot2 = second.rotation.to_rotation_matrix();
+    let rot1_t = {
+        let mut m = [[0.0f32; 3]; 3];
+        for y in 0..3 {
+            for x in 0..3 {
+                m[y][x] = rot1[x][y];
+            }
+        }
+        m
+    };
+
+    let rs2 = mul_3x3_f32(&rot2, &second.scale);
+    let t1 = first.translation;
+    let translation = |y: usize| {
+        (rs2[y][0] * t1.x()) + (rs2[y][1] * t1.y()) + (rs2[y][2] * t1.z())
+    };
+    let scale = mul_3x3_f32(
+        &mul_3x3_f32(&mul_3x3_f32(&rot1_t, &second.scale), &rot1),
+        &first.scale,
+    );
+
+    Transform {
+        translation: second.translation +
+            Vector::new(translation(0), translation(1), translation(2)),
+        rotation: Quaternion::from_rotation_matrix(&mul_3x3_f32(&rot2, &rot1)),
+        scale: scale,
+    }
+}
+
+
+fn mul_3x3_f32(a: &[[f32; 3]; 3], b: &[[f32; 3]; 3]) -> [[f32; 3]; 3] {
+    let mut m = [[0.0f32; 3]; 3];
+    for y in 0..3 {
+        for x in 0..3 {
+            m[y][x] = (a[y][0] * b[0][x]) + (a[y][1] * b[1][x]) + (a[y][2] * b[2][x]);
+        }
+    }
+    m
+}
+
+fn to_f32_3x3(m: &[[f64; 3]; 3]) -> [[f32; 3]; 3] {
+    let mut m2 = [[0.0f32; 3]; 3];
+    for y in 0..3 {
+        for x in 0..3 {
+            m2[y][x] = m[y][x] as f32;
+        }
+    }
+    m2
+}
+
+fn transpose_3x3(m: &[[f64; 3]; 3]) -> [[f64; 3]; 3] {
+    let mut m2 = [[0.0f64; 3]; 3];
+    for y in 0..3 {
+        for x in 0..3 {
+            m2[y][x] = m[x][y];
+        }
+    }
+    m2
+}
+
+fn mul_3x3(a: &[[f64; 3]; 3], b: &[[f64; 3]; 3]) -> [[f64; 3]; 3] {
+    let mut m = [[0.0f64; 3]; 3];
+    for y in 0..3 {
+        for x in 0..3 {
+            m[y][x] = (a[y][0] * b[0][x]) + (a[y][1] * b[1][x]) + (a[y][2] * b[2][x]);
+        }
+    }
+    m
+}
+
+fn determinant_3x3(m: &[[f64; 3]; 3]) -> f64 {
+    (m[0][0] * ((m[1][1] * m[2][2]) - (m[1][2] * m[2][1]))) -
+        (m[0][1] * ((m[1][0] * m[2][2]) - (m[1][2] * m[2][0]))) +
+        (m[0][2] * ((m[1][0] * m[2][1]) - (m[1][1] * m[2][0])))
+}
+
+fn inverse_3x3(m: &[[f64; 3]; 3]) -> Option<[[f64; 3]; 3]> {
+    let det = determinant_3x3(m);
+    if det.abs() < 1.0e-30 {
+        return None;
+    }
+    let inv_det = 1.0 / det;
+
+    Some(
+        [
+            [
+                ((m[1][1] * m[2][2]) - (m[1][2] * m[2][1])) * inv_det,
+                ((m[0][2] * m[2][1]) - (m[0][1] * m[2][2])) * inv_det,
+                ((m[0][1] * m[1][2]) - (m[0][2] * m[1][1])) * inv_det,
+            ],
+            [
+                ((m[1][2] * m[2][0]) - (m[1][0] * m[2][2])) * inv_det,
+                ((m[0][0] * m[2][2]) - (m[0][2] * m[2][0])) * inv_det,
+                ((m[0][2] * m[1][0]) - (m[0][0] * m[1][2])) * inv_det,
+            ],
+            [
+                ((m[1][0] * m[2][1]) - (m[1][1] * m[2][0])) * inv_det,
+                ((m[0][1] * m[2][0]) - (m[0][0] * m[2][1])) * inv_det,
+                ((m[0][0] * m[1][1]) - (m[0][1] * m[1][0])) * inv_det,
+            ],
+        ],
+    )
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f32::consts::PI;
+    use super::super::Point;
+
+    fn aprx_eq(a: Matrix4x4, b: Matrix4x4) -> bool {
+        (0..4).all(|y| (0..4).all(|x| (a[y].get_n(x) - b[y].get_n(x)).abs() < 0.0001))
+    }
+
+    #[test]
+    fn decompose_and_recompose() {
+        let mat = Matrix4x4::new_from_values(
+            0.0,
+            -2.0,
+            0.5,
+            1.0,
+            3.0,
+            0.0,
+            0.0,
+            2.0,
+            0.0,
+            0.5,
+            1.5,
+            -3.0,
+            0.0,
+            0.0,
+            0.0,
+            1.0,
+        );
+        let xform = Transform::from_matrix(mat);
+        assert!(aprx_eq(xform.to_matrix(), mat));
+    }
+
+    #[test]
+    fn recompose_inverse() {
+        let mat = Matrix4x4::new_from_values(
+            0.0,
+            -2.0,
+            0.5,
+            1.0,
+            3.0,
+            0.0,
+            0.0,
+            2.0,
+            0.0,
+            0.5,
+            1.5,
+            -3.0,
+            0.0,
+            0.0,
+            0.0,
+            1.0,
+        );
+        let xform = Transform::from_matrix(mat);
+        assert!(aprx_eq(xform.to_inverse_matrix(), mat.inverse()));
+    }
+
+    #[test]
+    fn decompose_reflection() {
+        let mat = Matrix4x4::new_from_values(
+            -1.0,
+            0.0,
+            0.0,
+            0.0,
+            0.0,
+            2.0,
+            0.0,
+            0.0,
+            0.0,
+            0.0,
+            3.0,
+            0.0,
+            0.0,
+            0.0,
+            0.0,
+            1.0,
+        );
+        let xform = Transform::from_matrix(mat);
+        assert!(aprx_eq(xform.to_matrix(), mat));
+    }
+
+    #[test]
+    fn compose() {
+        let mat1 = Matrix4x4::new_from_values(
+            0.0,
+            -2.0,
+            0.5,
+            1.0,
+            3.0,
+            0.0,
+            0.0,
+            2.0,
+            0.0,
+            0.5,
+            1.5,
+            -3.0,
+            0.0,
+            0.0,
+            0.0,
+            1.0,
+        );
+        let mat2 = Matrix4x4::new_from_values(
+            1.0,
+            0.0,
+            0.0,
+            -4.0,
+            0.0,
+            0.0,
+            -2.0,
+            0.5,
+            0.0,
+            3.0,
+            0.0,
+            1.0,
+            0.0,
+            0.0,
+            0.0,
+            1.0,
+        );
+        let xform = Transform::from_matrix(mat1) * Transform::from_matrix(mat2);
+        assert!(aprx_eq(xform.to_matrix(), mat1 * mat2));
+        assert!(aprx_eq(xform.to_inverse_matrix(), (mat1 * mat2).inverse()));
+    }
+
+    #[test]
+    fn lerp_rotation() {
+        let mut a = Transform::new();
+        a.translation = Vector::new(1.0, 2.0, 3.0);
+        let mut b = a;
+        b.rotation = Quaternion::from_axis_angle((0.0, 0.0, 1.0), PI * 0.5);
+
+        // The point stays the same distance from the center of rotation.
+        let p = Point::new(1.0, 0.0, 0.0) * a.lerp(b, 0.5).to_matrix();
+        let v = p - Point::new(1.0, 2.0, 3.0);
+        assert!((v.length() - 1.0).abs() < 0.0001);
+        assert!((v.x() - v.y()).abs() < 0.0001);
+    }
+}
@@ -1,5 +1,6 @@
 use std::slice;
-use std::cell::{Cell, RefCell};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::mem::{size_of, align_of};
 use std::cmp::max;
 
@@ -20,24 +21,27 @@ fn alignment_offset(addr: usize, alignment: usize) -> usize {
 ///
 /// Additionally, it attempts to minimize wasted space through some heuristics.  By
 /// default, it tries to keep memory waste within the arena below 10%.
+///
+/// The arena can be shared between threads.  Allocations lock it only long
+/// enough to hand out the memory.
 #[derive(Debug)]
 pub struct MemArena {
-    blocks: RefCell<Vec<Vec<u8>>>,
+    blocks: Mutex<Vec<Vec<u8>>>,
     min_block_size: usize,
     max_waste_percentage: usize,
-    stat_space_occupied: Cell<usize>,
-    stat_space_allocated: Cell<usize>,
+    stat_space_occupied: AtomicUsize,
+    stat_space_allocated: AtomicUsize,
 }
 
 impl MemArena {
     /// Create a new arena, with default minimum block size.
     pub fn new() -> MemArena {
         MemArena {
-            blocks: RefCell::new(vec![Vec::with_capacity(DEFAULT_MIN_BLOCK_SIZE)]),
+            blocks: Mutex::new(vec![Vec::with_capacity(DEFAULT_MIN_BLOCK_SIZE)]),
             min_block_size: DEFAULT_MIN_BLOCK_SIZE,
             max_waste_percentage: DEFAULT_MAX_WASTE_PERCENTAGE,
-            stat_space_occupied: Cell::new(DEFAULT_MIN_BLOCK_SIZE),
-            stat_space_allocated: Cell::new(0),
+            stat_space_occupied: AtomicUsize::new(DEFAULT_MIN_BLOCK_SIZE),
+            stat_space_allocated: AtomicUsize::new(0),
         }
     }
 
@@ -46,11 +50,11 @@ impl MemArena {
         assert!(min_block_size > 0);
 
         MemArena {
-            blocks: RefCell::new(vec![Vec::with_capacity(min_block_size)]),
+            blocks: Mutex::new(vec![Vec::with_capacity(min_block_size)]),
             min_block_size: min_block_size,
             max_waste_percentage: DEFAULT_MAX_WASTE_PERCENTAGE,
-            stat_space_occupied: Cell::new(min_block_size),
-            stat_space_allocated: Cell::new(0),
+            stat_space_occupied: AtomicUsize::new(min_block_size),
+            stat_space_allocated: AtomicUsize::new(0),
         }
     }
 
@@ -60,11 +64,11 @@ impl MemArena {
         assert!(max_waste_percentage > 0 && max_waste_percentage <= 100);
 
         MemArena {
-            blocks: RefCell::new(vec![Vec::with_capacity(min_block_size)]),
+            blocks: Mutex::new(vec![Vec::with_capacity(min_block_size)]),
             min_block_size: min_block_size,
             max_waste_percentage: max_waste_percentage,
-            stat_space_occupied: Cell::new(min_block_size),
-            stat_space_allocated: Cell::new(0),
+            stat_space_occupied: AtomicUsize::new(min_block_size),
+            stat_space_allocated: AtomicUsize::new(0),
         }
     }
 
@@ -80,11 +84,11 @@ impl MemArena {
     ///
     /// Block count is the number of blocks that have been allocated.
     pub fn stats(&self) -> (usize, usize, usize) {
-        let occupied = self.stat_space_occupied.get();
-        let allocated = self.stat_space_allocated.get();
-        let blocks = self.blocks.borrow().len();
+        let blocks = self.blocks.lock().unwrap();
+        let occupied = self.stat_space_occupied.load(Ordering::Relaxed);
+        let allocated = self.stat_space_allocated.load(Ordering::Relaxed);
 
-        (occupied, allocated, blocks)
+        (occupied, allocated, blocks.len())
     }
 
     /// Frees all memory currently allocated by the arena, resetting itself to start
@@ -93,14 +97,14 @@ impl MemArena {
     /// CAUTION: this is unsafe because it does NOT ensure that all references to the data are
     /// gone, so this can potentially lead to dangling references.
     pub unsafe fn free_all_and_reset(&self) {
-        let mut blocks = self.blocks.borrow_mut();
+        let mut blocks = self.blocks.lock().unwrap();
 
         blocks.clear();
         blocks.shrink_to_fit();
         blocks.push(Vec::with_capacity(self.min_block_size));
 
-        self.stat_space_occupied.set(self.min_block_size);
-        self.stat_space_allocated.set(0);
+        self.stat_space_occupied.store(self.min_block_size, Ordering::Relaxed);
+        self.stat_space_allocated.store(0, Ordering::Relaxed);
     }
 
     /// Allocates memory for and initializes a type T, returning a mutable reference to it.
@@ -261,12 +265,11 @@ impl MemArena {
     unsafe fn alloc_raw(&self, size: usize, alignment: usize) -> *mut u8 {
         assert!(alignment > 0);
 
-        self.stat_space_allocated.set(
-            self.stat_space_allocated.get() +
-                size,
-        ); // Update stats
+        // The stats are only updated while the blocks are locked, so they
+        // stay consistent with each other.
+        let mut blocks = self.blocks.lock().unwrap();
 
-        let mut blocks = self.blocks.borrow_mut();
+        self.stat_space_allocated.fetch_add(size, Ordering::Relaxed); // Update stats
 
         // If it's a zero-size allocation, just point to the beginning of the current block.
         if size == 0 {
@@ -290,7 +293,7 @@ impl MemArena {
             // If it won't fit in the current block, create a new block and use that.
             else {
                 let next_size = if blocks.len() >= GROWTH_FRACTION {
-                    let a = self.stat_space_occupied.get() / GROWTH_FRACTION;
+                    let a = self.stat_space_occupied.load(Ordering::Relaxed) / GROWTH_FRACTION;
                     let b = a % self.min_block_size;
                     if b > 0 {
                         a - b + self.min_block_size
@@ -301,21 +304,23 @@ impl MemArena {
                     self.min_block_size
                 };
 
+                // Note: the allocated space already includes this
+                // allocation, so it can exceed the occupied space here.
                 let waste_percentage = {
                     let w1 = ((blocks[0].capacity() - blocks[0].len()) * 100) /
                         blocks[0].capacity();
-                    let w2 = ((self.stat_space_occupied.get() - self.stat_space_allocated.get()) *
-                                  100) /
-                        self.stat_space_occupied.get();
+                    let occupied = self.stat_space_occupied.load(Ordering::Relaxed);
+                    let allocated = self.stat_space_allocated.load(Ordering::Relaxed);
+                    let w2 = (occupied.saturating_sub(allocated) * 100) / occupied;
                     if w1 < w2 { w1 } else { w2 }
                 };
 
                 // If it's a "large allocation", give it its own memory block.
                 if (size + alignment) > next_size || waste_percentage > self.max_waste_percentage {
                     // Update stats
-                    self.stat_space_occupied.set(
-                        self.stat_space_occupied.get() + size + alignment -
-                            1,
+                    self.stat_space_occupied.fetch_add(
+                        size + alignment - 1,
+                        Ordering::Relaxed,
                     );
 
                     blocks.push(Vec::with_capacity(size + alignment - 1));
@@ -330,10 +335,7 @@ impl MemArena {
                 // Otherwise create a new shared block.
                 else {
                     // Update stats
-                    self.stat_space_occupied.set(
-                        self.stat_space_occupied.get() +
-                            next_size,
-                    );
+                    self.stat_space_occupied.fetch_add(next_size, Ordering::Relaxed);
 
                     blocks.push(Vec::with_capacity(next_size));
                     let block_count = blocks.len();